@@ -0,0 +1,279 @@
+//! Syscall numbers, errors and wrappers shared by the kernel and user programs
+//!
+//! See `syscalls_doc.txt` for the calling convention, in short: `int 0xf0` with
+//! the id in `rax`, arguments in `r9`, `r10`, `r11`, `r12` and the result in
+//! `r15`. Results above `-4096` (as `i64`) are a negated `Error`, anything else
+//! is the value. Buffers are always given as pointer + length, strings are not
+//! NUL terminated.
+#![cfg_attr(not(test), no_std)]
+
+pub mod line;
+
+pub const VECTOR: u8 = 0xf0;
+
+/// Syscall ids (`rax`)
+pub mod id {
+    pub const CONSOLE_WRITE: u64 = 0x100;
+    pub const CONSOLE_READ: u64 = 0x101;
+
+    pub const ABORT: u64 = 0x600;
+    pub const EXIT: u64 = 0x602;
+    pub const GETPID: u64 = 0x603;
+
+    pub const YIELD: u64 = 0x700;
+    pub const NEW_WORKER: u64 = 0x710;
+    pub const NEW_TASK: u64 = 0x711;
+    pub const WORKER_COUNT: u64 = 0x712;
+
+    pub const VFS_LOOKUP: u64 = 0x900;
+    pub const VFS_READ: u64 = 0x901;
+    pub const VFS_WRITE: u64 = 0x902;
+    pub const VFS_CHILD: u64 = 0x903;
+    pub const VFS_NAME: u64 = 0x904;
+    pub const VFS_PARENT: u64 = 0x905;
+    pub const VFS_OPEN: u64 = 0x906;
+    pub const VFS_CLOSE: u64 = 0x907;
+    pub const VFS_SEEK: u64 = 0x908;
+    pub const VFS_STAT: u64 = 0x909;
+    pub const VFS_PATH: u64 = 0x90a;
+
+    pub const POLICY_HAS_CAPABILITY: u64 = 0xa00;
+    pub const POLICY_USER_NAME: u64 = 0xa01;
+    pub const POLICY_GROUP_NAME: u64 = 0xa02;
+    pub const POLICY_WHOAMI: u64 = 0xa03;
+
+    pub const DEBUG_COMMAND: u64 = 0xb00;
+    pub const DEBUG_COMMAND_NAME: u64 = 0xb01;
+}
+
+/// Same bits as `policy::Capability` in the kernel
+pub mod capability {
+    pub const READ_FILESYSTEM: u16 = 0x01;
+    pub const WRITE_LOG: u16 = 0x02;
+    pub const SPAWN_TASK: u16 = 0x04;
+    pub const NETWORK_ACCESS: u16 = 0x08;
+    /// Kernel console commands, raw memory access
+    pub const DEBUG: u16 = 0x10;
+}
+
+/// `whence` of `sys::vfs_seek`
+pub mod seek {
+    pub const START: u64 = 0;
+    pub const CURRENT: u64 = 1;
+    /// From the size `sys::vfs_stat` gives
+    pub const END: u64 = 2;
+}
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Unknown syscall id
+    NoSys = 1,
+    /// A buffer is not mapped for user access
+    Fault = 2,
+    /// Policy said no
+    Denied = 3,
+    NotFound = 4,
+    Invalid = 5,
+    /// Nothing to read right now
+    WouldBlock = 6,
+    /// The node provider failed
+    Io = 7,
+    /// A debug command ran but reported an error
+    Failed = 8,
+}
+impl Error {
+    pub const MAX: u64 = 4095;
+
+    pub const fn encode(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+    pub fn decode(value: u64) -> Result<u64, Error> {
+        if value.wrapping_neg() > Self::MAX || value == 0 {
+            return Ok(value);
+        }
+        Err(match value.wrapping_neg() {
+            1 => Error::NoSys,
+            2 => Error::Fault,
+            3 => Error::Denied,
+            4 => Error::NotFound,
+            5 => Error::Invalid,
+            6 => Error::WouldBlock,
+            7 => Error::Io,
+            8 => Error::Failed,
+            _ => Error::Invalid,
+        })
+    }
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Error::NoSys => "no such syscall",
+            Error::Fault => "bad address",
+            Error::Denied => "denied by policy",
+            Error::NotFound => "not found",
+            Error::Invalid => "invalid argument",
+            Error::WouldBlock => "would block",
+            Error::Io => "i/o error",
+            Error::Failed => "failed",
+        }
+    }
+}
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub fn encode_result(result: Result<u64, Error>) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(e) => e.encode(),
+    }
+}
+
+/// Raw `int 0xf0`, prefer the wrappers in `sys`
+///
+/// # Safety
+/// Pointer arguments must be valid for what `id` does with them
+#[inline(always)]
+pub unsafe fn syscall(id: u64, args: [u64; 4]) -> u64 {
+    let ret;
+    unsafe {
+        core::arch::asm!(
+            "int 0xf0",
+            in("rax") id,
+            in("r9") args[0],
+            in("r10") args[1],
+            in("r11") args[2],
+            in("r12") args[3],
+            lateout("r15") ret,
+        );
+    }
+    ret
+}
+
+/// Wrappers for user programs, nodes and workers are the kernel handles as-is
+pub mod sys {
+    use super::{Error, id, syscall};
+
+    fn call(id: u64, args: [u64; 4]) -> Result<u64, Error> {
+        Error::decode(unsafe { syscall(id, args) })
+    }
+
+    pub fn console_write(s: &[u8]) -> Result<u64, Error> {
+        call(id::CONSOLE_WRITE, [s.as_ptr() as u64, s.len() as u64, 0, 0])
+    }
+    pub fn console_read() -> Result<u8, Error> {
+        call(id::CONSOLE_READ, [0; 4]).map(|b| b as u8)
+    }
+
+    pub fn abort() -> ! {
+        let _ = call(id::ABORT, [0; 4]);
+        unreachable!()
+    }
+    pub fn exit(code: u64) -> ! {
+        let _ = call(id::EXIT, [code, 0, 0, 0]);
+        unreachable!()
+    }
+    pub fn getpid() -> u64 {
+        call(id::GETPID, [0; 4]).unwrap_or(0)
+    }
+
+    pub fn yield_now() {
+        let _ = call(id::YIELD, [0; 4]);
+    }
+    pub fn new_worker() -> Result<u64, Error> {
+        call(id::NEW_WORKER, [0; 4])
+    }
+    pub fn new_task(worker: u64) -> Result<u64, Error> {
+        call(id::NEW_TASK, [worker, 0, 0, 0])
+    }
+    pub fn worker_count() -> u64 {
+        call(id::WORKER_COUNT, [0; 4]).unwrap_or(0)
+    }
+
+    /// Walks `path` from `from` (or the root if it starts with `/`)
+    pub fn vfs_lookup(from: u64, path: &str) -> Result<u64, Error> {
+        call(id::VFS_LOOKUP, [from, path.as_ptr() as u64, path.len() as u64, 0])
+    }
+    /// A file of the calling worker, at offset 0
+    pub fn vfs_open(node: u64) -> Result<u64, Error> {
+        call(id::VFS_OPEN, [node, 0, 0, 0])
+    }
+    pub fn vfs_close(file: u64) -> Result<(), Error> {
+        call(id::VFS_CLOSE, [file, 0, 0, 0]).map(|_| ())
+    }
+    /// At the file's offset, 0 at the end
+    pub fn vfs_read(file: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        call(id::VFS_READ, [file, buf.as_mut_ptr() as u64, buf.len() as u64, 0]).map(|n| n as usize)
+    }
+    pub fn vfs_write(file: u64, buf: &[u8]) -> Result<usize, Error> {
+        call(id::VFS_WRITE, [file, buf.as_ptr() as u64, buf.len() as u64, 0]).map(|n| n as usize)
+    }
+    /// `whence` is one of `seek::*`, returns the new offset
+    pub fn vfs_seek(file: u64, offset: i64, whence: u64) -> Result<u64, Error> {
+        call(id::VFS_SEEK, [file, offset as u64, whence, 0])
+    }
+    /// Size of `node` in bytes, 0 for most that are not files
+    pub fn vfs_stat(node: u64) -> Result<u64, Error> {
+        call(id::VFS_STAT, [node, 0, 0, 0])
+    }
+    /// The `index`th child of `node`, `Error::NotFound` past the last one
+    pub fn vfs_child(node: u64, index: u64) -> Result<u64, Error> {
+        call(id::VFS_CHILD, [node, index, 0, 0])
+    }
+    pub fn vfs_name(node: u64, buf: &mut [u8]) -> Result<&str, Error> {
+        let len = call(id::VFS_NAME, [node, buf.as_mut_ptr() as u64, buf.len() as u64, 0])?;
+        core::str::from_utf8(&buf[..len as usize]).map_err(|_| Error::Invalid)
+    }
+    /// The absolute path of `node`, cut to fit `buf`
+    pub fn vfs_path(node: u64, buf: &mut [u8]) -> Result<&str, Error> {
+        let len = call(id::VFS_PATH, [node, buf.as_mut_ptr() as u64, buf.len() as u64, 0])?;
+        core::str::from_utf8(&buf[..len as usize]).map_err(|_| Error::Invalid)
+    }
+    pub fn vfs_parent(node: u64) -> Result<u64, Error> {
+        call(id::VFS_PARENT, [node, 0, 0, 0])
+    }
+
+    /// Whether the calling worker holds every bit of `capability`
+    pub fn has_capability(capability: u16) -> bool {
+        call(id::POLICY_HAS_CAPABILITY, [capability as u64, 0, 0, 0]) == Ok(1)
+    }
+    pub fn user_name(index: u64, buf: &mut [u8]) -> Result<&str, Error> {
+        let len = call(id::POLICY_USER_NAME, [index, buf.as_mut_ptr() as u64, buf.len() as u64, 0])?;
+        core::str::from_utf8(&buf[..len as usize]).map_err(|_| Error::Invalid)
+    }
+    pub fn group_name(index: u64, buf: &mut [u8]) -> Result<&str, Error> {
+        let len = call(id::POLICY_GROUP_NAME, [index, buf.as_mut_ptr() as u64, buf.len() as u64, 0])?;
+        core::str::from_utf8(&buf[..len as usize]).map_err(|_| Error::Invalid)
+    }
+    /// The name of the user the calling worker runs as
+    pub fn whoami(buf: &mut [u8]) -> Result<&str, Error> {
+        let len = call(id::POLICY_WHOAMI, [buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0])?;
+        core::str::from_utf8(&buf[..len as usize]).map_err(|_| Error::Invalid)
+    }
+
+    /// Runs a line on the kernel console as the calling worker, needs `capability::DEBUG`
+    pub fn debug_command(line: &str) -> Result<u64, Error> {
+        call(id::DEBUG_COMMAND, [line.as_ptr() as u64, line.len() as u64, 0, 0])
+    }
+    /// The `index`th command the kernel console has, `Error::NotFound` past the last one
+    pub fn debug_command_name(index: u64, buf: &mut [u8]) -> Result<&str, Error> {
+        let len = call(id::DEBUG_COMMAND_NAME, [index, buf.as_mut_ptr() as u64, buf.len() as u64, 0])?;
+        core::str::from_utf8(&buf[..len as usize]).map_err(|_| Error::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn result_round_trip() {
+        for value in [0, 1, 0x1000_0000, u64::MAX - Error::MAX] {
+            assert_eq!(Error::decode(encode_result(Ok(value))), Ok(value));
+        }
+        for e in [Error::NoSys, Error::Fault, Error::Denied, Error::WouldBlock, Error::Failed] {
+            assert_eq!(Error::decode(e.encode()), Err(e));
+        }
+    }
+}
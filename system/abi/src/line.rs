@@ -0,0 +1,818 @@
+//! Line editing and word splitting, the same for the kernel console and
+//! user programs like the shell
+//!
+//! Understands the ANSI sequences common terminals (picocom, screen, xterm)
+//! send for the arrow/home/end/delete keys, plus the usual control keys:
+//! ^A/^E home/end, ^C cancel, ^U kill to start, ^W kill word. Tab completion
+//! and running the line are up to the caller, see `Event`. What the editor
+//! echoes goes to the `fmt::Write` it is given.
+//!
+//! Submitted lines are split by `Args`, commands describe what they take with
+//! a slice of `ArgSpec` and get the words checked before they run.
+
+use core::fmt::{self, Write};
+
+pub const MAX_LINE: usize = 256;
+pub const HISTORY_SIZE: usize = 32;
+/// History lines share this much space, long lines push out old ones sooner
+pub const HISTORY_BYTES: usize = 4096;
+/// Most words a line can be split into, command name included
+pub const MAX_ARGS: usize = 16;
+
+/// Where each line or word of a buffer ends (or starts), up to `N` of them
+struct Offsets<const N: usize> {
+    items: [u16; N],
+    len: usize,
+}
+impl<const N: usize> Offsets<N> {
+    const fn new() -> Self {
+        Self { items: [0; N], len: 0 }
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Full is up to the caller
+    fn push(&mut self, offset: u16) {
+        self.items[self.len] = offset;
+        self.len += 1;
+    }
+    fn remove_first(&mut self) -> u16 {
+        let first = self.items[0];
+        self.items.copy_within(1..self.len, 0);
+        self.len -= 1;
+        first
+    }
+    fn get(&self, index: usize) -> Option<u16> {
+        self.items[..self.len].get(index).copied()
+    }
+    fn as_mut_slice(&mut self) -> &mut [u16] {
+        &mut self.items[..self.len]
+    }
+}
+impl<const N: usize> core::ops::Index<usize> for Offsets<N> {
+    type Output = u16;
+    fn index(&self, index: usize) -> &u16 {
+        &self.items[..self.len][index]
+    }
+}
+
+/// Previously entered lines, oldest first
+pub struct History {
+    bytes: [u8; HISTORY_BYTES],
+    /// End of each line in `bytes`, lines are back to back
+    ends: Offsets<HISTORY_SIZE>,
+}
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl History {
+    pub fn new() -> Self {
+        Self {
+            bytes: [0; HISTORY_BYTES],
+            ends: Offsets::new(),
+        }
+    }
+    /// Blank lines and repeats of the last line are not recorded, the oldest
+    /// entries go away once out of room
+    pub fn push(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || line.len() > HISTORY_BYTES || self.get_recent(0) == Some(line) {
+            return;
+        }
+        while self.ends.len() >= HISTORY_SIZE || self.get_used() + line.len() > HISTORY_BYTES {
+            self.drop_oldest();
+        }
+        let start = self.get_used();
+        self.bytes[start..start + line.len()].copy_from_slice(line.as_bytes());
+        self.ends.push((start + line.len()) as u16);
+    }
+    fn get_used(&self) -> usize {
+        self.ends.len().checked_sub(1).map_or(0, |i| self.ends[i] as usize)
+    }
+    fn drop_oldest(&mut self) {
+        let first = self.ends.remove_first() as usize;
+        self.bytes.copy_within(first.., 0);
+        for end in self.ends.as_mut_slice() {
+            *end -= first as u16;
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+    pub fn get(&self, index: usize) -> Option<&str> {
+        if index >= self.ends.len() {
+            return None;
+        }
+        let end = self.ends[index] as usize;
+        let start = index.checked_sub(1).map_or(0, |i| self.ends[i] as usize);
+        // Only whole `&str`s are ever copied in
+        Some(unsafe { core::str::from_utf8_unchecked(&self.bytes[start..end]) })
+    }
+    /// 0 is the most recent line
+    pub fn get_recent(&self, index: usize) -> Option<&str> {
+        self.ends.len().checked_sub(index + 1).and_then(|i| self.get(i))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    None,
+    /// Enter was pressed, the line is in `as_str`
+    Submit,
+    /// ^C, the line was thrown away
+    Cancel,
+    /// Tab, complete the word before the cursor
+    Complete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(u8),
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    KillLine,
+    KillWord,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// Got ESC
+    Start,
+    /// Got ESC [, with the numeric parameter so far
+    Csi(u8),
+    /// Got ESC O
+    Ss3,
+}
+
+pub struct LineEditor {
+    buffer: [u8; MAX_LINE],
+    len: usize,
+    cursor: usize,
+    escape: Escape,
+    last_was_cr: bool,
+    /// Which history entry is shown, `None` while editing a fresh line
+    history_index: Option<usize>,
+    /// The fresh line, restored when going back down past the newest entry
+    draft: [u8; MAX_LINE],
+    draft_len: usize,
+}
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl LineEditor {
+    pub fn new() -> Self {
+        Self {
+            buffer: [0; MAX_LINE],
+            len: 0,
+            cursor: 0,
+            escape: Escape::None,
+            last_was_cr: false,
+            history_index: None,
+            draft: [0; MAX_LINE],
+            draft_len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only printable ASCII is ever inserted
+        unsafe { core::str::from_utf8_unchecked(&self.buffer[..self.len]) }
+    }
+
+    pub fn get_cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Feed one byte from the terminal, echoing whatever changed to `out`
+    pub fn feed<W: Write>(&mut self, b: u8, history: &History, out: &mut W) -> Event {
+        let was_cr = core::mem::replace(&mut self.last_was_cr, b == b'\r');
+        let key = match self.escape {
+            Escape::None => match b {
+                0x1b => {
+                    self.escape = Escape::Start;
+                    return Event::None;
+                }
+                b'\r' => return self.submit(),
+                // CR LF is one enter, a lone LF (piped input) is one too
+                b'\n' => return if was_cr { Event::None } else { self.submit() },
+                b'\t' => return Event::Complete,
+                0x03 => {
+                    self.clear();
+                    self.history_index = None;
+                    return Event::Cancel;
+                }
+                0x01 => Key::Home,
+                0x05 => Key::End,
+                0x08 | 0x7f => Key::Backspace,
+                0x15 => Key::KillLine,
+                0x17 => Key::KillWord,
+                0x20..=0x7e => Key::Char(b),
+                _ => return Event::None,
+            },
+            Escape::Start => {
+                self.escape = match b {
+                    b'[' => Escape::Csi(0),
+                    b'O' => Escape::Ss3,
+                    _ => Escape::None,
+                };
+                return Event::None;
+            }
+            Escape::Csi(param) => {
+                self.escape = Escape::None;
+                match b {
+                    b'0'..=b'9' => {
+                        self.escape = Escape::Csi(param.saturating_mul(10).saturating_add(b - b'0'));
+                        return Event::None;
+                    }
+                    // Modifiers (ESC [ 1 ; 5 C), treated like the plain key
+                    b';' => {
+                        self.escape = Escape::Csi(param);
+                        return Event::None;
+                    }
+                    b'A' => Key::Up,
+                    b'B' => Key::Down,
+                    b'C' => Key::Right,
+                    b'D' => Key::Left,
+                    b'H' => Key::Home,
+                    b'F' => Key::End,
+                    b'~' => match param {
+                        1 | 7 => Key::Home,
+                        4 | 8 => Key::End,
+                        3 => Key::Delete,
+                        _ => return Event::None,
+                    },
+                    _ => return Event::None,
+                }
+            }
+            Escape::Ss3 => {
+                self.escape = Escape::None;
+                match b {
+                    b'A' => Key::Up,
+                    b'B' => Key::Down,
+                    b'C' => Key::Right,
+                    b'D' => Key::Left,
+                    b'H' => Key::Home,
+                    b'F' => Key::End,
+                    _ => return Event::None,
+                }
+            }
+        };
+        self.apply(key, history, out);
+        Event::None
+    }
+
+    fn submit(&mut self) -> Event {
+        self.history_index = None;
+        Event::Submit
+    }
+
+    fn apply<W: Write>(&mut self, key: Key, history: &History, out: &mut W) {
+        let old_cursor = self.cursor;
+        match key {
+            Key::Char(c) => {
+                if self.len < MAX_LINE {
+                    self.buffer.copy_within(self.cursor..self.len, self.cursor + 1);
+                    self.buffer[self.cursor] = c;
+                    self.len += 1;
+                    self.cursor += 1;
+                    if self.cursor == self.len {
+                        let _ = out.write_char(c as char);
+                    } else {
+                        self.refresh(old_cursor, out);
+                    }
+                }
+            }
+            Key::Backspace => {
+                if self.cursor > 0 {
+                    self.remove(self.cursor - 1, self.cursor);
+                    self.refresh(old_cursor, out);
+                }
+            }
+            Key::Delete => {
+                if self.cursor < self.len {
+                    self.remove(self.cursor, self.cursor + 1);
+                    self.refresh(old_cursor, out);
+                }
+            }
+            Key::KillLine => {
+                self.remove(0, self.cursor);
+                self.refresh(old_cursor, out);
+            }
+            Key::KillWord => {
+                let before = &self.buffer[..self.cursor];
+                let end = before.iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+                let start = before[..end].iter().rposition(|c| *c == b' ').map_or(0, |i| i + 1);
+                self.remove(start, self.cursor);
+                self.refresh(old_cursor, out);
+            }
+            Key::Left => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    let _ = out.write_str("\x1b[D");
+                }
+            }
+            Key::Right => {
+                if self.cursor < self.len {
+                    self.cursor += 1;
+                    let _ = out.write_str("\x1b[C");
+                }
+            }
+            Key::Home => {
+                if self.cursor > 0 {
+                    let _ = write!(out, "\x1b[{}D", self.cursor);
+                    self.cursor = 0;
+                }
+            }
+            Key::End => {
+                if self.cursor < self.len {
+                    let _ = write!(out, "\x1b[{}C", self.len - self.cursor);
+                    self.cursor = self.len;
+                }
+            }
+            Key::Up => {
+                let next = self.history_index.map_or(0, |i| i + 1);
+                if let Some(entry) = history.get_recent(next) {
+                    if self.history_index.is_none() {
+                        self.draft = self.buffer;
+                        self.draft_len = self.len;
+                    }
+                    self.history_index = Some(next);
+                    self.replace(entry, out);
+                }
+            }
+            Key::Down => match self.history_index {
+                Some(0) => {
+                    self.history_index = None;
+                    let draft = self.draft;
+                    // The draft was copied from the line, so it is ASCII too
+                    self.replace(unsafe { core::str::from_utf8_unchecked(&draft[..self.draft_len]) }, out);
+                }
+                Some(i) => {
+                    self.history_index = Some(i - 1);
+                    if let Some(entry) = history.get_recent(i - 1) {
+                        self.replace(entry, out);
+                    }
+                }
+                None => {}
+            },
+        }
+    }
+
+    fn remove(&mut self, start: usize, end: usize) {
+        self.buffer.copy_within(end..self.len, start);
+        self.len -= end - start;
+        self.cursor = start;
+    }
+
+    /// Throw the line away without touching the terminal
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.cursor = 0;
+        self.escape = Escape::None;
+    }
+
+    /// Swap the whole line, cursor at the end
+    pub fn replace<W: Write>(&mut self, s: &str, out: &mut W) {
+        let old_cursor = self.cursor;
+        self.len = 0;
+        self.cursor = 0;
+        self.insert_bytes(s.as_bytes());
+        self.refresh(old_cursor, out);
+    }
+
+    /// Insert at the cursor, used for completions, anything past the line limit is dropped
+    pub fn insert_str<W: Write>(&mut self, s: &str, out: &mut W) {
+        let old_cursor = self.cursor;
+        self.insert_bytes(s.as_bytes());
+        self.refresh(old_cursor, out);
+    }
+
+    fn insert_bytes(&mut self, s: &[u8]) {
+        let n = s.len().min(MAX_LINE - self.len);
+        self.buffer.copy_within(self.cursor..self.len, self.cursor + n);
+        self.buffer[self.cursor..self.cursor + n].copy_from_slice(&s[..n]);
+        self.len += n;
+        self.cursor += n;
+    }
+
+    /// Reprint the line from the terminal's cursor, which is at `old_cursor`
+    fn refresh<W: Write>(&self, old_cursor: usize, out: &mut W) {
+        if old_cursor > 0 {
+            let _ = write!(out, "\x1b[{}D", old_cursor);
+        }
+        self.redraw(out);
+    }
+
+    /// Print the whole line assuming the terminal cursor is where it starts,
+    /// e.g right after the prompt
+    pub fn redraw<W: Write>(&self, out: &mut W) {
+        let _ = write!(out, "{}\x1b[K", self.as_str());
+        if self.len > self.cursor {
+            let _ = write!(out, "\x1b[{}D", self.len - self.cursor);
+        }
+    }
+}
+
+/// `0x`/`0h` hex, `0b` binary, otherwise decimal
+pub fn parse_literal(a: &str) -> Option<usize> {
+    if let Some(hex) = a.strip_prefix("0x").or_else(|| a.strip_prefix("0h")) {
+        usize::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = a.strip_prefix("0b") {
+        usize::from_str_radix(bin, 2).ok()
+    } else {
+        a.parse::<usize>().ok()
+    }
+}
+
+pub fn parse_boolean(a: &str) -> Option<bool> {
+    const YES: [&str; 6] = ["yes", "on", "true", "y", "t", "1"];
+    const NO: [&str; 6] = ["no", "off", "false", "n", "f", "0"];
+    if YES.iter().any(|y| a.eq_ignore_ascii_case(y)) {
+        Some(true)
+    } else if NO.iter().any(|n| a.eq_ignore_ascii_case(n)) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    UnterminatedQuote,
+    /// `\` as the very last character
+    TrailingEscape,
+    TooManyWords,
+    TooLong,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnterminatedQuote => write!(f, "unterminated quote"),
+            Self::TrailingEscape => write!(f, "nothing to escape at end of line"),
+            Self::TooManyWords => write!(f, "more than {} words", MAX_ARGS),
+            Self::TooLong => write!(f, "line longer than {} bytes", MAX_LINE),
+        }
+    }
+}
+
+/// A line split into words like a shell would, word 0 is the command name.
+///
+/// Whitespace separates words, `"..."` groups with `\n \t \r \e` and
+/// `\<any>` escapes, `'...'` groups verbatim and `\<any>` outside quotes is
+/// that character. A `#` starting a word comments out the rest of the line.
+pub struct Args<'a> {
+    line: &'a str,
+    /// Where the comment starts, or the end of `line`
+    line_end: usize,
+    /// Words with quotes and escapes resolved, back to back
+    buffer: [u8; MAX_LINE],
+    /// End of each word in `buffer`
+    ends: Offsets<MAX_ARGS>,
+    /// Start of each word in `line`, for `rest`
+    starts: Offsets<MAX_ARGS>,
+}
+impl<'a> Args<'a> {
+    pub fn parse(line: &'a str) -> Result<Self, ParseError> {
+        if line.len() > u16::MAX as usize {
+            return Err(ParseError::TooLong);
+        }
+        let mut args = Self {
+            line,
+            line_end: line.len(),
+            buffer: [0; MAX_LINE],
+            ends: Offsets::new(),
+            starts: Offsets::new(),
+        };
+        let bytes = line.as_bytes();
+        let mut len = 0;
+        let mut i = 0;
+        loop {
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if i >= bytes.len() {
+                break;
+            }
+            if bytes[i] == b'#' {
+                args.line_end = i;
+                break;
+            }
+            if args.ends.len() >= MAX_ARGS {
+                return Err(ParseError::TooManyWords);
+            }
+            args.starts.push(i as u16);
+            let mut quote = None;
+            while i < bytes.len() {
+                let b = bytes[i];
+                i += 1;
+                let out = match (quote, b) {
+                    (None, b) if b.is_ascii_whitespace() => break,
+                    (None, b'"' | b'\'') => {
+                        quote = Some(b);
+                        continue;
+                    }
+                    (Some(q), b) if b == q => {
+                        quote = None;
+                        continue;
+                    }
+                    (Some(b'\''), b) => b,
+                    (_, b'\\') => {
+                        let next = *bytes.get(i).ok_or(ParseError::TrailingEscape)?;
+                        i += 1;
+                        match (quote, next) {
+                            (Some(_), b'n') => b'\n',
+                            (Some(_), b't') => b'\t',
+                            (Some(_), b'r') => b'\r',
+                            (Some(_), b'e') => 0x1b,
+                            _ => next,
+                        }
+                    }
+                    (_, b) => b,
+                };
+                if len >= MAX_LINE {
+                    return Err(ParseError::TooLong);
+                }
+                args.buffer[len] = out;
+                len += 1;
+            }
+            if quote.is_some() {
+                return Err(ParseError::UnterminatedQuote);
+            }
+            args.ends.push(len as u16);
+        }
+        Ok(args)
+    }
+
+    /// Number of words, command name included
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+    pub fn get(&self, index: usize) -> Option<&str> {
+        if index >= self.ends.len() {
+            return None;
+        }
+        let start = index.checked_sub(1).map_or(0, |i| self.ends[i] as usize);
+        // Whole UTF-8 sequences are copied byte by byte, escapes are ASCII
+        Some(unsafe { core::str::from_utf8_unchecked(&self.buffer[start..self.ends[index] as usize]) })
+    }
+    pub fn get_literal(&self, index: usize) -> Option<usize> {
+        self.get(index).and_then(parse_literal)
+    }
+    pub fn get_boolean(&self, index: usize) -> Option<bool> {
+        self.get(index).and_then(parse_boolean)
+    }
+    /// The line as typed from word `index` on, quotes and all, without the comment
+    pub fn get_rest(&self, index: usize) -> &'a str {
+        match self.starts.get(index) {
+            Some(start) => self.line[start as usize..self.line_end].trim_end(),
+            None => "",
+        }
+    }
+
+    /// Validate the words after the command name against `spec`
+    pub fn check(&self, spec: &[ArgSpec]) -> Result<(), ArgError> {
+        for (i, arg) in spec.iter().enumerate() {
+            let Some(word) = self.get(i + 1) else {
+                return if arg.optional { Ok(()) } else { Err(ArgError::Missing(arg.name)) };
+            };
+            let valid = match arg.kind {
+                ArgKind::Literal => parse_literal(word).is_some(),
+                ArgKind::Boolean => parse_boolean(word).is_some(),
+                ArgKind::Word => true,
+                ArgKind::Rest => return Ok(()),
+            };
+            if !valid {
+                return Err(ArgError::Invalid(arg.name, arg.kind));
+            }
+        }
+        if self.len() > spec.len() + 1 {
+            return Err(ArgError::TooMany);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// Number, see `parse_literal`
+    Literal,
+    /// yes/no, on/off..., see `parse_boolean`
+    Boolean,
+    /// Any single word, e.g a path
+    Word,
+    /// Everything left on the line as typed, only makes sense last
+    Rest,
+}
+
+/// One argument of a command, optional ones go after required ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+impl ArgSpec {
+    pub const fn required(name: &'static str, kind: ArgKind) -> Self {
+        Self { name, kind, optional: false }
+    }
+    pub const fn optional(name: &'static str, kind: ArgKind) -> Self {
+        Self { name, kind, optional: true }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgError {
+    Missing(&'static str),
+    Invalid(&'static str, ArgKind),
+    TooMany,
+}
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Missing(name) => write!(f, "missing <{name}>"),
+            Self::Invalid(name, ArgKind::Literal) => write!(f, "<{name}> must be a number"),
+            Self::Invalid(name, ArgKind::Boolean) => write!(f, "<{name}> must be on or off"),
+            Self::Invalid(name, _) => write!(f, "invalid <{name}>"),
+            Self::TooMany => write!(f, "too many arguments"),
+        }
+    }
+}
+
+/// `name <required> [optional] <rest...>`, for usage messages and help
+pub struct Usage<'a>(pub &'a str, pub &'a [ArgSpec]);
+impl fmt::Display for Usage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        for arg in self.1 {
+            let dots = if arg.kind == ArgKind::Rest { "..." } else { "" };
+            if arg.optional {
+                write!(f, " [{}{dots}]", arg.name)?;
+            } else {
+                write!(f, " <{}{dots}>", arg.name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{format, string::String, vec::Vec};
+
+    fn feed(editor: &mut LineEditor, history: &History, input: &[u8]) -> Event {
+        let mut last = Event::None;
+        for &b in input {
+            last = editor.feed(b, history, &mut String::new());
+        }
+        last
+    }
+
+    #[test]
+    fn insert_and_cursor_keys() {
+        let history = History::new();
+        let mut e = LineEditor::new();
+        assert_eq!(feed(&mut e, &history, b"pek\x1b[D\x1b[De"), Event::None);
+        assert_eq!(e.as_str(), "peek");
+        feed(&mut e, &history, b"\x01x\x05y\x1bOHz\x1b[4~w");
+        assert_eq!(e.as_str(), "zxpeekyw");
+        feed(&mut e, &history, b"\x1b[1~\x1b[3~\x1b[C\x7f");
+        assert_eq!(e.as_str(), "peekyw");
+        assert_eq!(e.get_cursor(), 0);
+    }
+
+    #[test]
+    fn kill_keys() {
+        let history = History::new();
+        let mut e = LineEditor::new();
+        feed(&mut e, &history, b"map 0x1000  0x2000 \x17");
+        assert_eq!(e.as_str(), "map 0x1000  ");
+        feed(&mut e, &history, b"\x1b[D\x15");
+        assert_eq!(e.as_str(), " ");
+        assert_eq!(feed(&mut e, &history, b"abc\x03"), Event::Cancel);
+        assert_eq!(e.as_str(), "");
+    }
+
+    #[test]
+    fn enter_tab_and_line_limit() {
+        let history = History::new();
+        let mut e = LineEditor::new();
+        assert_eq!(feed(&mut e, &history, b"he\t"), Event::Complete);
+        e.insert_str("lp ", &mut String::new());
+        assert_eq!(feed(&mut e, &history, b"\r"), Event::Submit);
+        assert_eq!(feed(&mut e, &history, b"\n"), Event::None);
+        assert_eq!(feed(&mut e, &history, b"\n"), Event::Submit);
+        assert_eq!(e.as_str(), "help ");
+        let mut e = LineEditor::new();
+        feed(&mut e, &history, &[b'a'; MAX_LINE + 10]);
+        assert_eq!(e.as_str().len(), MAX_LINE);
+    }
+
+    #[test]
+    fn history_recall() {
+        let mut history = History::new();
+        history.push("users");
+        history.push("tree");
+        history.push("tree");
+        history.push("   ");
+        assert_eq!(history.len(), 2);
+        let mut e = LineEditor::new();
+        feed(&mut e, &history, b"dra");
+        feed(&mut e, &history, b"\x1b[A");
+        assert_eq!(e.as_str(), "tree");
+        feed(&mut e, &history, b"\x1b[A\x1b[A");
+        assert_eq!(e.as_str(), "users");
+        feed(&mut e, &history, b"\x1b[B");
+        assert_eq!(e.as_str(), "tree");
+        feed(&mut e, &history, b"\x1b[Bft");
+        assert_eq!(e.as_str(), "draft");
+    }
+
+    #[test]
+    fn history_drops_oldest() {
+        let mut history = History::new();
+        for i in 0..HISTORY_SIZE + 3 {
+            history.push(&"x".repeat(i + 1));
+        }
+        assert_eq!(history.len(), HISTORY_SIZE);
+        assert_eq!(history.get(0), Some("xxxx"));
+        assert_eq!(history.get_recent(0).map(str::len), Some(HISTORY_SIZE + 3));
+    }
+
+    #[test]
+    fn history_long_lines_share_space() {
+        let mut history = History::new();
+        let line = |i: usize| format!("{i:02}{}", "y".repeat(MAX_LINE - 2));
+        for i in 0..HISTORY_SIZE {
+            history.push(&line(i));
+        }
+        assert_eq!(history.len(), HISTORY_BYTES / MAX_LINE);
+        assert_eq!(history.get_recent(0), Some(line(HISTORY_SIZE - 1).as_str()));
+        assert_eq!(history.get(history.len()), None);
+    }
+
+    #[test]
+    fn args_split_quote_and_escape() {
+        let args = Args::parse(r#"  echo a "b c"  'd\ e' f\ g "\"q\"\t" "" "#).unwrap();
+        let words: Vec<_> = (0..args.len()).map(|i| args.get(i).unwrap()).collect();
+        assert_eq!(words, ["echo", "a", "b c", "d\\ e", "f g", "\"q\"\t", ""]);
+        assert_eq!(args.get_rest(2), r#""b c"  'd\ e' f\ g "\"q\"\t" """#);
+        assert_eq!(args.get_rest(7), "");
+
+        let args = Args::parse("map 0x1000 # 0x2000").unwrap();
+        assert_eq!(args.len(), 2);
+        assert_eq!(args.get_rest(1), "0x1000");
+        assert_eq!(args.get_literal(1), Some(0x1000));
+        assert!(Args::parse("   # nothing").unwrap().is_empty());
+
+        assert_eq!(Args::parse("echo \"open").err(), Some(ParseError::UnterminatedQuote));
+        assert_eq!(Args::parse("echo \\").err(), Some(ParseError::TrailingEscape));
+        assert_eq!(Args::parse(&"a ".repeat(MAX_ARGS + 1)).err(), Some(ParseError::TooManyWords));
+    }
+
+    #[test]
+    fn args_check_against_spec() {
+        const POKE: [ArgSpec; 3] = [
+            ArgSpec::required("addr", ArgKind::Literal),
+            ArgSpec::required("value", ArgKind::Literal),
+            ArgSpec::optional("count", ArgKind::Literal),
+        ];
+        let check = |line: &str| Args::parse(line).unwrap().check(&POKE);
+        assert_eq!(check("poke 0x1000 0b101"), Ok(()));
+        assert_eq!(check("poke 0x1000 5 16"), Ok(()));
+        assert_eq!(check("poke 0x1000"), Err(ArgError::Missing("value")));
+        assert_eq!(check("poke 0x1000 zz"), Err(ArgError::Invalid("value", ArgKind::Literal)));
+        assert_eq!(check("poke 1 2 3 4"), Err(ArgError::TooMany));
+
+        const ECHO: [ArgSpec; 2] = [
+            ArgSpec::required("on", ArgKind::Boolean),
+            ArgSpec::optional("text", ArgKind::Rest),
+        ];
+        assert_eq!(Args::parse("echo yes a b c").unwrap().check(&ECHO), Ok(()));
+        assert_eq!(
+            Args::parse("echo maybe").unwrap().check(&ECHO),
+            Err(ArgError::Invalid("on", ArgKind::Boolean))
+        );
+        assert_eq!(format!("{}", Usage("poke", &POKE)), "poke <addr> <value> [count]");
+        assert_eq!(format!("{}", Usage("echo", &ECHO)), "echo <on> [text...]");
+    }
+}
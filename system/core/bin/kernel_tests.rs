@@ -0,0 +1,147 @@
+//! Integration tests run inside QEMU by `make test-kernel`, see `radian_core::testing`
+//!
+//! Everything here runs after `rust_start` brought up the kernel worker, the
+//! kernel address space and the heap, on the global database.
+
+extern crate alloc;
+use radian_core::{kassert, kassert_eq, kernel_test, prelude::*, task, testing, vmm};
+
+/// Somewhere nothing else maps, below the KASAN shadow
+const SCRATCH_VADDR: u64 = 0x0800_0000_0000;
+
+#[kernel_test]
+fn pmm_alloc_distinct_pages(_db: &mut db::Database) -> testing::Result {
+    let a = pmm::Manager::alloc_page();
+    let b = pmm::Manager::alloc_page();
+    kassert!(a != b);
+    kassert_eq!(a.get() as usize % pmm::PAGE_SIZE, 0);
+    kassert_eq!(b.get() as usize % pmm::PAGE_SIZE, 0);
+    pmm::Manager::free_page(a);
+    pmm::Manager::free_page(b);
+    Ok(())
+}
+
+#[kernel_test]
+fn pmm_free_page_is_reused(_db: &mut db::Database) -> testing::Result {
+    let a = pmm::Manager::alloc_page();
+    pmm::Manager::free_page(a);
+    kassert_eq!(pmm::Manager::alloc_page(), a);
+    pmm::Manager::free_page(a);
+    Ok(())
+}
+
+#[kernel_test]
+fn pmm_alloc_zeroed(_db: &mut db::Database) -> testing::Result {
+    let a = pmm::Manager::alloc_page();
+    unsafe { a.get_mut().write_bytes(0xaa, pmm::PAGE_SIZE) };
+    pmm::Manager::free_page(a);
+    let b = pmm::Manager::alloc_page_zeroed();
+    let page = unsafe { core::slice::from_raw_parts(b.get(), pmm::PAGE_SIZE) };
+    kassert!(page.iter().all(|&byte| byte == 0));
+    pmm::Manager::free_page(b);
+    Ok(())
+}
+
+#[kernel_test]
+fn vmm_map_single(db: &mut db::Database) -> testing::Result {
+    let aspace = vmm::AddressSpaceHandle::get_kernel();
+    kassert!(!vmm::Manager::has_mapping_present(db, aspace, SCRATCH_VADDR));
+    let page = pmm::Manager::alloc_page_zeroed();
+    vmm::Manager::map_single(db, aspace, page.get() as u64, SCRATCH_VADDR, vmm::Page::PRESENT | vmm::Page::READ_WRITE);
+    vmm::Manager::invalidate_single(SCRATCH_VADDR);
+    kassert!(vmm::Manager::has_mapping_present(db, aspace, SCRATCH_VADDR));
+    kassert!(!vmm::Manager::has_mapping_present(db, aspace, SCRATCH_VADDR + pmm::PAGE_SIZE as u64));
+    unsafe { (SCRATCH_VADDR as *mut u64).write_volatile(0xdead_beef) };
+    kassert_eq!(unsafe { (page.get() as *const u64).read_volatile() }, 0xdead_beef);
+    let mut leaf = vmm::Page::default();
+    vmm::Manager::traverse_page_table(db, aspace, SCRATCH_VADDR, |p| leaf = *p);
+    kassert_eq!(leaf.get_physaddr(), page.get() as u64);
+    Ok(())
+}
+
+#[kernel_test]
+fn vmm_translate_checks_user(db: &mut db::Database) -> testing::Result {
+    let aspace = vmm::Manager::new_address_space(db, pmm::Manager::alloc_page_zeroed());
+    let page = pmm::Manager::alloc_page_zeroed();
+    let user = vmm::Page::PRESENT | vmm::Page::READ_WRITE | vmm::Page::USER_SUPERVISOR;
+    vmm::Manager::map_single(db, aspace, page.get() as u64, SCRATCH_VADDR, user);
+    kassert_eq!(vmm::Manager::translate(db, aspace, SCRATCH_VADDR + 8, user), Some(page.get() as u64 + 8));
+    // The kernel image is mapped for ring 0 only
+    let kernel = rust_start_address();
+    kassert!(vmm::Manager::translate(db, aspace, kernel, vmm::Page::PRESENT).is_some());
+    kassert!(vmm::Manager::translate(db, aspace, kernel, vmm::Page::USER_SUPERVISOR).is_none());
+    kassert!(vmm::Manager::translate(db, aspace, SCRATCH_VADDR + pmm::PAGE_SIZE as u64, 0).is_none());
+    Ok(())
+}
+
+#[kernel_test]
+fn heap_box_and_vec(_db: &mut db::Database) -> testing::Result {
+    let boxed = alloc::boxed::Box::new([7u8; 100]);
+    kassert_eq!(boxed.iter().map(|&b| b as usize).sum::<usize>(), 700);
+    let mut v = alloc::vec::Vec::new();
+    for i in 0..1000usize {
+        v.push(i);
+    }
+    kassert_eq!(v.iter().sum::<usize>(), 999 * 1000 / 2);
+    let big = alloc::vec![0u8; 64 * 1024];
+    kassert_eq!(big.as_ptr() as usize % 8, 0);
+    Ok(())
+}
+
+#[kernel_test]
+fn elf_load_maps_entry(db: &mut db::Database) -> testing::Result {
+    let aspace = vmm::Manager::new_address_space(db, pmm::Manager::alloc_page_zeroed());
+    let worker = task::Manager::new_worker(db, aspace);
+    task::Manager::load_elf_into_worker(db, worker, include_bytes!("test.elf"), true);
+    let entry = db.workers[worker.get_id() as usize].get_entry_point();
+    kassert!(entry != 0);
+    kassert!(vmm::Manager::has_mapping_present(db, aspace, entry & !0xfff));
+    // Kernel image must still be there in the new space
+    kassert!(vmm::Manager::has_mapping_present(db, aspace, rust_start_address() & !0xfff));
+    Ok(())
+}
+
+#[kernel_test]
+fn scheduler_round_robin(db: &mut db::Database) -> testing::Result {
+    task::Manager::new_worker(db, vmm::AddressSpaceHandle::get_kernel());
+    let count = db.workers.len();
+    kassert!(count >= 2);
+    let first = task::Manager::scheduler_tick(db).get_id() as usize;
+    for i in 1..=count {
+        let next = task::Manager::scheduler_tick(db).get_id() as usize;
+        kassert_eq!(next, (first + i) % count);
+        kassert!(db.workers[next].is_active());
+        kassert_eq!(db.workers.iter().take(count).filter(|w| w.is_active()).count(), 1);
+    }
+    Ok(())
+}
+
+#[kernel_test]
+fn policy_rule_lifecycle(db: &mut db::Database) -> testing::Result {
+    let worker = task::Manager::new_worker(db, vmm::AddressSpaceHandle::get_kernel());
+    let start_task = policy::Action::default().with(policy::Action::START_TASK);
+    let spawn = policy::Capability::new().with(policy::Capability::SPAWN_TASK);
+    kassert!(!policy::Manager::check_action(db, worker, start_task));
+    let rule = policy::Manager::add_rule(
+        db,
+        policy::PolicyRule {
+            subject: worker,
+            allowed: start_task,
+            capabilities: spawn,
+        },
+    );
+    kassert!(policy::Manager::check_action(db, worker, start_task));
+    kassert!(policy::Manager::check_capability(db, worker, spawn));
+    kassert!(!policy::Manager::check_capability(
+        db,
+        worker,
+        policy::Capability::new().with(policy::Capability::NETWORK_ACCESS)
+    ));
+    policy::Manager::remove_rule(db, rule);
+    kassert!(!policy::Manager::check_action(db, worker, start_task));
+    Ok(())
+}
+
+fn rust_start_address() -> u64 {
+    super::rust_start as *const () as u64
+}
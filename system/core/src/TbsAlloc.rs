@@ -11,6 +11,8 @@ use core::ptr::NonNull;
 
 use crate::containers::{FlexibleArray, StaticVec};
 use crate::{db, kprint, pmm, vmm};
+use crate::console::{ArgKind, ArgSpec, Command};
+use crate::{console_commands, console_error};
 
 pub const CACHE_LINE_SIZE: usize = 64;
 
@@ -62,6 +64,7 @@ impl IntrusiveIntervalTree {
         self.nodes[root].base = root_base;
         self.nodes[root].length = root_length;
         self.nodes[root].is_free = true;
+        self.nodes[root].height = 1;
         self.root = root;
     }
     #[inline] fn get_node<'a>(&'a self, index: usize) -> &'a IntrusiveIntervalNode {
@@ -77,10 +80,11 @@ impl IntrusiveIntervalTree {
             }
         }
         self.extent += 1;
+        // EVIL NON-DETERMINISM IF YOU DONT DO THIS
+        self.nodes[self.extent] = IntrusiveIntervalNode::default();
         // This is horrible but i don't give a fuck
+        #[cfg(not(test))]
         unsafe {
-            // EVIL NON-DETERMINISM IF YOU DONT DO THIS
-            self.nodes[self.extent] = IntrusiveIntervalNode::default();
             let vaddr = &raw const self.nodes[self.extent] as u64;
             let db = db::Database::get_mut();
             let aspace =vmm::AddressSpaceHandle::get_kernel();
@@ -147,16 +151,30 @@ impl IntrusiveIntervalTree {
             self.nodes[new_node].base = base;
             self.nodes[new_node].length = length;
             self.nodes[new_node].is_free = is_free;
+            // Leaves are height 1, 0 is reserved for the null node
+            self.nodes[new_node].height = 1;
             new_node
         }
     }
-    fn find_free(&mut self, index: usize, length: usize) -> Option<usize> {
+    /// Whether a block of `length` bytes aligned to `align` can be carved
+    /// from the end of the given free node
+    #[inline]
+    fn fits(&self, index: usize, length: usize, align: usize) -> bool {
+        let node = &self.nodes[index];
+        if node.is_free && node.length >= length {
+            let new_ptr = (node.base + node.length - length) & !(align - 1);
+            new_ptr >= node.base
+        } else {
+            false
+        }
+    }
+    fn find_free(&mut self, index: usize, length: usize, align: usize) -> Option<usize> {
         if self.nodes[index].is_present() {
-            if self.nodes[index].length >= length && self.nodes[index].is_free == true {
+            if self.fits(index, length, align) {
                 return Some(index);
-            } else if let Some(left) = self.find_free(self.nodes[index].left, length) {
+            } else if let Some(left) = self.find_free(self.nodes[index].left, length, align) {
                 return Some(left);
-            } else if let Some(right) = self.find_free(self.nodes[index].right, length) {
+            } else if let Some(right) = self.find_free(self.nodes[index].right, length, align) {
                 return Some(right);
             } else {
                 None
@@ -165,21 +183,63 @@ impl IntrusiveIntervalTree {
             None
         }
     }
-    fn find_by_base(&mut self, index: usize, base: usize) -> Option<usize> {
+    fn find_by_base(&self, index: usize, base: usize) -> Option<usize> {
         if self.nodes[index].is_present() {
             if self.nodes[index].base == base {
-                return Some(index);
-            } else if let Some(left) = self.find_free(self.nodes[index].left, base) {
-                return Some(left);
-            } else if let Some(right) = self.find_free(self.nodes[index].right, base) {
-                return Some(right);
+                Some(index)
+            } else if base < self.nodes[index].base {
+                self.find_by_base(self.nodes[index].left, base)
             } else {
-                None
+                self.find_by_base(self.nodes[index].right, base)
             }
         } else {
             None
         }
     }
+    /// Carve an aligned block out of the end of a free node, whatever is left
+    /// past the aligned block is given back as a new free node
+    fn carve(&mut self, free: usize, length: usize, align: usize) -> usize {
+        let end = self.nodes[free].base + self.nodes[free].length;
+        let new_ptr = (end - length) & !(align - 1);
+        let tail = end - (new_ptr + length);
+        if new_ptr == self.nodes[free].base {
+            self.nodes[free].length = length;
+            self.nodes[free].is_free = false;
+        } else {
+            self.nodes[free].length = new_ptr - self.nodes[free].base;
+            self.root = self.insert(self.root, new_ptr, length, false);
+        }
+        if tail > 0 {
+            self.root = self.insert(self.root, new_ptr + length, tail, true);
+        }
+        new_ptr
+    }
+    /// Resize a used block without moving it, grows only by eating into the
+    /// free node right after it
+    fn resize_in_place(&mut self, used: usize, length: usize) -> bool {
+        let old_length = self.nodes[used].length;
+        if length < old_length {
+            self.nodes[used].length = length;
+            let base = self.nodes[used].base + length;
+            self.root = self.insert(self.root, base, old_length - length, true);
+            true
+        } else if length > old_length {
+            let extra = length - old_length;
+            let next_base = self.nodes[used].base + old_length;
+            match self.find_by_base(self.root, next_base) {
+                // Strictly greater, a zero-length node would alias the base of its successor
+                Some(next) if self.nodes[next].is_free && self.nodes[next].length > extra => {
+                    self.nodes[next].base += extra;
+                    self.nodes[next].length -= extra;
+                    self.nodes[used].length = length;
+                    true
+                }
+                _ => false,
+            }
+        } else {
+            true
+        }
+    }
 
     fn print_debug(&self, index: usize, level: usize) {
         let tree_print_node = |index: usize, level: usize| {
@@ -220,19 +280,42 @@ pub fn print_debug() {
         let arenas = &raw mut TBS_ALLOCATOR.arenas;
         for i in 0..MAX_ARENAS {
             if (*arenas)[i].is_present() {
-                kprint!("==>Arena#{i}\r\n");
+                kprint!("==>Arena#{i}{}\r\n", ["", " (dedicated)"][(*arenas)[i].dedicated as usize]);
                 let tree = ((*arenas)[i].get_base_mut() as *mut IntrusiveIntervalTree)
                     .as_mut().unwrap();
                 tree.print_debug(tree.root, 0);
             }
         }
     }
+    #[cfg(feature = "heap-debug")]
+    heap_debug::print_leaks();
+}
+
+#[inline]
+fn aligned_size(layout: Layout) -> usize {
+    layout.size().div_ceil(CACHE_LINE_SIZE) * CACHE_LINE_SIZE
+}
+
+/// Map every page touched by the given range if not already
+fn map_range(base: usize, length: usize) {
+    let aspace = vmm::AddressSpaceHandle::get_kernel();
+    let db = db::Database::get_mut();
+    let mut page = base & !0xfff;
+    while page < base + length {
+        if !vmm::Manager::has_mapping_present(db, aspace, page as u64) {
+            let handle = pmm::Manager::alloc_page();
+            vmm::Manager::map(db, aspace, handle.get() as u64, page as u64, 1, vmm::Page::PRESENT | vmm::Page::READ_WRITE);
+        }
+        page += pmm::PAGE_SIZE;
+    }
 }
 
 #[derive(Default, Debug)]
 struct Arena {
     base: usize,
     length: usize,
+    /// Only reachable through its `ArenaHandle`, never used for global allocations
+    dedicated: bool,
     lock: core::sync::atomic::AtomicBool,
 }
 impl Arena {
@@ -242,6 +325,7 @@ impl Arena {
         Self{
             base,
             length,
+            dedicated: false,
             lock: core::sync::atomic::AtomicBool::new(false),
         }
     }
@@ -256,7 +340,108 @@ impl Arena {
     pub fn is_present(&self) -> bool {
         self.base != 0
     }
+    #[inline]
+    fn get_tree(&mut self) -> &mut IntrusiveIntervalTree {
+        unsafe { (self.get_base_mut() as *mut IntrusiveIntervalTree).as_mut().unwrap() }
+    }
+    fn alloc(&mut self, layout: Layout) -> Option<*mut u8> {
+        let aligned_size = aligned_size(layout);
+        let align = layout.align().max(CACHE_LINE_SIZE);
+        let tree = self.get_tree();
+        let free = tree.find_free(tree.root, aligned_size, align)?;
+        let new_ptr = tree.carve(free, aligned_size, align);
+        map_range(new_ptr, aligned_size);
+        Some(new_ptr as *mut u8)
+    }
+    fn dealloc(&mut self, ptr: *mut u8) {
+        let tree = self.get_tree();
+        if let Some(used) = tree.find_by_base(tree.root, ptr as usize) {
+            // TODO: better merge algo this is literally a fuckign joke
+            tree.nodes[used].is_free = true;
+            return;
+        }
+        unreachable!()
+    }
+    fn resize(&mut self, ptr: *mut u8, layout: Layout) -> bool {
+        let aligned_size = aligned_size(layout);
+        let tree = self.get_tree();
+        if let Some(used) = tree.find_by_base(tree.root, ptr as usize) {
+            if tree.resize_in_place(used, aligned_size) {
+                map_range(ptr as usize, aligned_size);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Handle to a single arena, pass it to `Vec::new_in`/`Box::new_in` to keep the
+/// allocations of a subsystem in their own arena
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ArenaHandle(u8);
+impl ArenaHandle {
+    /// The arena shared with the global allocator
+    pub const DEFAULT: Self = Self(0);
+    /// Arena that owns the given heap pointer
+    #[inline]
+    fn from_ptr(ptr: *const u8) -> Self {
+        Self(((ptr as usize - ARENA_DEFAULT_BASE) / ARENA_DEFAULT_SPACING) as u8)
+    }
+    fn get_arena(self) -> Option<&'static mut Arena> {
+        unsafe {
+            let arenas = &raw mut TBS_ALLOCATOR.arenas;
+            (*arenas).get_mut(self.0 as usize).filter(|a| a.is_present())
+        }
+    }
+}
+unsafe impl Allocator for ArenaHandle {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return Ok(NonNull::slice_from_raw_parts(layout.dangling_ptr(), 0));
+        }
+        let arena = self.get_arena().ok_or(AllocError)?;
+        let ptr = arena.alloc(layout).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(NonNull::new(ptr).ok_or(AllocError)?, aligned_size(layout)))
+    }
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.get_arena().unwrap().dealloc(ptr.as_ptr());
+        }
+    }
+    unsafe fn grow(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if old_layout.size() != 0 && ptr.as_ptr().is_aligned_to(new_layout.align()) {
+            let arena = self.get_arena().ok_or(AllocError)?;
+            if arena.resize(ptr.as_ptr(), new_layout) {
+                return Ok(NonNull::slice_from_raw_parts(ptr, aligned_size(new_layout)));
+            }
+        }
+        let new_ptr = self.allocate(new_layout)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast::<u8>().as_ptr(), old_layout.size());
+            self.deallocate(ptr, old_layout);
+        }
+        Ok(new_ptr)
+    }
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if new_layout.size() == 0 {
+            unsafe { self.deallocate(ptr, old_layout) };
+            return Ok(NonNull::slice_from_raw_parts(new_layout.dangling_ptr(), 0));
+        }
+        if ptr.as_ptr().is_aligned_to(new_layout.align()) {
+            let arena = self.get_arena().ok_or(AllocError)?;
+            if arena.resize(ptr.as_ptr(), new_layout) {
+                return Ok(NonNull::slice_from_raw_parts(ptr, aligned_size(new_layout)));
+            }
+        }
+        let new_ptr = self.allocate(new_layout)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast::<u8>().as_ptr(), new_layout.size());
+            self.deallocate(ptr, old_layout);
+        }
+        Ok(new_ptr)
+    }
 }
+
 pub struct TbsAllocator {
     arenas: [Arena; MAX_ARENAS]
 }
@@ -276,77 +461,538 @@ impl TbsAllocator {
             ],
         }
     }
-    pub fn init(db: &mut db::Database, aspace: vmm::AddressSpaceHandle) {
+    fn init_arena(db: &mut db::Database, aspace: vmm::AddressSpaceHandle, index: usize, dedicated: bool) {
         unsafe {
-            // Initialize first arena
-            (*&raw mut TBS_ALLOCATOR).arenas[0] = Arena::new(ARENA_DEFAULT_BASE, ARENA_DEFAULT_SIZE);
+            let base = ARENA_DEFAULT_BASE + index * ARENA_DEFAULT_SPACING;
+            let arena = &mut (*&raw mut TBS_ALLOCATOR).arenas[index];
+            *arena = Arena::new(base, ARENA_DEFAULT_SIZE);
+            arena.dedicated = dedicated;
+            #[cfg(feature = "kasan")]
+            crate::kasan::Manager::add_region(db, aspace, base, ARENA_DEFAULT_SIZE);
             // Create first page for tree span
             let handle = pmm::Manager::alloc_page();
-            vmm::Manager::map(db, aspace, handle.get() as u64, ARENA_DEFAULT_BASE as u64, 1, vmm::Page::PRESENT | vmm::Page::READ_WRITE);
-            let tree = (*&raw mut TBS_ALLOCATOR).arenas[0].get_base_mut() as *mut IntrusiveIntervalTree;
-            (*tree).init(ARENA_DEFAULT_BASE, ARENA_DEFAULT_SIZE);
+            vmm::Manager::map(db, aspace, handle.get() as u64, base as u64, 1, vmm::Page::PRESENT | vmm::Page::READ_WRITE);
+            arena.get_tree().init(base, ARENA_DEFAULT_SIZE);
         }
     }
+    pub fn init(db: &mut db::Database, aspace: vmm::AddressSpaceHandle) {
+        // Initialize first arena
+        Self::init_arena(db, aspace, 0, false);
+    }
+    /// Sets up an arena only reachable through the returned handle
+    pub fn new_dedicated_arena(db: &mut db::Database, aspace: vmm::AddressSpaceHandle) -> Option<ArenaHandle> {
+        let index = unsafe {
+            let arenas = &raw const TBS_ALLOCATOR.arenas;
+            (*arenas).iter().position(|a| !a.is_present())?
+        };
+        Self::init_arena(db, aspace, index, true);
+        Some(ArenaHandle(index as u8))
+    }
 }
-unsafe impl GlobalAlloc for TbsAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl TbsAllocator {
+    unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
         if layout.size() == 0 {
             return core::ptr::null_mut();
         }
-        assert!(layout.align() <= CACHE_LINE_SIZE);
-        let aligned_size = layout.size().div_ceil(CACHE_LINE_SIZE) * CACHE_LINE_SIZE;
         unsafe {
             let arenas = &raw mut TBS_ALLOCATOR.arenas;
             for i in 0..MAX_ARENAS {
-                if (*arenas)[i].is_present() {
-                    let tree = ((*arenas)[i].get_base_mut() as *mut IntrusiveIntervalTree)
-                        .as_mut().unwrap();
-                    if let Some(free) = tree.find_free(tree.root, aligned_size) {
-                        let new_ptr;
-                        if tree.nodes[free].length == aligned_size {
-                            new_ptr = tree.nodes[free].base;
-                            tree.nodes[free].is_free = false;
-                        } else {
-                            new_ptr = tree.nodes[free].base + tree.nodes[free].length - aligned_size;
-                            tree.nodes[free].length -= aligned_size;
-                            tree.root = tree.insert(tree.root, new_ptr, aligned_size, false);
-                        }
-                        // Map if not already
-                        let aspace = vmm::AddressSpaceHandle::get_kernel();
-                        let db = db::Database::get_mut();
-                        if !vmm::Manager::has_mapping_present(db, aspace, (new_ptr & !0xfff) as u64) {
-                            let handle = pmm::Manager::alloc_page();
-                            vmm::Manager::map(db, aspace, handle.get() as u64, (new_ptr & !0xfff) as u64, 1, vmm::Page::PRESENT | vmm::Page::READ_WRITE);
-                        }
-                        return new_ptr as *mut u8;
+                let arena = &mut (*arenas)[i];
+                if arena.is_present() && !arena.dedicated {
+                    if let Some(new_ptr) = arena.alloc(layout) {
+                        return new_ptr;
                     }
                 }
             }
         }
-        unreachable!()
+        core::ptr::null_mut()
     }
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
         if layout.size() == 0 {
             unreachable!(); // ne
         }
-        assert!(layout.align() <= CACHE_LINE_SIZE);
-        let aligned_size = layout.size().div_ceil(CACHE_LINE_SIZE) * CACHE_LINE_SIZE;
+        ArenaHandle::from_ptr(ptr).get_arena().unwrap().dealloc(ptr);
+    }
+}
+#[cfg(not(feature = "heap-debug"))]
+unsafe impl GlobalAlloc for TbsAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.alloc_raw(layout) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.dealloc_raw(ptr, layout) }
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        if ArenaHandle::from_ptr(ptr).get_arena().unwrap().resize(ptr, new_layout) {
+            return ptr;
+        }
         unsafe {
-            let arenas = &raw mut TBS_ALLOCATOR.arenas;
-            for i in 0..MAX_ARENAS {
-                if (*arenas)[i].is_present() {
-                    let tree = ((*arenas)[i].get_base_mut() as *mut IntrusiveIntervalTree)
-                        .as_mut().unwrap();
-                    if let Some(used) = tree.find_by_base(tree.root, ptr as usize) {
-                        // TODO: better merge algo this is literally a fuckign joke
-                        tree.nodes[used].is_free = true;
-                        return;
-                    }
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            new_ptr
+        }
+    }
+}
+
+/// Heap debugging, enabled with the `heap-debug` feature
+///
+/// Every allocation is laid out as `[header | front guard | data | back guard]`,
+/// the header links all live allocations together and records the call site.
+/// Guards are checked on free, freed blocks are poisoned. Call sites come from
+/// walking the frame pointers so build with `-C force-frame-pointers=yes`.
+///
+/// With `kasan` the guards are also poisoned in the shadow memory and freed
+/// blocks sit in a quarantine for a while, so use-after-free can be reported
+/// together with the site that made the allocation.
+#[cfg(feature = "heap-debug")]
+mod heap_debug {
+    use super::*;
+    #[cfg(feature = "kasan")]
+    use crate::kasan;
+
+    pub const GUARD_SIZE: usize = 16;
+    pub const GUARD_BYTE: u8 = 0xfd;
+    pub const ALLOC_POISON: u8 = 0xcd;
+    pub const FREE_POISON: u8 = 0xdd;
+    pub const HEADER_MAGIC: u64 = 0x7b5a_110c_7b5a_110c;
+    pub const FREED_MAGIC: u64 = 0x7b5a_f4ee_7b5a_f4ee;
+    /// Return addresses recorded per allocation, the first few are still in
+    /// `__rust_alloc` and `alloc` (`RawVec`, `Box::new`...) so it takes more
+    /// than those to reach who allocated
+    pub const MAX_FRAMES: usize = 8;
+    /// Distinct call sites shown in the leak report
+    pub const MAX_SITES: usize = 32;
+    /// Freed blocks held back before being given to the arena again
+    #[cfg(feature = "kasan")]
+    pub const QUARANTINE_SIZE: usize = 64;
+
+    unsafe extern "C" {
+        unsafe static STACK_BOTTOM: u8;
+        unsafe static STACK_TOP: u8;
+    }
+
+    #[repr(C)]
+    struct Header {
+        magic: u64,
+        size: usize,
+        align: usize,
+        frames: [usize; MAX_FRAMES],
+        next: *mut Header,
+        prev: *mut Header,
+    }
+    impl Header {
+        #[inline]
+        fn get_layout(&self) -> Layout {
+            unsafe { Layout::from_size_align_unchecked(self.size, self.align) }
+        }
+    }
+
+    static mut LIVE: *mut Header = core::ptr::null_mut();
+    #[cfg(feature = "kasan")]
+    static mut QUARANTINE: ([*mut Header; QUARANTINE_SIZE], usize) =
+        ([core::ptr::null_mut(); QUARANTINE_SIZE], 0);
+
+    #[inline]
+    fn front_size(layout: Layout) -> usize {
+        let align = layout.align().max(CACHE_LINE_SIZE);
+        (core::mem::size_of::<Header>() + GUARD_SIZE).div_ceil(align) * align
+    }
+    #[inline]
+    fn outer_layout(layout: Layout) -> Layout {
+        let size = front_size(layout) + layout.size() + GUARD_SIZE;
+        Layout::from_size_align(size, layout.align().max(CACHE_LINE_SIZE)).unwrap()
+    }
+
+    /// Walk the frame pointer chain up from the allocator, inlined so `alloc`
+    /// itself is not a frame
+    #[inline(always)]
+    fn capture_frames() -> [usize; MAX_FRAMES] {
+        let mut frames = [0; MAX_FRAMES];
+        let stack = (&raw const STACK_BOTTOM as usize)..(&raw const STACK_TOP as usize);
+        let mut rbp: usize;
+        unsafe {
+            core::arch::asm!("mov {}, rbp", out(reg) rbp);
+        }
+        for f in frames.iter_mut() {
+            if !stack.contains(&rbp) || rbp % 8 != 0 {
+                break;
+            }
+            unsafe {
+                *f = (rbp as *const usize).add(1).read();
+                rbp = (rbp as *const usize).read();
+            }
+        }
+        frames
+    }
+
+    fn print_frames(frames: &[usize; MAX_FRAMES]) {
+        for (i, f) in frames.iter().take_while(|&&f| f != 0).enumerate() {
+            kprint!("{}{:016x}", ["", " <- "][(i != 0) as usize], f);
+        }
+    }
+
+    /// Returns what is wrong with the allocation, if anything
+    #[cfg_attr(feature = "kasan", sanitize(address = "off"))]
+    unsafe fn check(header: *const Header) -> Option<&'static str> {
+        unsafe {
+            if (*header).magic == FREED_MAGIC {
+                return Some("double free");
+            } else if (*header).magic != HEADER_MAGIC {
+                return Some("bad header (wild pointer)");
+            }
+            let base = header as *const u8;
+            let front = front_size((*header).get_layout());
+            for i in core::mem::size_of::<Header>()..front {
+                if base.add(i).read() != GUARD_BYTE {
+                    return Some("front red zone corrupted");
+                }
+            }
+            for i in 0..GUARD_SIZE {
+                if base.add(front + (*header).size + i).read() != GUARD_BYTE {
+                    return Some("back red zone corrupted");
                 }
             }
         }
-        unreachable!()
+        None
+    }
+
+    #[cfg_attr(feature = "kasan", sanitize(address = "off"))]
+    fn report(header: *const Header, what: &str) {
+        unsafe {
+            kprint!("[tbs] {what} at {:016x}", header as usize);
+            if (*header).magic == HEADER_MAGIC || (*header).magic == FREED_MAGIC {
+                let frames = (*header).frames;
+                kprint!(", {} bytes from ", (*header).size);
+                print_frames(&frames);
+            }
+            kprint!("\r\n");
+        }
+    }
+
+    #[cfg_attr(feature = "kasan", sanitize(address = "off"))]
+    unsafe fn unlink(header: *mut Header) {
+        unsafe {
+            if !(*header).prev.is_null() {
+                (*(*header).prev).next = (*header).next;
+            } else {
+                LIVE = (*header).next;
+            }
+            if !(*header).next.is_null() {
+                (*(*header).next).prev = (*header).prev;
+            }
+        }
+    }
+
+    /// Block is poisoned but kept around, the oldest one is actually released
+    #[cfg(feature = "kasan")]
+    #[sanitize(address = "off")]
+    unsafe fn quarantine(tbs: &TbsAllocator, header: *mut Header) {
+        unsafe {
+            let (blocks, next) = &mut *&raw mut QUARANTINE;
+            let old = core::mem::replace(&mut blocks[*next], header);
+            *next = (*next + 1) % QUARANTINE_SIZE;
+            if !old.is_null() {
+                let outer = outer_layout((*old).get_layout());
+                (old as *mut u8).write_bytes(FREE_POISON, outer.size());
+                tbs.dealloc_raw(old as *mut u8, outer);
+            }
+        }
+    }
+
+    unsafe impl GlobalAlloc for TbsAllocator {
+        #[cfg_attr(feature = "kasan", sanitize(address = "off"))]
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if layout.size() == 0 {
+                return core::ptr::null_mut();
+            }
+            let front = front_size(layout);
+            unsafe {
+                let outer = outer_layout(layout);
+                let block = self.alloc_raw(outer);
+                if block.is_null() {
+                    return block;
+                }
+                let header = block as *mut Header;
+                header.write(Header {
+                    magic: HEADER_MAGIC,
+                    size: layout.size(),
+                    align: layout.align(),
+                    frames: capture_frames(),
+                    next: LIVE,
+                    prev: core::ptr::null_mut(),
+                });
+                if !LIVE.is_null() {
+                    (*LIVE).prev = header;
+                }
+                LIVE = header;
+                let ptr = block.add(front);
+                block.add(core::mem::size_of::<Header>()).write_bytes(GUARD_BYTE, front - core::mem::size_of::<Header>());
+                ptr.write_bytes(ALLOC_POISON, layout.size());
+                ptr.add(layout.size()).write_bytes(GUARD_BYTE, GUARD_SIZE);
+                #[cfg(feature = "kasan")]
+                {
+                    kasan::Manager::poison(block as usize, front, kasan::HEAP_LEFT_REDZONE);
+                    kasan::Manager::poison(ptr as usize, outer.size() - front, kasan::HEAP_RIGHT_REDZONE);
+                    kasan::Manager::unpoison(ptr as usize, layout.size());
+                }
+                ptr
+            }
+        }
+        #[cfg_attr(feature = "kasan", sanitize(address = "off"))]
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe {
+                let header = ptr.sub(front_size(layout)) as *mut Header;
+                if let Some(what) = check(header) {
+                    report(header, what);
+                    panic!("heap corruption");
+                }
+                if (*header).size != layout.size() {
+                    report(header, "size mismatch on free");
+                    panic!("heap corruption");
+                }
+                unlink(header);
+                #[cfg(feature = "kasan")]
+                {
+                    // Keep the header so reports can still name the allocation site
+                    (*header).magic = FREED_MAGIC;
+                    ptr.write_bytes(FREE_POISON, layout.size());
+                    kasan::Manager::poison(ptr as usize, layout.size(), kasan::HEAP_FREED);
+                    quarantine(self, header);
+                }
+                #[cfg(not(feature = "kasan"))]
+                {
+                    let outer = outer_layout(layout);
+                    (header as *mut u8).write_bytes(FREE_POISON, outer.size());
+                    self.dealloc_raw(header as *mut u8, outer);
+                }
+            }
+        }
+    }
+
+    /// Verify every live allocation and print them grouped by call site
+    #[cfg_attr(feature = "kasan", sanitize(address = "off"))]
+    pub fn print_leaks() {
+        let mut sites: StaticVec<([usize; MAX_FRAMES], usize, usize), MAX_SITES> = StaticVec::new();
+        let mut other = (0, 0);
+        unsafe {
+            let mut header = LIVE;
+            while !header.is_null() {
+                if let Some(what) = check(header) {
+                    report(header, what);
+                }
+                let frames = (*header).frames;
+                let len = sites.len();
+                if let Some(site) = sites.iter_mut().take(len).find(|s| s.0 == frames) {
+                    site.1 += 1;
+                    site.2 += (*header).size;
+                } else if sites.len() < sites.max_len() {
+                    sites.push((frames, 1, (*header).size));
+                } else {
+                    other.0 += 1;
+                    other.1 += (*header).size;
+                }
+                header = (*header).next;
+            }
+        }
+        kprint!("==>Live allocations by call site\r\n");
+        for site in sites.iter().take(sites.len()) {
+            kprint!("{:>6} allocs {:>10} bytes from ", site.1, site.2);
+            print_frames(&site.0);
+            kprint!("\r\n");
+        }
+        if other.0 != 0 {
+            kprint!("{:>6} allocs {:>10} bytes from other sites\r\n", other.0, other.1);
+        }
+    }
+
+    /// Print the live or quarantined allocation the address falls into
+    #[cfg(feature = "kasan")]
+    #[sanitize(address = "off")]
+    pub fn describe_address(addr: usize) {
+        let contains = |header: *mut Header| unsafe {
+            let base = header as usize;
+            addr >= base && addr < base + outer_layout((*header).get_layout()).size()
+        };
+        unsafe {
+            let mut header = LIVE;
+            while !header.is_null() {
+                if contains(header) {
+                    let offset = addr as isize - (header as usize + front_size((*header).get_layout())) as isize;
+                    kprint!("[kasan] {offset} bytes into live allocation of {} bytes from ", (*header).size);
+                    let frames = (*header).frames;
+                    print_frames(&frames);
+                    kprint!("\r\n");
+                    return;
+                }
+                header = (*header).next;
+            }
+            let (blocks, _) = &*&raw const QUARANTINE;
+            for &header in blocks.iter() {
+                if !header.is_null() && contains(header) {
+                    kprint!("[kasan] inside freed allocation of {} bytes from ", (*header).size);
+                    let frames = (*header).frames;
+                    print_frames(&frames);
+                    kprint!("\r\n");
+                    return;
+                }
+            }
+        }
+        kprint!("[kasan] not inside any known allocation\r\n");
+    }
+}
+
+/// Used by the sanitizer to name the allocation behind a bad access
+#[cfg(feature = "kasan")]
+pub fn describe_address(addr: usize) {
+    heap_debug::describe_address(addr);
+}
+
+/// Not the `#[global_allocator]` itself, `slab::SlabAllocator` sits in front of it
+pub(crate) static mut TBS_ALLOCATOR: TbsAllocator = TbsAllocator::new();
+
+console_commands! {
+    Command {
+        name: "pal",
+        category: "memory",
+        desc: "print allocator info",
+        help: "",
+        args: &[],
+        handler: |_state, _args| print_debug(),
+    },
+    Command {
+        name: "leak",
+        category: "memory",
+        desc: "leak this amt of memory",
+        help: "Allocates from the global heap and never frees, prints the pointer.",
+        args: &[ArgSpec::required("length", ArgKind::Literal), ArgSpec::optional("align", ArgKind::Literal)],
+        handler: |state, args| {
+            let size = args.get_literal(1).unwrap();
+            let align = args.get_literal(2).unwrap_or(1);
+            if let Ok(layout) = Layout::from_size_align(size, align) {
+                unsafe {
+                    let p = alloc::alloc::alloc(layout);
+                    kprint!("{:?}\r\n", p);
+                }
+            } else {
+                console_error!(state, "invalid align\r\n");
+            }
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Backing storage for a tree, the node array follows the header
+    struct TestTree {
+        buffer: std::vec::Vec<u64>,
+    }
+    impl TestTree {
+        fn new(base: usize, length: usize) -> Self {
+            let mut t = Self { buffer: std::vec![0; 0x4000] };
+            t.get().init(base, length);
+            t
+        }
+        fn get(&mut self) -> &mut IntrusiveIntervalTree {
+            unsafe { (self.buffer.as_mut_ptr() as *mut IntrusiveIntervalTree).as_mut().unwrap() }
+        }
+    }
+
+    fn alloc(tree: &mut IntrusiveIntervalTree, length: usize, align: usize) -> Option<usize> {
+        let free = tree.find_free(tree.root, length, align)?;
+        Some(tree.carve(free, length, align))
+    }
+
+    /// Returns the height, asserting ordering and AVL balance along the way
+    fn check_subtree(tree: &IntrusiveIntervalTree, index: usize, lo: usize, hi: usize) -> i8 {
+        let node = tree.get_node(index);
+        if !node.is_present() {
+            return 0;
+        }
+        assert!(node.base > lo && node.base < hi, "{:x} out of order", node.base);
+        let left = check_subtree(tree, node.left, lo, node.base);
+        let right = check_subtree(tree, node.right, node.base, hi);
+        assert!((left - right).abs() <= 1, "unbalanced at {:x}", node.base);
+        assert_eq!(node.height, 1 + left.max(right));
+        1 + left.max(right)
+    }
+
+    /// Sum of all node lengths, free and used, must always cover the whole arena
+    fn total_length(tree: &IntrusiveIntervalTree, index: usize) -> usize {
+        let node = tree.get_node(index);
+        if node.is_present() {
+            node.length + total_length(tree, node.left) + total_length(tree, node.right)
+        } else {
+            0
+        }
+    }
+
+    #[test]
+    fn carve_from_end() {
+        let mut t = TestTree::new(0x1000, 0x10000);
+        let tree = t.get();
+        let a = alloc(tree, 0x100, 8).unwrap();
+        assert_eq!(a, 0x11000 - 0x100);
+        let b = alloc(tree, 0x100, 8).unwrap();
+        assert_eq!(b, a - 0x100);
+        assert!(!tree.get_node(tree.find_by_base(tree.root, a).unwrap()).is_free);
+        assert_eq!(total_length(tree, tree.root), 0x10000);
+    }
+
+    #[test]
+    fn carve_aligned_leaves_free_tail() {
+        let mut t = TestTree::new(0x1000, 0x10000);
+        let tree = t.get();
+        let a = alloc(tree, 0x100, 0x4000).unwrap();
+        assert_eq!(a % 0x4000, 0);
+        let tail = tree.find_by_base(tree.root, a + 0x100).unwrap();
+        assert!(tree.get_node(tail).is_free);
+        assert_eq!(a + 0x100 + tree.get_node(tail).length, 0x11000);
+        assert_eq!(total_length(tree, tree.root), 0x10000);
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut t = TestTree::new(0x1000, 0x1000);
+        let tree = t.get();
+        assert!(alloc(tree, 0x2000, 8).is_none());
+        assert_eq!(alloc(tree, 0x1000, 8), Some(0x1000));
+        assert!(alloc(tree, 8, 8).is_none());
+    }
+
+    #[test]
+    fn stays_balanced() {
+        let mut t = TestTree::new(0x1000, 0x100000);
+        let tree = t.get();
+        for i in 0..200 {
+            alloc(tree, 16 + (i % 7) * 16, 16).unwrap();
+        }
+        let height = check_subtree(tree, tree.root, 0, usize::MAX);
+        // AVL bound is ~1.44 log2(n), n is at most 201 here
+        assert!(height <= 11, "height {height}");
+        assert_eq!(total_length(tree, tree.root), 0x100000);
+    }
+
+    #[test]
+    fn resize_in_place_shrink_and_grow() {
+        let mut t = TestTree::new(0x1000, 0x10000);
+        let tree = t.get();
+        let a = alloc(tree, 0x400, 8).unwrap();
+        let b = alloc(tree, 0x400, 8).unwrap();
+        let used = tree.find_by_base(tree.root, b).unwrap();
+        assert!(tree.resize_in_place(used, 0x100));
+        let freed = tree.find_by_base(tree.root, b + 0x100).unwrap();
+        assert!(tree.get_node(freed).is_free);
+        assert!(tree.resize_in_place(used, 0x200));
+        assert_eq!(tree.get_node(freed).base, b + 0x200);
+        // Only 0x200 free after it, eating all of it is refused
+        assert!(!tree.resize_in_place(used, 0x400));
+        assert!(tree.get_node(tree.find_by_base(tree.root, a).unwrap()).length == 0x400);
+        assert_eq!(total_length(tree, tree.root), 0x10000);
     }
 }
-#[global_allocator]
-static mut TBS_ALLOCATOR: TbsAllocator = TbsAllocator::new();
@@ -0,0 +1,693 @@
+//! AHCI over DMA
+//!
+//! Finds the first SATA controller in AHCI mode on the PCI bus (q35 has one
+//! at 00:1f.2, where `-drive if=ide` ends up there), maps its registers (the
+//! ABAR, BAR 5) uncached and brings up every implemented port with a disk
+//! behind it. A page of physical memory per port holds its command list,
+//! received FISes and the one command table it uses, whose PRDT points at
+//! bounce pages the data is copied through. Commands are NCQ-less READ/WRITE
+//! DMA (EXT) and FLUSH CACHE (EXT) from slot 0, one per port at a time, and
+//! finish on the controller's interrupt, the PCI line through the PIC; with
+//! interrupts off (at boot) or once one never came the port is polled
+//! instead, as `ata` does. Disks register with the block layer as
+//! `/devices/sataN`, N being the port, and get scanned for partitions.
+//!
+//! ATAPI drives and port multipliers are only listed.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+use crate::ata::{self, Bits, Identity};
+use crate::console::Command;
+use crate::{block, clock, console_commands, const_assert, cpu, db, klog, kprint, partition, pci, pic, pmm, vmm};
+
+/// Mass storage, SATA, AHCI 1.0
+const PCI_CLASS: (u8, u8, u8) = (0x01, 0x06, 0x01);
+const ABAR_INDEX: usize = 5;
+const MAX_PORTS: usize = 32;
+
+// Generic host control registers
+const CAP: usize = 0x00;
+const GHC: usize = 0x04;
+const IS: usize = 0x08;
+const PI: usize = 0x0c;
+const VS: usize = 0x10;
+const CAP_S64A: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+// Port registers, from `PORT_BASE + port * PORT_SIZE`
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_FB: usize = 0x08;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SCTL: usize = 0x2c;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+/// Task file, host bus data, host bus fatal and interface fatal errors
+const IS_ERRORS: u32 = 0xf << 27;
+/// D2H register, PIO setup, DMA setup, set device bits and the errors
+const IE_ALL: u32 = 0xf | IS_ERRORS;
+/// DET in SSTS, a device is there and talking
+const SSTS_ESTABLISHED: u32 = 3;
+/// DET in SCTL, COMRESET while it is set
+const SCTL_RESET: u32 = 1;
+
+const SIG_ATA: u32 = 0x0000_0101;
+const SIG_ATAPI: u32 = 0xeb14_0101;
+const SIG_SEMB: u32 = 0xc33c_0101;
+const SIG_PORT_MULTIPLIER: u32 = 0x9669_0101;
+
+const FIS_REGISTER_H2D: u8 = 0x27;
+/// The FIS carries a command, not a device control update
+const FIS_COMMAND: u8 = 0x80;
+const DEVICE_LBA: u8 = 0x40;
+const CMD_READ_DMA: u8 = 0xc8;
+const CMD_READ_DMA_EXT: u8 = 0x25;
+const CMD_WRITE_DMA: u8 = 0xca;
+const CMD_WRITE_DMA_EXT: u8 = 0x35;
+
+// Where things sit in a port's page: the 32 command headers (only slot 0 is
+// used), the received FIS area and the command table, its PRDT after the
+// command FIS, ATAPI command and reserved bytes
+const COMMAND_LIST: usize = 0x000;
+const RECEIVED_FIS: usize = 0x400;
+const COMMAND_TABLE: usize = 0x500;
+const PRDT: usize = COMMAND_TABLE + 0x80;
+const PRD_SIZE: usize = 16;
+/// Bounce pages per port, a PRD each, so a command moves at most 64 KiB
+const BOUNCE_PAGES: usize = 16;
+const_assert!(PRDT + BOUNCE_PAGES * PRD_SIZE <= pmm::PAGE_SIZE);
+const MAX_SECTORS: usize = BOUNCE_PAGES * pmm::PAGE_SIZE / ata::SECTOR_SIZE as usize;
+
+/// How long ST/FRE may take to stop or the link to come back after a COMRESET
+const PORT_TIMEOUT_US: u64 = 500_000;
+/// How long a command may take before the port gets reset
+const COMMAND_TIMEOUT_US: u64 = 5_000_000;
+/// How long a command waits for its interrupt before the port gets polled
+const IRQ_TIMEOUT_US: u64 = 500_000;
+
+/// The H2D register FIS that issues `command` on `count` sectors from `lba`,
+/// LBA28 unless `ext`
+fn command_fis(command: u8, lba: u64, count: u16, ext: bool) -> [u8; 20] {
+    let mut fis = [0u8; 20];
+    fis[0] = FIS_REGISTER_H2D;
+    fis[1] = FIS_COMMAND;
+    fis[2] = command;
+    fis[4..7].copy_from_slice(&lba.to_le_bytes()[..3]);
+    fis[7] = DEVICE_LBA;
+    if ext {
+        fis[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+        fis[12..14].copy_from_slice(&count.to_le_bytes());
+    } else {
+        fis[7] |= (lba >> 24) as u8 & 0x0f;
+        // 256 sectors go in as 0
+        fis[12] = count as u8;
+    }
+    fis
+}
+/// Command header 0: FIS length in dwords, direction, PRDT length and where the table is
+fn command_header(write: bool, prds: usize, table: u64) -> [u32; 4] {
+    let flags = (20 / 4) | (write as u32) << 6 | (prds as u32) << 16;
+    [flags, 0, table as u32, (table >> 32) as u32]
+}
+/// A PRD for `len` bytes at `addr`, the byte count goes in minus one
+fn prd(addr: u64, len: usize) -> [u32; 4] {
+    [addr as u32, (addr >> 32) as u32, 0, (len as u32 - 1) & 0x3f_ffff]
+}
+
+/// The driver data of a disk's block device
+struct Disk {
+    port: usize,
+    identity: Identity,
+}
+
+/// A block request while its port works on it
+struct Transfer {
+    command: u8,
+    lba48: bool,
+    op: block::Op,
+    lba: u64,
+    data: Vec<u8>,
+    /// Bytes moved so far
+    done: usize,
+    /// Sectors the command on the port moves
+    count: usize,
+}
+
+/// How a transfer went and its data
+type Finished = (Result<(), block::Error>, Vec<u8>);
+
+struct Port {
+    /// What answered the port, 0 with nothing there
+    signature: u32,
+    /// Set up and holding a disk
+    present: bool,
+    /// Gave up on its interrupt, see `IRQ_TIMEOUT_US`
+    polled: bool,
+    /// Command list, received FISes and command table, see `COMMAND_LIST`
+    memory: Option<pmm::Handle>,
+    bounce: Vec<pmm::Handle>,
+    active: Option<Transfer>,
+    /// When the active command was issued
+    since: u64,
+    /// PxIS bits seen since then
+    events: u32,
+    /// Until its device polls for it
+    finished: Option<Finished>,
+}
+impl Port {
+    const fn new() -> Self {
+        Self {
+            signature: 0,
+            present: false,
+            polled: false,
+            memory: None,
+            bounce: Vec::new(),
+            active: None,
+            since: 0,
+            events: 0,
+            finished: None,
+        }
+    }
+}
+
+struct Controller {
+    function: Option<pci::Function>,
+    /// What the firmware routed INTx to, `None` to only poll
+    irq: Option<u8>,
+    ports: [Port; MAX_PORTS],
+}
+
+enum Event {
+    /// Still running, or the interrupt did not come yet
+    Nothing,
+    Done,
+    /// The PxIS error bits
+    Failed(u32),
+    TimedOut,
+}
+
+static mut CONTROLLER_STATE: Controller = Controller { function: None, irq: None, ports: [const { Port::new() }; MAX_PORTS] };
+/// Where the registers are, identity mapped
+static ABAR: AtomicU64 = AtomicU64::new(0);
+/// PxIS bits the interrupt cleared, taken by `Manager::wait_event`
+static IRQ_STATUS: [AtomicU32; MAX_PORTS] = [const { AtomicU32::new(0) }; MAX_PORTS];
+
+fn get_controller() -> &'static mut Controller {
+    unsafe { (&raw mut CONTROLLER_STATE).as_mut().unwrap() }
+}
+
+fn read(reg: usize) -> u32 {
+    unsafe { ((ABAR.load(Ordering::Relaxed) as usize + reg) as *const u32).read_volatile() }
+}
+fn write(reg: usize, value: u32) {
+    unsafe { ((ABAR.load(Ordering::Relaxed) as usize + reg) as *mut u32).write_volatile(value) }
+}
+fn port_read(port: usize, reg: usize) -> u32 {
+    read(PORT_BASE + port * PORT_SIZE + reg)
+}
+fn port_write(port: usize, reg: usize, value: u32) {
+    write(PORT_BASE + port * PORT_SIZE + reg, value)
+}
+/// Polls `done` until it holds, false if it did not within `timeout` microseconds
+fn wait_until(timeout: u64, done: impl Fn() -> bool) -> bool {
+    let start = clock::Manager::get_micros();
+    while !done() {
+        if clock::Manager::get_micros() - start > timeout {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+fn write_dwords(at: *mut u8, dwords: &[u32]) {
+    for (i, &dword) in dwords.iter().enumerate() {
+        unsafe { (at as *mut u32).add(i).write_volatile(dword) }
+    }
+}
+
+pub struct Manager;
+impl Manager {
+    /// Needs `pic::Manager::init`, the block layer and the kernel address
+    /// space to map the ABAR into, polls until interrupts are on
+    pub fn init(db: &mut db::Database, aspace: vmm::AddressSpaceHandle) {
+        let found = pci::Manager::find(PCI_CLASS);
+        let Some(&function) = found.first() else {
+            return;
+        };
+        if found.len() > 1 {
+            klog!(Warn, "ahci", "{} controllers, only {} is used", found.len(), function.address);
+        }
+        let Some(pci::Bar::Memory { base, size, .. }) = pci::Manager::get_bar(function.address, ABAR_INDEX) else {
+            klog!(Warn, "ahci", "{}: no memory BAR {ABAR_INDEX}, left alone", function.address);
+            return;
+        };
+        let pages = (size as usize).div_ceil(pmm::PAGE_SIZE);
+        let flags = vmm::Page::PRESENT | vmm::Page::READ_WRITE | vmm::Page::WRITE_THROUGH | vmm::Page::CACHE_DISABLE;
+        vmm::Manager::map(db, aspace, base, base, pages, flags);
+        for page in 0..pages {
+            vmm::Manager::invalidate_single(base + (page * pmm::PAGE_SIZE) as u64);
+        }
+        pci::Manager::enable_bus_master(function.address);
+        ABAR.store(base, Ordering::Relaxed);
+        write(GHC, (read(GHC) | GHC_AE) & !GHC_IE);
+        let (cap, implemented, version) = (read(CAP), read(PI), read(VS));
+        klog!(
+            Info,
+            "ahci",
+            "{} AHCI {}.{} at {base:#x}, ports {implemented:#x}{}",
+            function.address,
+            version >> 16,
+            (version >> 8) & 0xff,
+            if cap & CAP_S64A != 0 { ", 64 bit" } else { "" }
+        );
+        for port in (0..MAX_PORTS).filter(|port| implemented & (1 << port) != 0) {
+            Self::attach(db, port, cap & CAP_S64A != 0);
+        }
+        let c = get_controller();
+        c.function = Some(function);
+        match function.interrupt_line {
+            irq if irq < pic::IRQ_COUNT => {
+                c.irq = Some(irq);
+                pic::Manager::register(Self::entry as *const () as u64, irq);
+            }
+            _ => klog!(Warn, "ahci", "{}: no interrupt line, polling", function.address),
+        }
+        write(IS, read(IS));
+        write(GHC, read(GHC) | GHC_IE);
+    }
+
+    fn attach(db: &mut db::Database, port: usize, wide: bool) {
+        if port_read(port, PX_SSTS) & 0xf != SSTS_ESTABLISHED {
+            return;
+        }
+        let signature = port_read(port, PX_SIG);
+        let name = alloc::format!("sata{port}");
+        get_controller().ports[port].signature = signature;
+        match signature {
+            SIG_ATA => {}
+            _ => {
+                klog!(Info, "ahci", "{name}: {}, left alone", Self::describe(signature));
+                return;
+            }
+        }
+        // The firmware may still have the port running on its own memory
+        if !Self::stop(port) {
+            klog!(Warn, "ahci", "{name}: would not stop, left alone");
+            return;
+        }
+        let memory = pmm::Manager::alloc_page_zeroed();
+        let bounce: Vec<_> = (0..BOUNCE_PAGES).map(|_| pmm::Manager::alloc_page()).collect();
+        let high = core::iter::once(&memory).chain(&bounce).any(|page| page.get() as u64 >> 32 != 0);
+        if high && !wide {
+            klog!(Warn, "ahci", "{name}: memory above 4 GiB and no 64 bit DMA, left alone");
+            core::iter::once(memory).chain(bounce).for_each(pmm::Manager::free_page);
+            return;
+        }
+        let base = memory.get() as u64;
+        for (reg, addr) in [(PX_CLB, base + COMMAND_LIST as u64), (PX_FB, base + RECEIVED_FIS as u64)] {
+            port_write(port, reg, addr as u32);
+            port_write(port, reg + 4, (addr >> 32) as u32);
+        }
+        port_write(port, PX_SERR, u32::MAX);
+        port_write(port, PX_IS, u32::MAX);
+        port_write(port, PX_IE, IE_ALL);
+        let p = &mut get_controller().ports[port];
+        p.memory = Some(memory);
+        p.bounce = bounce;
+        Self::start_port(port);
+        p.present = true;
+
+        let Some(identity) = Self::identify(port) else {
+            klog!(Warn, "ahci", "{name}: IDENTIFY failed or no LBA, left alone");
+            return;
+        };
+        klog!(
+            Info,
+            "ahci",
+            "{name}: {}, {} sectors of {}{}",
+            identity.model,
+            identity.sectors,
+            ata::SECTOR_SIZE,
+            if identity.lba48 { ", LBA48" } else { "" }
+        );
+        let sectors = identity.sectors;
+        let driver = block::Driver { start: Self::start, poll: Self::poll, ..block::Driver::DEFAULT };
+        match block::Manager::register(db, &name, ata::SECTOR_SIZE, sectors, driver, Some(Box::new(Disk { port, identity }))) {
+            Ok(device) => match partition::Manager::scan(db, device) {
+                Ok(0) => {}
+                Ok(count) => klog!(Info, "ahci", "{name}: {count} partitions"),
+                Err(e) => klog!(Warn, "ahci", "{name}: partition scan failed: {:?}", e),
+            },
+            Err(e) => klog!(Warn, "ahci", "{name}: {:?}", e),
+        }
+    }
+    /// IDENTIFY DEVICE through DMA like any other command, polled as interrupts are still off
+    fn identify(port: usize) -> Option<Identity> {
+        let mut t = Transfer {
+            command: ata::CMD_IDENTIFY,
+            lba48: false,
+            op: block::Op::Read,
+            lba: 0,
+            data: alloc::vec![0u8; ata::SECTOR_SIZE as usize],
+            done: 0,
+            count: 0,
+        };
+        if Self::issue(port, &mut t).is_some() {
+            return None;
+        }
+        get_controller().ports[port].active = Some(t);
+        let (result, bytes) = loop {
+            Self::service(port);
+            if let Some(finished) = get_controller().ports[port].finished.take() {
+                break finished;
+            }
+            core::hint::spin_loop();
+        };
+        result.ok()?;
+        let mut words = [0u16; 256];
+        for (word, b) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+            *word = u16::from_le_bytes([b[0], b[1]]);
+        }
+        Identity::parse(&words, false)
+    }
+
+    /// Clears ST and FRE and waits for the port to say it stopped
+    fn stop(port: usize) -> bool {
+        port_write(port, PX_CMD, port_read(port, PX_CMD) & !CMD_ST);
+        if !wait_until(PORT_TIMEOUT_US, || port_read(port, PX_CMD) & CMD_CR == 0) {
+            return false;
+        }
+        port_write(port, PX_CMD, port_read(port, PX_CMD) & !CMD_FRE);
+        wait_until(PORT_TIMEOUT_US, || port_read(port, PX_CMD) & CMD_FR == 0)
+    }
+    fn start_port(port: usize) {
+        port_write(port, PX_CMD, port_read(port, PX_CMD) | CMD_FRE);
+        port_write(port, PX_CMD, port_read(port, PX_CMD) | CMD_ST);
+    }
+    /// After an error or a timeout: the port restarts, and if the drive is
+    /// still busy the link gets a COMRESET first
+    fn recover(port: usize) {
+        Self::stop(port);
+        port_write(port, PX_SERR, u32::MAX);
+        port_write(port, PX_IS, u32::MAX);
+        let busy = (ata::STATUS_BSY | ata::STATUS_DRQ) as u32;
+        if port_read(port, PX_TFD) & busy != 0 {
+            let control = port_read(port, PX_SCTL) & !0xf;
+            port_write(port, PX_SCTL, control | SCTL_RESET);
+            // At least 1ms for the device to see it
+            wait_until(1_000, || false);
+            port_write(port, PX_SCTL, control);
+            if !wait_until(PORT_TIMEOUT_US, || port_read(port, PX_SSTS) & 0xf == SSTS_ESTABLISHED) {
+                klog!(Warn, "ahci", "sata{port}: link did not come back after a reset");
+            }
+            port_write(port, PX_SERR, u32::MAX);
+        }
+        Self::start_port(port);
+    }
+
+    fn start(db: &mut db::Database, device: block::DeviceHandle, request: &mut block::Request) -> Option<Result<(), block::Error>> {
+        let Some(d) = block::Manager::get_data::<Disk>(db, device) else {
+            return Some(Err(block::Error::Unknown));
+        };
+        if request.op != block::Op::Flush && request.data.is_empty() {
+            return Some(Ok(()));
+        }
+        let lba48 = d.identity.lba48;
+        let command = match (request.op, lba48) {
+            (block::Op::Read, false) => CMD_READ_DMA,
+            (block::Op::Read, true) => CMD_READ_DMA_EXT,
+            (block::Op::Write, false) => CMD_WRITE_DMA,
+            (block::Op::Write, true) => CMD_WRITE_DMA_EXT,
+            (block::Op::Flush, false) => ata::CMD_FLUSH,
+            (block::Op::Flush, true) => ata::CMD_FLUSH_EXT,
+        };
+        let mut transfer = Transfer {
+            command,
+            lba48,
+            op: request.op,
+            lba: request.lba,
+            data: core::mem::take(&mut request.data),
+            done: 0,
+            count: 0,
+        };
+        let port = d.port;
+        let result = Self::issue(port, &mut transfer);
+        let p = &mut get_controller().ports[port];
+        match result {
+            None => p.active = Some(transfer),
+            Some(result) => p.finished = Some((result, transfer.data)),
+        }
+        Self::poll(db, device, request)
+    }
+    fn poll(db: &mut db::Database, device: block::DeviceHandle, request: &mut block::Request) -> Option<Result<(), block::Error>> {
+        let port = block::Manager::get_data::<Disk>(db, device)?.port;
+        Self::service(port);
+        let (result, data) = get_controller().ports[port].finished.take()?;
+        request.data = data;
+        Some(result)
+    }
+
+    /// Finishes the port's command once it is done and starts the next one
+    /// for the rest of its transfer
+    fn service(port: usize) {
+        let Some(mut t) = get_controller().ports[port].active.take() else {
+            return;
+        };
+        let result = match Self::wait_event(port) {
+            Event::Nothing => {
+                get_controller().ports[port].active = Some(t);
+                return;
+            }
+            Event::Done => Self::complete(port, &mut t),
+            Event::Failed(events) => {
+                let tfd = port_read(port, PX_TFD);
+                let what = alloc::format!(
+                    "status {} error {} is {events:#010x}",
+                    Bits(tfd as u8, &ata::STATUS_NAMES),
+                    Bits((tfd >> 8) as u8, &ata::ERROR_NAMES)
+                );
+                Self::log_failure(port, &t, &what);
+                Self::recover(port);
+                Some(Err(block::Error::Io))
+            }
+            Event::TimedOut => {
+                Self::log_failure(port, &t, "timed out");
+                Self::recover(port);
+                Some(Err(block::Error::Io))
+            }
+        };
+        let p = &mut get_controller().ports[port];
+        match result {
+            None => p.active = Some(t),
+            Some(result) => p.finished = Some((result, core::mem::take(&mut t.data))),
+        }
+    }
+    /// Puts the command for what is left of `t` into slot 0 and issues it,
+    /// or finishes it right away
+    fn issue(port: usize, t: &mut Transfer) -> Option<Result<(), block::Error>> {
+        let sector_size = ata::SECTOR_SIZE as usize;
+        let busy = (ata::STATUS_BSY | ata::STATUS_DRQ) as u32;
+        if !wait_until(COMMAND_TIMEOUT_US, || port_read(port, PX_TFD) & busy == 0) {
+            Self::log_failure(port, t, "drive stayed busy");
+            Self::recover(port);
+            return Some(Err(block::Error::Io));
+        }
+        t.count = match t.op {
+            block::Op::Flush => 0,
+            _ => ((t.data.len() - t.done) / sector_size).min(MAX_SECTORS),
+        };
+        let bytes = t.count * sector_size;
+        let p = &mut get_controller().ports[port];
+        let base = p.memory.unwrap().get_mut();
+        let mut prds = 0;
+        for (i, chunk) in t.data[t.done..t.done + bytes].chunks(pmm::PAGE_SIZE).enumerate() {
+            let page = p.bounce[i];
+            if t.op == block::Op::Write {
+                unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), page.get_mut(), chunk.len()) };
+            }
+            write_dwords(unsafe { base.add(PRDT + i * PRD_SIZE) }, &prd(page.get() as u64, chunk.len()));
+            prds += 1;
+        }
+        let lba = t.lba + (t.done / sector_size) as u64;
+        let fis = command_fis(t.command, lba, t.count as u16, t.lba48);
+        unsafe { core::ptr::copy_nonoverlapping(fis.as_ptr(), base.add(COMMAND_TABLE), fis.len()) };
+        let table = base as u64 + COMMAND_TABLE as u64;
+        write_dwords(unsafe { base.add(COMMAND_LIST) }, &command_header(t.op == block::Op::Write, prds, table));
+        port_write(port, PX_IS, u32::MAX);
+        IRQ_STATUS[port].store(0, Ordering::SeqCst);
+        p.events = 0;
+        p.since = clock::Manager::get_micros();
+        // Everything above has to be in memory before the controller looks
+        fence(Ordering::SeqCst);
+        port_write(port, PX_CI, 1);
+        None
+    }
+    /// Takes in what the command moved, `None` while another one is needed for the rest
+    fn complete(port: usize, t: &mut Transfer) -> Option<Result<(), block::Error>> {
+        let p = &get_controller().ports[port];
+        if t.op == block::Op::Flush {
+            return Some(Ok(()));
+        }
+        fence(Ordering::SeqCst);
+        let base = p.memory.unwrap().get();
+        // PRDBC, what the controller says went over the wire
+        let moved = unsafe { (base.add(COMMAND_LIST + 4) as *const u32).read_volatile() } as usize;
+        let bytes = t.count * ata::SECTOR_SIZE as usize;
+        if moved != bytes {
+            Self::log_failure(port, t, &alloc::format!("moved {moved} of {bytes} bytes"));
+            return Some(Err(block::Error::Io));
+        }
+        if t.op == block::Op::Read {
+            for (i, chunk) in t.data[t.done..t.done + bytes].chunks_mut(pmm::PAGE_SIZE).enumerate() {
+                unsafe { core::ptr::copy_nonoverlapping(p.bounce[i].get(), chunk.as_mut_ptr(), chunk.len()) };
+            }
+        }
+        t.done += bytes;
+        match t.done >= t.data.len() {
+            true => Some(Ok(())),
+            false => Self::issue(port, t),
+        }
+    }
+    /// The interrupt, or PxCI itself while polling
+    fn wait_event(port: usize) -> Event {
+        let c = get_controller();
+        let irq = c.irq;
+        let p = &mut c.ports[port];
+        let elapsed = clock::Manager::get_micros() - p.since;
+        p.events |= IRQ_STATUS[port].swap(0, Ordering::SeqCst);
+        if let Some(irq) = irq.filter(|_| p.events == 0 && !p.polled && cpu::Manager::are_interrupts_enabled()) {
+            if elapsed < IRQ_TIMEOUT_US {
+                return Event::Nothing;
+            }
+            if port_read(port, PX_CI) & 1 == 0 {
+                klog!(Warn, "ahci", "sata{port}: irq {irq} never came, polling it from now on");
+                p.polled = true;
+            }
+        }
+        let status = port_read(port, PX_IS);
+        port_write(port, PX_IS, status);
+        p.events |= status;
+        if p.events & IS_ERRORS != 0 {
+            return Event::Failed(p.events & IS_ERRORS);
+        }
+        if port_read(port, PX_CI) & 1 != 0 {
+            return if elapsed > COMMAND_TIMEOUT_US { Event::TimedOut } else { Event::Nothing };
+        }
+        Event::Done
+    }
+    fn describe(signature: u32) -> &'static str {
+        match signature {
+            SIG_ATA => "ATA",
+            SIG_ATAPI => "ATAPI",
+            SIG_PORT_MULTIPLIER => "port multiplier",
+            SIG_SEMB => "enclosure bridge",
+            _ => "unknown device",
+        }
+    }
+    fn log_failure(port: usize, t: &Transfer, what: &str) {
+        let sector = t.lba + (t.done / ata::SECTOR_SIZE as usize) as u64;
+        klog!(Warn, "ahci", "sata{port}: {:?} ({:#04x}) at {sector} failed, {what}", t.op, t.command);
+    }
+
+    fn handle_irq(irq: u8) {
+        let pending = read(IS);
+        for port in (0..MAX_PORTS).filter(|port| pending & (1 << port) != 0) {
+            // The port's bits first, they are what holds the line up
+            let status = port_read(port, PX_IS);
+            port_write(port, PX_IS, status);
+            IRQ_STATUS[port].fetch_or(status, Ordering::SeqCst);
+        }
+        write(IS, pending);
+        pic::Manager::end_of_interrupt(irq);
+    }
+
+    #[unsafe(naked)]
+    unsafe extern "C" fn entry() {
+        #[unsafe(no_mangle)]
+        fn ahci_irq_inner(rsp: u64) {
+            let frame = unsafe { (rsp as *mut cpu::InterruptStackFrame).as_mut() }.unwrap();
+            Manager::handle_irq((frame.get_irq() as u8).wrapping_sub(pic::IRQ_BASE));
+        }
+        cpu::standard_interrupt_body!("call ahci_irq_inner");
+    }
+}
+
+console_commands! {
+    Command {
+        name: "ahci",
+        category: "devices",
+        desc: "AHCI controller and its ports",
+        help: "The controller in use, its interrupt, and each port with a device: what it is and whether it waits on the interrupt or polls.",
+        args: &[],
+        handler: |state, _args| {
+            let c = get_controller();
+            let Some(function) = c.function else {
+                kprint!("no AHCI controller\r\n");
+                return;
+            };
+            kprint!("{} {:04x}:{:04x} ABAR {:#x} ", function.address, function.vendor, function.device, ABAR.load(Ordering::Relaxed));
+            match c.irq {
+                Some(irq) => kprint!("irq {irq}\r\n"),
+                None => kprint!("polled\r\n"),
+            }
+            for (port, p) in c.ports.iter().enumerate().filter(|(_, p)| p.signature != 0) {
+                kprint!("port {port} {}", Manager::describe(p.signature));
+                match (p.present, p.polled) {
+                    (false, _) => kprint!(", left alone\r\n"),
+                    (true, false) => kprint!(", interrupt driven\r\n"),
+                    (true, true) => kprint!(", polled\r\n"),
+                }
+            }
+            block::Manager::for_each_device(state.db, |device, d| {
+                let Some(disk) = block::Manager::get_data::<Disk>(state.db, device) else {
+                    return;
+                };
+                kprint!(
+                    "sata{} {:?}, {} sectors of {}{}\r\n",
+                    disk.port,
+                    disk.identity.model,
+                    d.get_sector_count(),
+                    d.get_sector_size(),
+                    if disk.identity.lba48 { ", LBA48" } else { "" }
+                );
+            });
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_fis_packs_lba_and_count() {
+        let fis = command_fis(CMD_READ_DMA_EXT, 0x0605_0403_0201, 0x0180, true);
+        assert_eq!(fis[..4], [FIS_REGISTER_H2D, FIS_COMMAND, CMD_READ_DMA_EXT, 0]);
+        assert_eq!(fis[4..14], [0x01, 0x02, 0x03, DEVICE_LBA, 0x04, 0x05, 0x06, 0, 0x80, 0x01]);
+        // LBA28 keeps bits 24-27 in the device register, 256 sectors as 0
+        let fis = command_fis(CMD_WRITE_DMA, 0x0abc_def1, 256, false);
+        assert_eq!(fis[4..14], [0xf1, 0xde, 0xbc, DEVICE_LBA | 0x0a, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn command_header_and_prd_layout() {
+        let header = command_header(true, 3, 0x1_2345_6500);
+        assert_eq!(header, [0x0003_0045, 0, 0x2345_6500, 0x1]);
+        assert_eq!(command_header(false, 1, 0x8500)[0], 0x0001_0005);
+        assert_eq!(prd(0x7_0000_1000, 4096), [0x1000, 0x7, 0, 0xfff]);
+        assert_eq!(MAX_SECTORS, 128);
+    }
+}
@@ -0,0 +1,749 @@
+//! ATA and ATAPI over PIO
+//!
+//! Probes the two legacy IDE channels (0x1f0 on IRQ 14, 0x170 on IRQ 15),
+//! IDENTIFYs the master and slave of each and registers what answers with
+//! the block layer as `/devices/ataN`, N being channel * 2 + drive, then
+//! scans the disks for partitions. Disks read and write by LBA28, or LBA48
+//! past what that reaches, ATAPI drives only read, 2048 byte sectors through
+//! READ(12) packets. Data moves a sector at a time through the data port as
+//! the channel's interrupt says it is ready, with interrupts off (at boot)
+//! or once one never came the status is polled instead. Both drives of a
+//! channel queue on it and take turns.
+//!
+//! `system/drivers` only holds a stub of it as a loadable binary, until
+//! drivers can be loaded it lives here. Only the legacy ports are
+//! used, a PCI IDE function running a channel in native mode is switched
+//! to compatibility mode when it allows. On q35 (where `-drive if=ide` sits
+//! behind AHCI) there is nothing to find, `make qemu-ide` boots `-M pc`
+//! whose PIIX has them.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::console::Command;
+use crate::{block, clock, console_commands, cpu, db, klog, kprint, partition, pci, pic};
+
+/// Base port, control port and IRQ of the primary and secondary channel
+pub const CHANNELS: [(u16, u16, u8); 2] = [(0x1f0, 0x3f6, 14), (0x170, 0x376, 15)];
+
+// An IDE function's programming interface, per channel: in native PCI mode
+// rather than at the legacy ports, and able to switch between the two
+const IDE_CLASS: (u8, u8) = (0x01, 0x01);
+const PROG_IF: u8 = 0x09;
+const PROG_IF_NATIVE: [u8; 2] = [0x01, 0x04];
+const PROG_IF_SWITCHABLE: [u8; 2] = [0x02, 0x08];
+
+// Register offsets from the base port
+const DATA: u16 = 0;
+const ERROR: u16 = 1; // FEATURES on write
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4; // ATAPI byte count low
+const LBA_HIGH: u16 = 5; // ATAPI byte count high
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7; // COMMAND on write
+
+/// Device control, written to the control port, whose reads are the alternate status
+const CONTROL_RESET: u8 = 0x04;
+/// Bit 6 selects LBA, 7 and 5 are always set on old drives, bit 4 picks the slave
+const DRIVE_LBA: u8 = 0xe0;
+const DRIVE_LBA48: u8 = 0x40;
+
+pub(crate) const STATUS_ERR: u8 = 0x01;
+pub(crate) const STATUS_DRQ: u8 = 0x08;
+pub(crate) const STATUS_DF: u8 = 0x20;
+pub(crate) const STATUS_BSY: u8 = 0x80;
+pub(crate) const STATUS_NAMES: [&str; 8] = ["ERR", "IDX", "CORR", "DRQ", "SRV", "DF", "DRDY", "BSY"];
+pub(crate) const ERROR_NAMES: [&str; 8] = ["AMNF", "TK0NF", "ABRT", "MCR", "IDNF", "MC", "UNC", "ICRC"];
+
+const CMD_READ: u8 = 0x20;
+const CMD_READ_EXT: u8 = 0x24;
+const CMD_WRITE: u8 = 0x30;
+const CMD_WRITE_EXT: u8 = 0x34;
+const CMD_PACKET: u8 = 0xa0;
+const CMD_IDENTIFY_PACKET: u8 = 0xa1;
+pub(crate) const CMD_FLUSH: u8 = 0xe7;
+pub(crate) const CMD_FLUSH_EXT: u8 = 0xea;
+pub(crate) const CMD_IDENTIFY: u8 = 0xec;
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_12: u8 = 0xa8;
+/// What LBA mid and high say after a reset or IDENTIFY for an ATAPI drive
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xeb);
+
+pub(crate) const SECTOR_SIZE: u32 = 512;
+/// Sectors per command, 256 is what LBA28 takes at most
+const MAX_SECTORS: usize = 256;
+const LBA28_END: u64 = 1 << 28;
+/// How long BSY may stay up before the drive counts as hung
+const BUSY_TIMEOUT_US: u64 = 2_000_000;
+/// How long a command waits for its interrupt before the channel gets polled
+const IRQ_TIMEOUT_US: u64 = 500_000;
+/// READ CAPACITY tries, the first command after a reset reports a unit attention
+const CAPACITY_TRIES: usize = 3;
+
+/// What IDENTIFY (PACKET) DEVICE says, as far as the driver cares
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Identity {
+    pub(crate) model: String,
+    /// Nothing for ATAPI, READ CAPACITY says
+    pub(crate) sectors: u64,
+    pub(crate) lba48: bool,
+}
+impl Identity {
+    /// `None` for a disk without LBA
+    pub(crate) fn parse(words: &[u16; 256], atapi: bool) -> Option<Self> {
+        // Two characters a word, the first in the high byte, padded with spaces
+        let model: String = words[27..47].iter().flat_map(|w| w.to_be_bytes()).map(char::from).collect();
+        let model = String::from(model.trim());
+        if atapi {
+            return Some(Self { model, sectors: 0, lba48: false });
+        }
+        if words[49] & (1 << 9) == 0 {
+            return None;
+        }
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = match lba48 {
+            true => words[100..104].iter().rev().fold(0, |n, &w| (n << 16) | w as u64),
+            false => ((words[61] as u64) << 16) | words[60] as u64,
+        };
+        Some(Self { model, sectors, lba48 })
+    }
+}
+
+/// The set bits of a register by name, `-` for none
+pub(crate) struct Bits(pub(crate) u8, pub(crate) &'static [&'static str; 8]);
+impl fmt::Display for Bits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = (0..8).rev().filter(|i| self.0 & (1 << i) != 0).map(|i| self.1[i]);
+        match names.next() {
+            Some(first) => {
+                f.write_str(first)?;
+                names.try_for_each(|name| write!(f, "|{name}"))
+            }
+            None => f.write_str("-"),
+        }
+    }
+}
+
+/// The driver data of a drive's block device
+struct Drive {
+    channel: usize,
+    slave: bool,
+    atapi: bool,
+    sector_size: u32,
+    identity: Identity,
+}
+
+/// A block request while the channel works on it
+struct Transfer {
+    slave: bool,
+    atapi: bool,
+    lba48: bool,
+    sector_size: usize,
+    op: block::Op,
+    lba: u64,
+    data: Vec<u8>,
+    /// Bytes moved so far
+    done: usize,
+    /// Sectors the command on the channel still has to move
+    left: usize,
+}
+
+/// How a transfer went and its data
+type Finished = (Result<(), block::Error>, Vec<u8>);
+
+struct Channel {
+    present: bool,
+    /// Gave up on its interrupt, see `IRQ_TIMEOUT_US`
+    polled: bool,
+    active: Option<Transfer>,
+    /// When the active command was issued or last heard from
+    since: u64,
+    /// Per drive, taken in turns once the channel is free
+    waiting: [Option<Transfer>; 2],
+    /// Per drive, until its device polls for it
+    finished: [Option<Finished>; 2],
+    /// Which drive had the channel last
+    last: usize,
+}
+impl Channel {
+    const fn new() -> Self {
+        Self { present: false, polled: false, active: None, since: 0, waiting: [None, None], finished: [None, None], last: 0 }
+    }
+}
+
+enum Event {
+    /// Still busy, or the interrupt did not come yet
+    Nothing,
+    /// Not busy anymore, read from the status register which acks the interrupt
+    Status(u8),
+    TimedOut,
+}
+
+static mut CHANNEL_STATE: [Channel; 2] = [const { Channel::new() }; 2];
+/// Set by the interrupt, taken by `Manager::wait_event`
+static IRQ_FIRED: [AtomicBool; 2] = [const { AtomicBool::new(false) }; 2];
+
+fn get_channels() -> &'static mut [Channel; 2] {
+    unsafe { (&raw mut CHANNEL_STATE).as_mut().unwrap() }
+}
+
+fn read(channel: usize, reg: u16) -> u8 {
+    cpu::Manager::port_read_u8(CHANNELS[channel].0 + reg)
+}
+fn write(channel: usize, reg: u16, value: u8) {
+    cpu::Manager::port_write_u8(CHANNELS[channel].0 + reg, value)
+}
+fn alt_status(channel: usize) -> u8 {
+    cpu::Manager::port_read_u8(CHANNELS[channel].1)
+}
+/// The 400ns a drive needs to put up its status after a select or command,
+/// about 100 each alternate status read
+fn settle(channel: usize) {
+    for _ in 0..4 {
+        alt_status(channel);
+    }
+}
+fn read_data(channel: usize, data: &mut [u8]) {
+    for word in data.chunks_exact_mut(2) {
+        word.copy_from_slice(&cpu::Manager::port_read_u16(CHANNELS[channel].0 + DATA).to_le_bytes());
+    }
+}
+fn write_data(channel: usize, data: &[u8]) {
+    for word in data.chunks_exact(2) {
+        cpu::Manager::port_write_u16(CHANNELS[channel].0 + DATA, u16::from_le_bytes([word[0], word[1]]));
+    }
+}
+
+pub struct Manager;
+impl Manager {
+    /// Needs `pic::Manager::init` and the block layer, polls until interrupts are on
+    pub fn init(db: &mut db::Database) {
+        let legacy = Self::find_controller();
+        for (channel, &(_, _, irq)) in CHANNELS.iter().enumerate() {
+            if !legacy[channel] || !Self::probe(channel) {
+                continue;
+            }
+            get_channels()[channel].present = true;
+            pic::Manager::register(Self::entry as *const () as u64, irq);
+            for slave in [false, true] {
+                Self::attach(db, channel, slave);
+            }
+        }
+    }
+
+    /// Which channels are at the legacy ports. All of them when there is no
+    /// IDE function on PCI, an ISA machine, else those the function runs in
+    /// compatibility mode after switching over what it lets
+    fn find_controller() -> [bool; 2] {
+        let mut found = None;
+        pci::Manager::for_each_function(|f| {
+            if found.is_none() && (f.class.0, f.class.1) == IDE_CLASS {
+                found = Some(*f);
+            }
+        });
+        let Some(function) = found else {
+            return [true; 2];
+        };
+        let mut interface = function.class.2;
+        for channel in 0..2 {
+            if interface & PROG_IF_NATIVE[channel] != 0 && interface & PROG_IF_SWITCHABLE[channel] != 0 {
+                interface &= !PROG_IF_NATIVE[channel];
+            }
+        }
+        if interface != function.class.2 {
+            pci::Manager::write_u8(function.address, PROG_IF, interface);
+            interface = pci::Manager::read_u8(function.address, PROG_IF);
+        }
+        klog!(Info, "ata", "{}: IDE controller {:04x}:{:04x}, interface {interface:#04x}", function.address, function.vendor, function.device);
+        core::array::from_fn(|channel| {
+            let native = interface & PROG_IF_NATIVE[channel] != 0;
+            if native {
+                klog!(Warn, "ata", "channel {channel}: stuck in native PCI mode, left alone");
+            }
+            !native
+        })
+    }
+    /// A floating bus reads all ones, else both drives get reset
+    fn probe(channel: usize) -> bool {
+        if alt_status(channel) == 0xff {
+            return false;
+        }
+        cpu::Manager::port_write_u8(CHANNELS[channel].1, CONTROL_RESET);
+        settle(channel);
+        cpu::Manager::port_write_u8(CHANNELS[channel].1, 0);
+        settle(channel);
+        Self::wait_busy(channel).is_some()
+    }
+    fn attach(db: &mut db::Database, channel: usize, slave: bool) {
+        let Some((atapi, words)) = Self::identify(channel, slave) else {
+            return;
+        };
+        let name = alloc::format!("ata{}", channel * 2 + slave as usize);
+        let Some(identity) = Identity::parse(&words, atapi) else {
+            klog!(Warn, "ata", "{name}: no LBA, left alone");
+            return;
+        };
+        let (sector_size, sectors) = match atapi {
+            true => match Self::read_capacity(channel, slave) {
+                Some(capacity) => capacity,
+                None => {
+                    klog!(Info, "ata", "{name}: ATAPI {}, no medium", identity.model);
+                    return;
+                }
+            },
+            false => (SECTOR_SIZE, identity.sectors),
+        };
+        klog!(
+            Info,
+            "ata",
+            "{name}: {} {}, {sectors} sectors of {sector_size}{}",
+            if atapi { "ATAPI" } else { "ATA" },
+            identity.model,
+            if identity.lba48 { ", LBA48" } else { "" }
+        );
+        let drive = Drive { channel, slave, atapi, sector_size, identity };
+        let driver = block::Driver { start: Self::start, poll: Self::poll, ..block::Driver::DEFAULT };
+        match block::Manager::register(db, &name, sector_size, sectors, driver, Some(Box::new(drive))) {
+            Ok(device) if !atapi => match partition::Manager::scan(db, device) {
+                Ok(0) => {}
+                Ok(count) => klog!(Info, "ata", "{name}: {count} partitions"),
+                Err(e) => klog!(Warn, "ata", "{name}: partition scan failed: {:?}", e),
+            },
+            Ok(_) => {}
+            Err(e) => klog!(Warn, "ata", "{name}: {:?}", e),
+        }
+    }
+
+    /// ATAPI or not and the 256 words IDENTIFY (PACKET) DEVICE returns, `None` with no drive there
+    fn identify(channel: usize, slave: bool) -> Option<(bool, [u16; 256])> {
+        write(channel, DRIVE, 0xa0 | (slave as u8) << 4);
+        settle(channel);
+        for reg in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            write(channel, reg, 0);
+        }
+        write(channel, STATUS, CMD_IDENTIFY);
+        settle(channel);
+        if read(channel, STATUS) == 0 {
+            return None;
+        }
+        Self::wait_busy(channel)?;
+        // ATAPI aborts IDENTIFY and leaves its signature, SATA has another one
+        let atapi = match (read(channel, LBA_MID), read(channel, LBA_HIGH)) {
+            (0, 0) => false,
+            ATAPI_SIGNATURE => true,
+            _ => return None,
+        };
+        if atapi {
+            write(channel, STATUS, CMD_IDENTIFY_PACKET);
+            settle(channel);
+        }
+        if Self::wait_data(channel)? & STATUS_ERR != 0 {
+            return None;
+        }
+        let mut bytes = [0u8; 512];
+        read_data(channel, &mut bytes);
+        read(channel, STATUS);
+        let mut words = [0u16; 256];
+        for (word, b) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+            *word = u16::from_le_bytes([b[0], b[1]]);
+        }
+        Some((atapi, words))
+    }
+    /// Sector size and count of the medium in an ATAPI drive, polled
+    fn read_capacity(channel: usize, slave: bool) -> Option<(u32, u64)> {
+        let mut cdb = [0u8; 12];
+        cdb[0] = SCSI_READ_CAPACITY;
+        let mut reply = [0u8; 8];
+        (0..CAPACITY_TRIES).find(|_| Self::packet_polled(channel, slave, &cdb, &mut reply))?;
+        let last = u32::from_be_bytes(reply[..4].try_into().unwrap()) as u64;
+        let size = u32::from_be_bytes(reply[4..].try_into().unwrap());
+        (size.is_power_of_two() && size as usize <= block::BUFFER_SIZE).then_some((size, last + 1))
+    }
+    /// A packet command reading into `reply`, done before interrupts are on
+    fn packet_polled(channel: usize, slave: bool, cdb: &[u8; 12], reply: &mut [u8]) -> bool {
+        if !Self::send_packet(channel, slave, cdb, reply.len() as u16) {
+            return false;
+        }
+        let mut done = 0;
+        loop {
+            settle(channel);
+            let Some(status) = Self::wait_busy(channel) else {
+                return false;
+            };
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                read(channel, STATUS);
+                return false;
+            }
+            if status & STATUS_DRQ == 0 {
+                read(channel, STATUS);
+                return done >= reply.len();
+            }
+            let at = done.min(reply.len());
+            done += Self::read_packet_data(channel, &mut reply[at..]);
+        }
+    }
+    /// PACKET, then the command itself once the drive asks for it
+    fn send_packet(channel: usize, slave: bool, cdb: &[u8; 12], byte_count: u16) -> bool {
+        write(channel, DRIVE, 0xa0 | (slave as u8) << 4);
+        settle(channel);
+        write(channel, ERROR, 0);
+        write(channel, LBA_MID, byte_count as u8);
+        write(channel, LBA_HIGH, (byte_count >> 8) as u8);
+        write(channel, STATUS, CMD_PACKET);
+        settle(channel);
+        match Self::wait_data(channel) {
+            Some(status) if status & STATUS_ERR == 0 => {
+                write_data(channel, cdb);
+                true
+            }
+            _ => false,
+        }
+    }
+    /// As much as the drive has ready, what did not fit is read and dropped
+    fn read_packet_data(channel: usize, data: &mut [u8]) -> usize {
+        let count = (read(channel, LBA_MID) as usize) | (read(channel, LBA_HIGH) as usize) << 8;
+        let len = count.min(data.len()) & !1;
+        read_data(channel, &mut data[..len]);
+        for _ in (len..count).step_by(2) {
+            cpu::Manager::port_read_u16(CHANNELS[channel].0 + DATA);
+        }
+        len
+    }
+
+    /// The status once BSY is down, `None` if it stays up
+    fn wait_busy(channel: usize) -> Option<u8> {
+        let start = clock::Manager::get_micros();
+        loop {
+            let status = alt_status(channel);
+            if status & STATUS_BSY == 0 {
+                return Some(status);
+            }
+            if clock::Manager::get_micros() - start > BUSY_TIMEOUT_US {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    /// The status once the drive wants data moved or failed
+    fn wait_data(channel: usize) -> Option<u8> {
+        let start = clock::Manager::get_micros();
+        loop {
+            let status = Self::wait_busy(channel)?;
+            if status & (STATUS_DRQ | STATUS_ERR | STATUS_DF) != 0 {
+                return Some(status);
+            }
+            if clock::Manager::get_micros() - start > BUSY_TIMEOUT_US {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    /// The interrupt, or the status itself while polling
+    fn wait_event(channel: usize) -> Event {
+        let c = &mut get_channels()[channel];
+        let elapsed = clock::Manager::get_micros() - c.since;
+        let fired = IRQ_FIRED[channel].swap(false, Ordering::SeqCst);
+        if !fired && !c.polled && cpu::Manager::are_interrupts_enabled() {
+            if elapsed < IRQ_TIMEOUT_US {
+                return Event::Nothing;
+            }
+            if alt_status(channel) & STATUS_BSY == 0 {
+                klog!(Warn, "ata", "channel {channel}: irq {} never came, polling it from now on", CHANNELS[channel].2);
+                c.polled = true;
+            }
+        }
+        if alt_status(channel) & STATUS_BSY != 0 {
+            return if elapsed > BUSY_TIMEOUT_US { Event::TimedOut } else { Event::Nothing };
+        }
+        Event::Status(read(channel, STATUS))
+    }
+
+    fn start(db: &mut db::Database, device: block::DeviceHandle, request: &mut block::Request) -> Option<Result<(), block::Error>> {
+        let Some(d) = block::Manager::get_data::<Drive>(db, device) else {
+            return Some(Err(block::Error::Unknown));
+        };
+        if request.op != block::Op::Flush && request.data.is_empty() {
+            return Some(Ok(()));
+        }
+        let transfer = Transfer {
+            slave: d.slave,
+            atapi: d.atapi,
+            lba48: d.identity.lba48,
+            sector_size: d.sector_size as usize,
+            op: request.op,
+            lba: request.lba,
+            data: core::mem::take(&mut request.data),
+            done: 0,
+            left: 0,
+        };
+        get_channels()[d.channel].waiting[d.slave as usize] = Some(transfer);
+        Self::poll(db, device, request)
+    }
+    fn poll(db: &mut db::Database, device: block::DeviceHandle, request: &mut block::Request) -> Option<Result<(), block::Error>> {
+        let d = block::Manager::get_data::<Drive>(db, device)?;
+        let (channel, slave) = (d.channel, d.slave as usize);
+        Self::service(channel);
+        let (result, data) = get_channels()[channel].finished[slave].take()?;
+        request.data = data;
+        Some(result)
+    }
+
+    /// Moves the channel's command along as far as the drive lets it, and
+    /// starts the next one once it is done
+    fn service(channel: usize) {
+        loop {
+            let c = &mut get_channels()[channel];
+            let (mut transfer, result) = match c.active.take() {
+                Some(mut transfer) => match Self::wait_event(channel) {
+                    Event::Nothing => {
+                        get_channels()[channel].active = Some(transfer);
+                        return;
+                    }
+                    Event::Status(status) => {
+                        get_channels()[channel].since = clock::Manager::get_micros();
+                        let result = Self::step(channel, &mut transfer, status);
+                        (transfer, result)
+                    }
+                    Event::TimedOut => {
+                        Self::log_failure(channel, &transfer, "timed out");
+                        // Whatever the drive was doing, it is not doing it anymore
+                        Self::probe(channel);
+                        (transfer, Some(Err(block::Error::Io)))
+                    }
+                },
+                None => {
+                    let next = [1 - c.last, c.last].into_iter().find(|&slave| c.waiting[slave].is_some());
+                    let Some(slave) = next else {
+                        return;
+                    };
+                    c.last = slave;
+                    let mut transfer = c.waiting[slave].take().unwrap();
+                    let result = Self::issue(channel, &mut transfer);
+                    (transfer, result)
+                }
+            };
+            let c = &mut get_channels()[channel];
+            match result {
+                None => c.active = Some(transfer),
+                Some(result) => c.finished[transfer.slave as usize] = Some((result, core::mem::take(&mut transfer.data))),
+            }
+        }
+    }
+    /// Starts the command for what is left of `transfer`, or finishes it
+    /// right away. Writes put their first sector in
+    fn issue(channel: usize, transfer: &mut Transfer) -> Option<Result<(), block::Error>> {
+        let t = transfer;
+        let first = (t.done / t.sector_size) as u64;
+        let lba = t.lba + first;
+        t.left = ((t.data.len() - t.done) / t.sector_size).min(MAX_SECTORS);
+        IRQ_FIRED[channel].store(false, Ordering::SeqCst);
+        get_channels()[channel].since = clock::Manager::get_micros();
+        if t.atapi {
+            return match t.op {
+                block::Op::Read => {
+                    let mut cdb = [0u8; 12];
+                    cdb[0] = SCSI_READ_12;
+                    cdb[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+                    cdb[6..10].copy_from_slice(&(t.left as u32).to_be_bytes());
+                    match Self::send_packet(channel, t.slave, &cdb, t.sector_size as u16) {
+                        true => None,
+                        false => Some(Self::fail(channel, t)),
+                    }
+                }
+                block::Op::Write => Some(Err(block::Error::ReadOnly)),
+                block::Op::Flush => Some(Ok(())),
+            };
+        }
+        let ext = t.lba48 && (t.op == block::Op::Flush || lba + t.left as u64 > LBA28_END);
+        let slave = (t.slave as u8) << 4;
+        if ext {
+            write(channel, DRIVE, DRIVE_LBA48 | slave);
+            settle(channel);
+            write(channel, SECTOR_COUNT, (t.left >> 8) as u8);
+            write(channel, LBA_LOW, (lba >> 24) as u8);
+            write(channel, LBA_MID, (lba >> 32) as u8);
+            write(channel, LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            write(channel, DRIVE, DRIVE_LBA | slave | (lba >> 24) as u8 & 0x0f);
+            settle(channel);
+        }
+        // 256 sectors go in as 0
+        write(channel, SECTOR_COUNT, t.left as u8);
+        write(channel, LBA_LOW, lba as u8);
+        write(channel, LBA_MID, (lba >> 8) as u8);
+        write(channel, LBA_HIGH, (lba >> 16) as u8);
+        let command = match (t.op, ext) {
+            (block::Op::Read, false) => CMD_READ,
+            (block::Op::Read, true) => CMD_READ_EXT,
+            (block::Op::Write, false) => CMD_WRITE,
+            (block::Op::Write, true) => CMD_WRITE_EXT,
+            (block::Op::Flush, false) => CMD_FLUSH,
+            (block::Op::Flush, true) => CMD_FLUSH_EXT,
+        };
+        write(channel, STATUS, command);
+        settle(channel);
+        // The drive asks for the first sector of a write without an interrupt
+        if t.op == block::Op::Write {
+            match Self::wait_data(channel) {
+                Some(status) if status & (STATUS_ERR | STATUS_DF) == 0 => Self::write_sector(channel, t),
+                _ => return Some(Self::fail(channel, t)),
+            }
+        }
+        None
+    }
+    /// What the drive said after an interrupt means for `t`, `None` while
+    /// there is more to come
+    fn step(channel: usize, t: &mut Transfer, status: u8) -> Option<Result<(), block::Error>> {
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Some(Self::fail(channel, t));
+        }
+        let drq = status & STATUS_DRQ != 0;
+        match t.op {
+            block::Op::Flush => Some(Ok(())),
+            block::Op::Read if drq && t.atapi => {
+                t.done += Self::read_packet_data(channel, &mut t.data[t.done..]);
+                None
+            }
+            block::Op::Read if drq => {
+                read_data(channel, &mut t.data[t.done..t.done + t.sector_size]);
+                settle(channel);
+                t.done += t.sector_size;
+                t.left -= 1;
+                // No interrupt after the last sector, the command is done
+                if t.left > 0 {
+                    return None;
+                }
+                Self::next(channel, t)
+            }
+            // ATAPI says it is done with an interrupt without data
+            block::Op::Read if t.atapi => Self::next(channel, t),
+            block::Op::Read => {
+                Self::log_failure(channel, t, "no data");
+                Some(Err(block::Error::Io))
+            }
+            block::Op::Write if drq && t.left > 0 => {
+                Self::write_sector(channel, t);
+                None
+            }
+            block::Op::Write => Self::next(channel, t),
+        }
+    }
+    /// The next command for `t`, or done if there is nothing left
+    fn next(channel: usize, t: &mut Transfer) -> Option<Result<(), block::Error>> {
+        match t.done >= t.data.len() {
+            true => Some(Ok(())),
+            false => Self::issue(channel, t),
+        }
+    }
+    fn write_sector(channel: usize, t: &mut Transfer) {
+        write_data(channel, &t.data[t.done..t.done + t.sector_size]);
+        settle(channel);
+        t.done += t.sector_size;
+        t.left -= 1;
+    }
+    fn fail(channel: usize, t: &Transfer) -> Result<(), block::Error> {
+        let (status, error) = (read(channel, STATUS), read(channel, ERROR));
+        let what = alloc::format!("status {} error {}", Bits(status, &STATUS_NAMES), Bits(error, &ERROR_NAMES));
+        Self::log_failure(channel, t, &what);
+        Err(block::Error::Io)
+    }
+    fn log_failure(channel: usize, t: &Transfer, what: &str) {
+        let sector = t.lba + (t.done / t.sector_size) as u64;
+        klog!(Warn, "ata", "ata{}: {:?} at {sector} failed, {what}", channel * 2 + t.slave as usize, t.op);
+    }
+
+    fn handle_irq(irq: u8) {
+        for channel in (0..CHANNELS.len()).filter(|&c| CHANNELS[c].2 == irq) {
+            // Reading the status is what lowers the line
+            read(channel, STATUS);
+            IRQ_FIRED[channel].store(true, Ordering::SeqCst);
+        }
+        pic::Manager::end_of_interrupt(irq);
+    }
+
+    #[unsafe(naked)]
+    unsafe extern "C" fn entry() {
+        #[unsafe(no_mangle)]
+        fn ata_irq_inner(rsp: u64) {
+            let frame = unsafe { (rsp as *mut cpu::InterruptStackFrame).as_mut() }.unwrap();
+            Manager::handle_irq((frame.get_irq() as u8).wrapping_sub(pic::IRQ_BASE));
+        }
+        cpu::standard_interrupt_body!("call ata_irq_inner");
+    }
+}
+
+console_commands! {
+    Command {
+        name: "ata",
+        category: "devices",
+        desc: "ATA and ATAPI drives",
+        help: "Each channel, whether it waits on its interrupt or polls, and the drives found on it.",
+        args: &[],
+        handler: |state, _args| {
+            for (channel, &(base, _, irq)) in CHANNELS.iter().enumerate() {
+                let c = &get_channels()[channel];
+                let (present, polled) = (c.present, c.polled);
+                kprint!("channel {channel} {base:#x} irq {irq} ");
+                match (present, polled) {
+                    (false, _) => kprint!("absent\r\n"),
+                    (true, false) => kprint!("interrupt driven\r\n"),
+                    (true, true) => kprint!("polled\r\n"),
+                }
+            }
+            block::Manager::for_each_device(state.db, |device, d| {
+                let Some(drive) = block::Manager::get_data::<Drive>(state.db, device) else {
+                    return;
+                };
+                kprint!(
+                    "ata{} {} {:?}, {} sectors of {}{}\r\n",
+                    drive.channel * 2 + drive.slave as usize,
+                    if drive.atapi { "ATAPI" } else { "ATA" },
+                    drive.identity.model,
+                    d.get_sector_count(),
+                    drive.sector_size,
+                    if drive.identity.lba48 { ", LBA48" } else { "" }
+                );
+            });
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identify_words(model: &str) -> [u16; 256] {
+        let mut words = [0u16; 256];
+        let mut padded = [b' '; 40];
+        padded[..model.len()].copy_from_slice(model.as_bytes());
+        for (word, pair) in words[27..47].iter_mut().zip(padded.chunks_exact(2)) {
+            *word = u16::from_be_bytes([pair[0], pair[1]]);
+        }
+        words
+    }
+
+    #[test]
+    fn identify_gives_model_and_size() {
+        let mut words = identify_words("QEMU HARDDISK");
+        assert_eq!(Identity::parse(&words, false), None);
+        words[49] = 1 << 9;
+        words[60] = 0x4000;
+        words[61] = 0x0002;
+        let identity = Identity::parse(&words, false).unwrap();
+        assert_eq!((identity.model.as_str(), identity.sectors, identity.lba48), ("QEMU HARDDISK", 0x2_4000, false));
+        words[83] = 1 << 10;
+        words[100..104].copy_from_slice(&[0x0000, 0x2000, 0x0001, 0]);
+        assert_eq!(Identity::parse(&words, false).unwrap().sectors, 0x1_2000_0000);
+        let cd = Identity::parse(&identify_words("QEMU DVD-ROM"), true).unwrap();
+        assert_eq!((cd.model.as_str(), cd.sectors), ("QEMU DVD-ROM", 0));
+    }
+
+    #[test]
+    fn registers_by_name() {
+        assert_eq!(std::format!("{}", Bits(0x51, &STATUS_NAMES)), "DRDY|SRV|ERR");
+        assert_eq!(std::format!("{}", Bits(0x44, &ERROR_NAMES)), "UNC|ABRT");
+        assert_eq!(std::format!("{}", Bits(0, &ERROR_NAMES)), "-");
+    }
+}
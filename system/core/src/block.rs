@@ -0,0 +1,684 @@
+//! Block devices
+//!
+//! A disk, a partition or a RAM image is a number of equally sized sectors
+//! its driver reads and writes by LBA. `Manager::register` puts it in the
+//! table and under `/devices` by name, where the node reads and writes as
+//! plain bytes. Filesystems are mounted from that node and go through
+//! `Manager::read` and `Manager::write`, or the byte versions for anything
+//! smaller than a sector, never through the driver itself.
+//!
+//! Those all go through one LRU cache of 4 KiB buffers shared by every
+//! device, written back when evicted or on `Manager::flush`. A partition
+//! has buffers of its own for sectors the disk has too, so writing to one
+//! writes back and forgets what the other holds there. Below it each
+//! device has a queue of requests its driver works through one at a time,
+//! `Manager::submit` adds to it directly and `Manager::poll` picks up what
+//! is done.
+
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::any::Any;
+
+use crate::{db, klog, kprint, vfs};
+use crate::console::{ArgKind, ArgSpec, Command};
+use crate::{console_commands, console_error};
+
+pub const MAX_DEVICES: usize = 16;
+/// What the cache holds of a device at a time, aligned to its own size
+pub const BUFFER_SIZE: usize = 4096;
+pub const CACHE_BUFFERS: usize = 256;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[default]
+    Unknown,
+    /// Past the last sector, or not a whole number of them
+    OutOfRange,
+    /// The device failed the request
+    Io,
+    ReadOnly,
+    TooMany,
+    /// A device by that name is already there
+    Exists,
+}
+impl From<Error> for vfs::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::OutOfRange => vfs::Error::Invalid,
+            Error::ReadOnly => vfs::Error::Unsupported,
+            Error::TooMany => vfs::Error::TooMany,
+            Error::Exists => vfs::Error::Exists,
+            _ => vfs::Error::Io,
+        }
+    }
+}
+
+/// `data` is a whole number of sectors starting at `lba`, all on the device
+pub type ReadFn = fn(db: &mut db::Database, device: DeviceHandle, lba: u64, data: &mut [u8]) -> Result<(), Error>;
+/// Like `ReadFn`, done once it returns or at the next flush
+pub type WriteFn = fn(db: &mut db::Database, device: DeviceHandle, lba: u64, data: &[u8]) -> Result<(), Error>;
+pub type FlushFn = fn(db: &mut db::Database, device: DeviceHandle) -> Result<(), Error>;
+/// Begins the request at the head of the queue, `None` while it is still going
+pub type StartFn = fn(db: &mut db::Database, device: DeviceHandle, request: &mut Request) -> Option<Result<(), Error>>;
+/// Asked about the started request until it gives `Some`
+pub type PollFn = fn(db: &mut db::Database, device: DeviceHandle, request: &mut Request) -> Option<Result<(), Error>>;
+
+/// What a driver does, `..Driver::DEFAULT` for the rest
+#[derive(Clone, Copy)]
+pub struct Driver {
+    pub read: ReadFn,
+    pub write: WriteFn,
+    pub flush: FlushFn,
+    /// Only for drivers that finish requests later, by interrupt or DMA
+    pub start: StartFn,
+    pub poll: PollFn,
+}
+impl Driver {
+    /// Fails reads, refuses writes, has nothing to flush and does each
+    /// request right away with those
+    pub const DEFAULT: Self = Self {
+        read: |_, _, _, _| Err(Error::Io),
+        write: |_, _, _, _| Err(Error::ReadOnly),
+        flush: |_, _| Ok(()),
+        start: |db, device, request| Some(Manager::run_now(db, device, request)),
+        poll: |_, _, _| Some(Err(Error::Io)),
+    };
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceHandle(u16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    Flush,
+}
+
+pub struct Request {
+    pub op: Op,
+    pub lba: u64,
+    /// Whole sectors read into or written from, empty for a flush
+    pub data: Vec<u8>,
+    id: u32,
+    result: Option<Result<(), Error>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestHandle {
+    device: DeviceHandle,
+    id: u32,
+}
+
+pub struct Device {
+    sector_size: u32,
+    sector_count: u64,
+    driver: Driver,
+    node: vfs::NodeHandle,
+    /// The driver's own, see `Manager::get_data`
+    data: Option<Box<dyn Any>>,
+    /// The device this one is a part of and the byte it starts at there
+    parent: Option<(DeviceHandle, u64)>,
+    queue: VecDeque<Request>,
+    /// Started and not done yet
+    active: Option<Request>,
+    /// Done and not picked up by `Manager::poll` yet
+    done: Vec<Request>,
+    next_request: u32,
+}
+impl Device {
+    pub fn get_sector_size(&self) -> u32 {
+        self.sector_size
+    }
+    pub fn get_sector_count(&self) -> u64 {
+        self.sector_count
+    }
+    /// In bytes
+    pub fn get_size(&self) -> u64 {
+        self.sector_count * self.sector_size as u64
+    }
+    pub fn get_node(&self) -> vfs::NodeHandle {
+        self.node
+    }
+    /// Submitted and not done yet
+    pub fn get_pending(&self) -> usize {
+        self.queue.len() + self.active.is_some() as usize
+    }
+}
+
+struct Buffer {
+    device: DeviceHandle,
+    /// Which `BUFFER_SIZE` piece of the device, the last one may be shorter
+    index: u64,
+    data: Vec<u8>,
+    dirty: bool,
+    used: u64,
+}
+
+/// The buffers of every device, see `Manager::read_bytes`
+#[derive(Default)]
+pub struct Cache {
+    buffers: Vec<Buffer>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+impl Cache {
+    fn find(&mut self, device: DeviceHandle, index: u64) -> Option<usize> {
+        self.clock += 1;
+        let slot = self.buffers.iter().position(|b| b.device == device && b.index == index)?;
+        self.buffers[slot].used = self.clock;
+        Some(slot)
+    }
+}
+
+pub struct Manager;
+impl Manager {
+    /// Needs the VFS tree (for `/devices`)
+    pub fn init(db: &mut db::Database) {
+        db.block_cache = Some(Cache::default());
+        db.block_provider = vfs::Manager::new_provider(
+            db,
+            vfs::Provider { read: Self::node_read, write: Self::node_write, stat: Self::node_stat, ..vfs::Provider::DEFAULT },
+        );
+    }
+
+    /// Adds a device as `/devices/<name>`, `sector_size` is a power of two
+    pub fn register(
+        db: &mut db::Database,
+        name: &str,
+        sector_size: u32,
+        sector_count: u64,
+        driver: Driver,
+        data: Option<Box<dyn Any>>,
+    ) -> Result<DeviceHandle, Error> {
+        let devices = vfs::Manager::find_children(db, vfs::NodeHandle::default(), "devices").unwrap();
+        Self::register_at(db, devices, name, sector_size, sector_count, driver, data)
+    }
+    /// Like `register` as `<directory>/<name>`, e.g a partition under its disk
+    pub fn register_at(
+        db: &mut db::Database,
+        directory: vfs::NodeHandle,
+        name: &str,
+        sector_size: u32,
+        sector_count: u64,
+        driver: Driver,
+        data: Option<Box<dyn Any>>,
+    ) -> Result<DeviceHandle, Error> {
+        assert!(sector_size.is_power_of_two() && sector_size as usize <= BUFFER_SIZE);
+        if db.block_devices.len() == db.block_devices.max_len() {
+            return Err(Error::TooMany);
+        }
+        if vfs::Manager::find_children(db, directory, name).is_some() {
+            return Err(Error::Exists);
+        }
+        let node = vfs::Manager::new_node_with_provider(db, name, directory, db.block_provider);
+        db.block_devices.push(Device {
+            sector_size,
+            sector_count,
+            driver,
+            node,
+            data,
+            parent: None,
+            queue: VecDeque::new(),
+            active: None,
+            done: Vec::new(),
+            next_request: 0,
+        });
+        klog!(Info, "block", "{}: {sector_count} sectors of {sector_size} bytes", vfs::Manager::get_path(db, node));
+        Ok(DeviceHandle((db.block_devices.len() - 1) as u16))
+    }
+    /// A device kept on the heap, `image` is its contents and is padded to a whole sector
+    pub fn new_ram_disk(db: &mut db::Database, name: &str, sector_size: u32, mut image: Vec<u8>) -> Result<DeviceHandle, Error> {
+        image.resize(image.len().next_multiple_of(sector_size as usize), 0);
+        let sector_count = (image.len() / sector_size as usize) as u64;
+        let driver = Driver {
+            read: |db, device, lba, data| {
+                let image = Self::get_data::<Vec<u8>>(db, device).ok_or(Error::Unknown)?;
+                let start = (lba * Self::get_device(db, device).sector_size as u64) as usize;
+                data.copy_from_slice(&image[start..start + data.len()]);
+                Ok(())
+            },
+            write: |db, device, lba, data| {
+                let start = (lba * Self::get_device(db, device).sector_size as u64) as usize;
+                let image = Self::get_data_mut::<Vec<u8>>(db, device).ok_or(Error::Unknown)?;
+                image[start..start + data.len()].copy_from_slice(data);
+                Ok(())
+            },
+            ..Driver::DEFAULT
+        };
+        Self::register(db, name, sector_size, sector_count, driver, Some(Box::new(image)))
+    }
+
+    pub fn get_device(db: &db::Database, device: DeviceHandle) -> &Device {
+        &db.block_devices[device.0 as usize]
+    }
+    /// The device behind `/devices/<name>`
+    pub fn find_by_node(db: &db::Database, node: vfs::NodeHandle) -> Option<DeviceHandle> {
+        db.block_devices.iter().take(db.block_devices.len()).position(|d| d.node == node).map(|i| DeviceHandle(i as u16))
+    }
+    pub fn for_each_device<F: FnMut(DeviceHandle, &Device)>(db: &db::Database, mut f: F) {
+        for (i, device) in db.block_devices.iter().take(db.block_devices.len()).enumerate() {
+            f(DeviceHandle(i as u16), device);
+        }
+    }
+    /// What the driver registered with, for the driver
+    pub fn get_data<T: 'static>(db: &db::Database, device: DeviceHandle) -> Option<&T> {
+        Self::get_device(db, device).data.as_ref()?.downcast_ref()
+    }
+    pub fn get_data_mut<T: 'static>(db: &mut db::Database, device: DeviceHandle) -> Option<&mut T> {
+        db.block_devices[device.0 as usize].data.as_mut()?.downcast_mut()
+    }
+
+    fn check_range(db: &db::Database, device: DeviceHandle, lba: u64, len: usize) -> Result<(), Error> {
+        let d = Self::get_device(db, device);
+        let count = (len / d.sector_size as usize) as u64;
+        if !len.is_multiple_of(d.sector_size as usize) || lba.checked_add(count).is_none_or(|end| end > d.sector_count) {
+            return Err(Error::OutOfRange);
+        }
+        Ok(())
+    }
+    /// Whole sectors from `lba` on
+    pub fn read(db: &mut db::Database, device: DeviceHandle, lba: u64, data: &mut [u8]) -> Result<(), Error> {
+        Self::check_range(db, device, lba, data.len())?;
+        Self::read_bytes(db, device, lba * Self::get_device(db, device).sector_size as u64, data)
+    }
+    /// Whole sectors from `lba` on
+    pub fn write(db: &mut db::Database, device: DeviceHandle, lba: u64, data: &[u8]) -> Result<(), Error> {
+        Self::check_range(db, device, lba, data.len())?;
+        Self::write_bytes(db, device, lba * Self::get_device(db, device).sector_size as u64, data)
+    }
+    /// Writes back what the cache has of the device, then has the driver flush
+    pub fn flush(db: &mut db::Database, device: DeviceHandle) -> Result<(), Error> {
+        Self::write_back(db, device, 0..u64::MAX, false)?;
+        let request = Self::enqueue(db, device, Op::Flush, 0, Vec::new());
+        Self::wait(db, request).map(|_| ())
+    }
+    /// Has `device` share the cache with `parent` from `lba` on, for a partition
+    pub fn set_parent(db: &mut db::Database, device: DeviceHandle, parent: DeviceHandle, lba: u64) {
+        let start = lba * Self::get_device(db, parent).sector_size as u64;
+        db.block_devices[device.0 as usize].parent = Some((parent, start));
+    }
+    /// Writes back what the parent or the parts of the device hold of the
+    /// byte range under their own buffers, and forgets it there if `forget`
+    fn write_back_shared(db: &mut db::Database, device: DeviceHandle, range: core::ops::Range<u64>, forget: bool) -> Result<(), Error> {
+        let mut shared = Vec::new();
+        if let Some((parent, start)) = Self::get_device(db, device).parent {
+            shared.push((parent, start + range.start..start + range.end));
+        }
+        Self::for_each_device(db, |other, d| match d.parent {
+            Some((parent, start)) if parent == device && range.end > start => {
+                shared.push((other, range.start.saturating_sub(start)..range.end - start));
+            }
+            _ => {}
+        });
+        for (other, range) in shared {
+            Self::write_back(db, other, range, forget)?;
+        }
+        Ok(())
+    }
+    /// Any range of bytes, through the cache
+    pub fn read_bytes(db: &mut db::Database, device: DeviceHandle, offset: u64, data: &mut [u8]) -> Result<(), Error> {
+        Self::write_back_shared(db, device, offset..offset.saturating_add(data.len() as u64), false)?;
+        Self::for_each_buffer(db, device, offset, data.len(), true, |buffer, within, done| {
+            let len = (data.len() - done).min(buffer.data.len() - within);
+            data[done..done + len].copy_from_slice(&buffer.data[within..within + len]);
+            len
+        })
+    }
+    /// Any range of bytes, into the cache and onto the device once written back
+    pub fn write_bytes(db: &mut db::Database, device: DeviceHandle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        Self::write_back_shared(db, device, offset..offset.saturating_add(data.len() as u64), true)?;
+        Self::for_each_buffer(db, device, offset, data.len(), false, |buffer, within, done| {
+            let len = (data.len() - done).min(buffer.data.len() - within);
+            buffer.data[within..within + len].copy_from_slice(&data[done..done + len]);
+            buffer.dirty = true;
+            len
+        })
+    }
+    /// Hands `f` each buffer the range touches with where in it the range
+    /// goes on and how much is done, `f` gives how much it did
+    fn for_each_buffer<F: FnMut(&mut Buffer, usize, usize) -> usize>(
+        db: &mut db::Database,
+        device: DeviceHandle,
+        offset: u64,
+        len: usize,
+        reading: bool,
+        mut f: F,
+    ) -> Result<(), Error> {
+        if offset.checked_add(len as u64).is_none_or(|end| end > Self::get_device(db, device).get_size()) {
+            return Err(Error::OutOfRange);
+        }
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let (index, within) = (pos / BUFFER_SIZE as u64, (pos % BUFFER_SIZE as u64) as usize);
+            // Nothing to read for a buffer about to be written whole
+            let whole = !reading && within == 0 && len - done >= Self::buffer_len(db, device, index);
+            let slot = Self::get_buffer(db, device, index, !whole)?;
+            done += f(&mut db.block_cache.as_mut().unwrap().buffers[slot], within, done);
+        }
+        Ok(())
+    }
+    fn buffer_len(db: &db::Database, device: DeviceHandle, index: u64) -> usize {
+        (Self::get_device(db, device).get_size() - index * BUFFER_SIZE as u64).min(BUFFER_SIZE as u64) as usize
+    }
+    /// The slot of a buffer in the cache, in place of the least recently used
+    /// one once full, read from the device if `fill`
+    fn get_buffer(db: &mut db::Database, device: DeviceHandle, index: u64, fill: bool) -> Result<usize, Error> {
+        let cache = db.block_cache.as_mut().unwrap();
+        if let Some(slot) = cache.find(device, index) {
+            cache.hits += 1;
+            return Ok(slot);
+        }
+        cache.misses += 1;
+        if cache.buffers.len() == CACHE_BUFFERS {
+            let slot = (0..cache.buffers.len()).min_by_key(|&i| cache.buffers[i].used).unwrap();
+            let (device, index) = (cache.buffers[slot].device, cache.buffers[slot].index);
+            Self::write_buffer(db, slot)?;
+            let cache = db.block_cache.as_mut().unwrap();
+            cache.buffers.retain(|b| b.device != device || b.index != index);
+        }
+        let mut data = alloc::vec![0u8; Self::buffer_len(db, device, index)];
+        if fill {
+            let lba = index * (BUFFER_SIZE / Self::get_device(db, device).sector_size as usize) as u64;
+            let request = Self::enqueue(db, device, Op::Read, lba, data);
+            data = Self::wait(db, request)?;
+        }
+        let cache = db.block_cache.as_mut().unwrap();
+        cache.buffers.push(Buffer { device, index, data, dirty: false, used: cache.clock });
+        Ok(cache.buffers.len() - 1)
+    }
+    /// Onto the device if dirty, slots may move meanwhile
+    fn write_buffer(db: &mut db::Database, slot: usize) -> Result<(), Error> {
+        let buffer = &mut db.block_cache.as_mut().unwrap().buffers[slot];
+        if !buffer.dirty {
+            return Ok(());
+        }
+        let (device, index, data) = (buffer.device, buffer.index, core::mem::take(&mut buffer.data));
+        let lba = index * (BUFFER_SIZE / Self::get_device(db, device).sector_size as usize) as u64;
+        let request = Self::enqueue(db, device, Op::Write, lba, data);
+        let result = Self::wait(db, request);
+        let buffers = &mut db.block_cache.as_mut().unwrap().buffers;
+        let slot = buffers.iter().position(|b| b.device == device && b.index == index).unwrap();
+        match result {
+            Ok(data) => {
+                buffers[slot].data = data;
+                buffers[slot].dirty = false;
+                Ok(())
+            }
+            Err(e) => {
+                // The data went down with the request
+                buffers.swap_remove(slot);
+                Err(e)
+            }
+        }
+    }
+    /// Writes back the dirty buffers of the device within the byte range,
+    /// and forgets them all there if `forget`
+    fn write_back(db: &mut db::Database, device: DeviceHandle, range: core::ops::Range<u64>, forget: bool) -> Result<(), Error> {
+        let mut slot = 0;
+        while let Some(buffer) = db.block_cache.as_ref().unwrap().buffers.get(slot) {
+            let start = buffer.index * BUFFER_SIZE as u64;
+            if buffer.device != device || start >= range.end || start + buffer.data.len() as u64 <= range.start {
+                slot += 1;
+                continue;
+            }
+            let index = buffer.index;
+            Self::write_buffer(db, slot)?;
+            if forget {
+                // What was past it moves into its slot
+                db.block_cache.as_mut().unwrap().buffers.retain(|b| b.device != device || b.index != index);
+            } else {
+                slot += 1;
+            }
+        }
+        Ok(())
+    }
+    /// Buffers in the cache, how many are dirty, hits and misses
+    pub fn get_cache_stats(db: &db::Database) -> (usize, usize, u64, u64) {
+        let cache = db.block_cache.as_ref().unwrap();
+        (cache.buffers.len(), cache.buffers.iter().filter(|b| b.dirty).count(), cache.hits, cache.misses)
+    }
+
+    /// Queues a request for the driver past the cache, which first writes
+    /// back what it has there and forgets it for a write. `data` is whole
+    /// sectors to read into or write, nothing for a flush
+    pub fn submit(db: &mut db::Database, device: DeviceHandle, op: Op, lba: u64, data: Vec<u8>) -> Result<RequestHandle, Error> {
+        if op != Op::Flush {
+            Self::check_range(db, device, lba, data.len())?;
+            let start = lba * Self::get_device(db, device).sector_size as u64;
+            Self::write_back(db, device, start..start + data.len() as u64, op == Op::Write)?;
+        }
+        Ok(Self::enqueue(db, device, op, lba, data))
+    }
+    fn enqueue(db: &mut db::Database, device: DeviceHandle, op: Op, lba: u64, data: Vec<u8>) -> RequestHandle {
+        let d = &mut db.block_devices[device.0 as usize];
+        let id = d.next_request;
+        d.next_request = id.wrapping_add(1);
+        d.queue.push_back(Request { op, lba, data, id, result: None });
+        Self::run_queue(db, device);
+        RequestHandle { device, id }
+    }
+    /// Starts requests until one is left going or none are left
+    fn run_queue(db: &mut db::Database, device: DeviceHandle) {
+        loop {
+            let d = &mut db.block_devices[device.0 as usize];
+            let driver = d.driver;
+            let result = match d.active.take() {
+                Some(mut request) => ((driver.poll)(db, device, &mut request), request),
+                None => match d.queue.pop_front() {
+                    Some(mut request) => ((driver.start)(db, device, &mut request), request),
+                    None => return,
+                },
+            };
+            let d = &mut db.block_devices[device.0 as usize];
+            match result {
+                (None, request) => {
+                    d.active = Some(request);
+                    return;
+                }
+                (result, mut request) => {
+                    request.result = result;
+                    d.done.push(request);
+                }
+            }
+        }
+    }
+    /// The data of a request once done, `None` while it is not
+    pub fn poll(db: &mut db::Database, request: RequestHandle) -> Option<Result<Vec<u8>, Error>> {
+        Self::run_queue(db, request.device);
+        let done = &mut db.block_devices[request.device.0 as usize].done;
+        let request = done.swap_remove(done.iter().position(|r| r.id == request.id)?);
+        Some(request.result.unwrap().map(|()| request.data))
+    }
+    pub fn wait(db: &mut db::Database, request: RequestHandle) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(result) = Self::poll(db, request) {
+                return result;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    /// A request done with the driver's `read`, `write` or `flush`, what
+    /// `Driver::DEFAULT` starts with
+    pub fn run_now(db: &mut db::Database, device: DeviceHandle, request: &mut Request) -> Result<(), Error> {
+        let driver = Self::get_device(db, device).driver;
+        match request.op {
+            Op::Read => (driver.read)(db, device, request.lba, &mut request.data),
+            Op::Write => (driver.write)(db, device, request.lba, &request.data),
+            Op::Flush => (driver.flush)(db, device),
+        }
+    }
+
+    fn node_read(db: &mut db::Database, _actor: db::ObjectHandle, node: vfs::NodeHandle, offset: u64, data: &mut [u8]) -> vfs::Result {
+        let device = Self::find_by_node(db, node).ok_or(vfs::Error::NotFound)?;
+        let len = Self::get_device(db, device).get_size().saturating_sub(offset).min(data.len() as u64) as usize;
+        Self::read_bytes(db, device, offset, &mut data[..len])?;
+        Ok(len)
+    }
+    fn node_write(db: &mut db::Database, _actor: db::ObjectHandle, node: vfs::NodeHandle, offset: u64, data: &[u8]) -> vfs::Result {
+        let device = Self::find_by_node(db, node).ok_or(vfs::Error::NotFound)?;
+        // Disks do not grow
+        if offset.checked_add(data.len() as u64).is_none_or(|end| end > Self::get_device(db, device).get_size()) {
+            return Err(vfs::Error::Invalid);
+        }
+        Self::write_bytes(db, device, offset, data)?;
+        Ok(data.len())
+    }
+    fn node_stat(db: &db::Database, _actor: db::ObjectHandle, node: vfs::NodeHandle) -> Result<vfs::Stat, vfs::Error> {
+        let device = Self::find_by_node(db, node).ok_or(vfs::Error::NotFound)?;
+        let children = vfs::Manager::get_node(db, node).get_child_count();
+        Ok(vfs::Stat { size: Self::get_device(db, device).get_size(), children })
+    }
+}
+
+console_commands! {
+    Command {
+        name: "disks",
+        category: "devices",
+        desc: "list block devices",
+        help: "Path, sector size and count, size and requests in flight, then what the cache holds.",
+        args: &[],
+        handler: |state, _args| {
+            Manager::for_each_device(state.db, |_, device| {
+                kprint!(
+                    "{} {} x {} bytes, {} KiB, {} pending\r\n",
+                    vfs::Manager::get_path(state.db, device.node),
+                    device.sector_count,
+                    device.sector_size,
+                    device.get_size() / 1024,
+                    device.get_pending()
+                );
+            });
+            let (buffers, dirty, hits, misses) = Manager::get_cache_stats(state.db);
+            kprint!("cache {buffers}/{CACHE_BUFFERS} buffers, {dirty} dirty, {hits} hits, {misses} misses\r\n");
+        },
+    },
+    Command {
+        name: "sync",
+        category: "devices",
+        desc: "write back cached disk data",
+        help: "Writes what the cache has changed onto every block device and flushes them.",
+        args: &[],
+        handler: |state, _args| {
+            for i in 0..state.db.block_devices.len() {
+                if let Err(e) = Manager::flush(state.db, DeviceHandle(i as u16)) {
+                    console_error!(state, "{}: {:?}\r\n", i, e);
+                }
+            }
+        },
+    },
+    Command {
+        name: "ramdisk",
+        category: "devices",
+        desc: "create an empty RAM disk",
+        help: "Shows up as /devices/<name> with 512 byte sectors, gone on reboot.",
+        args: &[ArgSpec::required("name", ArgKind::Word), ArgSpec::required("kib", ArgKind::Literal)],
+        handler: |state, args| {
+            let image = alloc::vec![0u8; args.get_literal(2).unwrap() * 1024];
+            if let Err(e) = Manager::new_ram_disk(state.db, args.get(1).unwrap(), 512, image) {
+                console_error!(state, "{:?}\r\n", e);
+            }
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::Subsystem::*;
+
+    #[test]
+    fn bytes_across_sectors() {
+        let mut db = db::Database::new_with(&[Vfs, Block]);
+        let disk = Manager::new_ram_disk(&mut db, "ram0", 512, alloc::vec![0; 2000]).unwrap();
+        assert_eq!(Manager::get_device(&db, disk).get_sector_count(), 4);
+        let data: Vec<u8> = (0..1200).map(|i| i as u8).collect();
+        Manager::write_bytes(&mut db, disk, 300, &data).unwrap();
+        let mut back = alloc::vec![0; 1200];
+        Manager::read_bytes(&mut db, disk, 300, &mut back).unwrap();
+        assert_eq!(back, data);
+        let mut sector = [0u8; 512];
+        Manager::read(&mut db, disk, 1, &mut sector).unwrap();
+        assert_eq!(sector[0], (512 - 300) as u8);
+        assert_eq!(Manager::read(&mut db, disk, 4, &mut sector), Err(Error::OutOfRange));
+        assert_eq!(Manager::read(&mut db, disk, 0, &mut sector[..100]), Err(Error::OutOfRange));
+        assert_eq!(Manager::new_ram_disk(&mut db, "ram0", 512, Vec::new()), Err(Error::Exists));
+    }
+
+    #[test]
+    fn device_nodes_read_as_bytes() {
+        let mut db = db::Database::new_with(&[Vfs, Block]);
+        let actor = db::ObjectHandle::default();
+        let disk = Manager::new_ram_disk(&mut db, "ram0", 512, alloc::vec![7; 1024]).unwrap();
+        let node = vfs::Manager::resolve_path(&db, actor, vfs::NodeHandle::default(), "/devices/ram0").unwrap();
+        assert_eq!(Manager::find_by_node(&db, node), Some(disk));
+        assert_eq!(vfs::Manager::stat(&db, actor, node).unwrap().size, 1024);
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, node, 1020, b"abcd"), Ok(4));
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, node, 1022, b"abcd"), Err(vfs::Error::Invalid));
+        let mut buf = [0u8; 8];
+        assert_eq!(vfs::Manager::read_node(&mut db, actor, node, 1018, &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"\x07\x07abcd");
+    }
+
+    #[test]
+    fn writes_stay_cached_until_written_back() {
+        let mut db = db::Database::new_with(&[Vfs, Block]);
+        let disk = Manager::new_ram_disk(&mut db, "ram0", 512, alloc::vec![0; 2 << 20]).unwrap();
+        let image = |db: &db::Database, at: usize| Manager::get_data::<Vec<u8>>(db, disk).unwrap()[at];
+        Manager::write_bytes(&mut db, disk, 100, b"abc").unwrap();
+        assert_eq!(image(&db, 100), 0);
+        let mut back = [0u8; 3];
+        Manager::read_bytes(&mut db, disk, 100, &mut back).unwrap();
+        assert_eq!(&back, b"abc");
+        Manager::flush(&mut db, disk).unwrap();
+        assert_eq!(image(&db, 100), b'a');
+        assert_eq!(Manager::get_cache_stats(&db).1, 0);
+        // Evicted once the cache is full
+        Manager::write_bytes(&mut db, disk, 4096, b"x").unwrap();
+        for i in 2..CACHE_BUFFERS as u64 + 2 {
+            Manager::read_bytes(&mut db, disk, i * BUFFER_SIZE as u64, &mut back).unwrap();
+        }
+        assert_eq!(image(&db, 4096), b'x');
+        assert_eq!(Manager::get_cache_stats(&db).0, CACHE_BUFFERS);
+        // Requests past the cache see what it had and leave it nothing stale
+        Manager::write_bytes(&mut db, disk, 8192, b"y").unwrap();
+        let request = Manager::submit(&mut db, disk, Op::Read, 16, alloc::vec![0; 512]).unwrap();
+        assert_eq!(Manager::wait(&mut db, request).unwrap()[0], b'y');
+        let request = Manager::submit(&mut db, disk, Op::Write, 16, alloc::vec![b'z'; 512]).unwrap();
+        Manager::wait(&mut db, request).unwrap();
+        Manager::read_bytes(&mut db, disk, 8192, &mut back).unwrap();
+        assert_eq!(&back, b"zzz");
+        assert_eq!(Manager::read_bytes(&mut db, disk, (2 << 20) - 1, &mut back), Err(Error::OutOfRange));
+    }
+
+    #[test]
+    fn requests_finish_in_order_when_the_driver_says() {
+        let mut db = db::Database::new_with(&[Vfs, Block]);
+        // Done on the first poll after starting, sector `n` is all `n`
+        let driver = Driver {
+            start: |_, _, _| None,
+            poll: |_, _, request| {
+                let sector = request.lba as u8;
+                for (i, chunk) in request.data.chunks_mut(512).enumerate() {
+                    chunk.fill(sector + i as u8);
+                }
+                Some(Ok(()))
+            },
+            ..Driver::DEFAULT
+        };
+        let disk = Manager::register(&mut db, "slow0", 512, 16, driver, None).unwrap();
+        let first = Manager::submit(&mut db, disk, Op::Read, 3, alloc::vec![0; 512]).unwrap();
+        assert_eq!(Manager::get_device(&db, disk).get_pending(), 1);
+        // Looking at the queue again finishes the first and starts this one
+        let second = Manager::submit(&mut db, disk, Op::Read, 5, alloc::vec![0; 1024]).unwrap();
+        assert_eq!(Manager::get_device(&db, disk).get_pending(), 1);
+        assert_eq!(Manager::poll(&mut db, first).unwrap().unwrap()[0], 3);
+        assert_eq!(Manager::poll(&mut db, second).unwrap().unwrap()[512], 6);
+        assert!(Manager::poll(&mut db, second).is_none());
+        let mut back = [0u8; 2];
+        Manager::read_bytes(&mut db, disk, 7 * 512 - 1, &mut back).unwrap();
+        assert_eq!(back, [6, 7]);
+        assert_eq!(Manager::submit(&mut db, disk, Op::Read, 16, alloc::vec![0; 512]), Err(Error::OutOfRange));
+    }
+}
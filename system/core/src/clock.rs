@@ -0,0 +1,63 @@
+//! Monotonic time from the TSC
+//!
+//! `init` counts TSC ticks over `CALIBRATION_US` of PIT channel 2, the speaker
+//! one, whose output can be polled on port 0x61 without any interrupt. Until
+//! then, or if the PIT never counts down, the TSC is assumed to run at 1 GHz.
+//! Callers keep raw ticks (see `now`) and convert late, so what was stamped
+//! before calibration still comes out right.
+
+use crate::{cpu, klog};
+
+const PIT_HZ: u64 = 1_193_182;
+const CALIBRATION_US: u64 = 10_000;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Bit 0 gates channel 2, bit 1 drives the speaker, bit 5 reads its output
+const SPEAKER_CONTROL: u16 = 0x61;
+/// Channel 2, low then high byte, mode 0 (output goes high at zero)
+const PIT_ONE_SHOT: u8 = 0xb0;
+const POLL_SPINS: usize = 10_000_000;
+
+static mut TICKS_PER_US: u64 = 1000;
+
+pub struct Manager;
+impl Manager {
+    pub fn init() {
+        let count = PIT_HZ * CALIBRATION_US / 1_000_000;
+        let control = cpu::Manager::port_read_u8(SPEAKER_CONTROL);
+        cpu::Manager::port_write_u8(SPEAKER_CONTROL, control & !0x03);
+        cpu::Manager::port_write_u8(PIT_COMMAND, PIT_ONE_SHOT);
+        cpu::Manager::port_write_u8(PIT_CHANNEL2, count as u8);
+        cpu::Manager::port_write_u8(PIT_CHANNEL2, (count >> 8) as u8);
+        // Raising the gate starts the count
+        cpu::Manager::port_write_u8(SPEAKER_CONTROL, (control & !0x02) | 0x01);
+        let start = Self::now();
+        let done = (0..POLL_SPINS).any(|_| cpu::Manager::port_read_u8(SPEAKER_CONTROL) & 0x20 != 0);
+        let ticks = Self::now() - start;
+        cpu::Manager::port_write_u8(SPEAKER_CONTROL, control);
+        if done && ticks >= CALIBRATION_US {
+            unsafe {
+                TICKS_PER_US = ticks / CALIBRATION_US;
+            }
+        }
+        klog!(Info, "clock", "tsc at {} MHz{}", Self::get_ticks_per_us(), if done { "" } else { " (guessed)" });
+    }
+
+    /// Raw TSC, only meaningful through `ticks_to_micros`
+    pub fn now() -> u64 {
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    pub fn get_ticks_per_us() -> u64 {
+        unsafe { TICKS_PER_US }
+    }
+
+    pub fn ticks_to_micros(ticks: u64) -> u64 {
+        ticks / Self::get_ticks_per_us()
+    }
+
+    /// Since the TSC was reset, which is about when the machine was
+    pub fn get_micros() -> u64 {
+        Self::ticks_to_micros(Self::now())
+    }
+}
@@ -0,0 +1,549 @@
+//! The serial console: commands, scripts, completion and the prompt
+//!
+//! Line editing and splitting lines into `Args` are `radian_abi::line`, the
+//! user shell edits its lines the same way. Commands describe what they take
+//! with a slice of `ArgSpec` and get the words checked before they run.
+//!
+//! Commands are not listed in one place: any module (or the kernel binary)
+//! adds its own with `console_commands!`, which puts them in the
+//! `console_commands` link section that `Manager::get_commands` reads back.
+
+use core::str;
+
+pub use radian_abi::line::{
+    parse_boolean, parse_literal, ArgError, ArgKind, ArgSpec, Args, Event, History, LineEditor, ParseError, Usage, HISTORY_BYTES,
+    HISTORY_SIZE, MAX_ARGS, MAX_LINE,
+};
+
+use crate::containers::StaticString;
+use crate::styles::{RADOS, RESET, USER};
+use crate::{cpu, db, kprint, policy, task, vfs, vmm, DebugSerial};
+
+/// Max size of a script read with `source` or `batch`
+pub const MAX_SCRIPT_SIZE: usize = 16384;
+pub const MAX_SCRIPT_DEPTH: usize = 8;
+
+/// What the console is working on, shared by all commands
+pub struct State<'a> {
+    pub db: &'a mut db::Database,
+    pub current_node: vfs::NodeHandle,
+    pub current_aspace: vmm::AddressSpaceHandle,
+    pub current_actor: db::ObjectHandle,
+    pub current_task: task::TaskHandle,
+    pub current_user: db::ObjectHandle,
+    pub history: History,
+    /// Set by a handler through `console_error!`, cleared before each command
+    pub failed: bool,
+    /// Nested `source`, so a script sourcing itself does not blow the stack
+    pub script_depth: usize,
+}
+impl<'a> State<'a> {
+    pub fn new(db: &'a mut db::Database, aspace: vmm::AddressSpaceHandle, actor: db::ObjectHandle) -> Self {
+        Self {
+            db,
+            current_node: vfs::NodeHandle::default(),
+            current_aspace: aspace,
+            current_actor: actor,
+            current_task: task::TaskHandle::default(),
+            current_user: db::ObjectHandle::default(),
+            history: History::new(),
+            failed: false,
+            script_depth: 0,
+        }
+    }
+}
+
+/// Print an error and mark the running command as failed, scripts stop on it
+#[macro_export]
+macro_rules! console_error {
+    ($state:expr, $($arg:tt)*) => {{
+        $crate::kprint!($($arg)*);
+        $state.failed = true;
+    }};
+}
+
+pub struct Command {
+    pub name: &'static str,
+    /// Commands are grouped by this in `help`, e.g "memory" or "vfs"
+    pub category: &'static str,
+    /// One line, shown in the list
+    pub desc: &'static str,
+    /// Shown by `help <name>` after the usage, empty if `desc` says it all
+    pub help: &'static str,
+    /// Checked before `handler` runs, which then reads them from `Args` by
+    /// position, 1 being the first after the name
+    pub args: &'static [ArgSpec],
+    pub handler: fn(&mut State, &Args),
+}
+
+/// Adds commands to the console, usable from any module or crate linked into
+/// the kernel:
+///
+/// ```ignore
+/// console_commands! {
+///     console::Command { name: "tlb_reload", category: "memory", ... },
+/// }
+/// ```
+#[macro_export]
+macro_rules! console_commands {
+    ($($command:expr),* $(,)?) => {
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = "console_commands")]
+            static COMMANDS: [$crate::console::Command; [$(stringify!($command)),*].len()] = [$($command),*];
+        };
+    };
+}
+
+// Provided by `kernel.ld`, and by the linker itself for host builds since the
+// section name is a valid identifier
+#[allow(non_upper_case_globals)]
+unsafe extern "C" {
+    unsafe static __start_console_commands: u8;
+    unsafe static __stop_console_commands: u8;
+}
+
+pub struct Manager;
+impl Manager {
+    /// Everything registered with `console_commands!`, in link order
+    pub fn get_commands() -> &'static [Command] {
+        unsafe {
+            let start = &raw const __start_console_commands as *const Command;
+            let end = &raw const __stop_console_commands as *const Command;
+            core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+        }
+    }
+
+    pub fn find_command(name: &str) -> Option<&'static Command> {
+        Self::get_commands().iter().find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Calls `f` once per category, in the order they first show up
+    fn for_each_category<F: FnMut(&'static str)>(mut f: F) {
+        let commands = Self::get_commands();
+        for (i, c) in commands.iter().enumerate() {
+            if !commands[..i].iter().any(|prev| prev.category == c.category) {
+                f(c.category);
+            }
+        }
+    }
+
+    /// Runs one console line, false if the command failed or does not exist
+    pub fn execute_line(state: &mut State, line: &str) -> bool {
+        let args = match Args::parse(line) {
+            Ok(args) => args,
+            Err(e) => {
+                kprint!("{}\r\n", e);
+                return false;
+            }
+        };
+        let Some(cmd) = args.get(0) else {
+            return true;
+        };
+        if let Some(c) = Self::find_command(cmd) {
+            if let Err(e) = args.check(c.args) {
+                kprint!("{}\r\nusage: {}\r\n", e, Usage(c.name, c.args));
+                return false;
+            }
+            // Whatever held it may have removed the node since the last line
+            if !vfs::Manager::is_valid(state.db, state.current_node) {
+                kprint!("current node is gone, back to /\r\n");
+                state.current_node = vfs::NodeHandle::default();
+            }
+            state.failed = false;
+            (c.handler)(state, &args);
+            !state.failed
+        } else {
+            let mut min_dist = usize::MAX;
+            let mut min_cmd = None;
+            for c in Self::get_commands() {
+                let dist = levenshtein_distance(c.name, cmd);
+                if dist < min_dist {
+                    min_dist = dist;
+                    min_cmd = Some(c);
+                }
+            }
+            if let Some(c) = min_cmd {
+                kprint!("maybe you meant <{}>?\r\n", c.name);
+            } else {
+                kprint!("unknown command <{}>\r\n", cmd);
+            }
+            false
+        }
+    }
+
+    /// Runs every line of a script, stopping at the first one that fails, always
+    /// ends with a single `[script] <name>: ...` status line
+    pub fn run_script(state: &mut State, name: &str, script: &str) -> bool {
+        if state.script_depth >= MAX_SCRIPT_DEPTH {
+            kprint!("[script] {name}: failed, nested too deep\r\n");
+            return false;
+        }
+        state.script_depth += 1;
+        let mut count = 0;
+        let mut failed_at = None;
+        for (i, line) in script.lines().enumerate() {
+            if Args::parse(line).is_ok_and(|args| args.is_empty()) {
+                continue;
+            }
+            kprint!("+ {}\r\n", line.trim());
+            count += 1;
+            if !Self::execute_line(state, line) {
+                failed_at = Some(i + 1);
+                break;
+            }
+        }
+        state.script_depth -= 1;
+        if let Some(line) = failed_at {
+            kprint!("[script] {name}: failed at line {line}\r\n");
+            false
+        } else {
+            kprint!("[script] {name}: ok, {count} commands\r\n");
+            true
+        }
+    }
+
+    /// Walk a `/` separated path from the current node (or root if absolute)
+    pub fn resolve_path(state: &State, path: &str) -> Result<vfs::NodeHandle, vfs::Error> {
+        vfs::Manager::resolve_path(state.db, state.current_actor, state.current_node, path)
+    }
+
+    pub fn print_prompt(state: &State) {
+        let user_name = policy::Manager::get_user(state.db, state.current_user).get_name();
+        let hostname = "radiant-pc";
+        kprint!("{RADOS}RadianOS:{USER}{user_name}@{hostname}{RESET}>");
+    }
+
+    /// Calls `f` with every name that could complete `word` and the character
+    /// to put after it, commands for the first word and nodes for the rest
+    fn for_each_completion<F: FnMut(&str, char)>(state: &State, first_word: bool, word: &str, mut f: F) {
+        if first_word {
+            for c in Self::get_commands().iter().filter(|c| c.name.starts_with(word)) {
+                f(c.name, ' ');
+            }
+            return;
+        }
+        let (dir, prefix) = match word.rfind('/') {
+            Some(0) => (Some(vfs::NodeHandle::default()), &word[1..]),
+            Some(i) => (Self::resolve_path(state, &word[..i]).ok(), &word[i + 1..]),
+            None => (Some(state.current_node).filter(|&node| vfs::Manager::is_valid(state.db, node)), word),
+        };
+        if let Some(dir) = dir {
+            vfs::Manager::for_each_children(state.db, dir, |handle| {
+                let name = vfs::Manager::get_node(state.db, handle).get_name();
+                if name.starts_with(prefix) {
+                    let mut has_children = false;
+                    vfs::Manager::for_each_children(state.db, handle, |_| has_children = true);
+                    f(name, if has_children { '/' } else { ' ' });
+                }
+            });
+        }
+    }
+
+    /// Tab: a single match is completed, otherwise complete what all matches
+    /// share or list them when there is nothing left to add
+    pub fn complete(state: &State, editor: &mut LineEditor) {
+        let line = StaticString::<MAX_LINE>::from_str(editor.as_str());
+        let before = &line.as_str()[..editor.get_cursor()];
+        let start = before.rfind(' ').map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let first_word = before[..start].trim().is_empty();
+        let prefix_len = word.len() - word.rfind('/').filter(|_| !first_word).map_or(0, |i| i + 1);
+
+        let mut count = 0;
+        let mut common = StaticString::<MAX_LINE>::new();
+        let mut common_len = 0;
+        let mut suffix = ' ';
+        Self::for_each_completion(state, first_word, word, |name, end| {
+            if count == 0 {
+                common = StaticString::from_str(name);
+                common_len = name.len();
+                suffix = end;
+            } else {
+                common_len = common.as_str()[..common_len]
+                    .bytes()
+                    .zip(name.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+            }
+            count += 1;
+        });
+        let extra = &common.as_str()[prefix_len.min(common_len)..common_len];
+        if count == 1 {
+            editor.insert_str(extra, &mut DebugSerial);
+            editor.insert_str(suffix.encode_utf8(&mut [0; 4]), &mut DebugSerial);
+        } else if !extra.is_empty() {
+            editor.insert_str(extra, &mut DebugSerial);
+        } else if count > 1 {
+            kprint!("\r\n");
+            Self::for_each_completion(state, first_word, word, |name, end| {
+                kprint!("{}{}  ", name, if end == '/' { "/" } else { "" });
+            });
+            kprint!("\r\n");
+            Self::print_prompt(state);
+            editor.redraw(&mut DebugSerial);
+        }
+    }
+
+    fn print_help(state: &mut State, topic: Option<&str>) {
+        let Some(topic) = topic else {
+            Self::for_each_category(|category| {
+                kprint!("{category}:\r\n");
+                for c in Self::get_commands().iter().filter(|c| c.category == category) {
+                    kprint!("  {}: {}\r\n", Usage(c.name, c.args), c.desc);
+                }
+            });
+            kprint!("<help command> or <help category> for more\r\n");
+            return;
+        };
+        if let Some(c) = Self::find_command(topic) {
+            kprint!("usage: {}\r\n{} ({})\r\n", Usage(c.name, c.args), c.desc, c.category);
+            for arg in c.args {
+                let kind = match arg.kind {
+                    ArgKind::Literal => "number, 0x/0b prefixes work",
+                    ArgKind::Boolean => "on/off, yes/no, true/false, 1/0",
+                    ArgKind::Word => "word, quote it if it has spaces",
+                    ArgKind::Rest => "rest of the line, as typed",
+                };
+                let optional = if arg.optional { ", optional" } else { "" };
+                kprint!("  {:<12} {kind}{optional}\r\n", arg.name);
+            }
+            for line in c.help.lines() {
+                kprint!("{}\r\n", line);
+            }
+        } else if Self::get_commands().iter().any(|c| c.category.eq_ignore_ascii_case(topic)) {
+            for c in Self::get_commands().iter().filter(|c| c.category.eq_ignore_ascii_case(topic)) {
+                kprint!("  {}: {}\r\n", Usage(c.name, c.args), c.desc);
+            }
+        } else {
+            console_error!(state, "no command or category <{}>\r\n", topic);
+        }
+    }
+}
+
+fn levenshtein_distance(s1: &str, s2: &str) -> usize {
+    if s1.len() >= 16 || s2.len() >= 16 {
+        return 0;
+    }
+    let insertion_cost = 1;
+    let deletion_cost = 1;
+    let subst_cost = 1;
+    if s1.is_empty() || s2.is_empty() {
+        [s1.len(), s2.len()][(s1.is_empty()) as usize]
+    } else {
+        let mut dist = [[0u32; 16]; 16];
+        for (i, row) in dist.iter_mut().enumerate().take(s1.len()).skip(1) {
+            row[0] = i as u32;
+        }
+        for (i, cell) in dist[0].iter_mut().enumerate().take(s2.len()).skip(1) {
+            *cell = i as u32;
+        }
+        for j in 1..s2.len() {
+            for i in 1..s1.len() {
+                let cost = if s1.as_bytes()[i] == s2.as_bytes()[j] {
+                    0
+                } else {
+                    subst_cost
+                };
+                let x = (dist[i - 1][j] + deletion_cost).min(dist[i][j - 1] + insertion_cost);
+                dist[i][j] = (dist[i - 1][j - 1] + cost).min(x);
+            }
+        }
+        dist[s1.len() - 1][s2.len() - 1] as usize
+    }
+}
+
+crate::console_commands! {
+    Command {
+        name: "help",
+        category: "console",
+        desc: "get help",
+        help: "",
+        args: &[ArgSpec::optional("command/category", ArgKind::Word)],
+        handler: |state, args| Manager::print_help(state, args.get(1)),
+    },
+    Command {
+        name: "clear",
+        category: "console",
+        desc: "clear the screen",
+        help: "",
+        args: &[],
+        handler: |_state, _args| {
+            // Clear screen ANSI escape sequence
+            kprint!("\x1b[2J");
+            // Move cursor to home position (0,0)
+            kprint!("\x1b[H");
+        },
+    },
+    Command {
+        name: "history",
+        category: "console",
+        desc: "print local command history",
+        help: "Up/Down at the prompt walk the same list.",
+        args: &[],
+        handler: |state, _args| {
+            for i in 0..state.history.len() {
+                kprint!("{:3} {}\r\n", i, state.history.get(i).unwrap());
+            }
+        },
+    },
+    Command {
+        name: "echo",
+        category: "console",
+        desc: "print text",
+        help: "",
+        args: &[ArgSpec::optional("text", ArgKind::Rest)],
+        handler: |_state, args| {
+            // Like a shell: quotes removed, words joined by one space
+            for i in 1..args.len() {
+                kprint!("{}{}", args.get(i).unwrap(), if i + 1 < args.len() { " " } else { "" });
+            }
+            kprint!("\r\n");
+        },
+    },
+    Command {
+        name: "source",
+        category: "console",
+        desc: "run the console commands in a node",
+        help: "One command per line, `#` comments. Stops at the first command that\n\
+               fails and prints a `[script] <name>: ok|failed ...` line either way.",
+        args: &[ArgSpec::required("node", ArgKind::Word)],
+        handler: |state, args| {
+            let path = args.get(1).unwrap();
+            if let Ok(handle) = Manager::resolve_path(state, path) {
+                // One byte over so a script that does not fit is told apart
+                let mut buffer = alloc::vec![0u8; MAX_SCRIPT_SIZE + 1];
+                let mut len = 0;
+                while len < buffer.len() {
+                    match vfs::Manager::read_node(state.db, state.current_actor, handle, len as u64, &mut buffer[len..]) {
+                        Ok(0) => break,
+                        Ok(n) => len += n,
+                        Err(e) => {
+                            console_error!(state, "{}: {:?}\r\n", path, e);
+                            return;
+                        }
+                    }
+                }
+                if len > MAX_SCRIPT_SIZE {
+                    console_error!(state, "{}: script too long\r\n", path);
+                } else if let Ok(script) = str::from_utf8(&buffer[..len]) {
+                    state.failed = !Manager::run_script(state, path, script);
+                } else {
+                    console_error!(state, "{} is not text\r\n", path);
+                }
+            } else {
+                console_error!(state, "{} not found\r\n", path);
+            }
+        },
+    },
+    Command {
+        name: "batch",
+        category: "console",
+        desc: "read a script from serial until a lone `.`, then run it",
+        help: "Meant for pasting, ^D also ends the script. Runs like `source`.",
+        args: &[],
+        handler: |state, _args| {
+            // Bytes as they come, decoded once so multi-byte characters survive
+            let mut script = alloc::vec::Vec::new();
+            let mut line_start = 0;
+            // From the debug syscall interrupts are off and must stay so
+            let halt = cpu::Manager::are_interrupts_enabled();
+            loop {
+                if let Some(b) = DebugSerial::get_byte() {
+                    match b {
+                        0x04 => break, // ^D
+                        b'\r' | b'\n' => {
+                            if script[line_start..].trim_ascii() == b"." {
+                                script.truncate(line_start);
+                                break;
+                            }
+                            script.push(b'\n');
+                            line_start = script.len();
+                        }
+                        _ => script.push(b),
+                    }
+                    if script.len() >= MAX_SCRIPT_SIZE {
+                        console_error!(state, "script too long\r\n");
+                        return;
+                    }
+                } else if halt {
+                    cpu::Manager::wait_for_interrupt();
+                } else {
+                    core::hint::spin_loop();
+                }
+            }
+            let Ok(script) = str::from_utf8(&script) else {
+                console_error!(state, "batch: not text\r\n");
+                return;
+            };
+            state.failed = !Manager::run_script(state, "batch", script);
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_collects_every_module() {
+        let commands = Manager::get_commands();
+        for (i, c) in commands.iter().enumerate() {
+            assert!(!commands[..i].iter().any(|prev| prev.name == c.name), "{} twice", c.name);
+        }
+        assert_eq!(Manager::find_command("help").unwrap().category, "console");
+        assert_eq!(Manager::find_command("MAP").unwrap().category, "memory");
+        assert_eq!(Manager::find_command("cd").unwrap().category, "vfs");
+        assert_eq!(Manager::find_command("users").unwrap().category, "policy");
+        assert!(Manager::find_command("nosuch").is_none());
+    }
+
+    #[test]
+    fn execute_line_checks_args() {
+        let mut db = db::Database::new_zeroed();
+        let mut state = State::new(&mut db, vmm::AddressSpaceHandle::default(), db::ObjectHandle::default());
+        assert!(Manager::execute_line(&mut state, "   # nothing to do"));
+        assert!(Manager::execute_line(&mut state, "echo \"hello  there\""));
+        assert!(Manager::execute_line(&mut state, "help map"));
+        assert!(Manager::execute_line(&mut state, "help memory"));
+        assert!(!Manager::execute_line(&mut state, "help nothing"));
+        assert!(!Manager::execute_line(&mut state, "map 0x1000"));
+        assert!(!Manager::execute_line(&mut state, "peek nowhere"));
+        assert!(!Manager::execute_line(&mut state, "echo 'open"));
+        assert!(!Manager::execute_line(&mut state, "nosuch"));
+    }
+
+    #[test]
+    fn removed_current_node_falls_back_to_root() {
+        let mut db = db::Database::new_with(&[db::Subsystem::Vfs, db::Subsystem::Tmpfs]);
+        let mut state = State::new(&mut db, vmm::AddressSpaceHandle::default(), db::ObjectHandle::default());
+        assert!(Manager::execute_line(&mut state, "mkdir /temp/dir"));
+        assert!(Manager::execute_line(&mut state, "cd /temp/dir"));
+        // Gone behind the console's back, not through `rm`
+        let dir = state.current_node;
+        vfs::Manager::remove(state.db, db::ObjectHandle::default(), dir).unwrap();
+        assert_eq!(Manager::resolve_path(&state, "."), Err(vfs::Error::NotFound));
+        assert!(Manager::execute_line(&mut state, "cd temp"));
+        assert_eq!(Manager::resolve_path(&state, "."), Manager::resolve_path(&state, "/temp"));
+    }
+
+    #[test]
+    fn source_runs_whole_scripts_and_refuses_bigger_ones() {
+        let mut db = db::Database::new_with(&[db::Subsystem::Vfs, db::Subsystem::Tmpfs]);
+        let actor = db::ObjectHandle::default();
+        let temp = vfs::Manager::resolve_path(&db, actor, vfs::NodeHandle::default(), "/temp").unwrap();
+        let write = |db: &mut db::Database, name, text: &str| {
+            let node = vfs::Manager::create(db, actor, temp, name, vfs::NodeKind::File).unwrap();
+            vfs::Manager::write_node(db, actor, node, 0, text.as_bytes()).unwrap();
+        };
+        let padding = "#".repeat(MAX_SCRIPT_SIZE - 100);
+        write(&mut db, "fits", &std::format!("{padding}\n# ünïcode\nmkdir /temp/made\n"));
+        // Cut at `MAX_SCRIPT_SIZE` this would still run, as nothing but comments
+        write(&mut db, "big", &std::format!("{padding}{padding}\nmkdir /temp/never\n"));
+        let mut state = State::new(&mut db, vmm::AddressSpaceHandle::default(), actor);
+        assert!(Manager::execute_line(&mut state, "source /temp/fits"));
+        assert!(Manager::resolve_path(&state, "/temp/made").is_ok());
+        assert!(!Manager::execute_line(&mut state, "source /temp/big"));
+        assert!(Manager::resolve_path(&state, "/temp/never").is_err());
+    }
+}
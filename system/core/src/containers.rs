@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use core::{marker::PhantomData, ops::{Index, IndexMut}};
 
 #[derive(Debug, Clone)]
@@ -58,6 +59,16 @@ impl<T, const N: usize> StaticVec<T, N> {
         }
     }
 
+    /// Removes and returns the element at `index`, shifting the rest down
+    pub fn remove(&mut self, index: usize) -> T
+    where
+        T: Default,
+    {
+        assert!(index < self.size);
+        self.inner[index..self.size].rotate_left(1);
+        self.pop().unwrap()
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         self.size
@@ -170,6 +181,150 @@ impl<const N: usize> Default for StaticString<N> {
     }
 }
 
+/// Fixed size FIFO, `push` fails when full and `push_overwrite` drops the oldest
+#[derive(Debug, Clone)]
+pub struct RingBuffer<T, const N: usize> {
+    inner: [T; N],
+    head: usize,
+    size: usize,
+}
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new_with_default(default: T) -> Self {
+        Self {
+            inner: [default; N],
+            head: 0,
+            size: 0,
+        }
+    }
+    pub fn push(&mut self, data: T) -> Result<(), T> {
+        if self.size == N {
+            return Err(data);
+        }
+        self.inner[(self.head + self.size) % N] = data;
+        self.size += 1;
+        Ok(())
+    }
+    /// Returns what was dropped to make room, if anything
+    pub fn push_overwrite(&mut self, data: T) -> Option<T> {
+        let dropped = if self.size == N { self.pop() } else { None };
+        let _ = self.push(data);
+        dropped
+    }
+    pub fn pop(&mut self) -> Option<T> {
+        if self.size == 0 {
+            return None;
+        }
+        let data = self.inner[self.head];
+        self.head = (self.head + 1) % N;
+        self.size -= 1;
+        Some(data)
+    }
+    /// 0 is the oldest
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.size {
+            return None;
+        }
+        Some(&self.inner[(self.head + index) % N])
+    }
+    pub fn len(&self) -> usize {
+        self.size
+    }
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+    pub fn is_full(&self) -> bool {
+        self.size == N
+    }
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.size = 0;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Slot<T> {
+    /// Bumped on remove, so handles to what was here stop matching
+    generation: u16,
+    value: Option<T>,
+    /// Index + 1 of the next free slot while this one is free, 0 ends the list
+    next_free: u32,
+}
+
+/// Heap slots addressed by index and generation, removed slots are reused
+/// under a new generation so a stale handle gets `None` instead of whatever
+/// moved in. A slot whose generation ran out is never reused, wrapping would
+/// bring back the oldest handles. All-zero is empty, nothing is allocated
+/// until the first insert
+#[derive(Debug, Clone)]
+pub struct SlotVec<T> {
+    slots: Option<Vec<Slot<T>>>,
+    /// Index + 1 of the first free slot, 0 if none
+    free: u32,
+    len: usize,
+}
+impl<T> Default for SlotVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T> SlotVec<T> {
+    pub const fn new() -> Self {
+        Self { slots: None, free: 0, len: 0 }
+    }
+    /// Returns the index and generation to get it back with
+    pub fn insert(&mut self, value: T) -> (u32, u16) {
+        let slots = self.slots.get_or_insert_default();
+        self.len += 1;
+        if self.free != 0 {
+            let index = self.free - 1;
+            let slot = &mut slots[index as usize];
+            self.free = slot.next_free;
+            slot.value = Some(value);
+            return (index, slot.generation);
+        }
+        slots.push(Slot { generation: 0, value: Some(value), next_free: 0 });
+        ((slots.len() - 1) as u32, 0)
+    }
+    pub fn remove(&mut self, index: u32, generation: u16) -> Option<T> {
+        self.get(index, generation)?;
+        let slot = &mut self.slots.as_mut()?[index as usize];
+        if let Some(generation) = slot.generation.checked_add(1) {
+            slot.generation = generation;
+            slot.next_free = self.free;
+            self.free = index + 1;
+        }
+        self.len -= 1;
+        slot.value.take()
+    }
+    pub fn get(&self, index: u32, generation: u16) -> Option<&T> {
+        let slot = self.slots.as_ref()?.get(index as usize)?;
+        if slot.generation != generation {
+            return None;
+        }
+        slot.value.as_ref()
+    }
+    pub fn get_mut(&mut self, index: u32, generation: u16) -> Option<&mut T> {
+        let slot = self.slots.as_mut()?.get_mut(index as usize)?;
+        if slot.generation != generation {
+            return None;
+        }
+        slot.value.as_mut()
+    }
+    /// Whatever generation is in slot `index` now
+    pub fn get_generation(&self, index: u32) -> Option<u16> {
+        let slot = self.slots.as_ref()?.get(index as usize)?;
+        slot.value.as_ref().map(|_| slot.generation)
+    }
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 pub struct FlexibleArray<T>{ _phantom: PhantomData<T>, }
 impl<T> FlexibleArray<T> {
@@ -188,3 +343,149 @@ impl<T> IndexMut<usize> for FlexibleArray<T> {
         unsafe { ((&raw mut self._phantom) as *mut T).add(index).as_mut().unwrap() }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_vec_push_pop() {
+        let mut v = StaticVec::<u32, 4>::new();
+        assert!(v.is_empty());
+        v.push(1);
+        v.push(2);
+        assert_eq!(v.len(), 2);
+        assert_eq!(v.max_len(), 4);
+        assert_eq!(v[1], 2);
+        assert_eq!(v.pop(), Some(2));
+        assert_eq!(v.pop(), Some(1));
+        assert_eq!(v.pop(), None);
+    }
+
+    #[test]
+    fn static_vec_push_fifo_when_full() {
+        let mut v = StaticVec::<u32, 3>::new();
+        for i in 0..3 {
+            v.push_fifo(i);
+        }
+        v.push_fifo(3);
+        assert_eq!(v.len(), 3);
+        assert_eq!(v[2], 3);
+    }
+
+    #[test]
+    fn static_vec_remove_shifts() {
+        let mut v = StaticVec::<u32, 4>::new();
+        for i in 0..4 {
+            v.push(i);
+        }
+        assert_eq!(v.remove(1), 1);
+        assert_eq!(v.len(), 3);
+        assert_eq!([v[0], v[1], v[2]], [0, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn static_vec_push_past_capacity() {
+        let mut v = StaticVec::<u8, 1>::new();
+        v.push(0);
+        v.push(1);
+    }
+
+    #[test]
+    fn static_vec_iter_covers_capacity() {
+        // Iterates every slot, not just the pushed ones, callers `take(len())`
+        let mut v = StaticVec::<u8, 4>::new();
+        v.push(7);
+        assert_eq!(v.iter().count(), 4);
+        assert_eq!(v.iter().take(v.len()).copied().sum::<u8>(), 7);
+    }
+
+    #[test]
+    fn static_string_round_trip() {
+        let s = StaticString::<16>::from_str("admin");
+        assert_eq!(s.len(), 5);
+        assert_eq!(s.as_str(), "admin");
+        assert_eq!(StaticString::<16>::new().as_str(), "");
+        assert_eq!(StaticString::<4>::from_str("full").as_str(), "full");
+    }
+
+    #[test]
+    fn ring_buffer_wraps_around() {
+        let mut r = RingBuffer::<u8, 3>::new_with_default(0);
+        assert_eq!(r.pop(), None);
+        for i in 0..3 {
+            r.push(i).unwrap();
+        }
+        assert!(r.is_full());
+        assert_eq!(r.push(9), Err(9));
+        assert_eq!(r.pop(), Some(0));
+        r.push(3).unwrap();
+        assert_eq!([r.pop(), r.pop(), r.pop(), r.pop()], [Some(1), Some(2), Some(3), None]);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn ring_buffer_overwrites_oldest() {
+        let mut r = RingBuffer::<u8, 3>::new_with_default(0);
+        for i in 0..3 {
+            assert_eq!(r.push_overwrite(i), None);
+        }
+        assert_eq!(r.push_overwrite(3), Some(0));
+        assert_eq!([r.get(0), r.get(2), r.get(3)], [Some(&1), Some(&3), None]);
+    }
+
+    #[test]
+    fn slot_vec_detects_stale_handles() {
+        let mut v = SlotVec::<&str>::new();
+        let (a, a_gen) = v.insert("a");
+        let (b, b_gen) = v.insert("b");
+        assert_eq!(v.remove(a, a_gen), Some("a"));
+        assert_eq!(v.remove(a, a_gen), None);
+        let (c, c_gen) = v.insert("c");
+        assert_eq!((c, c_gen), (a, a_gen + 1));
+        assert_eq!(v.get(a, a_gen), None);
+        assert_eq!(v.get(c, c_gen), Some(&"c"));
+        assert_eq!(v.get(b, b_gen), Some(&"b"));
+        assert_eq!(v.get_generation(b), Some(b_gen));
+        assert_eq!(v.len(), 2);
+        // Zeroed memory is an empty one, like in `db::Database`
+        let zeroed: SlotVec<u64> = unsafe { core::mem::zeroed() };
+        assert!(zeroed.is_empty() && zeroed.get(0, 0).is_none());
+    }
+
+    #[test]
+    fn slot_vec_retires_slots_instead_of_wrapping() {
+        let mut v = SlotVec::<u32>::new();
+        let (first, first_gen) = v.insert(0);
+        let (mut index, mut generation) = (first, first_gen);
+        for i in 1..=u16::MAX as u32 {
+            assert_eq!(v.remove(index, generation), Some(i - 1));
+            (index, generation) = v.insert(i);
+            assert_eq!(index, first);
+        }
+        assert_eq!(generation, u16::MAX);
+        v.remove(index, generation);
+        // Generation 0 in that slot again would make `first_gen` valid
+        let (index, generation) = v.insert(7);
+        assert_ne!(index, first);
+        assert_eq!((v.get(first, first_gen), v.get(index, generation)), (None, Some(&7)));
+        assert_eq!(v.get_generation(first), None);
+    }
+
+    #[test]
+    fn flexible_array_indexes_past_header() {
+        #[repr(C)]
+        struct Header {
+            count: usize,
+            items: FlexibleArray<u64>,
+        }
+        let mut buffer = [0u64; 8];
+        let header = unsafe { (buffer.as_mut_ptr() as *mut Header).as_mut().unwrap() };
+        header.count = 3;
+        for i in 0..header.count {
+            header.items[i] = i as u64 * 10;
+        }
+        assert_eq!(buffer[..4], [3, 0, 10, 20]);
+    }
+}
@@ -1,12 +1,12 @@
-use crate::{const_assert, kprint};
+use crate::{const_assert, klog, kprint};
+use crate::console::{ArgKind, ArgSpec, Command};
+use crate::console_commands;
 
 const KERNEL_CODE_SEGMENT: usize = 0x08;
 #[allow(dead_code)]
 const KERNEL_DATA_SEGMENT: usize = 0x10;
-#[allow(dead_code)]
-const USER_CODE_SEGMENT: usize = 0x18;
-#[allow(dead_code)]
-const USER_DATA_SEGMENT: usize = 0x20;
+pub const USER_CODE_SEGMENT: usize = 0x18;
+pub const USER_DATA_SEGMENT: usize = 0x20;
 
 #[derive(Debug)]
 #[repr(C, packed)]
@@ -36,6 +36,21 @@ impl InterruptStackFrame {
     pub const RAX: usize = 8 * 13;
     pub const RBP: usize = 8 * 14;
     pub const RSP: usize = 8 * 15;
+
+    /// `reg` is one of the offsets above
+    pub fn get_register(&self, reg: usize) -> u64 {
+        self.gpr[reg / 8]
+    }
+    pub fn set_register(&mut self, reg: usize, value: u64) {
+        self.gpr[reg / 8] = value;
+    }
+    pub fn get_irq(&self) -> u64 {
+        self.irq
+    }
+    /// Interrupted code was running in ring 3
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
 }
 
 
@@ -78,6 +93,10 @@ impl InterruptDescriptor {
     pub fn new_interrupt_gate(addr: u64) -> Self {
         Self::new(addr, 0x8e, 0)
     }
+    /// Interrupt gate reachable with `int` from ring 3
+    pub fn new_user_interrupt_gate(addr: u64) -> Self {
+        Self::new(addr, 0xee, 0)
+    }
     /// Trap gate
     #[allow(dead_code)]
     pub fn new_trap_gate(addr: u64) -> Self {
@@ -218,10 +237,12 @@ macro_rules! standard_interrupt_body {
             "pop rax",
             "pop rbp",
             "pop rsp",
+            "add rsp, 8", //pushed vector
             "iretq",
         );
     }
 }
+pub(crate) use standard_interrupt_body;
 
 pub struct Manager;
 impl Manager {
@@ -255,6 +276,75 @@ impl Manager {
         }
     }
 
+    pub fn are_interrupts_enabled() -> bool {
+        let rflags: u64;
+        unsafe {
+            core::arch::asm!("pushfq", "pop {}", out(reg) rflags);
+        }
+        rflags & 0x200 != 0
+    }
+
+    /// Runs `f` with interrupts off, restoring whatever it was before
+    pub fn without_interrupts<R, F: FnOnce() -> R>(f: F) -> R {
+        // `cli` faults in a host process, there is nothing to mask there anyway
+        if cfg!(test) {
+            return f();
+        }
+        let enabled = Self::are_interrupts_enabled();
+        if enabled {
+            Self::set_interrupts::<false>();
+        }
+        let r = f();
+        if enabled {
+            Self::set_interrupts::<true>();
+        }
+        r
+    }
+
+    /// Sleeps until the next interrupt, enabling them if they were not
+    pub fn wait_for_interrupt() {
+        unsafe {
+            core::arch::asm!("sti", "hlt");
+        }
+    }
+
+    pub fn port_read_u8(port: u16) -> u8 {
+        let value;
+        unsafe {
+            core::arch::asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack));
+        }
+        value
+    }
+    pub fn port_write_u8(port: u16, value: u8) {
+        unsafe {
+            core::arch::asm!("out dx, al", in("al") value, in("dx") port, options(nomem, nostack));
+        }
+    }
+    pub fn port_read_u16(port: u16) -> u16 {
+        let value;
+        unsafe {
+            core::arch::asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack));
+        }
+        value
+    }
+    pub fn port_write_u16(port: u16, value: u16) {
+        unsafe {
+            core::arch::asm!("out dx, ax", in("ax") value, in("dx") port, options(nomem, nostack));
+        }
+    }
+    pub fn port_read_u32(port: u16) -> u32 {
+        let value;
+        unsafe {
+            core::arch::asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack));
+        }
+        value
+    }
+    pub fn port_write_u32(port: u16, value: u32) {
+        unsafe {
+            core::arch::asm!("out dx, eax", in("eax") value, in("dx") port, options(nomem, nostack));
+        }
+    }
+
     fn load_idt_thunk() {
         unsafe {
             core::arch::asm!("lidt [GLOBAL_IDT_R]",);
@@ -305,9 +395,16 @@ impl Manager {
         }
     }
 
+    /// Lets ring 3 raise `irq` with `int`, e.g for syscalls
+    pub fn allow_user_interrupt(irq: usize) {
+        unsafe {
+            GLOBAL_IDT[irq] = InterruptDescriptor::new_user_interrupt_gate(GLOBAL_IDT_ASM.0[irq].as_ptr() as u64);
+        }
+    }
+
     pub fn init() {
         const_assert!(core::mem::size_of::<GlobalDescriptor>() == 64 / 8);
-        kprint!("[cpu] loading new gdt\r\n");
+        klog!(Info, "cpu", "loading new gdt");
         unsafe {
             let (low, high) =
                 GlobalDescriptor::new_tss((&raw const GLOBAL_TSS) as u64, 0x89, 0x0);
@@ -315,7 +412,7 @@ impl Manager {
             GLOBAL_GDT[6] = high;
             Self::load_gdt(&raw mut GLOBAL_GDT);
         }
-        kprint!("[cpu] loading new idt\r\n");
+        klog!(Info, "cpu", "loading new idt");
         for i in 0..256 {
             unsafe {
                 GLOBAL_IDT_ASM.0[i][1] = i as u8; //update pushed value (WHY IS THIS AT RUNTIME?) fuck rust x2
@@ -324,7 +421,7 @@ impl Manager {
             Self::register_interrupt(Self::dummy_int_handler as u64, i);
         }
         Self::load_idt(&raw mut GLOBAL_IDT);
-        kprint!("[cpu] set tss\r\n");
+        klog!(Info, "cpu", "set tss");
         unsafe {
             GLOBAL_TSS.rsp0 = (&STACK_TOP) as *const _ as u64;
             GLOBAL_TSS.iopb = 104;
@@ -370,3 +467,20 @@ static mut GLOBAL_IDT: [InterruptDescriptor; 256] = [InterruptDescriptor::new_ze
 static mut GLOBAL_GDT_R: TableDescriptor = TableDescriptor { limit: 0, base: 0 };
 #[unsafe(no_mangle)]
 static mut GLOBAL_IDT_R: TableDescriptor = TableDescriptor { limit: 0, base: 0 };
+
+console_commands! {
+    Command {
+        name: "sti",
+        category: "cpu",
+        desc: "enable/disable interrupts",
+        help: "",
+        args: &[ArgSpec::required("on/off", ArgKind::Boolean)],
+        handler: |_state, args| {
+            if args.get_boolean(1).unwrap() {
+                Manager::set_interrupts::<true>();
+            } else {
+                Manager::set_interrupts::<false>();
+            }
+        },
+    },
+}
@@ -1,4 +1,6 @@
-use crate::{containers::StaticVec, pmm, policy, vfs, task};
+use alloc::vec::Vec;
+
+use crate::{block, containers::{SlotVec, StaticVec}, pmm, policy, task, tmpfs, vfs};
 
 /// "Fat pointer" - only use if you absolutely dont know the source of id
 /// or if the object does not have a handle of its own, in such case, you're more than
@@ -26,6 +28,9 @@ impl ObjectHandle {
     pub const fn get_id(self) -> u16 {
         self.id
     }
+    pub const fn get_type(self) -> u16 {
+        self.type_
+    }
 }
 
 pub struct Database {
@@ -33,16 +38,71 @@ pub struct Database {
     pub groups: StaticVec<policy::Group, 8>,
     pub workers: StaticVec<task::Worker, 64>,
     pub policy_rule: StaticVec<policy::PolicyRule, 128>,
-    pub vfs_nodes: StaticVec<vfs::Node, 128>,
+    pub vfs_nodes: SlotVec<vfs::Node>,
     pub vfs_providers: StaticVec<vfs::Provider, 32>,
+    pub vfs_fs_types: StaticVec<vfs::RegisteredFs, { vfs::MAX_FS_TYPES }>,
+    pub vfs_mounts: StaticVec<vfs::Mount, { vfs::MAX_MOUNTS }>,
+    /// Indexed by node id, grown as needed, a default entry is an unused slot
+    pub tmpfs_inodes: Option<Vec<tmpfs::Inode>>,
     pub aspaces: StaticVec<pmm::Handle, 64>,
+    pub block_devices: StaticVec<block::Device, { block::MAX_DEVICES }>,
+    /// Serves every `/devices` node of a block device
+    pub block_provider: vfs::ProviderHandle,
+    /// Shared by every block device, see `block::Manager::read_bytes`
+    pub block_cache: Option<block::Cache>,
+}
+/// What `Database::new_with` sets up for a test besides the policy
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Vfs,
+    Tmpfs,
+    Block,
+    Fat,
+    Ext2,
 }
+
 static mut GLOBAL_DATABASE: [u8; core::mem::size_of::<Database>()] =
     [0u8; core::mem::size_of::<Database>()];
 
 impl Database {
     pub fn init() {}
 
+    /// Fresh database for host tests, same all-zero state the kernel boots with
+    #[cfg(test)]
+    pub fn new_zeroed() -> std::boxed::Box<Self> {
+        unsafe { std::boxed::Box::<Self>::new_zeroed().assume_init() }
+    }
+
+    /// `new_zeroed` with the policy and `subsystems` initialized, in the order
+    /// `rust_start` does whatever order they are listed in
+    #[cfg(test)]
+    pub fn new_with(subsystems: &[Subsystem]) -> std::boxed::Box<Self> {
+        let mut db = Self::new_zeroed();
+        policy::Manager::init(&mut db);
+        // Slot 0 is reserved, same as `rust_start`
+        policy::Manager::add_rule(&mut db, policy::PolicyRule::default());
+        let inits: [(Subsystem, fn(&mut Self)); 5] = [
+            (Subsystem::Vfs, vfs::Manager::init),
+            (Subsystem::Tmpfs, |db| {
+                tmpfs::Manager::init(db);
+            }),
+            (Subsystem::Block, block::Manager::init),
+            (Subsystem::Fat, |db| {
+                crate::fat::Manager::init(db);
+            }),
+            (Subsystem::Ext2, |db| {
+                crate::ext2::Manager::init(db);
+            }),
+        ];
+        for (subsystem, init) in inits {
+            if subsystems.contains(&subsystem) {
+                init(&mut db);
+            }
+        }
+        db
+    }
+
     /// Assumed lock
     pub fn get() -> &'static Self {
         unsafe {
@@ -121,17 +181,22 @@ impl<'a> Path<'a> {
     pub fn components(&self) -> core::str::Split<'a, &'a str> {
         self.inner.inner.split("/")
     }
+    /// The last name, trailing slashes aside, dots and all
     pub fn file_name(&'a self) -> Option<&'a str> {
-        self.components()
-            .last()
-            .map(|p| p.split(".").next())
-            .unwrap_or(None)
+        self.components().filter(|c| !c.is_empty()).last().filter(|&c| c != "." && c != "..")
+    }
+    /// The file name without its extension, `archive.tar` for `archive.tar.gz`
+    pub fn file_stem(&'a self) -> Option<&'a str> {
+        let name = self.file_name()?;
+        Some(Self::split_extension(name).map_or(name, |(stem, _)| stem))
     }
+    /// After the last dot, `None` for `README` and `.profile`
     pub fn extension(&'a self) -> Option<&'a str> {
-        self.components()
-            .last()
-            .map(|p| p.split(".").last())
-            .unwrap_or(None)
+        Self::split_extension(self.file_name()?).map(|(_, extension)| extension)
+    }
+    fn split_extension(name: &str) -> Option<(&str, &str)> {
+        // A leading dot hides the file, it does not start an extension
+        name.rfind('.').filter(|&i| i > 0).map(|i| (&name[..i], &name[i + 1..]))
     }
 }
 
@@ -140,3 +205,50 @@ impl<'a> Default for Path<'a> {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_from_str_in_range() {
+        let mut db = Database::new_zeroed();
+        policy::Manager::new_user(&mut db, "admin");
+        policy::Manager::new_user(&mut db, "guest");
+        let handle = db.find_from_str("user_1").unwrap();
+        assert_eq!(handle, ObjectHandle::new::<{ ObjectHandle::USER }>(1));
+        assert!(db.find_from_str("user_2").is_none());
+        assert!(db.find_from_str("worker_0").is_none());
+        assert!(db.find_from_str("group_0").is_none());
+    }
+
+    #[test]
+    fn path_components() {
+        let buf = PathBuf::from_str("mutable/logs/radian_core.log");
+        let path = buf.path();
+        let parts: std::vec::Vec<&str> = path.components().collect();
+        assert_eq!(parts, ["mutable", "logs", "radian_core.log"]);
+        assert_eq!(path.file_name(), Some("radian_core.log"));
+        assert_eq!(path.file_stem(), Some("radian_core"));
+        assert_eq!(path.extension(), Some("log"));
+    }
+
+    #[test]
+    fn path_multi_dot_names() {
+        let buf = PathBuf::from_str("/temp//archive.tar.gz/");
+        assert_eq!(buf.path().file_name(), Some("archive.tar.gz"));
+        assert_eq!(buf.path().file_stem(), Some("archive.tar"));
+        assert_eq!(buf.path().extension(), Some("gz"));
+        let buf = PathBuf::from_str("home/.profile");
+        assert_eq!(buf.path().file_stem(), Some(".profile"));
+        assert_eq!(buf.path().extension(), None);
+        assert_eq!(PathBuf::from_str("/").path().file_name(), None);
+        assert_eq!(PathBuf::from_str("a/..").path().file_name(), None);
+    }
+
+    #[test]
+    fn default_path_is_current_node() {
+        let path = Path::default();
+        assert_eq!(path.components().collect::<std::vec::Vec<_>>(), ["."]);
+    }
+}
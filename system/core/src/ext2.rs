@@ -0,0 +1,1480 @@
+//! ext2
+//!
+//! Registered as the `ext2` filesystem type and mounted from a block device
+//! node, `mount ext2 /mount/data /devices/ram0`. Like `fat` the directory
+//! tree is read into nodes at mount, one node per name, so the names of a
+//! hard linked file are several nodes of one inode. Files find their blocks
+//! through the inode's direct and indirect pointers and may have holes,
+//! symlinks shorter than 60 bytes live in the inode itself. Features past
+//! revision 1 refuse the mount if reading needs them and make it read-only
+//! otherwise. `Manager::format` makes an empty filesystem on a device.
+
+use alloc::{boxed::Box, collections::BTreeMap, collections::BTreeSet, string::String, vec::Vec};
+use core::ops::Range;
+
+use crate::{block, db, klog, policy, vfs};
+use crate::console::{self, ArgKind, ArgSpec, Command};
+use crate::{console_commands, console_error};
+
+/// Where the superblock is on the device, whatever the block size
+const SUPERBLOCK: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+/// Below this inodes are reserved, revision 1 can say otherwise
+const FIRST_INODE: u32 = 11;
+/// Of an inode, what revision 0 has and all that is read or written, the rest is left alone
+const INODE_SIZE: usize = 128;
+const GROUP_DESC_SIZE: u64 = 32;
+/// Block pointers in an inode before the single, double and triple indirect one
+const DIRECT: usize = 12;
+/// Symlink targets shorter than this are kept where the block pointers would be
+const FAST_LINK: usize = 60;
+
+const MODE_TYPE: u16 = 0xf000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_SYMLINK: u16 = 0xa000;
+/// The type byte of a directory entry, with `INCOMPAT_FILETYPE`
+const TYPE_FILE: u8 = 1;
+const TYPE_DIRECTORY: u8 = 2;
+const TYPE_SYMLINK: u8 = 7;
+/// A directory with a hashed index, which only the Linux ext3 and ext4 drivers
+/// keep up to date, cleared on change so they go back to reading it in order
+const FLAG_INDEX: u32 = 0x1000;
+
+const STATE_CLEAN: u16 = 1;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+
+/// Where everything is, as the superblock says
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    device: block::DeviceHandle,
+    block_size: u32,
+    blocks_count: u32,
+    /// Block 1 with 1 KiB blocks, 0 otherwise
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inodes_count: u32,
+    inode_size: u32,
+    first_inode: u32,
+    group_count: u32,
+    /// Directory entries have a type byte
+    file_type: bool,
+    large_file: bool,
+    read_only: bool,
+}
+impl Layout {
+    fn parse(device: block::DeviceHandle, sb: &[u8], device_size: u64) -> Result<Self, vfs::Error> {
+        if get16(sb, 56) != MAGIC || get32(sb, 24) > 6 {
+            return Err(vfs::Error::Invalid);
+        }
+        let block_size = 1024 << get32(sb, 24);
+        let (inodes_count, blocks_count, first_data_block) = (get32(sb, 0), get32(sb, 4), get32(sb, 20));
+        let (blocks_per_group, inodes_per_group) = (get32(sb, 32), get32(sb, 40));
+        let (first_inode, inode_size, incompat, ro_compat) = match get32(sb, 76) {
+            0 => (FIRST_INODE, INODE_SIZE as u32, 0, 0),
+            _ => (get32(sb, 84), get16(sb, 88) as u32, get32(sb, 96), get32(sb, 100)),
+        };
+        // Before anything is worked out from the counts, they are only bounded by the device
+        if blocks_count as u64 * block_size as u64 > device_size {
+            return Err(vfs::Error::Invalid);
+        }
+        let bits = block_size * 8;
+        if !(1..=bits).contains(&blocks_per_group) || !(1..=bits).contains(&inodes_per_group) || first_data_block >= blocks_count {
+            return Err(vfs::Error::Invalid);
+        }
+        if !inode_size.is_power_of_two() || !(INODE_SIZE as u32..=block_size).contains(&inode_size) {
+            return Err(vfs::Error::Invalid);
+        }
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        let descriptor_blocks = (group_count as u64 * GROUP_DESC_SIZE).div_ceil(block_size as u64);
+        if inodes_count as u64 > group_count as u64 * inodes_per_group as u64
+            || !(ROOT_INODE + 1..inodes_count).contains(&first_inode)
+            || first_data_block as u64 + 1 + descriptor_blocks > blocks_count as u64
+        {
+            return Err(vfs::Error::Invalid);
+        }
+        // Compressed, journal devices, extents, 64 bit and the like
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            klog!(Warn, "ext2", "incompatible features {:#x}", incompat);
+            return Err(vfs::Error::Unsupported);
+        }
+        Ok(Self {
+            device,
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inodes_count,
+            inode_size,
+            first_inode,
+            group_count,
+            file_type: incompat & INCOMPAT_FILETYPE != 0,
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only: ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) != 0,
+        })
+    }
+    fn descriptor_offset(&self, group: u32) -> u64 {
+        (self.first_data_block as u64 + 1) * self.block_size as u64 + group as u64 * GROUP_DESC_SIZE
+    }
+    /// Whether a single, double or triple indirect block can reach it
+    fn max_size(&self) -> u64 {
+        let per = self.block_size as u64 / 4;
+        let blocks = DIRECT as u64 + per + per * per + per * per * per;
+        let limit = if self.large_file { u64::MAX } else { i32::MAX as u64 };
+        (blocks * self.block_size as u64).min(limit)
+    }
+}
+
+/// A block group, as its descriptor says
+#[derive(Debug, Clone, Copy)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+}
+
+/// The first `INODE_SIZE` bytes of an inode
+#[derive(Clone, Copy)]
+struct Inode([u8; INODE_SIZE]);
+impl Inode {
+    fn new(mode: u16, links: u16) -> Self {
+        let mut inode = Self([0; INODE_SIZE]);
+        put16(&mut inode.0, 0, mode);
+        put16(&mut inode.0, 26, links);
+        inode
+    }
+    fn kind(&self) -> EntryKind {
+        match get16(&self.0, 0) & MODE_TYPE {
+            MODE_FILE => EntryKind::File,
+            MODE_DIRECTORY => EntryKind::Directory,
+            MODE_SYMLINK => EntryKind::Symlink,
+            _ => EntryKind::Unused,
+        }
+    }
+    /// The upper half is only there for files
+    fn size(&self) -> u64 {
+        let high = if self.kind() == EntryKind::File { get32(&self.0, 108) } else { 0 };
+        ((high as u64) << 32) | get32(&self.0, 4) as u64
+    }
+    fn set_size(&mut self, size: u64) {
+        put32(&mut self.0, 4, size as u32);
+        if self.kind() == EntryKind::File {
+            put32(&mut self.0, 108, (size >> 32) as u32);
+        }
+    }
+    fn links(&self) -> u16 {
+        get16(&self.0, 26)
+    }
+    fn set_links(&mut self, links: u16) {
+        put16(&mut self.0, 26, links);
+    }
+    /// Of 512 bytes, for data and indirect blocks alike
+    fn sectors(&self) -> u32 {
+        get32(&self.0, 28)
+    }
+    fn add_sectors(&mut self, block_size: u32, blocks: i32) {
+        let sectors = self.sectors().wrapping_add_signed(blocks * (block_size / 512) as i32);
+        put32(&mut self.0, 28, sectors);
+    }
+    fn flags(&self) -> u32 {
+        get32(&self.0, 32)
+    }
+    fn set_flags(&mut self, flags: u32) {
+        put32(&mut self.0, 32, flags);
+    }
+    fn block(&self, slot: usize) -> u32 {
+        get32(&self.0, 40 + slot * 4)
+    }
+    fn set_block(&mut self, slot: usize, block: u32) {
+        put32(&mut self.0, 40 + slot * 4, block);
+    }
+    /// A symlink with its target in place of the block pointers, the block of
+    /// extended attributes does not count
+    fn is_fast_link(&self, block_size: u32) -> bool {
+        let attributes = if get32(&self.0, 104) != 0 { block_size / 512 } else { 0 };
+        self.kind() == EntryKind::Symlink && self.sectors() == attributes
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    /// Not a node of this volume, all-zero, or an inode of a kind it has none for
+    #[default]
+    Unused,
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+struct Entry {
+    kind: EntryKind,
+    inode: u32,
+    /// Kept here too since `stat` cannot read the device
+    size: u64,
+}
+
+/// A mounted instance, the superblock `vfs::Manager::get_superblock` returns
+pub struct Volume {
+    layout: Layout,
+    /// By node id like `db::Database::tmpfs_inodes`
+    entries: Vec<Entry>,
+    /// Symlink targets by inode, read at mount since `readlink` cannot get at the device
+    targets: BTreeMap<u32, String>,
+}
+impl Volume {
+    pub fn get_block_size(&self) -> u32 {
+        self.layout.block_size
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.layout.read_only
+    }
+    fn get(&self, node: vfs::NodeHandle) -> Entry {
+        self.entries.get(node.get_id() as usize).copied().unwrap_or_default()
+    }
+    fn set(&mut self, node: vfs::NodeHandle, entry: Entry) {
+        let id = node.get_id() as usize;
+        if self.entries.len() <= id {
+            self.entries.resize(id + 1, Entry::default());
+        }
+        self.entries[id] = entry;
+    }
+    /// `Error::Invalid` for a node of no volume
+    fn get_any(&self, node: vfs::NodeHandle) -> Result<Entry, vfs::Error> {
+        Some(self.get(node)).filter(|e| e.kind != EntryKind::Unused).ok_or(vfs::Error::Invalid)
+    }
+    /// `Error::Invalid` if it is not of that `kind`
+    fn get_entry(&self, node: vfs::NodeHandle, kind: EntryKind) -> Result<Entry, vfs::Error> {
+        Some(self.get(node)).filter(|e| e.kind == kind).ok_or(vfs::Error::Invalid)
+    }
+    /// For every node of `inode`, there is one per name
+    fn set_size(&mut self, inode: u32, size: u64) {
+        for entry in self.entries.iter_mut().filter(|e| e.inode == inode && e.kind != EntryKind::Unused) {
+            entry.size = size;
+        }
+    }
+    fn check_writable(&self) -> Result<(), vfs::Error> {
+        match self.layout.read_only {
+            true => Err(vfs::Error::Unsupported),
+            false => Ok(()),
+        }
+    }
+}
+
+fn get16(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+fn get32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+fn put16(b: &mut [u8], at: usize, value: u16) {
+    b[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+fn put32(b: &mut [u8], at: usize, value: u32) {
+    b[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Bytes a directory entry with a name this long takes at least
+fn record_len(name_len: usize) -> usize {
+    (8 + name_len).next_multiple_of(4)
+}
+fn put_record(b: &mut [u8], at: usize, inode: u32, name: &[u8], len: usize, kind: u8) {
+    put32(b, at, inode);
+    put16(b, at + 4, len as u16);
+    b[at + 6] = name.len() as u8;
+    b[at + 7] = kind;
+    b[at + 8..at + 8 + name.len()].copy_from_slice(name);
+}
+
+pub const FS_TYPE: vfs::FsType = vfs::FsType { name: "ext2", provider: PROVIDER, mount: Manager::mount, unmount: Manager::unmount };
+
+pub const PROVIDER: vfs::Provider = vfs::Provider {
+    read: |db, _, node, offset, data| {
+        Manager::with_volume(db, node, |db, v| {
+            let entry = v.get_entry(node, EntryKind::File)?;
+            let mut inode = Manager::read_inode(db, v, entry.inode)?;
+            let len = entry.size.saturating_sub(offset).min(data.len() as u64) as usize;
+            Manager::read_data(db, v, entry.inode, &mut inode, offset, &mut data[..len])?;
+            Ok(len)
+        })
+    },
+    write: |db, _, node, offset, data| Manager::with_volume(db, node, |db, v| Manager::write_file(db, v, node, offset, data)),
+    stat: |db, _, node| {
+        let entry = Manager::get_volume(db, node)?.get_any(node)?;
+        Ok(vfs::Stat { size: entry.size, children: vfs::Manager::get_node(db, node).get_child_count() })
+    },
+    create: |db, _, parent, name, kind| {
+        let kind = match kind {
+            vfs::NodeKind::File => EntryKind::File,
+            vfs::NodeKind::Directory => EntryKind::Directory,
+        };
+        Manager::with_volume(db, parent, |db, v| Manager::create(db, v, parent, name, kind, &[]))
+    },
+    remove: |db, _, node| Manager::with_volume(db, node, |db, v| Manager::remove(db, v, node)),
+    rename: |db, _, node, parent, name| Manager::with_volume(db, node, |db, v| Manager::rename(db, v, node, parent, name)),
+    truncate: |db, _, node, size| Manager::with_volume(db, node, |db, v| Manager::truncate(db, v, node, size)),
+    readlink: |db, _, node, out| {
+        let volume = Manager::get_volume(db, node)?;
+        let entry = volume.get_entry(node, EntryKind::Symlink)?;
+        let target = volume.targets.get(&entry.inode).map(String::as_bytes).unwrap_or_default();
+        let len = target.len().min(out.len());
+        out[..len].copy_from_slice(&target[..len]);
+        Ok(len)
+    },
+    symlink: |db, _, parent, name, target| {
+        Manager::with_volume(db, parent, |db, v| Manager::create(db, v, parent, name, EntryKind::Symlink, target.as_bytes()))
+    },
+    ..vfs::Provider::DEFAULT
+};
+
+pub struct Manager;
+impl Manager {
+    /// Needs the VFS tree, registers the type
+    pub fn init(db: &mut db::Database) -> vfs::FsTypeHandle {
+        vfs::Manager::register_fs(db, FS_TYPE)
+    }
+
+    fn mount(
+        db: &mut db::Database,
+        actor: db::ObjectHandle,
+        root: vfs::NodeHandle,
+        source: Option<vfs::NodeHandle>,
+    ) -> Result<Option<Box<dyn core::any::Any>>, vfs::Error> {
+        let source = source.ok_or(vfs::Error::Invalid)?;
+        vfs::Manager::check_access(db, actor, source, policy::Access::Read)?;
+        let device = block::Manager::find_by_node(db, source).ok_or(vfs::Error::Invalid)?;
+        let mut sb = [0u8; 1024];
+        block::Manager::read_bytes(db, device, SUPERBLOCK, &mut sb)?;
+        let mut layout = Layout::parse(device, &sb, block::Manager::get_device(db, device).get_size())?;
+        if !layout.read_only && get16(&sb, 58) != STATE_CLEAN {
+            klog!(Warn, "ext2", "not cleanly unmounted or has errors, mounting read-only");
+            layout.read_only = true;
+        }
+        let mut volume = Volume { layout, entries: Vec::new(), targets: BTreeMap::new() };
+        volume.set(root, Entry { kind: EntryKind::Directory, inode: ROOT_INODE, size: 0 });
+        if let Err(e) = Self::load(db, &mut volume, root) {
+            Self::drop_tree(db, root);
+            return Err(e);
+        }
+        // Until unmount says otherwise, like Linux does, and on the device
+        // now rather than whenever the cache writes it back
+        if !layout.read_only {
+            block::Manager::write_bytes(db, device, SUPERBLOCK + 58, &0u16.to_le_bytes())?;
+            block::Manager::flush(db, device)?;
+        }
+        klog!(Info, "ext2", "{} blocks of {} bytes{}", layout.blocks_count, layout.block_size, if layout.read_only { ", read-only" } else { "" });
+        Ok(Some(Box::new(volume)))
+    }
+    fn unmount(db: &mut db::Database, _actor: db::ObjectHandle, root: vfs::NodeHandle) {
+        let Some(volume) = vfs::Manager::get_superblock::<Volume>(db, root) else {
+            return;
+        };
+        let layout = volume.layout;
+        if !layout.read_only {
+            let _ = block::Manager::write_bytes(db, layout.device, SUPERBLOCK + 58, &STATE_CLEAN.to_le_bytes());
+        }
+        if let Err(e) = block::Manager::flush(db, layout.device) {
+            klog!(Warn, "ext2", "flush on unmount failed: {:?}", e);
+        }
+    }
+    /// Every directory below `root` into nodes, each directory inode only once
+    /// should the image loop back on itself
+    fn load(db: &mut db::Database, v: &mut Volume, root: vfs::NodeHandle) -> Result<(), vfs::Error> {
+        let provider = *vfs::Manager::get_node(db, root).get_provider();
+        let mut seen = BTreeSet::from([ROOT_INODE]);
+        let mut stack = alloc::vec![root];
+        while let Some(dir) = stack.pop() {
+            let number = v.get(dir).inode;
+            let mut inode = Self::read_inode(db, v, number)?;
+            if inode.kind() != EntryKind::Directory {
+                return Err(vfs::Error::Invalid);
+            }
+            v.set(dir, Entry { kind: EntryKind::Directory, inode: number, size: inode.size() });
+            let raw = Self::read_all(db, v, number, &mut inode)?;
+            for (_, child, name) in Self::parse_dir(&v.layout, &raw) {
+                if name == b"." || name == b".." {
+                    continue;
+                }
+                let name = String::from_utf8_lossy(name);
+                let inode = match Self::read_inode(db, v, child) {
+                    Ok(inode) if !name.is_empty() && vfs::Manager::find_children(db, dir, &name).is_none() => inode,
+                    _ => {
+                        klog!(Warn, "ext2", "skipping entry {:?}", name);
+                        continue;
+                    }
+                };
+                let kind = inode.kind();
+                // Devices, pipes and sockets have no node kind here
+                if kind == EntryKind::Unused || (kind == EntryKind::Directory && !seen.insert(child)) {
+                    klog!(Warn, "ext2", "skipping entry {:?}", name);
+                    continue;
+                }
+                let node = vfs::Manager::new_node_with_provider(db, &name, dir, provider);
+                v.set(node, Entry { kind, inode: child, size: inode.size() });
+                match kind {
+                    EntryKind::Directory => stack.push(node),
+                    EntryKind::Symlink => {
+                        let target = Self::read_link(db, v, child, inode)?;
+                        v.targets.insert(child, target);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Children first, `node` itself stays
+    fn drop_tree(db: &mut db::Database, node: vfs::NodeHandle) {
+        while let Some(child) = vfs::Manager::get_child(db, node, 0) {
+            Self::drop_tree(db, child);
+            vfs::Manager::remove_node(db, child).expect("children go first");
+        }
+    }
+
+    fn get_volume(db: &db::Database, node: vfs::NodeHandle) -> Result<&Volume, vfs::Error> {
+        vfs::Manager::get_superblock::<Volume>(db, node).ok_or(vfs::Error::Invalid)
+    }
+    /// Runs `f` with the volume of `node` taken out of its mount for the
+    /// while, so it and the database can be borrowed at once
+    fn with_volume<R>(
+        db: &mut db::Database,
+        node: vfs::NodeHandle,
+        f: impl FnOnce(&mut db::Database, &mut Volume) -> Result<R, vfs::Error>,
+    ) -> Result<R, vfs::Error> {
+        // `f` may remove `node`, the root stays
+        let root = vfs::Manager::get_mount(db, node).ok_or(vfs::Error::Invalid)?.get_root();
+        let slot = vfs::Manager::get_superblock_mut::<Volume>(db, root).ok_or(vfs::Error::Invalid)?;
+        let empty = Volume { layout: slot.layout, entries: Vec::new(), targets: BTreeMap::new() };
+        let mut volume = core::mem::replace(slot, empty);
+        let result = f(db, &mut volume);
+        *vfs::Manager::get_superblock_mut::<Volume>(db, root).unwrap() = volume;
+        result
+    }
+
+    /// `data.len()` bytes at `at` on the device
+    fn read(db: &mut db::Database, v: &mut Volume, at: u64, data: &mut [u8]) -> Result<(), vfs::Error> {
+        Ok(block::Manager::read_bytes(db, v.layout.device, at, data)?)
+    }
+    fn write(db: &mut db::Database, v: &mut Volume, at: u64, data: &[u8]) -> Result<(), vfs::Error> {
+        Ok(block::Manager::write_bytes(db, v.layout.device, at, data)?)
+    }
+    fn read32(db: &mut db::Database, v: &mut Volume, at: u64) -> Result<u32, vfs::Error> {
+        let mut b = [0u8; 4];
+        Self::read(db, v, at, &mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+    /// `Error::Io` for what cannot be a block of this volume
+    fn check_block(layout: &Layout, block: u32) -> Result<u32, vfs::Error> {
+        match (layout.first_data_block..layout.blocks_count).contains(&block) {
+            true => Ok(block),
+            false => Err(vfs::Error::Io),
+        }
+    }
+
+    fn get_group(db: &mut db::Database, v: &mut Volume, group: u32) -> Result<Group, vfs::Error> {
+        let mut d = [0u8; GROUP_DESC_SIZE as usize];
+        Self::read(db, v, v.layout.descriptor_offset(group), &mut d)?;
+        Ok(Group {
+            block_bitmap: Self::check_block(&v.layout, get32(&d, 0))?,
+            inode_bitmap: Self::check_block(&v.layout, get32(&d, 4))?,
+            inode_table: Self::check_block(&v.layout, get32(&d, 8))?,
+            free_blocks: get16(&d, 12),
+            free_inodes: get16(&d, 14),
+        })
+    }
+    /// Adds to the free counts of `group` and the whole volume, and to its count of directories
+    fn count(db: &mut db::Database, v: &mut Volume, group: u32, blocks: i16, inodes: i16, directories: i16) -> Result<(), vfs::Error> {
+        let at = v.layout.descriptor_offset(group) + 12;
+        let mut d = [0u8; 6];
+        Self::read(db, v, at, &mut d)?;
+        for (i, n) in [blocks, inodes, directories].into_iter().enumerate() {
+            let value = get16(&d, i * 2).wrapping_add_signed(n);
+            put16(&mut d, i * 2, value);
+        }
+        Self::write(db, v, at, &d)?;
+        let mut s = [0u8; 8];
+        Self::read(db, v, SUPERBLOCK + 12, &mut s)?;
+        let (free_blocks, free_inodes) = (get32(&s, 0).wrapping_add_signed(blocks as i32), get32(&s, 4).wrapping_add_signed(inodes as i32));
+        put32(&mut s, 0, free_blocks);
+        put32(&mut s, 4, free_inodes);
+        Self::write(db, v, SUPERBLOCK + 12, &s)
+    }
+    /// Sets the first clear bit from `from` up to `count` in the bitmap at `block`
+    fn take_bit(db: &mut db::Database, v: &mut Volume, block: u32, from: u32, count: u32) -> Result<Option<u32>, vfs::Error> {
+        let at = block as u64 * v.layout.block_size as u64;
+        let mut bits = alloc::vec![0u8; v.layout.block_size as usize];
+        Self::read(db, v, at, &mut bits)?;
+        let Some(bit) = (from..count).find(|&i| bits[i as usize / 8] & (1 << (i % 8)) == 0) else {
+            return Ok(None);
+        };
+        let byte = bit as usize / 8;
+        Self::write(db, v, at + byte as u64, &[bits[byte] | 1 << (bit % 8)])?;
+        Ok(Some(bit))
+    }
+    /// Whether it was set
+    fn clear_bit(db: &mut db::Database, v: &mut Volume, block: u32, bit: u32) -> Result<bool, vfs::Error> {
+        let at = block as u64 * v.layout.block_size as u64 + bit as u64 / 8;
+        let mut byte = [0u8];
+        Self::read(db, v, at, &mut byte)?;
+        let mask = 1 << (bit % 8);
+        Self::write(db, v, at, &[byte[0] & !mask])?;
+        Ok(byte[0] & mask != 0)
+    }
+    /// A zeroed block, looked for from group `goal` on
+    fn alloc_block(db: &mut db::Database, v: &mut Volume, goal: u32) -> Result<u32, vfs::Error> {
+        let layout = v.layout;
+        for i in 0..layout.group_count {
+            let group = (goal + i) % layout.group_count;
+            let g = Self::get_group(db, v, group)?;
+            let first = layout.first_data_block + group * layout.blocks_per_group;
+            let count = layout.blocks_per_group.min(layout.blocks_count - first);
+            if g.free_blocks == 0 {
+                continue;
+            }
+            if let Some(bit) = Self::take_bit(db, v, g.block_bitmap, 0, count)? {
+                Self::count(db, v, group, -1, 0, 0)?;
+                let block = first + bit;
+                Self::write(db, v, block as u64 * layout.block_size as u64, &alloc::vec![0; layout.block_size as usize])?;
+                return Ok(block);
+            }
+        }
+        Err(vfs::Error::NoSpace)
+    }
+    fn free_block(db: &mut db::Database, v: &mut Volume, block: u32) -> Result<(), vfs::Error> {
+        let layout = v.layout;
+        let index = Self::check_block(&layout, block)? - layout.first_data_block;
+        let group = index / layout.blocks_per_group;
+        let g = Self::get_group(db, v, group)?;
+        if Self::clear_bit(db, v, g.block_bitmap, index % layout.blocks_per_group)? {
+            Self::count(db, v, group, 1, 0, 0)?;
+        }
+        Ok(())
+    }
+    /// A free inode number, looked for from group `goal` on
+    fn alloc_inode(db: &mut db::Database, v: &mut Volume, goal: u32, directory: bool) -> Result<u32, vfs::Error> {
+        let layout = v.layout;
+        for i in 0..layout.group_count {
+            let group = (goal + i) % layout.group_count;
+            let g = Self::get_group(db, v, group)?;
+            let first = group * layout.inodes_per_group;
+            let count = layout.inodes_per_group.min(layout.inodes_count - first);
+            if g.free_inodes == 0 {
+                continue;
+            }
+            // Reserved ones are all in the first group
+            let from = (layout.first_inode - 1).saturating_sub(first);
+            if let Some(bit) = Self::take_bit(db, v, g.inode_bitmap, from, count)? {
+                Self::count(db, v, group, 0, -1, directory as i16)?;
+                return Ok(first + bit + 1);
+            }
+        }
+        Err(vfs::Error::NoSpace)
+    }
+    fn free_inode(db: &mut db::Database, v: &mut Volume, inode: u32, directory: bool) -> Result<(), vfs::Error> {
+        let group = (inode - 1) / v.layout.inodes_per_group;
+        let g = Self::get_group(db, v, group)?;
+        if Self::clear_bit(db, v, g.inode_bitmap, (inode - 1) % v.layout.inodes_per_group)? {
+            Self::count(db, v, group, 0, 1, -(directory as i16))?;
+        }
+        Ok(())
+    }
+
+    fn inode_offset(db: &mut db::Database, v: &mut Volume, inode: u32) -> Result<u64, vfs::Error> {
+        let layout = v.layout;
+        if !(1..=layout.inodes_count).contains(&inode) {
+            return Err(vfs::Error::Io);
+        }
+        let (group, index) = ((inode - 1) / layout.inodes_per_group, (inode - 1) % layout.inodes_per_group);
+        let table = Self::get_group(db, v, group)?.inode_table;
+        Ok(table as u64 * layout.block_size as u64 + index as u64 * layout.inode_size as u64)
+    }
+    fn read_inode(db: &mut db::Database, v: &mut Volume, inode: u32) -> Result<Inode, vfs::Error> {
+        let at = Self::inode_offset(db, v, inode)?;
+        let mut data = Inode([0; INODE_SIZE]);
+        Self::read(db, v, at, &mut data.0)?;
+        Ok(data)
+    }
+    fn write_inode(db: &mut db::Database, v: &mut Volume, inode: u32, data: &Inode) -> Result<(), vfs::Error> {
+        let at = Self::inode_offset(db, v, inode)?;
+        Self::write(db, v, at, &data.0)
+    }
+    /// All of it, past `INODE_SIZE` too, so nothing of what was there before
+    /// comes back with the next file
+    fn clear_inode(db: &mut db::Database, v: &mut Volume, inode: u32) -> Result<(), vfs::Error> {
+        let at = Self::inode_offset(db, v, inode)?;
+        Self::write(db, v, at, &alloc::vec![0; v.layout.inode_size as usize])
+    }
+    /// The slot in the inode and then the one in each indirect block on the
+    /// way to the `index`th block of a file, and how many of those there are
+    fn block_path(layout: &Layout, index: u64) -> Result<([usize; 4], usize), vfs::Error> {
+        if index < DIRECT as u64 {
+            return Ok(([index as usize, 0, 0, 0], 1));
+        }
+        let per = layout.block_size as u64 / 4;
+        let (mut index, mut span) = (index - DIRECT as u64, 1);
+        for level in 1..=3 {
+            span *= per;
+            if index < span {
+                let mut path = [DIRECT + level - 1, 0, 0, 0];
+                let mut below = span;
+                for slot in path.iter_mut().take(level + 1).skip(1) {
+                    below /= per;
+                    *slot = (index / below % per) as usize;
+                }
+                return Ok((path, level + 1));
+            }
+            index -= span;
+        }
+        Err(vfs::Error::Invalid)
+    }
+    /// The device block holding the `index`th block of a file, 0 for a hole
+    /// unless `allocate`, which fills it and counts new blocks in `inode`
+    fn map_block(db: &mut db::Database, v: &mut Volume, number: u32, inode: &mut Inode, index: u64, allocate: bool) -> Result<u32, vfs::Error> {
+        let (path, depth) = Self::block_path(&v.layout, index)?;
+        let (goal, block_size) = ((number - 1) / v.layout.inodes_per_group, v.layout.block_size);
+        let mut block = inode.block(path[0]);
+        if block == 0 {
+            if !allocate {
+                return Ok(0);
+            }
+            block = Self::alloc_block(db, v, goal)?;
+            inode.set_block(path[0], block);
+            inode.add_sectors(block_size, 1);
+        }
+        for &slot in &path[1..depth] {
+            let at = Self::check_block(&v.layout, block)? as u64 * block_size as u64 + slot as u64 * 4;
+            let mut next = Self::read32(db, v, at)?;
+            if next == 0 {
+                if !allocate {
+                    return Ok(0);
+                }
+                next = Self::alloc_block(db, v, goal)?;
+                Self::write(db, v, at, &next.to_le_bytes())?;
+                inode.add_sectors(block_size, 1);
+            }
+            block = next;
+        }
+        Self::check_block(&v.layout, block)
+    }
+    /// Calls `f` with where on the device (0 for a hole) and the range of
+    /// `0..len` for each block of a file from `offset` on
+    #[allow(clippy::too_many_arguments)]
+    fn for_each_block(
+        db: &mut db::Database,
+        v: &mut Volume,
+        number: u32,
+        inode: &mut Inode,
+        offset: u64,
+        len: usize,
+        allocate: bool,
+        mut f: impl FnMut(&mut db::Database, &mut Volume, u64, Range<usize>) -> Result<(), vfs::Error>,
+    ) -> Result<(), vfs::Error> {
+        let size = v.layout.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = pos % size;
+            let n = (len - done).min((size - within) as usize);
+            let at = match Self::map_block(db, v, number, inode, pos / size, allocate)? {
+                0 => 0,
+                block => block as u64 * size + within,
+            };
+            f(db, v, at, done..done + n)?;
+            done += n;
+        }
+        Ok(())
+    }
+    /// Holes read as zeroes
+    fn read_data(db: &mut db::Database, v: &mut Volume, number: u32, inode: &mut Inode, offset: u64, data: &mut [u8]) -> Result<(), vfs::Error> {
+        Self::for_each_block(db, v, number, inode, offset, data.len(), false, |db, v, at, range| {
+            if at == 0 {
+                data[range].fill(0);
+                return Ok(());
+            }
+            Self::read(db, v, at, &mut data[range])
+        })
+    }
+    /// Fills holes on the way, the caller writes `inode` back
+    fn write_data(db: &mut db::Database, v: &mut Volume, number: u32, inode: &mut Inode, offset: u64, data: &[u8]) -> Result<(), vfs::Error> {
+        Self::for_each_block(db, v, number, inode, offset, data.len(), true, |db, v, at, range| Self::write(db, v, at, &data[range]))
+    }
+    fn read_all(db: &mut db::Database, v: &mut Volume, number: u32, inode: &mut Inode) -> Result<Vec<u8>, vfs::Error> {
+        let mut data = alloc::vec![0; usize::try_from(inode.size()).map_err(|_| vfs::Error::Io)?];
+        Self::read_data(db, v, number, inode, 0, &mut data)?;
+        Ok(data)
+    }
+    fn read_link(db: &mut db::Database, v: &mut Volume, number: u32, mut inode: Inode) -> Result<String, vfs::Error> {
+        let len = (inode.size() as usize).min(vfs::MAX_PATH);
+        let target = match inode.is_fast_link(v.layout.block_size) {
+            true => inode.0[40..40 + len.min(FAST_LINK)].to_vec(),
+            false => {
+                let mut target = alloc::vec![0; len];
+                Self::read_data(db, v, number, &mut inode, 0, &mut target)?;
+                target
+            }
+        };
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+    /// Frees the blocks of a file from the `keep`th on, and the indirect
+    /// blocks that then point at nothing
+    fn trim(db: &mut db::Database, v: &mut Volume, inode: &mut Inode, keep: u64) -> Result<(), vfs::Error> {
+        let per = v.layout.block_size as u64 / 4;
+        let mut start = 0;
+        for slot in 0..DIRECT + 3 {
+            let depth = slot.saturating_sub(DIRECT - 1) as u32;
+            let span = per.pow(depth);
+            let block = inode.block(slot);
+            if block != 0 && keep < start + span && Self::trim_tree(db, v, inode, block, depth, keep.saturating_sub(start))? {
+                inode.set_block(slot, 0);
+            }
+            start += span;
+        }
+        Ok(())
+    }
+    /// What of the `depth` levels of indirect blocks below `block` comes
+    /// after the `keep`th data block, true if that was all of it
+    fn trim_tree(db: &mut db::Database, v: &mut Volume, inode: &mut Inode, block: u32, depth: u32, keep: u64) -> Result<bool, vfs::Error> {
+        let block_size = v.layout.block_size;
+        if depth > 0 {
+            let at = Self::check_block(&v.layout, block)? as u64 * block_size as u64;
+            let span = (block_size as u64 / 4).pow(depth - 1);
+            let mut pointers = alloc::vec![0u8; block_size as usize];
+            Self::read(db, v, at, &mut pointers)?;
+            for (i, pointer) in pointers.chunks_exact(4).enumerate() {
+                let (child, start) = (get32(pointer, 0), i as u64 * span);
+                if child != 0 && keep < start + span && Self::trim_tree(db, v, inode, child, depth - 1, keep.saturating_sub(start))? && keep > 0 {
+                    Self::write(db, v, at + i as u64 * 4, &[0; 4])?;
+                }
+            }
+        }
+        if keep > 0 {
+            return Ok(false);
+        }
+        Self::free_block(db, v, block)?;
+        inode.add_sectors(block_size, -1);
+        Ok(true)
+    }
+    /// Zeroes the last block of a file past `size`, so growing it again reads zeroes
+    fn zero_tail(db: &mut db::Database, v: &mut Volume, number: u32, inode: &mut Inode, size: u64) -> Result<(), vfs::Error> {
+        let block_size = v.layout.block_size as u64;
+        let within = size % block_size;
+        if within == 0 {
+            return Ok(());
+        }
+        match Self::map_block(db, v, number, inode, size / block_size, false)? {
+            0 => Ok(()),
+            block => Self::write(db, v, block as u64 * block_size + within, &alloc::vec![0; (block_size - within) as usize]),
+        }
+    }
+
+    fn write_file(db: &mut db::Database, v: &mut Volume, node: vfs::NodeHandle, offset: u64, data: &[u8]) -> vfs::Result {
+        v.check_writable()?;
+        let entry = v.get_entry(node, EntryKind::File)?;
+        let end = offset.checked_add(data.len() as u64).filter(|&e| e <= v.layout.max_size()).ok_or(vfs::Error::Invalid)?;
+        let mut inode = Self::read_inode(db, v, entry.inode)?;
+        if offset > entry.size {
+            Self::zero_tail(db, v, entry.inode, &mut inode, entry.size)?;
+        }
+        let result = Self::write_data(db, v, entry.inode, &mut inode, offset, data);
+        match result {
+            Ok(()) if end > entry.size => inode.set_size(end),
+            Ok(()) => {}
+            // Whatever got allocated past the end goes again
+            Err(_) => Self::trim(db, v, &mut inode, entry.size.div_ceil(v.layout.block_size as u64))?,
+        }
+        Self::write_inode(db, v, entry.inode, &inode)?;
+        v.set_size(entry.inode, inode.size());
+        result.map(|_| data.len())
+    }
+    fn truncate(db: &mut db::Database, v: &mut Volume, node: vfs::NodeHandle, size: u64) -> Result<(), vfs::Error> {
+        v.check_writable()?;
+        let entry = v.get_entry(node, EntryKind::File)?;
+        if size > v.layout.max_size() {
+            return Err(vfs::Error::Invalid);
+        }
+        let mut inode = Self::read_inode(db, v, entry.inode)?;
+        if size < entry.size {
+            Self::trim(db, v, &mut inode, size.div_ceil(v.layout.block_size as u64))?;
+            Self::zero_tail(db, v, entry.inode, &mut inode, size)?;
+        } else {
+            Self::zero_tail(db, v, entry.inode, &mut inode, entry.size)?;
+        }
+        inode.set_size(size);
+        Self::write_inode(db, v, entry.inode, &inode)?;
+        v.set_size(entry.inode, size);
+        Ok(())
+    }
+
+    /// Offset in the directory, inode and name of each entry in use, `.` and `..` too
+    fn parse_dir<'a>(layout: &Layout, raw: &'a [u8]) -> Vec<(usize, u32, &'a [u8])> {
+        let mut found = Vec::new();
+        for (start, block) in raw.chunks(layout.block_size as usize).enumerate().map(|(i, b)| (i * layout.block_size as usize, b)) {
+            let mut pos = 0;
+            while pos + 8 <= block.len() {
+                let (inode, len, name_len) = (get32(block, pos), get16(block, pos + 4) as usize, block[pos + 6] as usize);
+                // A broken record makes the rest of the block unreadable
+                if len < 8 || len % 4 != 0 || pos + len > block.len() {
+                    break;
+                }
+                // Without the type byte it is the upper half of the name length
+                let long_name = !layout.file_type && block[pos + 7] != 0;
+                if inode != 0 && !long_name && 8 + name_len <= len {
+                    found.push((start + pos, inode, &block[pos + 8..pos + 8 + name_len]));
+                }
+                pos += len;
+            }
+        }
+        found
+    }
+    fn type_byte(layout: &Layout, kind: EntryKind) -> u8 {
+        match (layout.file_type, kind) {
+            (false, _) | (_, EntryKind::Unused) => 0,
+            (_, EntryKind::File) => TYPE_FILE,
+            (_, EntryKind::Directory) => TYPE_DIRECTORY,
+            (_, EntryKind::Symlink) => TYPE_SYMLINK,
+        }
+    }
+    /// The contents of directory `dir` and its inode, after `f` changed them
+    /// the inode goes back without the hashed index
+    fn change_dir<R>(
+        db: &mut db::Database,
+        v: &mut Volume,
+        dir: vfs::NodeHandle,
+        f: impl FnOnce(&mut db::Database, &mut Volume, u32, &mut Inode, &[u8]) -> Result<R, vfs::Error>,
+    ) -> Result<R, vfs::Error> {
+        let number = v.get_entry(dir, EntryKind::Directory)?.inode;
+        let mut inode = Self::read_inode(db, v, number)?;
+        let raw = Self::read_all(db, v, number, &mut inode)?;
+        let result = f(db, v, number, &mut inode, &raw)?;
+        inode.set_flags(inode.flags() & !FLAG_INDEX);
+        Self::write_inode(db, v, number, &inode)?;
+        v.set_size(number, inode.size());
+        Ok(result)
+    }
+    /// Puts `name` for `inode` in the first gap in `dir` big enough, or in a new block at its end
+    fn add_record(db: &mut db::Database, v: &mut Volume, dir: vfs::NodeHandle, name: &str, inode: u32, kind: EntryKind) -> Result<(), vfs::Error> {
+        let kind = Self::type_byte(&v.layout, kind);
+        let block_size = v.layout.block_size as usize;
+        let need = record_len(name.len());
+        Self::change_dir(db, v, dir, |db, v, number, dir_inode, raw| {
+            for start in (0..raw.len()).step_by(block_size) {
+                let block = &raw[start..(start + block_size).min(raw.len())];
+                let mut pos = 0;
+                while pos + 8 <= block.len() {
+                    let len = get16(block, pos + 4) as usize;
+                    if len < 8 || pos + len > block.len() {
+                        break;
+                    }
+                    let used = match get32(block, pos) {
+                        0 => 0,
+                        _ => record_len(block[pos + 6] as usize),
+                    };
+                    if len >= used + need {
+                        // The new record gets what the one there does not use
+                        let mut record = alloc::vec![0u8; need];
+                        put_record(&mut record, 0, inode, name.as_bytes(), len - used, kind);
+                        if used > 0 {
+                            Self::write_data(db, v, number, dir_inode, (start + pos + 4) as u64, &(used as u16).to_le_bytes())?;
+                        }
+                        return Self::write_data(db, v, number, dir_inode, (start + pos + used) as u64, &record);
+                    }
+                    pos += len;
+                }
+            }
+            let mut record = alloc::vec![0u8; block_size];
+            put_record(&mut record, 0, inode, name.as_bytes(), block_size, kind);
+            let end = raw.len().next_multiple_of(block_size);
+            Self::write_data(db, v, number, dir_inode, end as u64, &record)?;
+            dir_inode.set_size((end + block_size) as u64);
+            Ok(())
+        })
+    }
+    /// Takes `name` out of `dir`, its space goes to the record before it in
+    /// the block, returns the inode it was for
+    fn remove_record(db: &mut db::Database, v: &mut Volume, dir: vfs::NodeHandle, name: &str) -> Result<u32, vfs::Error> {
+        let block_size = v.layout.block_size as usize;
+        Self::change_dir(db, v, dir, |db, v, number, dir_inode, raw| {
+            let layout = v.layout;
+            let found = Self::parse_dir(&layout, raw);
+            let &(at, inode, _) = found.iter().find(|(_, _, n)| *n == name.as_bytes()).ok_or(vfs::Error::NotFound)?;
+            let start = at - at % block_size;
+            // Walk the block up to it for the record before
+            let mut previous = None;
+            let mut pos = start;
+            while pos < at {
+                previous = Some(pos);
+                pos += get16(raw, pos + 4) as usize;
+            }
+            match previous {
+                Some(previous) => {
+                    let len = (at - previous + get16(raw, at + 4) as usize) as u16;
+                    Self::write_data(db, v, number, dir_inode, previous as u64 + 4, &len.to_le_bytes())?;
+                }
+                None => Self::write_data(db, v, number, dir_inode, at as u64, &[0; 4])?,
+            }
+            Ok(inode)
+        })
+    }
+    /// Adds to the link count of the inode of directory `node`
+    fn add_links(db: &mut db::Database, v: &mut Volume, node: vfs::NodeHandle, n: i16) -> Result<(), vfs::Error> {
+        let number = v.get_entry(node, EntryKind::Directory)?.inode;
+        let mut inode = Self::read_inode(db, v, number)?;
+        inode.set_links(inode.links().wrapping_add_signed(n));
+        Self::write_inode(db, v, number, &inode)
+    }
+    /// Gives back the blocks and the number of an inode nothing links to any
+    /// more. It is cleared whole, a deleted inode needs the time it went
+    /// otherwise and there is no wall clock
+    fn release(db: &mut db::Database, v: &mut Volume, number: u32, inode: &mut Inode) -> Result<(), vfs::Error> {
+        if !inode.is_fast_link(v.layout.block_size) {
+            Self::trim(db, v, inode, 0)?;
+        }
+        Self::clear_inode(db, v, number)?;
+        Self::free_inode(db, v, number, inode.kind() == EntryKind::Directory)
+    }
+
+    /// A file, directory or symlink to `target` in `parent`
+    fn create(
+        db: &mut db::Database,
+        v: &mut Volume,
+        parent: vfs::NodeHandle,
+        name: &str,
+        kind: EntryKind,
+        target: &[u8],
+    ) -> Result<vfs::NodeHandle, vfs::Error> {
+        v.check_writable()?;
+        let dir = v.get_entry(parent, EntryKind::Directory)?;
+        let block_size = v.layout.block_size as usize;
+        if target.len() >= block_size {
+            return Err(vfs::Error::Invalid);
+        }
+        let (mode, links) = match kind {
+            EntryKind::Directory => (MODE_DIRECTORY | 0o755, 2),
+            EntryKind::Symlink => (MODE_SYMLINK | 0o777, 1),
+            _ => (MODE_FILE | 0o644, 1),
+        };
+        let number = Self::alloc_inode(db, v, (dir.inode - 1) / v.layout.inodes_per_group, kind == EntryKind::Directory)?;
+        Self::clear_inode(db, v, number)?;
+        let mut inode = Inode::new(mode, links);
+        let filled = match kind {
+            EntryKind::Directory => {
+                let mut dots = alloc::vec![0u8; block_size];
+                let kind = Self::type_byte(&v.layout, EntryKind::Directory);
+                put_record(&mut dots, 0, number, b".", 12, kind);
+                put_record(&mut dots, 12, dir.inode, b"..", block_size - 12, kind);
+                inode.set_size(block_size as u64);
+                Self::write_data(db, v, number, &mut inode, 0, &dots)
+            }
+            EntryKind::Symlink if target.len() < FAST_LINK => {
+                inode.0[40..40 + target.len()].copy_from_slice(target);
+                inode.set_size(target.len() as u64);
+                Ok(())
+            }
+            EntryKind::Symlink => {
+                inode.set_size(target.len() as u64);
+                Self::write_data(db, v, number, &mut inode, 0, target)
+            }
+            _ => Ok(()),
+        };
+        let added = filled
+            .and_then(|_| Self::write_inode(db, v, number, &inode))
+            .and_then(|_| Self::add_record(db, v, parent, name, number, kind));
+        if let Err(e) = added {
+            Self::release(db, v, number, &mut inode)?;
+            return Err(e);
+        }
+        if kind == EntryKind::Directory {
+            Self::add_links(db, v, parent, 1)?;
+        }
+        let provider = *vfs::Manager::get_node(db, parent).get_provider();
+        let node = vfs::Manager::new_node_with_provider(db, name, parent, provider);
+        v.set(node, Entry { kind, inode: number, size: inode.size() });
+        if kind == EntryKind::Symlink {
+            v.targets.insert(number, String::from_utf8_lossy(target).into_owned());
+        }
+        Ok(node)
+    }
+    /// The inode goes once its last name does
+    fn remove(db: &mut db::Database, v: &mut Volume, node: vfs::NodeHandle) -> Result<(), vfs::Error> {
+        v.check_writable()?;
+        let entry = v.get_any(node)?;
+        let parent = *vfs::Manager::get_node(db, node).get_parent();
+        let mut inode = Self::read_inode(db, v, entry.inode)?;
+        if entry.kind == EntryKind::Directory {
+            // Entries that got no node at mount are still there
+            let raw = Self::read_all(db, v, entry.inode, &mut inode)?;
+            if Self::parse_dir(&v.layout, &raw).iter().any(|(_, _, name)| *name != b"." && *name != b"..") {
+                return Err(vfs::Error::NotEmpty);
+            }
+        }
+        let name = String::from(vfs::Manager::get_node(db, node).get_name());
+        Self::remove_record(db, v, parent, &name)?;
+        let links = match entry.kind {
+            EntryKind::Directory => {
+                Self::add_links(db, v, parent, -1)?;
+                0
+            }
+            _ => inode.links().saturating_sub(1),
+        };
+        match links {
+            0 => {
+                Self::release(db, v, entry.inode, &mut inode)?;
+                v.targets.remove(&entry.inode);
+            }
+            n => {
+                inode.set_links(n);
+                Self::write_inode(db, v, entry.inode, &inode)?;
+            }
+        }
+        v.set(node, Entry::default());
+        vfs::Manager::remove_node(db, node)
+    }
+    /// The new record goes in before the old one goes, a directory's `..` follows
+    fn rename(db: &mut db::Database, v: &mut Volume, node: vfs::NodeHandle, parent: vfs::NodeHandle, name: &str) -> Result<(), vfs::Error> {
+        let root_of = |db: &db::Database, node| vfs::Manager::get_mount(db, node).map(|m| m.get_root());
+        if root_of(db, node) != root_of(db, parent) {
+            return Err(vfs::Error::Unsupported);
+        }
+        v.check_writable()?;
+        let entry = v.get_any(node)?;
+        let dir = v.get_entry(parent, EntryKind::Directory)?;
+        let old_parent = *vfs::Manager::get_node(db, node).get_parent();
+        let old_name = String::from(vfs::Manager::get_node(db, node).get_name());
+        Self::add_record(db, v, parent, name, entry.inode, entry.kind)?;
+        Self::remove_record(db, v, old_parent, &old_name)?;
+        if entry.kind == EntryKind::Directory && old_parent != parent {
+            let mut inode = Self::read_inode(db, v, entry.inode)?;
+            // `..` is the second record of the first block
+            let mut first = [0u8; 12];
+            Self::read_data(db, v, entry.inode, &mut inode, 0, &mut first)?;
+            Self::write_data(db, v, entry.inode, &mut inode, get16(&first, 4) as u64, &dir.inode.to_le_bytes())?;
+            Self::add_links(db, v, old_parent, -1)?;
+            Self::add_links(db, v, parent, 1)?;
+        }
+        vfs::Manager::move_node(db, node, parent, name);
+        Ok(())
+    }
+
+    /// An empty filesystem on `device` with a `lost+found`, 1 KiB blocks
+    /// below 512 MiB and 4 KiB from there, an inode per 8 KiB
+    pub fn format(db: &mut db::Database, device: block::DeviceHandle) -> Result<(), vfs::Error> {
+        let size = block::Manager::get_device(db, device).get_size();
+        let block_size: u64 = if size < 512 << 20 { 1024 } else { 4096 };
+        let first_data_block = (block_size == 1024) as u64;
+        let (per_group, inodes_per_block) = (block_size * 8, block_size / INODE_SIZE as u64);
+        // Groups 0 and 1 and the powers of 3, 5 and 7 have a copy of the superblock
+        let has_super = |group: u64| {
+            group <= 1
+                || [3, 5, 7].into_iter().any(|p| {
+                    let mut n = p;
+                    while n < group {
+                        n *= p;
+                    }
+                    n == group
+                })
+        };
+        let mut blocks = (size / block_size).min(u32::MAX as u64);
+        // A last group too small for its own bitmaps and inodes is left out
+        let (groups, inodes_per_group, descriptor_blocks, table_blocks) = loop {
+            if blocks <= first_data_block + 64 {
+                return Err(vfs::Error::Invalid);
+            }
+            let groups = (blocks - first_data_block).div_ceil(per_group);
+            let inodes_per_group = ((blocks * block_size / 8192) / groups).clamp(16, per_group).next_multiple_of(inodes_per_block);
+            let descriptor_blocks = (groups * GROUP_DESC_SIZE).div_ceil(block_size);
+            let table_blocks = inodes_per_group / inodes_per_block;
+            let last = blocks - first_data_block - (groups - 1) * per_group;
+            let overhead = if has_super(groups - 1) { 1 + descriptor_blocks } else { 0 } + 2 + table_blocks;
+            match groups > 1 && last < overhead + 64 {
+                true => blocks -= last,
+                false if last < overhead + 4 => return Err(vfs::Error::Invalid),
+                false => break (groups, inodes_per_group, descriptor_blocks, table_blocks),
+            }
+        };
+
+        let zeroes = alloc::vec![0u8; block_size as usize];
+        let mut descriptors = alloc::vec![0u8; (descriptor_blocks * block_size) as usize];
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        let mut first_blocks = (0, 0, 0);
+        for group in 0..groups {
+            let start = first_data_block + group * per_group;
+            let count = per_group.min(blocks - start);
+            let bitmaps = start + if has_super(group) { 1 + descriptor_blocks } else { 0 };
+            let table = bitmaps + 2;
+            // The root directory and lost+found get one block each
+            let used = table + table_blocks + if group == 0 { 2 } else { 0 } - start;
+            for block in bitmaps..table + table_blocks {
+                block::Manager::write_bytes(db, device, block * block_size, &zeroes)?;
+            }
+            // Past the end of the group counts as used
+            let mut bits = alloc::vec![0u8; block_size as usize];
+            for bit in (0..used).chain(count..per_group) {
+                bits[bit as usize / 8] |= 1 << (bit % 8);
+            }
+            block::Manager::write_bytes(db, device, bitmaps * block_size, &bits)?;
+            let reserved = if group == 0 { FIRST_INODE as u64 } else { 0 };
+            let mut bits = alloc::vec![0u8; block_size as usize];
+            for bit in (0..reserved).chain(inodes_per_group..per_group) {
+                bits[bit as usize / 8] |= 1 << (bit % 8);
+            }
+            block::Manager::write_bytes(db, device, (bitmaps + 1) * block_size, &bits)?;
+            let d = &mut descriptors[(group * GROUP_DESC_SIZE) as usize..];
+            put32(d, 0, bitmaps as u32);
+            put32(d, 4, bitmaps as u32 + 1);
+            put32(d, 8, table as u32);
+            put16(d, 12, (count - used) as u16);
+            put16(d, 14, (inodes_per_group - reserved) as u16);
+            put16(d, 16, if group == 0 { 2 } else { 0 });
+            free_blocks += count - used;
+            free_inodes += inodes_per_group - reserved;
+            if group == 0 {
+                first_blocks = (table, table + table_blocks, table + table_blocks + 1);
+            }
+        }
+
+        let mut sb = [0u8; 1024];
+        put32(&mut sb, 0, (inodes_per_group * groups) as u32);
+        put32(&mut sb, 4, blocks as u32);
+        put32(&mut sb, 12, free_blocks as u32);
+        put32(&mut sb, 16, free_inodes as u32);
+        put32(&mut sb, 20, first_data_block as u32);
+        put32(&mut sb, 24, block_size.trailing_zeros() - 10);
+        put32(&mut sb, 28, block_size.trailing_zeros() - 10);
+        put32(&mut sb, 32, per_group as u32);
+        put32(&mut sb, 36, per_group as u32);
+        put32(&mut sb, 40, inodes_per_group as u32);
+        // No checks forced by mount count
+        put16(&mut sb, 54, u16::MAX);
+        put16(&mut sb, 56, MAGIC);
+        put16(&mut sb, 58, STATE_CLEAN);
+        put16(&mut sb, 60, 1);
+        put32(&mut sb, 76, 1);
+        put32(&mut sb, 84, FIRST_INODE);
+        put16(&mut sb, 88, INODE_SIZE as u16);
+        put32(&mut sb, 96, INCOMPAT_FILETYPE);
+        put32(&mut sb, 100, RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE);
+        for (i, chunk) in sb[104..120].chunks_exact_mut(4).enumerate() {
+            put32(chunk, 0, 0x5241_4449 ^ (blocks as u32).rotate_left(i as u32 * 8));
+        }
+        for group in (0..groups).filter(|&g| has_super(g)) {
+            let start = first_data_block + group * per_group;
+            put16(&mut sb, 90, group as u16);
+            let at = if group == 0 { SUPERBLOCK } else { start * block_size };
+            block::Manager::write_bytes(db, device, at, &sb)?;
+            block::Manager::write_bytes(db, device, (start + 1) * block_size, &descriptors)?;
+        }
+
+        let (table, root_block, lost_block) = first_blocks;
+        let kind = TYPE_DIRECTORY;
+        for (number, parent, block, links, mode) in [(ROOT_INODE, ROOT_INODE, root_block, 3, 0o755), (FIRST_INODE, ROOT_INODE, lost_block, 2, 0o700)] {
+            let mut inode = Inode::new(MODE_DIRECTORY | mode, links);
+            inode.set_size(block_size);
+            inode.set_block(0, block as u32);
+            inode.add_sectors(block_size as u32, 1);
+            block::Manager::write_bytes(db, device, table * block_size + (number as u64 - 1) * INODE_SIZE as u64, &inode.0)?;
+            let mut records = alloc::vec![0u8; block_size as usize];
+            put_record(&mut records, 0, number, b".", 12, kind);
+            match number {
+                ROOT_INODE => {
+                    put_record(&mut records, 12, parent, b"..", 12, kind);
+                    put_record(&mut records, 24, FIRST_INODE, b"lost+found", block_size as usize - 24, kind);
+                }
+                _ => put_record(&mut records, 12, parent, b"..", block_size as usize - 12, kind),
+            }
+            block::Manager::write_bytes(db, device, block * block_size, &records)?;
+        }
+        block::Manager::flush(db, device)?;
+        klog!(Info, "ext2", "formatted {} blocks of {} bytes in {} groups", blocks, block_size, groups);
+        Ok(())
+    }
+}
+
+console_commands! {
+    Command {
+        name: "mkext2",
+        category: "vfs",
+        desc: "make an empty ext2 filesystem on a disk",
+        help: "<device> is a block device node, whatever was on it is gone.",
+        args: &[ArgSpec::required("device", ArgKind::Word)],
+        handler: |state, args| {
+            let result = console::Manager::resolve_path(state, args.get(1).unwrap())
+                .and_then(|node| vfs::Manager::check_access(state.db, state.current_actor, node, policy::Access::Write).map(|_| node))
+                .and_then(|node| block::Manager::find_by_node(state.db, node).ok_or(vfs::Error::Invalid))
+                .and_then(|device| Manager::format(state.db, device));
+            if let Err(e) = result {
+                console_error!(state, "{:?}\r\n", e);
+            }
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::Subsystem::*;
+    use vfs::{Error, NodeKind};
+
+    fn path(db: &db::Database, path: &str) -> Result<vfs::NodeHandle, Error> {
+        vfs::Manager::resolve_path(db, db::ObjectHandle::default(), vfs::NodeHandle::default(), path)
+    }
+
+    /// `/devices/ram0` mounted on `/mount/disk`
+    fn mount(db: &mut db::Database) -> Result<vfs::NodeHandle, Error> {
+        let actor = db::ObjectHandle::default();
+        let node = match path(db, "/mount/disk") {
+            Ok(node) => node,
+            Err(_) => vfs::Manager::create(db, actor, path(db, "/mount").unwrap(), "disk", NodeKind::Directory).unwrap(),
+        };
+        let fs = vfs::Manager::find_fs(db, "ext2").unwrap();
+        vfs::Manager::mount(db, actor, fs, node, Some(path(db, "/devices/ram0").unwrap()))?;
+        Ok(node)
+    }
+
+    fn remount(db: &mut db::Database, root: vfs::NodeHandle) {
+        vfs::Manager::unmount(db, db::ObjectHandle::default(), root).unwrap();
+        mount(db).unwrap();
+    }
+
+    fn formatted(kib: usize) -> (std::boxed::Box<db::Database>, vfs::NodeHandle) {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs, Block, Ext2]);
+        let disk = block::Manager::new_ram_disk(&mut db, "ram0", 512, alloc::vec![0; kib << 10]).unwrap();
+        assert_eq!(Manager::format(&mut db, disk), Ok(()));
+        let root = mount(&mut db).unwrap();
+        (db, root)
+    }
+
+    /// Free blocks and inodes, after checking the bitmaps, group descriptors
+    /// and superblock all agree on them
+    fn free_counts(db: &mut db::Database, root: vfs::NodeHandle) -> (u32, u32) {
+        Manager::with_volume(db, root, |db, v| {
+            let layout = v.layout;
+            let (mut blocks, mut inodes) = (0, 0);
+            for group in 0..layout.group_count {
+                let g = Manager::get_group(db, v, group).unwrap();
+                let first = layout.first_data_block + group * layout.blocks_per_group;
+                let zeroes = |db: &mut db::Database, v: &mut Volume, bitmap: u32, count: u32| {
+                    let mut bits = alloc::vec![0u8; layout.block_size as usize];
+                    Manager::read(db, v, bitmap as u64 * layout.block_size as u64, &mut bits).unwrap();
+                    (0..count).filter(|&i| bits[i as usize / 8] & (1 << (i % 8)) == 0).count() as u32
+                };
+                let free_blocks = zeroes(db, v, g.block_bitmap, layout.blocks_per_group.min(layout.blocks_count - first));
+                let free_inodes = zeroes(db, v, g.inode_bitmap, layout.inodes_per_group);
+                assert_eq!((g.free_blocks as u32, g.free_inodes as u32), (free_blocks, free_inodes), "group {group}");
+                blocks += free_blocks;
+                inodes += free_inodes;
+            }
+            let mut sb = [0u8; 8];
+            Manager::read(db, v, SUPERBLOCK + 12, &mut sb).unwrap();
+            assert_eq!((get32(&sb, 0), get32(&sb, 4)), (blocks, inodes));
+            Ok((blocks, inodes))
+        })
+        .unwrap()
+    }
+
+    fn read_all(db: &mut db::Database, node: vfs::NodeHandle) -> Vec<u8> {
+        let mut data = alloc::vec![0; vfs::Manager::stat(db, db::ObjectHandle::default(), node).unwrap().size as usize];
+        assert_eq!(vfs::Manager::read_node(db, db::ObjectHandle::default(), node, 0, &mut data), Ok(data.len()));
+        data
+    }
+
+    fn read_path(db: &mut db::Database, at: &str) -> Vec<u8> {
+        let node = path(db, at).unwrap();
+        read_all(db, node)
+    }
+
+    fn readlink(db: &db::Database, at: &str) -> String {
+        let mut out = [0u8; vfs::MAX_PATH];
+        let node = vfs::Manager::resolve_link(db, db::ObjectHandle::default(), vfs::NodeHandle::default(), at).unwrap();
+        let len = vfs::Manager::readlink(db, db::ObjectHandle::default(), node, &mut out).unwrap();
+        String::from_utf8(out[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn files_directories_and_symlinks_round_trip() {
+        let (mut db, root) = formatted(20 << 10);
+        let actor = db::ObjectHandle::default();
+        assert_eq!(Manager::get_volume(&db, root).unwrap().layout.group_count, 3);
+        assert!(path(&db, "/mount/disk/lost+found").is_ok());
+        let docs = vfs::Manager::create(&mut db, actor, root, "Documents", NodeKind::Directory).unwrap();
+        let file = vfs::Manager::create(&mut db, actor, docs, "big.bin", NodeKind::File).unwrap();
+        // Past the single indirect block, 12 + 256 of 1 KiB
+        let data: Vec<u8> = (0..300 << 10).map(|i| (i % 251) as u8).collect();
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 0, &data), Ok(data.len()));
+        let long = "../".repeat(20) + "mount/disk/Documents/big.bin";
+        vfs::Manager::symlink(&mut db, actor, root, "short", "Documents/big.bin").unwrap();
+        vfs::Manager::symlink(&mut db, actor, root, "long", &long).unwrap();
+
+        remount(&mut db, root);
+        assert_eq!(read_path(&mut db, "/mount/disk/Documents/big.bin"), data);
+        assert_eq!(readlink(&db, "/mount/disk/short"), "Documents/big.bin");
+        assert_eq!(readlink(&db, "/mount/disk/long"), long);
+        assert_eq!(read_path(&mut db, "/mount/disk/long").len(), data.len());
+        // Names are exact
+        assert_eq!(path(&db, "/mount/disk/documents"), Err(Error::NotFound));
+        free_counts(&mut db, root);
+    }
+
+    #[test]
+    fn files_shrink_grow_and_give_back_blocks() {
+        let (mut db, root) = formatted(8 << 10);
+        let actor = db::ObjectHandle::default();
+        let (blocks, inodes) = free_counts(&mut db, root);
+        let file = vfs::Manager::create(&mut db, actor, root, "sparse", NodeKind::File).unwrap();
+        // A hole up to there, only the last block and the indirect ones on the way
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 1 << 20, b"end"), Ok(3));
+        assert_eq!(free_counts(&mut db, root), (blocks - 3, inodes - 1));
+        let data = read_all(&mut db, file);
+        assert!(data[..1 << 20].iter().all(|&b| b == 0));
+        assert_eq!(&data[1 << 20..], b"end");
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 0, &[1; 5000]), Ok(5000));
+        assert_eq!(free_counts(&mut db, root).0, blocks - 8);
+        // Shrinking frees, growing again reads zeroes where there was data
+        assert_eq!(vfs::Manager::truncate(&mut db, actor, file, 10), Ok(()));
+        assert_eq!(free_counts(&mut db, root).0, blocks - 1);
+        assert_eq!(vfs::Manager::truncate(&mut db, actor, file, 3000), Ok(()));
+        remount(&mut db, root);
+        let file = path(&db, "/mount/disk/sparse").unwrap();
+        let data = read_all(&mut db, file);
+        assert_eq!((data.len(), &data[..10]), (3000, &[1; 10][..]));
+        assert!(data[10..].iter().all(|&b| b == 0));
+        assert_eq!(vfs::Manager::remove(&mut db, actor, file), Ok(()));
+        assert_eq!(free_counts(&mut db, root), (blocks, inodes));
+        remount(&mut db, root);
+        assert_eq!(path(&db, "/mount/disk/sparse"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn directories_move_and_keep_link_counts() {
+        let (mut db, root) = formatted(8 << 10);
+        let actor = db::ObjectHandle::default();
+        let (blocks, inodes) = free_counts(&mut db, root);
+        let a = vfs::Manager::create(&mut db, actor, root, "a", NodeKind::Directory).unwrap();
+        let b = vfs::Manager::create(&mut db, actor, root, "b", NodeKind::Directory).unwrap();
+        let sub = vfs::Manager::create(&mut db, actor, a, "sub", NodeKind::Directory).unwrap();
+        // Several blocks of records, then gaps
+        for i in 0..100 {
+            vfs::Manager::create(&mut db, actor, sub, &alloc::format!("file number {i}"), NodeKind::File).unwrap();
+        }
+        for i in (0..100).step_by(3) {
+            let file = path(&db, &alloc::format!("/mount/disk/a/sub/file number {i}")).unwrap();
+            vfs::Manager::remove(&mut db, actor, file).unwrap();
+        }
+        vfs::Manager::create(&mut db, actor, sub, "late", NodeKind::File).unwrap();
+        assert_eq!(vfs::Manager::remove(&mut db, actor, sub), Err(Error::NotEmpty));
+        assert_eq!(vfs::Manager::rename(&mut db, actor, sub, b, "moved"), Ok(()));
+        let temp = path(&db, "/temp").unwrap();
+        assert_eq!(vfs::Manager::rename(&mut db, actor, sub, temp, "x"), Err(Error::Unsupported));
+        remount(&mut db, root);
+        let moved = path(&db, "/mount/disk/b/moved").unwrap();
+        assert_eq!(vfs::Manager::get_node(&db, moved).get_child_count(), 67);
+        assert!(path(&db, "/mount/disk/b/moved/file number 98").is_ok());
+        assert_eq!(path(&db, "/mount/disk/b/moved/file number 99"), Err(Error::NotFound));
+        let links = |db: &mut db::Database, at: &str| {
+            let node = path(db, at).unwrap();
+            Manager::with_volume(db, node, |db, v| {
+                let number = v.get(node).inode;
+                let mut inode = Manager::read_inode(db, v, number)?;
+                let raw = Manager::read_all(db, v, number, &mut inode)?;
+                let dotdot = Manager::parse_dir(&v.layout, &raw).iter().find(|(_, _, name)| *name == b"..").unwrap().1;
+                Ok((inode.links(), dotdot))
+            })
+            .unwrap()
+        };
+        let (_, b_inode) = links(&mut db, "/mount/disk/b/moved/.");
+        assert_eq!(links(&mut db, "/mount/disk/a"), (2, ROOT_INODE));
+        assert_eq!(links(&mut db, "/mount/disk/b"), (3, ROOT_INODE));
+        assert_eq!(links(&mut db, "/mount/disk/b/moved").1, b_inode);
+        assert_eq!(links(&mut db, "/mount/disk"), (5, ROOT_INODE));
+        let mut children = Vec::new();
+        vfs::Manager::for_each_children(&db, moved, |child| children.push(child));
+        for child in children {
+            vfs::Manager::remove(&mut db, actor, child).unwrap();
+        }
+        for at in ["/mount/disk/b/moved", "/mount/disk/b", "/mount/disk/a"] {
+            let node = path(&db, at).unwrap();
+            vfs::Manager::remove(&mut db, actor, node).unwrap();
+        }
+        assert_eq!(free_counts(&mut db, root), (blocks, inodes));
+    }
+
+    #[test]
+    fn bad_images_do_not_mount() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs, Block, Ext2]);
+        let actor = db::ObjectHandle::default();
+        let disk = block::Manager::new_ram_disk(&mut db, "ram0", 512, alloc::vec![0; 1 << 20]).unwrap();
+        // All zeroes, no superblock
+        assert_eq!(mount(&mut db), Err(Error::Invalid));
+        Manager::format(&mut db, disk).unwrap();
+        let set = |db: &mut db::Database, at: u64, value: u32| block::Manager::write_bytes(db, disk, SUPERBLOCK + at, &value.to_le_bytes()).unwrap();
+        // Counts far past the device, refused before anything is worked out from them
+        let mut sb = alloc::vec![0u8; 1024];
+        block::Manager::read_bytes(&mut db, disk, SUPERBLOCK, &mut sb).unwrap();
+        set(&mut db, 4, 0x8000_0000);
+        set(&mut db, 32, 1);
+        set(&mut db, 40, 8192);
+        assert_eq!(mount(&mut db), Err(Error::Invalid));
+        // and where the device is that big, nothing overflows either
+        sb[4..8].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        sb[32..36].copy_from_slice(&1u32.to_le_bytes());
+        sb[40..44].copy_from_slice(&8192u32.to_le_bytes());
+        assert!(Layout::parse(disk, &sb, u64::MAX).is_ok());
+        Manager::format(&mut db, disk).unwrap();
+        // Extents
+        set(&mut db, 96, INCOMPAT_FILETYPE | 0x40);
+        assert_eq!(mount(&mut db), Err(Error::Unsupported));
+        // Metadata checksums only make it read-only
+        set(&mut db, 96, INCOMPAT_FILETYPE);
+        set(&mut db, 100, RO_COMPAT_SPARSE_SUPER | 0x400);
+        let root = mount(&mut db).unwrap();
+        assert!(Manager::get_volume(&db, root).unwrap().is_read_only());
+        assert_eq!(vfs::Manager::create(&mut db, actor, root, "x", NodeKind::File), Err(Error::Unsupported));
+        vfs::Manager::unmount(&mut db, actor, root).unwrap();
+        // So does not having been unmounted
+        set(&mut db, 100, RO_COMPAT_SPARSE_SUPER);
+        mount(&mut db).unwrap();
+        let mut state = [0u8; 2];
+        block::Manager::read_bytes(&mut db, disk, SUPERBLOCK + 58, &mut state).unwrap();
+        assert_eq!(state, [0, 0]);
+        let root = path(&db, "/mount/disk").unwrap();
+        vfs::Manager::unmount(&mut db, actor, root).unwrap();
+        block::Manager::write_bytes(&mut db, disk, SUPERBLOCK + 58, &[0, 0]).unwrap();
+        let root = mount(&mut db).unwrap();
+        assert!(Manager::get_volume(&db, root).unwrap().is_read_only());
+    }
+
+    /// `make ext2` then `RADIAN_EXT2_IMG=ext2.img make test`, skipped without
+    #[test]
+    fn reads_the_host_image() {
+        let Ok(image) = std::env::var("RADIAN_EXT2_IMG") else {
+            return;
+        };
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs, Block, Ext2]);
+        let actor = db::ObjectHandle::default();
+        block::Manager::new_ram_disk(&mut db, "ram0", 512, std::fs::read(image).unwrap()).unwrap();
+        let root = mount(&mut db).unwrap();
+        // Every file reads as long as it says it is
+        let mut stack = alloc::vec![root];
+        while let Some(node) = stack.pop() {
+            vfs::Manager::for_each_children(&db, node, |child| stack.push(child));
+            if Manager::get_volume(&db, node).unwrap().get(node).kind == EntryKind::File {
+                read_all(&mut db, node);
+            }
+        }
+        // Only the copy in memory changes
+        let file = vfs::Manager::create(&mut db, actor, root, "written by radian.txt", NodeKind::File).unwrap();
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 0, b"hello"), Ok(5));
+        let before = free_counts(&mut db, root);
+        remount(&mut db, root);
+        assert_eq!(read_path(&mut db, "/mount/disk/written by radian.txt"), b"hello");
+        let file = path(&db, "/mount/disk/written by radian.txt").unwrap();
+        vfs::Manager::remove(&mut db, actor, file).unwrap();
+        assert_eq!(free_counts(&mut db, root), (before.0 + 1, before.1 + 1));
+    }
+}
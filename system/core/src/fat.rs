@@ -0,0 +1,1365 @@
+//! FAT12, FAT16 and FAT32
+//!
+//! Registered as the `fat` filesystem type and mounted from a block device
+//! node, `mount fat /mount/esp /devices/ram0`. The whole directory tree is
+//! read into nodes at mount since `lookup` cannot add any, names are the long
+//! ones where there are and match without regard to ASCII case. What gets
+//! created has a long name unless it already is a plain upper case 8.3 one,
+//! with a `NAME~N.EXT` alias for the short entry. `Manager::format` makes an
+//! empty filesystem on a device, `Manager::mount_esp` puts the boot volume on
+//! `/mount/esp`.
+
+use alloc::{boxed::Box, collections::BTreeSet, string::String, vec::Vec};
+use core::ops::Range;
+
+use crate::{block, db, klog, kprint, partition, policy, vfs};
+use crate::console::{self, ArgKind, ArgSpec, Command};
+use crate::{console_commands, console_error};
+
+const ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Read-only, hidden, system and volume id at once, never a real entry
+const ATTR_LONG_NAME: u8 = 0x0f;
+const DELETED: u8 = 0xe5;
+/// Or'd into the order of the first long name entry, which holds the end of the name
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Of a name, in each long name entry
+const LONG_CHARS: usize = 13;
+/// Where those are in the entry, UTF-16 each
+const LONG_OFFSETS: [usize; LONG_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// In the short entry's case byte, the base and extension are lower case
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXTENSION: u8 = 0x10;
+/// 1980-01-01, there is no wall clock to stamp entries with
+const DATE: u16 = 0x21;
+const MAX_NAME: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+impl Kind {
+    /// Clusters each can count, FAT32 has 4 bits reserved
+    fn clusters(self) -> Range<u32> {
+        match self {
+            Kind::Fat12 => 1..4085,
+            Kind::Fat16 => 4085..65525,
+            Kind::Fat32 => 65525..0x0fff_fff5,
+        }
+    }
+    fn end_of_chain(self) -> u32 {
+        match self {
+            Kind::Fat12 => 0xfff,
+            Kind::Fat16 => 0xffff,
+            Kind::Fat32 => 0x0fff_ffff,
+        }
+    }
+    /// Bytes of one FAT for `entries`
+    fn fat_bytes(self, entries: u64) -> u64 {
+        match self {
+            Kind::Fat12 => (entries * 3).div_ceil(2),
+            Kind::Fat16 => entries * 2,
+            Kind::Fat32 => entries * 4,
+        }
+    }
+}
+
+/// Where everything is, as the boot sector says, all offsets in bytes
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    kind: Kind,
+    device: block::DeviceHandle,
+    cluster_size: u32,
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u8,
+    /// The fixed root directory of FAT12 and FAT16
+    root_start: u64,
+    root_entries: u32,
+    /// Where FAT32 keeps its root directory, 0 otherwise
+    root_cluster: u32,
+    data_start: u64,
+    /// Clusters are numbered from 2 up to this
+    cluster_end: u32,
+    /// The FAT32 FSInfo sector, 0 without one
+    info_start: u64,
+}
+impl Layout {
+    fn parse(device: block::DeviceHandle, boot: &[u8], device_size: u64) -> Result<Self, vfs::Error> {
+        if boot[510..512] != [0x55, 0xaa] {
+            return Err(vfs::Error::Invalid);
+        }
+        let sector_size = get16(boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let (reserved, fat_count, root_entries) = (get16(boot, 14) as u64, boot[16], get16(boot, 17) as u32);
+        let total = match get16(boot, 19) {
+            0 => get32(boot, 32) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match get16(boot, 22) {
+            0 => get32(boot, 36) as u64,
+            n => n as u64,
+        };
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096) || !sectors_per_cluster.is_power_of_two() || reserved == 0 || fat_count == 0 {
+            return Err(vfs::Error::Invalid);
+        }
+        let root_sectors = (root_entries as u64 * ENTRY_SIZE as u64).div_ceil(sector_size);
+        let data_sector = reserved + fat_count as u64 * fat_sectors + root_sectors;
+        if fat_sectors == 0 || data_sector >= total || total * sector_size > device_size {
+            return Err(vfs::Error::Invalid);
+        }
+        // What it is depends on nothing but the count of clusters
+        let clusters = (total - data_sector) / sectors_per_cluster;
+        let kind = [Kind::Fat12, Kind::Fat16, Kind::Fat32]
+            .into_iter()
+            .find(|k| k.clusters().contains(&(clusters as u32)))
+            .ok_or(vfs::Error::Invalid)?;
+        let root_cluster = match kind {
+            Kind::Fat32 => get32(boot, 44),
+            _ => 0,
+        };
+        let cluster_end = clusters as u32 + 2;
+        let fits = kind.fat_bytes(cluster_end as u64) <= fat_sectors * sector_size;
+        if !fits || (kind == Kind::Fat32) != (root_entries == 0) || (kind == Kind::Fat32 && !(2..cluster_end).contains(&root_cluster)) {
+            return Err(vfs::Error::Invalid);
+        }
+        let info_start = match (kind, get16(boot, 48) as u64) {
+            (Kind::Fat32, sector) if sector != 0 && sector < reserved => sector * sector_size,
+            _ => 0,
+        };
+        Ok(Self {
+            kind,
+            device,
+            cluster_size: (sectors_per_cluster * sector_size) as u32,
+            fat_start: reserved * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fat_count,
+            root_start: (reserved + fat_count as u64 * fat_sectors) * sector_size,
+            root_entries,
+            root_cluster,
+            data_start: data_sector * sector_size,
+            cluster_end,
+            info_start,
+        })
+    }
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - 2) as u64 * self.cluster_size as u64
+    }
+    /// Free, bad and end of chain markers are not
+    fn is_data(&self, cluster: u32) -> bool {
+        (2..self.cluster_end).contains(&cluster)
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    /// Not a node of this volume, all-zero
+    #[default]
+    Unused,
+    File,
+    Directory,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+struct Entry {
+    kind: EntryKind,
+    /// First cluster, 0 for an empty file and the fixed root directory
+    cluster: u32,
+    size: u32,
+    /// Of the short entry in the parent directory, the long name entries are right before it
+    index: u32,
+    long_entries: u32,
+    /// The last cluster looked up and where in the chain it is, so reading on from there does not walk it all again
+    cursor: (u32, u32),
+}
+
+/// A mounted instance, the superblock `vfs::Manager::get_superblock` returns
+pub struct Volume {
+    layout: Layout,
+    /// By node id like `db::Database::tmpfs_inodes`
+    entries: Vec<Entry>,
+    /// Where to look for a free cluster next
+    next_free: u32,
+    /// Clusters were allocated or freed, so the FSInfo count is off
+    dirty: bool,
+}
+impl Volume {
+    pub fn get_kind(&self) -> Kind {
+        self.layout.kind
+    }
+    pub fn get_cluster_size(&self) -> u32 {
+        self.layout.cluster_size
+    }
+    fn get(&self, node: vfs::NodeHandle) -> Entry {
+        self.entries.get(node.get_id() as usize).copied().unwrap_or_default()
+    }
+    fn set(&mut self, node: vfs::NodeHandle, entry: Entry) {
+        let id = node.get_id() as usize;
+        if self.entries.len() <= id {
+            self.entries.resize(id + 1, Entry::default());
+        }
+        self.entries[id] = entry;
+    }
+}
+
+/// A directory entry as read, see `Manager::parse_dir`
+struct Found {
+    name: String,
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+    index: u32,
+    long_entries: u32,
+}
+
+fn get16(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+fn get32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+fn put16(b: &mut [u8], at: usize, value: u16) {
+    b[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+fn put32(b: &mut [u8], at: usize, value: u32) {
+    b[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+pub const FS_TYPE: vfs::FsType = vfs::FsType { name: "fat", provider: PROVIDER, mount: Manager::mount, unmount: Manager::unmount };
+
+pub const PROVIDER: vfs::Provider = vfs::Provider {
+    read: |db, _, node, offset, data| {
+        let (layout, entry) = Manager::get_entry(db, node, EntryKind::File)?;
+        let len = (entry.size as u64).saturating_sub(offset).min(data.len() as u64) as usize;
+        Manager::for_each_run(db, node, &layout, offset, len, |db, at, range| {
+            Ok(block::Manager::read_bytes(db, layout.device, at, &mut data[range])?)
+        })?;
+        Ok(len)
+    },
+    write: |db, _, node, offset, data| {
+        let (layout, entry) = Manager::get_entry(db, node, EntryKind::File)?;
+        let end = offset.checked_add(data.len() as u64).and_then(|e| u32::try_from(e).ok()).ok_or(vfs::Error::Invalid)?;
+        if end > entry.size {
+            Manager::resize(db, node, end)?;
+        }
+        Manager::for_each_run(db, node, &layout, offset, data.len(), |db, at, range| {
+            Ok(block::Manager::write_bytes(db, layout.device, at, &data[range])?)
+        })?;
+        Ok(data.len())
+    },
+    stat: |db, _, node| {
+        let (_, entry) = Manager::get_any_entry(db, node)?;
+        Ok(vfs::Stat { size: entry.size as u64, children: vfs::Manager::get_node(db, node).get_child_count() })
+    },
+    create: |db, _, parent, name, kind| Manager::create(db, parent, name, kind),
+    remove: |db, _, node| {
+        let (layout, entry) = Manager::get_any_entry(db, node)?;
+        // Every entry has its node, so that is all a directory can hold
+        if vfs::Manager::get_node(db, node).get_child_count() != 0 {
+            return Err(vfs::Error::NotEmpty);
+        }
+        if layout.is_data(entry.cluster) {
+            Manager::free_chain(db, node, &layout, entry.cluster)?;
+        }
+        Manager::mark_deleted(db, node, &layout, &entry)?;
+        Manager::get_volume_mut(db, node)?.set(node, Entry::default());
+        vfs::Manager::remove_node(db, node)
+    },
+    rename: |db, _, node, parent, name| Manager::rename(db, node, parent, name),
+    truncate: |db, _, node, size| {
+        Manager::get_entry(db, node, EntryKind::File)?;
+        Manager::resize(db, node, u32::try_from(size).map_err(|_| vfs::Error::Invalid)?)
+    },
+    lookup: |db, _, node, name| Manager::find(db, node, name),
+    ..vfs::Provider::DEFAULT
+};
+
+pub struct Manager;
+impl Manager {
+    /// Needs the VFS tree, registers the type
+    pub fn init(db: &mut db::Database) -> vfs::FsTypeHandle {
+        vfs::Manager::register_fs(db, FS_TYPE)
+    }
+    /// Mounts the EFI system partition on `/mount/esp` once the disks are
+    /// registered: the first partition typed as one, else a disk without
+    /// partitions whose FAT volume has an `EFI` directory, which is how the
+    /// boot image looks when the firmware was handed it as a whole
+    pub fn mount_esp(db: &mut db::Database) -> Option<vfs::NodeHandle> {
+        let (mut partitions, mut disks) = (Vec::new(), Vec::new());
+        block::Manager::for_each_device(db, |device, d| match partition::Manager::get_partition(db, device) {
+            Some(p) if matches!(p.get_kind(), partition::Kind::Gpt(partition::Guid::EFI_SYSTEM) | partition::Kind::Mbr(0xef)) => {
+                partitions.push(device)
+            }
+            Some(_) => {}
+            None if vfs::Manager::get_node(db, d.get_node()).get_child_count() == 0 => disks.push(device),
+            None => {}
+        });
+        let actor = db::ObjectHandle::default();
+        let fs = vfs::Manager::find_fs(db, FS_TYPE.name)?;
+        let mount = vfs::Manager::find_children(db, vfs::NodeHandle::default(), "mount")?;
+        let node = match vfs::Manager::find_children(db, mount, "esp") {
+            Some(node) => node,
+            None => vfs::Manager::create(db, actor, mount, "esp", vfs::NodeKind::Directory).ok()?,
+        };
+        let candidates = partitions.into_iter().map(|device| (device, true)).chain(disks.into_iter().map(|device| (device, false)));
+        for (device, typed) in candidates {
+            let source = block::Manager::get_device(db, device).get_node();
+            if vfs::Manager::mount(db, actor, fs, node, Some(source)).is_err() {
+                continue;
+            }
+            if typed || vfs::Manager::find_children(db, node, "EFI").is_some() {
+                return Some(node);
+            }
+            let _ = vfs::Manager::unmount(db, actor, node);
+        }
+        None
+    }
+
+    fn mount(
+        db: &mut db::Database,
+        actor: db::ObjectHandle,
+        root: vfs::NodeHandle,
+        source: Option<vfs::NodeHandle>,
+    ) -> Result<Option<Box<dyn core::any::Any>>, vfs::Error> {
+        let source = source.ok_or(vfs::Error::Invalid)?;
+        vfs::Manager::check_access(db, actor, source, policy::Access::Read)?;
+        let device = block::Manager::find_by_node(db, source).ok_or(vfs::Error::Invalid)?;
+        let mut boot = [0u8; 512];
+        block::Manager::read_bytes(db, device, 0, &mut boot)?;
+        let mut layout = Layout::parse(device, &boot, block::Manager::get_device(db, device).get_size())?;
+        if layout.info_start != 0 {
+            let mut info = [0u8; 512];
+            block::Manager::read_bytes(db, device, layout.info_start, &mut info)?;
+            if get32(&info, 0) != 0x4161_5252 || get32(&info, 484) != 0x6141_7272 {
+                layout.info_start = 0;
+            }
+        }
+        let mut volume = Volume { layout, entries: Vec::new(), next_free: 2, dirty: false };
+        volume.set(root, Entry { kind: EntryKind::Directory, cluster: layout.root_cluster, ..Entry::default() });
+        if let Err(e) = Self::load(db, &mut volume, root) {
+            Self::drop_tree(db, root);
+            return Err(e);
+        }
+        klog!(Info, "fat", "{:?}, {} clusters of {} bytes", layout.kind, layout.cluster_end - 2, layout.cluster_size);
+        Ok(Some(Box::new(volume)))
+    }
+    fn unmount(db: &mut db::Database, _actor: db::ObjectHandle, root: vfs::NodeHandle) {
+        let Some(volume) = vfs::Manager::get_superblock::<Volume>(db, root) else {
+            return;
+        };
+        let (layout, dirty) = (volume.layout, volume.dirty);
+        // The free count is not kept up to date, whoever reads it has to count
+        if dirty && layout.info_start != 0 {
+            let _ = block::Manager::write_bytes(db, layout.device, layout.info_start + 488, &u32::MAX.to_le_bytes());
+        }
+        if let Err(e) = block::Manager::flush(db, layout.device) {
+            klog!(Warn, "fat", "flush on unmount failed: {:?}", e);
+        }
+    }
+    /// Every directory below `root` into nodes, each cluster only once should
+    /// the image loop back on itself
+    fn load(db: &mut db::Database, volume: &mut Volume, root: vfs::NodeHandle) -> Result<(), vfs::Error> {
+        let layout = volume.layout;
+        let provider = *vfs::Manager::get_node(db, root).get_provider();
+        let mut seen = BTreeSet::new();
+        let mut stack = alloc::vec![(root, layout.root_cluster)];
+        while let Some((dir, cluster)) = stack.pop() {
+            let raw = Self::read_dir(db, &layout, cluster)?;
+            for found in Self::parse_dir(&raw, layout.kind) {
+                let taken = vfs::Manager::find_children(db, dir, &found.name).is_some();
+                if taken || found.name.contains('/') || found.name.len() > vfs::MAX_NAME {
+                    klog!(Warn, "fat", "skipping entry {:?}", found.name);
+                    continue;
+                }
+                let node = vfs::Manager::new_node_with_provider(db, &found.name, dir, provider);
+                let kind = match found.attributes & ATTR_DIRECTORY {
+                    0 => EntryKind::File,
+                    _ => EntryKind::Directory,
+                };
+                let (index, long_entries) = (found.index, found.long_entries);
+                let size = if kind == EntryKind::File { found.size } else { 0 };
+                volume.set(node, Entry { kind, cluster: found.cluster, size, index, long_entries, cursor: (0, 0) });
+                if kind == EntryKind::Directory && layout.is_data(found.cluster) && seen.insert(found.cluster) {
+                    stack.push((node, found.cluster));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Children first, `node` itself stays
+    fn drop_tree(db: &mut db::Database, node: vfs::NodeHandle) {
+        while let Some(child) = vfs::Manager::get_child(db, node, 0) {
+            Self::drop_tree(db, child);
+            vfs::Manager::remove_node(db, child).expect("children go first");
+        }
+    }
+
+    /// The entries of a directory, long names put together, `.`, `..`,
+    /// deleted entries and the volume label left out. Only FAT32 has a high
+    /// cluster word, older systems kept other things at offset 20
+    fn parse_dir(raw: &[u8], kind: Kind) -> Vec<Found> {
+        // Name so far, order of the entry expected next and checksum
+        let mut long: Option<(Vec<u16>, u8, u8)> = None;
+        let mut found = Vec::new();
+        for (index, e) in raw.chunks_exact(ENTRY_SIZE).enumerate() {
+            match e[0] {
+                0 => break,
+                DELETED => {
+                    long = None;
+                    continue;
+                }
+                _ => {}
+            }
+            if e[11] & 0x3f == ATTR_LONG_NAME {
+                let order = e[0] & 0x1f;
+                if e[0] & LAST_LONG_ENTRY != 0 {
+                    long = Some((alloc::vec![0; order as usize * LONG_CHARS], order, e[13]));
+                }
+                long = long.filter(|&(_, next, checksum)| next == order && order > 0 && checksum == e[13]).map(|(mut name, _, checksum)| {
+                    for (i, &at) in LONG_OFFSETS.iter().enumerate() {
+                        name[(order as usize - 1) * LONG_CHARS + i] = get16(e, at);
+                    }
+                    (name, order - 1, checksum)
+                });
+                continue;
+            }
+            let long_name = long.take();
+            let short: [u8; 11] = e[..11].try_into().unwrap();
+            if e[11] & ATTR_VOLUME_ID != 0 || short[0] == b'.' {
+                continue;
+            }
+            let long_name = long_name.filter(|&(_, next, checksum)| next == 0 && checksum == Self::checksum(&short));
+            let long_entries = long_name.as_ref().map_or(0, |(name, _, _)| name.len() / LONG_CHARS) as u32;
+            let name = long_name.and_then(|(name, _, _)| {
+                let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                char::decode_utf16(name[..end].iter().copied()).collect::<Result<String, _>>().ok()
+            });
+            found.push(Found {
+                name: name.unwrap_or_else(|| Self::short_to_string(&short, e[12])),
+                attributes: e[11],
+                cluster: match kind {
+                    Kind::Fat32 => ((get16(e, 20) as u32) << 16) | get16(e, 26) as u32,
+                    Kind::Fat12 | Kind::Fat16 => get16(e, 26) as u32,
+                },
+                size: get32(e, 28),
+                index: index as u32,
+                long_entries,
+            });
+        }
+        found
+    }
+    fn short_to_string(short: &[u8; 11], case: u8) -> String {
+        let mut name = String::new();
+        let part = |bytes: &[u8], lower: bool, name: &mut String| {
+            for (i, &b) in bytes.iter().enumerate() {
+                // 0xe5 would mean deleted, so a name starting with it keeps 0x05 instead
+                let b = if i == 0 && b == 0x05 { DELETED } else { b };
+                name.push(if lower { b.to_ascii_lowercase() } else { b } as char);
+            }
+        };
+        let base = short[..8].trim_ascii_end();
+        let extension = short[8..].trim_ascii_end();
+        part(base, case & LOWER_BASE != 0, &mut name);
+        if !extension.is_empty() {
+            name.push('.');
+            part(extension, case & LOWER_EXTENSION != 0, &mut name);
+        }
+        name
+    }
+    fn checksum(short: &[u8; 11]) -> u8 {
+        short.iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+    }
+    fn is_short_char(b: u8) -> bool {
+        b.is_ascii_uppercase() || b.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&b)
+    }
+    /// `name` as a short entry if it is upper case 8.3 already
+    fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+        let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+        if base.is_empty() || base.len() > 8 || extension.len() > 3 || name.ends_with('.') {
+            return None;
+        }
+        if !base.bytes().chain(extension.bytes()).all(Self::is_short_char) {
+            return None;
+        }
+        let mut short = [b' '; 11];
+        short[..base.len()].copy_from_slice(base.as_bytes());
+        short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+        Some(short)
+    }
+    /// `NAME~N.EXT` from the long name, with the lowest `N` not in `taken`
+    fn alias(name: &str, taken: &[[u8; 11]]) -> Result<[u8; 11], vfs::Error> {
+        let clean = |s: &str| -> Vec<u8> {
+            s.chars()
+                .filter(|&c| c != ' ' && c != '.')
+                .map(|c| c.to_ascii_uppercase())
+                .map(|c| if c.is_ascii() && Self::is_short_char(c as u8) { c as u8 } else { b'_' })
+                .collect()
+        };
+        let name = name.trim_start_matches('.');
+        let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+        let (base, extension) = (clean(base), clean(extension));
+        let extension = &extension[..extension.len().min(3)];
+        for n in 1..1_000_000 {
+            let tail = alloc::format!("~{n}");
+            let keep = base.len().min(8 - tail.len());
+            let mut short = [b' '; 11];
+            short[..keep].copy_from_slice(&base[..keep]);
+            short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+            short[8..8 + extension.len()].copy_from_slice(extension);
+            if !taken.contains(&short) {
+                return Ok(short);
+            }
+        }
+        Err(vfs::Error::Exists)
+    }
+    /// What FAT cannot store, on top of what the VFS refuses
+    fn check_name(name: &str) -> Result<(), vfs::Error> {
+        let bad = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+        if name.ends_with(['.', ' ']) || name.chars().any(bad) || name.encode_utf16().count() > MAX_NAME {
+            return Err(vfs::Error::Invalid);
+        }
+        Ok(())
+    }
+    /// Long name entries, last part first, then the short entry
+    fn make_records(name: &str, short: [u8; 11], long: bool, mut record: [u8; ENTRY_SIZE]) -> Vec<[u8; ENTRY_SIZE]> {
+        record[..11].copy_from_slice(&short);
+        // Lower case is in the long name if there is one
+        record[12] = 0;
+        let mut records = Vec::new();
+        if long {
+            let units: Vec<u16> = name.encode_utf16().collect();
+            let count = units.len().div_ceil(LONG_CHARS);
+            for order in (1..=count).rev() {
+                let mut entry = [0u8; ENTRY_SIZE];
+                entry[0] = order as u8 | if order == count { LAST_LONG_ENTRY } else { 0 };
+                entry[11] = ATTR_LONG_NAME;
+                entry[13] = Self::checksum(&short);
+                for (i, &at) in LONG_OFFSETS.iter().enumerate() {
+                    // One terminating zero, then padding
+                    let pos = (order - 1) * LONG_CHARS + i;
+                    let unit = match pos.cmp(&units.len()) {
+                        core::cmp::Ordering::Less => units[pos],
+                        core::cmp::Ordering::Equal => 0,
+                        core::cmp::Ordering::Greater => 0xffff,
+                    };
+                    put16(&mut entry, at, unit);
+                }
+                records.push(entry);
+            }
+        }
+        records.push(record);
+        records
+    }
+    fn short_record(attributes: u8, cluster: u32, size: u32) -> [u8; ENTRY_SIZE] {
+        let mut record = [0u8; ENTRY_SIZE];
+        record[11] = attributes;
+        for at in [16, 18, 24] {
+            put16(&mut record, at, DATE);
+        }
+        Self::set_cluster(&mut record, cluster);
+        put32(&mut record, 28, size);
+        record
+    }
+    fn set_cluster(record: &mut [u8], cluster: u32) {
+        put16(record, 20, (cluster >> 16) as u16);
+        put16(record, 26, cluster as u16);
+    }
+
+    fn get_volume(db: &db::Database, node: vfs::NodeHandle) -> Result<&Volume, vfs::Error> {
+        vfs::Manager::get_superblock::<Volume>(db, node).ok_or(vfs::Error::Invalid)
+    }
+    fn get_volume_mut(db: &mut db::Database, node: vfs::NodeHandle) -> Result<&mut Volume, vfs::Error> {
+        vfs::Manager::get_superblock_mut::<Volume>(db, node).ok_or(vfs::Error::Invalid)
+    }
+    /// `Error::Invalid` for a node of no volume
+    fn get_any_entry(db: &db::Database, node: vfs::NodeHandle) -> Result<(Layout, Entry), vfs::Error> {
+        let volume = Self::get_volume(db, node)?;
+        let entry = volume.get(node);
+        match entry.kind {
+            EntryKind::Unused => Err(vfs::Error::Invalid),
+            _ => Ok((volume.layout, entry)),
+        }
+    }
+    /// `Error::Invalid` if it is not of that `kind`
+    fn get_entry(db: &db::Database, node: vfs::NodeHandle, kind: EntryKind) -> Result<(Layout, Entry), vfs::Error> {
+        let (layout, entry) = Self::get_any_entry(db, node)?;
+        match entry.kind == kind {
+            true => Ok((layout, entry)),
+            false => Err(vfs::Error::Invalid),
+        }
+    }
+    fn update_entry(db: &mut db::Database, node: vfs::NodeHandle, f: impl FnOnce(&mut Entry)) -> Result<(), vfs::Error> {
+        let volume = Self::get_volume_mut(db, node)?;
+        let mut entry = volume.get(node);
+        f(&mut entry);
+        volume.set(node, entry);
+        Ok(())
+    }
+    /// Exact first, then regardless of ASCII case like FAT does
+    fn find(db: &db::Database, dir: vfs::NodeHandle, name: &str) -> Option<vfs::NodeHandle> {
+        vfs::Manager::find_children(db, dir, name).or_else(|| {
+            let mut found = None;
+            vfs::Manager::for_each_children(db, dir, |child| {
+                if found.is_none() && vfs::Manager::get_node(db, child).get_name().eq_ignore_ascii_case(name) {
+                    found = Some(child);
+                }
+            });
+            found
+        })
+    }
+
+    fn get_fat(db: &mut db::Database, layout: &Layout, cluster: u32) -> Result<u32, vfs::Error> {
+        let mut b = [0u8; 4];
+        let value = match layout.kind {
+            Kind::Fat12 => {
+                block::Manager::read_bytes(db, layout.device, layout.fat_start + (cluster + cluster / 2) as u64, &mut b[..2])?;
+                let value = get16(&b, 0) as u32;
+                if cluster & 1 != 0 { value >> 4 } else { value & 0xfff }
+            }
+            Kind::Fat16 => {
+                block::Manager::read_bytes(db, layout.device, layout.fat_start + cluster as u64 * 2, &mut b[..2])?;
+                get16(&b, 0) as u32
+            }
+            Kind::Fat32 => {
+                block::Manager::read_bytes(db, layout.device, layout.fat_start + cluster as u64 * 4, &mut b)?;
+                get32(&b, 0) & 0x0fff_ffff
+            }
+        };
+        Ok(value)
+    }
+    /// In every copy of the FAT
+    fn set_fat(db: &mut db::Database, layout: &Layout, cluster: u32, value: u32) -> Result<(), vfs::Error> {
+        for copy in 0..layout.fat_count as u64 {
+            let start = layout.fat_start + copy * layout.fat_size;
+            let (at, len) = match layout.kind {
+                Kind::Fat12 => (start + (cluster + cluster / 2) as u64, 2),
+                Kind::Fat16 => (start + cluster as u64 * 2, 2),
+                Kind::Fat32 => (start + cluster as u64 * 4, 4),
+            };
+            let mut b = [0u8; 4];
+            block::Manager::read_bytes(db, layout.device, at, &mut b[..len])?;
+            let (old16, old32) = (get16(&b, 0), get32(&b, 0));
+            match layout.kind {
+                // Two entries share the middle byte
+                Kind::Fat12 if cluster & 1 != 0 => put16(&mut b, 0, (old16 & 0x000f) | (value as u16) << 4),
+                Kind::Fat12 => put16(&mut b, 0, (old16 & 0xf000) | (value as u16 & 0xfff)),
+                Kind::Fat16 => put16(&mut b, 0, value as u16),
+                // The top 4 bits are not ours
+                Kind::Fat32 => put32(&mut b, 0, (old32 & 0xf000_0000) | (value & 0x0fff_ffff)),
+            }
+            block::Manager::write_bytes(db, layout.device, at, &b[..len])?;
+        }
+        Ok(())
+    }
+    fn next_cluster(db: &mut db::Database, layout: &Layout, cluster: u32) -> Result<Option<u32>, vfs::Error> {
+        let next = Self::get_fat(db, layout, cluster)?;
+        Ok(layout.is_data(next).then_some(next))
+    }
+    /// Every cluster from `start` on, cut short should it loop
+    fn chain(db: &mut db::Database, layout: &Layout, start: u32) -> Result<Vec<u32>, vfs::Error> {
+        let mut chain = Vec::new();
+        let mut cluster = Some(start).filter(|&c| layout.is_data(c));
+        while let Some(c) = cluster {
+            if chain.len() >= (layout.cluster_end - 2) as usize {
+                return Err(vfs::Error::Io);
+            }
+            chain.push(c);
+            cluster = Self::next_cluster(db, layout, c)?;
+        }
+        Ok(chain)
+    }
+    /// A zeroed cluster marked as the end of a chain and appended to `previous`
+    fn alloc_cluster(db: &mut db::Database, node: vfs::NodeHandle, layout: &Layout, previous: Option<u32>) -> Result<u32, vfs::Error> {
+        let count = layout.cluster_end - 2;
+        let start = Self::get_volume(db, node)?.next_free.clamp(2, layout.cluster_end - 1) - 2;
+        let mut found = None;
+        for i in 0..count {
+            let cluster = 2 + (start + i) % count;
+            if Self::get_fat(db, layout, cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(vfs::Error::NoSpace)?;
+        block::Manager::write_bytes(db, layout.device, layout.cluster_offset(cluster), &alloc::vec![0; layout.cluster_size as usize])?;
+        Self::set_fat(db, layout, cluster, layout.kind.end_of_chain())?;
+        if let Some(previous) = previous {
+            Self::set_fat(db, layout, previous, cluster)?;
+        }
+        let volume = Self::get_volume_mut(db, node)?;
+        volume.next_free = cluster + 1;
+        volume.dirty = true;
+        Ok(cluster)
+    }
+    fn free_chain(db: &mut db::Database, node: vfs::NodeHandle, layout: &Layout, start: u32) -> Result<(), vfs::Error> {
+        for cluster in Self::chain(db, layout, start)? {
+            Self::set_fat(db, layout, cluster, 0)?;
+        }
+        Self::get_volume_mut(db, node)?.dirty = true;
+        Ok(())
+    }
+    /// The `index`th cluster of `node`, from its cursor when that is on the way
+    fn seek_cluster(db: &mut db::Database, node: vfs::NodeHandle, index: u32) -> Result<u32, vfs::Error> {
+        let (layout, entry) = Self::get_any_entry(db, node)?;
+        let (mut at, mut cluster) = match entry.cursor {
+            (at, cluster) if layout.is_data(cluster) && at <= index => (at, cluster),
+            _ => (0, entry.cluster),
+        };
+        if !layout.is_data(cluster) {
+            return Err(vfs::Error::Io);
+        }
+        while at < index {
+            cluster = Self::next_cluster(db, &layout, cluster)?.ok_or(vfs::Error::Io)?;
+            at += 1;
+        }
+        Self::update_entry(db, node, |e| e.cursor = (index, cluster))?;
+        Ok(cluster)
+    }
+    /// Calls `f` with the device offset and the range of `0..len` for each
+    /// cluster of `node` from `offset` on, which all have to be there
+    fn for_each_run(
+        db: &mut db::Database,
+        node: vfs::NodeHandle,
+        layout: &Layout,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(&mut db::Database, u64, Range<usize>) -> Result<(), vfs::Error>,
+    ) -> Result<(), vfs::Error> {
+        let size = layout.cluster_size as u64;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let cluster = Self::seek_cluster(db, node, (pos / size) as u32)?;
+            let within = pos % size;
+            let n = (len - done).min((size - within) as usize);
+            f(db, layout.cluster_offset(cluster) + within, done..done + n)?;
+            done += n;
+        }
+        Ok(())
+    }
+    /// Keeps the first `keep` clusters of `node` and frees the rest
+    fn trim(db: &mut db::Database, node: vfs::NodeHandle, layout: &Layout, keep: u32) -> Result<(), vfs::Error> {
+        let (_, entry) = Self::get_any_entry(db, node)?;
+        if !layout.is_data(entry.cluster) {
+            return Ok(());
+        }
+        if keep == 0 {
+            Self::free_chain(db, node, layout, entry.cluster)?;
+            return Self::update_entry(db, node, |e| (e.cluster, e.cursor) = (0, (0, 0)));
+        }
+        let last = Self::seek_cluster(db, node, keep - 1)?;
+        if let Some(next) = Self::next_cluster(db, layout, last)? {
+            Self::set_fat(db, layout, last, layout.kind.end_of_chain())?;
+            Self::free_chain(db, node, layout, next)?;
+        }
+        Self::update_entry(db, node, |e| e.cursor = (0, 0))
+    }
+    /// Grows with zeroes or shrinks a file, clusters and all
+    fn resize(db: &mut db::Database, node: vfs::NodeHandle, size: u32) -> Result<(), vfs::Error> {
+        let (layout, entry) = Self::get_entry(db, node, EntryKind::File)?;
+        let cluster_size = layout.cluster_size as u64;
+        let have = (entry.size as u64).div_ceil(cluster_size) as u32;
+        let need = (size as u64).div_ceil(cluster_size) as u32;
+        if size > entry.size {
+            // The rest of the last cluster may hold anything, new ones are zeroed
+            let tail_end = (have as u64 * cluster_size).min(size as u64);
+            let tail = (tail_end - entry.size as u64) as usize;
+            let zeroes = alloc::vec![0u8; tail];
+            Self::for_each_run(db, node, &layout, entry.size as u64, tail, |db, at, range| {
+                Ok(block::Manager::write_bytes(db, layout.device, at, &zeroes[range])?)
+            })?;
+            let mut last = match have {
+                0 => None,
+                n => Some(Self::seek_cluster(db, node, n - 1)?),
+            };
+            for _ in have..need {
+                match Self::alloc_cluster(db, node, &layout, last) {
+                    Ok(cluster) => {
+                        if last.is_none() {
+                            Self::update_entry(db, node, |e| e.cluster = cluster)?;
+                        }
+                        last = Some(cluster);
+                    }
+                    Err(e) => {
+                        Self::trim(db, node, &layout, have)?;
+                        return Err(e);
+                    }
+                }
+            }
+        } else if need < have {
+            Self::trim(db, node, &layout, need)?;
+        }
+        Self::update_entry(db, node, |e| e.size = size)?;
+        Self::write_short_entry(db, node)
+    }
+    /// Puts `node`'s first cluster and size back into its short entry
+    fn write_short_entry(db: &mut db::Database, node: vfs::NodeHandle) -> Result<(), vfs::Error> {
+        let (layout, entry) = Self::get_any_entry(db, node)?;
+        let at = Self::record_offset(db, node, &layout, entry.index)?;
+        let mut record = [0u8; ENTRY_SIZE];
+        block::Manager::read_bytes(db, layout.device, at, &mut record)?;
+        Self::set_cluster(&mut record, entry.cluster);
+        put32(&mut record, 28, entry.size);
+        Ok(block::Manager::write_bytes(db, layout.device, at, &record)?)
+    }
+    /// Where the `index`th record of the directory `node` is in sits on the device
+    fn record_offset(db: &mut db::Database, node: vfs::NodeHandle, layout: &Layout, index: u32) -> Result<u64, vfs::Error> {
+        let parent = *vfs::Manager::get_node(db, node).get_parent();
+        let (_, dir) = Self::get_entry(db, parent, EntryKind::Directory)?;
+        Self::dir_offset(db, layout, dir.cluster, index)
+    }
+    /// Where the `index`th record of the directory at `cluster` sits on the device
+    fn dir_offset(db: &mut db::Database, layout: &Layout, cluster: u32, index: u32) -> Result<u64, vfs::Error> {
+        let at = index as u64 * ENTRY_SIZE as u64;
+        if cluster == 0 {
+            return match index < layout.root_entries {
+                true => Ok(layout.root_start + at),
+                false => Err(vfs::Error::Io),
+            };
+        }
+        let size = layout.cluster_size as u64;
+        let mut cluster = cluster;
+        for _ in 0..at / size {
+            cluster = Self::next_cluster(db, layout, cluster)?.ok_or(vfs::Error::Io)?;
+        }
+        Ok(layout.cluster_offset(cluster) + at % size)
+    }
+    /// All records of the directory at `cluster`, 0 being the fixed root
+    fn read_dir(db: &mut db::Database, layout: &Layout, cluster: u32) -> Result<Vec<u8>, vfs::Error> {
+        if cluster == 0 {
+            let mut raw = alloc::vec![0; layout.root_entries as usize * ENTRY_SIZE];
+            block::Manager::read_bytes(db, layout.device, layout.root_start, &mut raw)?;
+            return Ok(raw);
+        }
+        let mut raw = Vec::new();
+        for cluster in Self::chain(db, layout, cluster)? {
+            let start = raw.len();
+            raw.resize(start + layout.cluster_size as usize, 0);
+            block::Manager::read_bytes(db, layout.device, layout.cluster_offset(cluster), &mut raw[start..])?;
+        }
+        Ok(raw)
+    }
+    fn write_records(db: &mut db::Database, layout: &Layout, cluster: u32, index: u32, records: &[[u8; ENTRY_SIZE]]) -> Result<(), vfs::Error> {
+        for (i, record) in records.iter().enumerate() {
+            let at = Self::dir_offset(db, layout, cluster, index + i as u32)?;
+            block::Manager::write_bytes(db, layout.device, at, record)?;
+        }
+        Ok(())
+    }
+    /// Short names in use in a directory, for picking an alias
+    fn short_names(raw: &[u8]) -> Vec<[u8; 11]> {
+        raw.chunks_exact(ENTRY_SIZE)
+            .take_while(|e| e[0] != 0)
+            .filter(|e| e[0] != DELETED && e[11] & 0x3f != ATTR_LONG_NAME)
+            .map(|e| e[..11].try_into().unwrap())
+            .collect()
+    }
+    /// Index of `count` free records in a row in `dir`, which grows by a
+    /// cluster or more if it has to, `raw` is what it holds now
+    fn place(db: &mut db::Database, dir: vfs::NodeHandle, layout: &Layout, raw: &[u8], count: usize) -> Result<u32, vfs::Error> {
+        let (_, entry) = Self::get_entry(db, dir, EntryKind::Directory)?;
+        let mut run = 0;
+        for (i, e) in raw.chunks_exact(ENTRY_SIZE).enumerate() {
+            match e[0] {
+                0 | DELETED => run += 1,
+                _ => run = 0,
+            }
+            if run == count {
+                return Ok((i + 1 - count) as u32);
+            }
+        }
+        // The fixed root cannot grow
+        if entry.cluster == 0 {
+            return Err(vfs::Error::NoSpace);
+        }
+        let per_cluster = layout.cluster_size as usize / ENTRY_SIZE;
+        let mut last = *Self::chain(db, layout, entry.cluster)?.last().ok_or(vfs::Error::Io)?;
+        for _ in 0..(count - run).div_ceil(per_cluster) {
+            last = Self::alloc_cluster(db, dir, layout, Some(last))?;
+        }
+        Ok((raw.len() / ENTRY_SIZE - run) as u32)
+    }
+    /// What `..` in a directory under `dir` points at, 0 for the root whatever its cluster
+    fn dotdot_cluster(db: &db::Database, dir: vfs::NodeHandle) -> Result<u32, vfs::Error> {
+        if vfs::Manager::get_mount(db, dir).map(|m| m.get_root()) == Some(dir) {
+            return Ok(0);
+        }
+        Ok(Self::get_entry(db, dir, EntryKind::Directory)?.1.cluster)
+    }
+    /// The short entry and long name entries of `node`, in the directory it is in now
+    fn mark_deleted(db: &mut db::Database, node: vfs::NodeHandle, layout: &Layout, entry: &Entry) -> Result<(), vfs::Error> {
+        for index in entry.index - entry.long_entries..=entry.index {
+            let at = Self::record_offset(db, node, layout, index)?;
+            block::Manager::write_bytes(db, layout.device, at, &[DELETED])?;
+        }
+        Ok(())
+    }
+    /// The short name `name` gets in a directory already holding `raw`
+    fn pick_short_name(name: &str, taken: &[[u8; 11]]) -> Result<([u8; 11], bool), vfs::Error> {
+        match Self::exact_short_name(name).filter(|short| !taken.contains(short)) {
+            Some(short) => Ok((short, false)),
+            None => Ok((Self::alias(name, taken)?, true)),
+        }
+    }
+
+    fn create(db: &mut db::Database, parent: vfs::NodeHandle, name: &str, kind: vfs::NodeKind) -> Result<vfs::NodeHandle, vfs::Error> {
+        let (layout, dir) = Self::get_entry(db, parent, EntryKind::Directory)?;
+        Self::check_name(name)?;
+        if Self::find(db, parent, name).is_some() {
+            return Err(vfs::Error::Exists);
+        }
+        let raw = Self::read_dir(db, &layout, dir.cluster)?;
+        let (short, long) = Self::pick_short_name(name, &Self::short_names(&raw))?;
+        let (entry_kind, attributes, cluster) = match kind {
+            vfs::NodeKind::File => (EntryKind::File, ATTR_ARCHIVE, 0),
+            vfs::NodeKind::Directory => {
+                let cluster = Self::alloc_cluster(db, parent, &layout, None)?;
+                let mut dots = [Self::short_record(ATTR_DIRECTORY, cluster, 0), Self::short_record(ATTR_DIRECTORY, Self::dotdot_cluster(db, parent)?, 0)];
+                dots[0][..11].copy_from_slice(b".          ");
+                dots[1][..11].copy_from_slice(b"..         ");
+                Self::write_records(db, &layout, cluster, 0, &dots)?;
+                (EntryKind::Directory, ATTR_DIRECTORY, cluster)
+            }
+        };
+        let records = Self::make_records(name, short, long, Self::short_record(attributes, cluster, 0));
+        let index = match Self::place(db, parent, &layout, &raw, records.len()) {
+            Ok(index) => index,
+            Err(e) => {
+                if cluster != 0 {
+                    Self::free_chain(db, parent, &layout, cluster)?;
+                }
+                return Err(e);
+            }
+        };
+        Self::write_records(db, &layout, dir.cluster, index, &records)?;
+        let provider = *vfs::Manager::get_node(db, parent).get_provider();
+        let node = vfs::Manager::new_node_with_provider(db, name, parent, provider);
+        let long_entries = records.len() as u32 - 1;
+        let entry = Entry { kind: entry_kind, cluster, size: 0, index: index + long_entries, long_entries, cursor: (0, 0) };
+        Self::get_volume_mut(db, node)?.set(node, entry);
+        Ok(node)
+    }
+    /// New records in `parent` with the old short entry's contents, then the old ones go
+    fn rename(db: &mut db::Database, node: vfs::NodeHandle, parent: vfs::NodeHandle, name: &str) -> Result<(), vfs::Error> {
+        let root_of = |db: &db::Database, node| vfs::Manager::get_mount(db, node).map(|m| m.get_root());
+        if root_of(db, node) != root_of(db, parent) {
+            return Err(vfs::Error::Unsupported);
+        }
+        let (layout, entry) = Self::get_any_entry(db, node)?;
+        let (_, dir) = Self::get_entry(db, parent, EntryKind::Directory)?;
+        Self::check_name(name)?;
+        if Self::find(db, parent, name).is_some_and(|other| other != node) {
+            return Err(vfs::Error::Exists);
+        }
+        let old_parent = *vfs::Manager::get_node(db, node).get_parent();
+        let mut record = [0u8; ENTRY_SIZE];
+        let at = Self::record_offset(db, node, &layout, entry.index)?;
+        block::Manager::read_bytes(db, layout.device, at, &mut record)?;
+        let raw = Self::read_dir(db, &layout, dir.cluster)?;
+        let mut taken = Self::short_names(&raw);
+        if old_parent == parent {
+            taken.retain(|short| short[..] != record[..11]);
+        }
+        let (short, long) = Self::pick_short_name(name, &taken)?;
+        let records = Self::make_records(name, short, long, record);
+        let index = Self::place(db, parent, &layout, &raw, records.len())?;
+        Self::write_records(db, &layout, dir.cluster, index, &records)?;
+        Self::mark_deleted(db, node, &layout, &entry)?;
+        if entry.kind == EntryKind::Directory && old_parent != parent {
+            let at = Self::dir_offset(db, &layout, entry.cluster, 1)?;
+            let mut dotdot = [0u8; ENTRY_SIZE];
+            block::Manager::read_bytes(db, layout.device, at, &mut dotdot)?;
+            Self::set_cluster(&mut dotdot, Self::dotdot_cluster(db, parent)?);
+            block::Manager::write_bytes(db, layout.device, at, &dotdot)?;
+        }
+        vfs::Manager::move_node(db, node, parent, name);
+        let long_entries = records.len() as u32 - 1;
+        Self::update_entry(db, node, |e| (e.index, e.long_entries) = (index + long_entries, long_entries))
+    }
+
+    /// An empty filesystem on `device`, of `kind` or whichever suits its size
+    pub fn format(db: &mut db::Database, device: block::DeviceHandle, kind: Option<Kind>) -> Result<Kind, vfs::Error> {
+        let d = block::Manager::get_device(db, device);
+        let (sector_size, total, size) = (d.get_sector_size() as u64, d.get_sector_count(), d.get_size());
+        if !(512..=4096).contains(&sector_size) || total > u32::MAX as u64 {
+            return Err(vfs::Error::Invalid);
+        }
+        let kind = kind.unwrap_or(match size {
+            s if s < 4 << 20 => Kind::Fat12,
+            s if s < 512 << 20 => Kind::Fat16,
+            _ => Kind::Fat32,
+        });
+        let (reserved, root_entries) = match kind {
+            Kind::Fat32 => (32u64, 0u64),
+            _ => (1, 512),
+        };
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(sector_size);
+        // The smallest clusters that leave a count this kind can have
+        let usable = total.saturating_sub(reserved + root_sectors);
+        let (sectors_per_cluster, fat_sectors, clusters) = (0..8)
+            .map(|shift| 1u64 << shift)
+            .map(|per_cluster| {
+                let fat_sectors = kind.fat_bytes(usable / per_cluster + 2).div_ceil(sector_size);
+                (per_cluster, fat_sectors, usable.saturating_sub(2 * fat_sectors) / per_cluster)
+            })
+            .find(|&(_, _, clusters)| u32::try_from(clusters).is_ok_and(|c| kind.clusters().contains(&c)))
+            .ok_or(vfs::Error::Invalid)?;
+
+        let mut boot = [0u8; 512];
+        boot[..3].copy_from_slice(&[0xeb, if kind == Kind::Fat32 { 0x58 } else { 0x3c }, 0x90]);
+        boot[3..11].copy_from_slice(b"RADIAN  ");
+        put16(&mut boot, 11, sector_size as u16);
+        boot[13] = sectors_per_cluster as u8;
+        put16(&mut boot, 14, reserved as u16);
+        boot[16] = 2;
+        put16(&mut boot, 17, root_entries as u16);
+        match total {
+            t if t < 0x10000 && kind != Kind::Fat32 => put16(&mut boot, 19, t as u16),
+            t => put32(&mut boot, 32, t as u32),
+        }
+        boot[21] = 0xf8;
+        put16(&mut boot, 24, 63);
+        put16(&mut boot, 26, 255);
+        let extended = match kind {
+            Kind::Fat32 => {
+                put32(&mut boot, 36, fat_sectors as u32);
+                put32(&mut boot, 44, 2);
+                put16(&mut boot, 48, 1);
+                put16(&mut boot, 50, 6);
+                64
+            }
+            _ => {
+                put16(&mut boot, 22, fat_sectors as u16);
+                36
+            }
+        };
+        boot[extended] = 0x80;
+        boot[extended + 2] = 0x29;
+        put32(&mut boot, extended + 3, 0x5241_4449 ^ total as u32);
+        boot[extended + 7..extended + 18].copy_from_slice(b"NO NAME    ");
+        boot[extended + 18..extended + 26].copy_from_slice(match kind {
+            Kind::Fat12 => b"FAT12   ",
+            Kind::Fat16 => b"FAT16   ",
+            Kind::Fat32 => b"FAT32   ",
+        });
+        boot[510..].copy_from_slice(&[0x55, 0xaa]);
+
+        // Everything up to the data area, plus the FAT32 root directory
+        let end = (reserved + 2 * fat_sectors + root_sectors) * sector_size;
+        let zeroes = alloc::vec![0u8; 64 << 10];
+        let mut at = 0;
+        while at < end {
+            let len = (end - at).min(zeroes.len() as u64) as usize;
+            block::Manager::write_bytes(db, device, at, &zeroes[..len])?;
+            at += len as u64;
+        }
+        block::Manager::write_bytes(db, device, 0, &boot)?;
+        let layout = Layout::parse(device, &boot, size)?;
+        debug_assert_eq!(layout.cluster_end as u64, clusters + 2);
+        Self::set_fat(db, &layout, 0, (kind.end_of_chain() & !0xff) | 0xf8)?;
+        Self::set_fat(db, &layout, 1, kind.end_of_chain())?;
+        if kind == Kind::Fat32 {
+            Self::set_fat(db, &layout, 2, kind.end_of_chain())?;
+            block::Manager::write_bytes(db, device, layout.cluster_offset(2), &alloc::vec![0; layout.cluster_size as usize])?;
+            let mut info = [0u8; 512];
+            put32(&mut info, 0, 0x4161_5252);
+            put32(&mut info, 484, 0x6141_7272);
+            put32(&mut info, 488, clusters as u32 - 1);
+            put32(&mut info, 492, 3);
+            put32(&mut info, 508, 0xaa55_0000);
+            // Backups of both at 6 and 7
+            for (sector, data) in [(1, &info), (6, &boot), (7, &info)] {
+                block::Manager::write_bytes(db, device, sector * sector_size, data)?;
+            }
+        }
+        block::Manager::flush(db, device)?;
+        klog!(Info, "fat", "formatted {:?}, {} clusters of {} bytes", kind, clusters, layout.cluster_size);
+        Ok(kind)
+    }
+}
+
+console_commands! {
+    Command {
+        name: "mkfat",
+        category: "vfs",
+        desc: "make an empty FAT filesystem on a disk",
+        help: "<device> is a block device node, [bits] 12, 16 or 32, by default whichever\n\
+               suits its size. Whatever was on it is gone.",
+        args: &[ArgSpec::required("device", ArgKind::Word), ArgSpec::optional("bits", ArgKind::Literal)],
+        handler: |state, args| {
+            let kind = match args.get_literal(2) {
+                None => None,
+                Some(12) => Some(Kind::Fat12),
+                Some(16) => Some(Kind::Fat16),
+                Some(32) => Some(Kind::Fat32),
+                Some(_) => {
+                    console_error!(state, "12, 16 or 32\r\n");
+                    return;
+                }
+            };
+            let result = console::Manager::resolve_path(state, args.get(1).unwrap())
+                .and_then(|node| vfs::Manager::check_access(state.db, state.current_actor, node, policy::Access::Write).map(|_| node))
+                .and_then(|node| block::Manager::find_by_node(state.db, node).ok_or(vfs::Error::Invalid))
+                .and_then(|device| Manager::format(state.db, device, kind));
+            match result {
+                Ok(kind) => kprint!("{:?}\r\n", kind),
+                Err(e) => console_error!(state, "{:?}\r\n", e),
+            }
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::Subsystem::*;
+    use vfs::{Error, NodeKind};
+
+    fn path(db: &db::Database, path: &str) -> Result<vfs::NodeHandle, Error> {
+        vfs::Manager::resolve_path(db, db::ObjectHandle::default(), vfs::NodeHandle::default(), path)
+    }
+
+    /// `/devices/ram0` mounted on `/mount/disk`
+    fn mount(db: &mut db::Database) -> vfs::NodeHandle {
+        let actor = db::ObjectHandle::default();
+        let node = match path(db, "/mount/disk") {
+            Ok(node) => node,
+            Err(_) => vfs::Manager::create(db, actor, path(db, "/mount").unwrap(), "disk", NodeKind::Directory).unwrap(),
+        };
+        let fs = vfs::Manager::find_fs(db, "fat").unwrap();
+        vfs::Manager::mount(db, actor, fs, node, Some(path(db, "/devices/ram0").unwrap())).unwrap();
+        node
+    }
+
+    fn remount(db: &mut db::Database, root: vfs::NodeHandle) {
+        vfs::Manager::unmount(db, db::ObjectHandle::default(), root).unwrap();
+        mount(db);
+    }
+
+    fn formatted(kib: usize, kind: Kind) -> (std::boxed::Box<db::Database>, vfs::NodeHandle) {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs, Block, Fat]);
+        let disk = block::Manager::new_ram_disk(&mut db, "ram0", 512, alloc::vec![0; kib << 10]).unwrap();
+        assert_eq!(Manager::format(&mut db, disk, Some(kind)), Ok(kind));
+        let root = mount(&mut db);
+        (db, root)
+    }
+
+    fn free_clusters(db: &mut db::Database, root: vfs::NodeHandle) -> usize {
+        let layout = Manager::get_volume(db, root).unwrap().layout;
+        (2..layout.cluster_end).filter(|&c| Manager::get_fat(db, &layout, c).unwrap() == 0).count()
+    }
+
+    fn read_all(db: &mut db::Database, node: vfs::NodeHandle) -> Vec<u8> {
+        let mut data = alloc::vec![0; vfs::Manager::stat(db, db::ObjectHandle::default(), node).unwrap().size as usize];
+        assert_eq!(vfs::Manager::read_node(db, db::ObjectHandle::default(), node, 0, &mut data), Ok(data.len()));
+        data
+    }
+
+    fn read_path(db: &mut db::Database, at: &str) -> Vec<u8> {
+        let node = path(db, at).unwrap();
+        read_all(db, node)
+    }
+
+    #[test]
+    fn every_kind_round_trips() {
+        for (kib, kind) in [(1024, Kind::Fat12), (8 << 10, Kind::Fat16), (40 << 10, Kind::Fat32)] {
+            let (mut db, root) = formatted(kib, kind);
+            let actor = db::ObjectHandle::default();
+            assert_eq!(vfs::Manager::get_superblock::<Volume>(&db, root).unwrap().get_kind(), kind);
+            let cluster_size = vfs::Manager::get_superblock::<Volume>(&db, root).unwrap().get_cluster_size() as usize;
+            let docs = vfs::Manager::create(&mut db, actor, root, "Documents", NodeKind::Directory).unwrap();
+            let file = vfs::Manager::create(&mut db, actor, docs, "A rather long file name.txt", NodeKind::File).unwrap();
+            let data: Vec<u8> = (0..cluster_size * 2 + 100).map(|i| (i % 251) as u8).collect();
+            assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 0, &data), Ok(data.len()));
+            let readme = vfs::Manager::create(&mut db, actor, root, "README.TXT", NodeKind::File).unwrap();
+            assert_eq!(vfs::Manager::write_node(&mut db, actor, readme, 0, b"hi"), Ok(2));
+
+            remount(&mut db, root);
+            // FAT does not care for case
+            let file = path(&db, "/mount/disk/documents/a RATHER long file name.TXT").unwrap();
+            assert_eq!(vfs::Manager::get_node(&db, file).get_name(), "A rather long file name.txt");
+            assert_eq!(read_all(&mut db, file), data);
+            assert_eq!(read_path(&mut db, "/mount/disk/README.TXT"), b"hi");
+            let layout = Manager::get_volume(&db, root).unwrap().layout;
+            let raw = Manager::read_dir(&mut db, &layout, layout.root_cluster).unwrap();
+            let found = Manager::parse_dir(&raw, kind);
+            let long: Vec<_> = found.iter().map(|f| (f.name.as_str(), f.long_entries)).collect();
+            assert_eq!(long, [("Documents", 1), ("README.TXT", 0)]);
+        }
+    }
+
+    #[test]
+    fn long_names_get_aliases() {
+        let (mut db, root) = formatted(1024, Kind::Fat12);
+        let actor = db::ObjectHandle::default();
+        for name in ["Long file name one.txt", "Long file name two.txt", "Grüße.text", ".profile"] {
+            vfs::Manager::create(&mut db, actor, root, name, NodeKind::File).unwrap();
+        }
+        assert_eq!(vfs::Manager::create(&mut db, actor, root, "long FILE name one.TXT", NodeKind::File), Err(Error::Exists));
+        assert_eq!(vfs::Manager::create(&mut db, actor, root, "a:b", NodeKind::File), Err(Error::Invalid));
+        assert_eq!(vfs::Manager::create(&mut db, actor, root, "dot.", NodeKind::File), Err(Error::Invalid));
+        let layout = Manager::get_volume(&db, root).unwrap().layout;
+        let raw = Manager::read_dir(&mut db, &layout, 0).unwrap();
+        let names = Manager::parse_dir(&raw, Kind::Fat12).into_iter().map(|f| f.name);
+        let shorts: Vec<(String, [u8; 11])> = names.zip(Manager::short_names(&raw)).collect();
+        assert_eq!(
+            shorts,
+            [
+                ("Long file name one.txt".into(), *b"LONGFI~1TXT"),
+                ("Long file name two.txt".into(), *b"LONGFI~2TXT"),
+                ("Grüße.text".into(), *b"GR__E~1 TEX"),
+                (".profile".into(), *b"PROFIL~1   "),
+            ]
+        );
+        remount(&mut db, root);
+        assert!(path(&db, "/mount/disk/Grüße.text").is_ok());
+        // Without a long name the case byte decides
+        let mut record = Manager::short_record(ATTR_ARCHIVE, 0, 0);
+        record[..11].copy_from_slice(b"KERNEL  ELF");
+        record[12] = LOWER_BASE;
+        assert_eq!(Manager::parse_dir(&record, Kind::Fat12)[0].name, "kernel.ELF");
+    }
+
+    #[test]
+    fn high_cluster_word_only_counts_on_fat32() {
+        let (mut db, root) = formatted(8 << 10, Kind::Fat16);
+        let actor = db::ObjectHandle::default();
+        let file = vfs::Manager::create(&mut db, actor, root, "OS2.INI", NodeKind::File).unwrap();
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 0, b"extended attributes"), Ok(19));
+        // OS/2 kept its extended attribute handle where FAT32 put the high word
+        let layout = Manager::get_volume(&db, root).unwrap().layout;
+        let raw = Manager::read_dir(&mut db, &layout, 0).unwrap();
+        let index = Manager::parse_dir(&raw, Kind::Fat16)[0].index;
+        let at = Manager::dir_offset(&mut db, &layout, 0, index).unwrap();
+        block::Manager::write_bytes(&mut db, layout.device, at + 20, &[0x34, 0x12]).unwrap();
+        remount(&mut db, root);
+        assert_eq!(read_path(&mut db, "/mount/disk/OS2.INI"), b"extended attributes");
+        let raw = Manager::read_dir(&mut db, &layout, 0).unwrap();
+        assert_eq!(Manager::parse_dir(&raw, Kind::Fat32)[0].cluster >> 16, 0x1234);
+    }
+
+    #[test]
+    fn esp_is_found_by_type_or_by_its_efi_directory() {
+        let (mut db, root) = formatted(1024, Kind::Fat12);
+        let actor = db::ObjectHandle::default();
+        vfs::Manager::unmount(&mut db, actor, root).unwrap();
+        assert_eq!(Manager::mount_esp(&mut db), None);
+        mount(&mut db);
+        vfs::Manager::create(&mut db, actor, root, "EFI", NodeKind::Directory).unwrap();
+        vfs::Manager::unmount(&mut db, actor, root).unwrap();
+        let esp = Manager::mount_esp(&mut db).unwrap();
+        assert_eq!(path(&db, "/mount/esp"), Ok(esp));
+        assert!(path(&db, "/mount/esp/efi").is_ok());
+
+        // On a partitioned disk only the type counts
+        let mut image = alloc::vec![0u8; 2 << 20];
+        image[446 + 4] = 0xef;
+        image[446 + 8..446 + 12].copy_from_slice(&64u32.to_le_bytes());
+        image[446 + 12..446 + 16].copy_from_slice(&((2 << 20) / 512 - 64u32).to_le_bytes());
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let disk = block::Manager::new_ram_disk(&mut db, "ram1", 512, image).unwrap();
+        assert_eq!(partition::Manager::scan(&mut db, disk), Ok(1));
+        let part = block::Manager::find_by_node(&db, path(&db, "/devices/ram1/part1").unwrap()).unwrap();
+        assert_eq!(Manager::format(&mut db, part, Some(Kind::Fat12)), Ok(Kind::Fat12));
+        vfs::Manager::unmount(&mut db, actor, esp).unwrap();
+        assert_eq!(Manager::mount_esp(&mut db), Some(esp));
+        assert_eq!(Manager::get_volume(&db, esp).unwrap().layout.device, part);
+    }
+
+    #[test]
+    fn files_grow_shrink_and_give_back_clusters() {
+        let (mut db, root) = formatted(8 << 10, Kind::Fat16);
+        let actor = db::ObjectHandle::default();
+        let free = free_clusters(&mut db, root);
+        let file = vfs::Manager::create(&mut db, actor, root, "data.bin", NodeKind::File).unwrap();
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 3000, b"end"), Ok(3));
+        assert_eq!(free_clusters(&mut db, root), free - 6);
+        let data = read_all(&mut db, file);
+        assert!(data[..3000].iter().all(|&b| b == 0));
+        assert_eq!(&data[3000..], b"end");
+        // Shrinking frees, growing again reads zeroes where there was data
+        assert_eq!(vfs::Manager::truncate(&mut db, actor, file, 513), Ok(()));
+        assert_eq!(free_clusters(&mut db, root), free - 2);
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 0, &[1; 600]), Ok(600));
+        assert_eq!(vfs::Manager::truncate(&mut db, actor, file, 10), Ok(()));
+        assert_eq!(vfs::Manager::truncate(&mut db, actor, file, 700), Ok(()));
+        remount(&mut db, root);
+        let file = path(&db, "/mount/disk/data.bin").unwrap();
+        let data = read_all(&mut db, file);
+        assert_eq!((data.len(), &data[..10]), (700, &[1; 10][..]));
+        assert!(data[10..].iter().all(|&b| b == 0));
+        assert_eq!(vfs::Manager::remove(&mut db, actor, file), Ok(()));
+        assert_eq!(free_clusters(&mut db, root), free);
+        remount(&mut db, root);
+        assert_eq!(path(&db, "/mount/disk/data.bin"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn directories_grow_and_move() {
+        let (mut db, root) = formatted(40 << 10, Kind::Fat32);
+        let actor = db::ObjectHandle::default();
+        let a = vfs::Manager::create(&mut db, actor, root, "a", NodeKind::Directory).unwrap();
+        let b = vfs::Manager::create(&mut db, actor, root, "b", NodeKind::Directory).unwrap();
+        let sub = vfs::Manager::create(&mut db, actor, a, "sub", NodeKind::Directory).unwrap();
+        // More than one cluster of records
+        for i in 0..40 {
+            vfs::Manager::create(&mut db, actor, sub, &alloc::format!("file number {i}"), NodeKind::File).unwrap();
+        }
+        assert_eq!(vfs::Manager::remove(&mut db, actor, sub), Err(Error::NotEmpty));
+        assert_eq!(vfs::Manager::rename(&mut db, actor, sub, b, "Moved Sub"), Ok(()));
+        let temp = path(&db, "/temp").unwrap();
+        assert_eq!(vfs::Manager::rename(&mut db, actor, sub, temp, "x"), Err(Error::Unsupported));
+        remount(&mut db, root);
+        let moved = path(&db, "/mount/disk/b/moved sub").unwrap();
+        assert_eq!(vfs::Manager::get_node(&db, moved).get_child_count(), 40);
+        assert!(path(&db, "/mount/disk/b/Moved Sub/file number 39").is_ok());
+        assert_eq!(vfs::Manager::readdir(&db, actor, path(&db, "/mount/disk/a").unwrap(), 0), None);
+        // `..` follows the move
+        let (layout, entry) = Manager::get_entry(&db, moved, EntryKind::Directory).unwrap();
+        let raw = Manager::read_dir(&mut db, &layout, entry.cluster).unwrap();
+        let b_cluster = Manager::get_entry(&db, path(&db, "/mount/disk/b").unwrap(), EntryKind::Directory).unwrap().1.cluster;
+        assert_eq!(((get16(&raw, 32 + 20) as u32) << 16) | get16(&raw, 32 + 26) as u32, b_cluster);
+    }
+
+    #[test]
+    fn bad_images_do_not_mount() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs, Block, Fat]);
+        let actor = db::ObjectHandle::default();
+        block::Manager::new_ram_disk(&mut db, "ram0", 512, alloc::vec![0; 64 << 10]).unwrap();
+        let (mount, temp, disk) = (path(&db, "/mount").unwrap(), path(&db, "/temp").unwrap(), path(&db, "/devices/ram0").unwrap());
+        let node = vfs::Manager::create(&mut db, actor, mount, "disk", NodeKind::Directory).unwrap();
+        let fs = vfs::Manager::find_fs(&db, "fat").unwrap();
+        assert_eq!(vfs::Manager::mount(&mut db, actor, fs, node, None), Err(Error::Invalid));
+        assert_eq!(vfs::Manager::mount(&mut db, actor, fs, node, Some(temp)), Err(Error::Invalid));
+        // All zeroes, no boot sector
+        assert_eq!(vfs::Manager::mount(&mut db, actor, fs, node, Some(disk)), Err(Error::Invalid));
+        assert_eq!(vfs::Manager::get_child(&db, node, 0), None);
+    }
+
+    /// `make fat` then `RADIAN_FAT_IMG=fat.img make test`, skipped without
+    #[test]
+    fn reads_the_build_image() {
+        let Ok(image) = std::env::var("RADIAN_FAT_IMG") else {
+            return;
+        };
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs, Block, Fat]);
+        let actor = db::ObjectHandle::default();
+        block::Manager::new_ram_disk(&mut db, "ram0", 512, std::fs::read(image).unwrap()).unwrap();
+        let root = mount(&mut db);
+        let kernel = read_path(&mut db, "/mount/disk/efi/boot/kernel");
+        assert_eq!(&kernel[..4], b"\x7fELF");
+        let loader = read_path(&mut db, "/mount/disk/EFI/BOOT/BOOTX64.EFI");
+        assert_eq!(&loader[..2], b"MZ");
+        // Only the copy in memory changes
+        let boot = path(&db, "/mount/disk/EFI/BOOT").unwrap();
+        let file = vfs::Manager::create(&mut db, actor, boot, "Written by radian.txt", NodeKind::File).unwrap();
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 0, b"hello"), Ok(5));
+        remount(&mut db, root);
+        assert_eq!(read_path(&mut db, "/mount/disk/efi/boot/written by radian.txt"), b"hello");
+        assert_eq!(read_path(&mut db, "/mount/disk/efi/boot/kernel"), kernel);
+    }
+}
@@ -0,0 +1,282 @@
+//! Kernel address sanitizer runtime, enabled with the `kasan` feature
+//!
+//! The compiler instruments every load/store with a call to `__asan_*_noabort`
+//! and poisons stack redzones through `__asan_set_shadow_*`, see `make KASAN=1`
+//! for the exact flags. Each 8 byte granule of a tracked region has one shadow
+//! byte at `SHADOW_OFFSET + addr / 8`: `0` means fully accessible, `1..=7` means
+//! only the first N bytes are, anything negative is poisoned and says why.
+//!
+//! Only registered regions (heap arenas and the kernel stack) have shadow, any
+//! other address is never checked. Heap poisoning is driven by `TbsAlloc`'s
+//! `heap-debug` layer, which also provides the allocation sites for reports.
+
+use crate::{TbsAlloc, db, klog, kprint, pmm, vmm};
+
+/// Must match `-asan-mapping-offset` in the Makefile
+pub const SHADOW_OFFSET: usize = 0x1000_0000_0000;
+pub const SHADOW_SCALE: usize = 3;
+pub const GRANULE_SIZE: usize = 1 << SHADOW_SCALE;
+pub const MAX_REGIONS: usize = 16;
+
+pub const HEAP_LEFT_REDZONE: u8 = 0xfa;
+pub const HEAP_RIGHT_REDZONE: u8 = 0xfb;
+pub const HEAP_FREED: u8 = 0xfd;
+pub const STACK_LEFT_REDZONE: u8 = 0xf1;
+pub const STACK_MID_REDZONE: u8 = 0xf2;
+pub const STACK_RIGHT_REDZONE: u8 = 0xf3;
+pub const STACK_USE_AFTER_SCOPE: u8 = 0xf8;
+
+unsafe extern "C" {
+    unsafe static STACK_BOTTOM: u8;
+    unsafe static STACK_TOP: u8;
+}
+
+/// Plain arrays and `while` loops only in here, any generic helper would be
+/// instrumented itself and call right back into the runtime
+struct Sanitizer {
+    regions: [(usize, usize); MAX_REGIONS],
+    num_regions: usize,
+    enabled: bool,
+    /// Set while checking an access, instrumented code called from the
+    /// runtime (formatting a report, etc) must not recurse
+    busy: bool,
+    reports: usize,
+}
+static mut SANITIZER: Sanitizer = Sanitizer {
+    regions: [(0, 0); MAX_REGIONS],
+    num_regions: 0,
+    enabled: false,
+    busy: false,
+    reports: 0,
+};
+
+#[inline(always)]
+fn shadow_of(addr: usize) -> *mut u8 {
+    (SHADOW_OFFSET + (addr >> SHADOW_SCALE)) as *mut u8
+}
+
+#[sanitize(address = "off")]
+fn is_tracked(addr: usize) -> bool {
+    unsafe {
+        let sanitizer = &raw const SANITIZER;
+        let mut i = 0;
+        while i < (*sanitizer).num_regions {
+            let (base, length) = (*sanitizer).regions[i];
+            if addr >= base && addr < base + length {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+#[sanitize(address = "off")]
+fn is_shadow_tracked(shadow: usize) -> bool {
+    shadow >= SHADOW_OFFSET && is_tracked((shadow - SHADOW_OFFSET) << SHADOW_SCALE)
+}
+
+fn describe(value: u8) -> &'static str {
+    match value {
+        HEAP_LEFT_REDZONE | HEAP_RIGHT_REDZONE => "heap-out-of-bounds",
+        HEAP_FREED => "use-after-free",
+        STACK_LEFT_REDZONE => "stack-underflow",
+        STACK_MID_REDZONE | STACK_RIGHT_REDZONE => "stack-out-of-bounds",
+        STACK_USE_AFTER_SCOPE => "stack-use-after-scope",
+        _ => "out-of-bounds",
+    }
+}
+
+pub struct Manager;
+impl Manager {
+    /// Registers the kernel stack and starts reporting, heap arenas register
+    /// themselves as they are created
+    pub fn init(db: &mut db::Database, aspace: vmm::AddressSpaceHandle) {
+        let bottom = &raw const STACK_BOTTOM as usize;
+        let top = &raw const STACK_TOP as usize;
+        Self::add_region(db, aspace, bottom, top - bottom);
+        unsafe {
+            (*&raw mut SANITIZER).enabled = true;
+        }
+        klog!(Info, "kasan", "shadow at {:016x}, tracking stack {bottom:016x}-{top:016x}", SHADOW_OFFSET);
+    }
+
+    /// Map (zeroed, so accessible) shadow for the given range and start tracking it
+    pub fn add_region(db: &mut db::Database, aspace: vmm::AddressSpaceHandle, base: usize, length: usize) {
+        let first = shadow_of(base) as usize & !(pmm::PAGE_SIZE - 1);
+        let last = shadow_of(base + length - 1) as usize & !(pmm::PAGE_SIZE - 1);
+        let mut page = first;
+        while page <= last {
+            if !vmm::Manager::has_mapping_present(db, aspace, page as u64) {
+                let handle = pmm::Manager::alloc_page_zeroed();
+                vmm::Manager::map_single(db, aspace, handle.get() as u64, page as u64, vmm::Page::PRESENT | vmm::Page::READ_WRITE);
+            }
+            page += pmm::PAGE_SIZE;
+        }
+        unsafe {
+            let sanitizer = &raw mut SANITIZER;
+            assert!((*sanitizer).num_regions < MAX_REGIONS);
+            (*sanitizer).regions[(*sanitizer).num_regions] = (base, length);
+            (*sanitizer).num_regions += 1;
+        }
+    }
+
+    /// Mark the whole range as poisoned, `addr` must be granule aligned
+    #[sanitize(address = "off")]
+    pub fn poison(addr: usize, length: usize, value: u8) {
+        if is_tracked(addr) {
+            unsafe {
+                shadow_of(addr).write_bytes(value, length.div_ceil(GRANULE_SIZE));
+            }
+        }
+    }
+
+    /// Mark the range as accessible, the last granule may be partial
+    #[sanitize(address = "off")]
+    pub fn unpoison(addr: usize, length: usize) {
+        if is_tracked(addr) {
+            unsafe {
+                shadow_of(addr).write_bytes(0, length / GRANULE_SIZE);
+                if length % GRANULE_SIZE != 0 {
+                    shadow_of(addr + length).write((length % GRANULE_SIZE) as u8);
+                }
+            }
+        }
+    }
+
+    /// Returns the shadow byte of the first bad byte in the range, if any
+    #[sanitize(address = "off")]
+    fn find_poisoned(addr: usize, size: usize) -> Option<u8> {
+        if !is_tracked(addr) {
+            return None;
+        }
+        let mut a = addr;
+        while a < addr + size {
+            let value = unsafe { shadow_of(a).read() };
+            if value != 0 && (value as i8) <= (a % GRANULE_SIZE) as i8 {
+                return Some(value);
+            }
+            a += 1;
+        }
+        None
+    }
+
+    #[sanitize(address = "off")]
+    fn check(addr: usize, size: usize, is_write: bool, pc: usize) {
+        unsafe {
+            let sanitizer = &raw mut SANITIZER;
+            if !(*sanitizer).enabled || (*sanitizer).busy {
+                return;
+            }
+            (*sanitizer).busy = true;
+            if let Some(value) = Self::find_poisoned(addr, size) {
+                (*sanitizer).reports += 1;
+                kprint!(
+                    "[kasan] {} {} of size {size} at {addr:016x} (pc {pc:016x}, shadow {value:02x})\r\n",
+                    describe(value),
+                    ["read", "write"][is_write as usize]
+                );
+                TbsAlloc::describe_address(addr);
+            }
+            (*sanitizer).busy = false;
+        }
+    }
+
+    pub fn get_report_count() -> usize {
+        unsafe { (*&raw const SANITIZER).reports }
+    }
+}
+
+/// Return address of the instrumented function, needs frame pointers
+macro_rules! caller_pc {
+    () => {{
+        let pc: usize;
+        unsafe {
+            core::arch::asm!("mov {}, [rbp + 8]", out(reg) pc);
+        }
+        pc
+    }};
+}
+
+macro_rules! asan_access {
+    ($load:ident $store:ident $size:literal) => {
+        #[unsafe(no_mangle)]
+        #[sanitize(address = "off")]
+        extern "C" fn $load(addr: usize) {
+            Manager::check(addr, $size, false, caller_pc!());
+        }
+        #[unsafe(no_mangle)]
+        #[sanitize(address = "off")]
+        extern "C" fn $store(addr: usize) {
+            Manager::check(addr, $size, true, caller_pc!());
+        }
+    };
+}
+asan_access!(__asan_load1_noabort __asan_store1_noabort 1);
+asan_access!(__asan_load2_noabort __asan_store2_noabort 2);
+asan_access!(__asan_load4_noabort __asan_store4_noabort 4);
+asan_access!(__asan_load8_noabort __asan_store8_noabort 8);
+asan_access!(__asan_load16_noabort __asan_store16_noabort 16);
+
+#[unsafe(no_mangle)]
+#[sanitize(address = "off")]
+extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+    Manager::check(addr, size, false, caller_pc!());
+}
+#[unsafe(no_mangle)]
+#[sanitize(address = "off")]
+extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+    Manager::check(addr, size, true, caller_pc!());
+}
+
+macro_rules! asan_set_shadow {
+    ($name:ident $value:literal) => {
+        /// Stack redzones, `shadow` is already a shadow address
+        #[unsafe(no_mangle)]
+        #[sanitize(address = "off")]
+        extern "C" fn $name(shadow: usize, size: usize) {
+            if is_shadow_tracked(shadow) {
+                unsafe { (shadow as *mut u8).write_bytes($value, size) };
+            }
+        }
+    };
+}
+asan_set_shadow!(__asan_set_shadow_00 0x00);
+asan_set_shadow!(__asan_set_shadow_f1 0xf1);
+asan_set_shadow!(__asan_set_shadow_f2 0xf2);
+asan_set_shadow!(__asan_set_shadow_f3 0xf3);
+asan_set_shadow!(__asan_set_shadow_f5 0xf5);
+asan_set_shadow!(__asan_set_shadow_f8 0xf8);
+
+/// Called before `noreturn` calls, whatever is below us on the stack is dead
+#[unsafe(no_mangle)]
+#[sanitize(address = "off")]
+extern "C" fn __asan_handle_no_return() {
+    let rsp: usize;
+    unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) rsp);
+    }
+    let bottom = &raw const STACK_BOTTOM as usize;
+    let top = &raw const STACK_TOP as usize;
+    if rsp > bottom && rsp <= top {
+        Manager::unpoison(bottom, rsp - bottom);
+    }
+}
+
+#[unsafe(no_mangle)]
+#[sanitize(address = "off")]
+extern "C" fn __asan_alloca_poison(addr: usize, size: usize) {
+    // 32 byte redzones on both sides, same as the compiler assumes
+    Manager::poison(addr - 32, 32, STACK_LEFT_REDZONE);
+    Manager::unpoison(addr, size);
+    let end = (addr + size).div_ceil(32) * 32;
+    Manager::poison(end, 32, STACK_RIGHT_REDZONE);
+}
+
+#[unsafe(no_mangle)]
+#[sanitize(address = "off")]
+extern "C" fn __asan_allocas_unpoison(top: usize, bottom: usize) {
+    if bottom > top {
+        Manager::unpoison(top, bottom - top);
+    }
+}
@@ -1,26 +1,59 @@
 #![allow(internal_features)]
-#![no_std]
+// Hosted under `cargo test` so the pure logic can be unit tested on the build machine
+#![cfg_attr(not(test), no_std)]
 #![feature(str_from_raw_parts)]
 #![feature(lang_items)]
 #![feature(c_size_t)]
 #![feature(pointer_is_aligned_to)]
 #![feature(abi_x86_interrupt)]
 #![feature(allocator_api)]
+#![cfg_attr(feature = "kasan", feature(sanitize))]
+#![cfg_attr(feature = "kernel-test", feature(macro_attr))]
 
+extern crate alloc;
 use core::str;
 pub mod TbsAlloc;
+pub mod ahci;
+pub mod ata;
+pub mod block;
+pub mod clock;
+pub mod console;
 pub mod containers;
 pub mod cpu;
 pub mod db;
+pub mod ext2;
+pub mod fat;
+#[cfg(feature = "kasan")]
+pub mod kasan;
+pub mod log;
+pub mod partition;
+pub mod pci;
+pub mod pic;
 pub mod pmm;
 pub mod policy;
 pub mod prelude;
+pub mod slab;
 pub mod smp;
 pub mod styles;
+pub mod syscall;
 pub mod task;
+#[cfg(feature = "kernel-test")]
+pub mod testing;
+pub mod tmpfs;
+pub mod uart;
 pub mod vfs;
 pub mod vmm;
 
+/// Normally from `kernel.ld`, only here so the host test binary links, the
+/// console commands reach code that uses them but no test runs it
+#[cfg(test)]
+mod linker_symbols {
+    #[unsafe(no_mangle)]
+    static KERNEL_START: u8 = 0;
+    #[unsafe(no_mangle)]
+    static KERNEL_END: u8 = 0;
+}
+
 #[macro_export]
 macro_rules! dense_bitfield {
     ($name:ident $repr:ident $($cap:ident = $value:expr,)*) => {
@@ -94,13 +127,17 @@ macro_rules! const_assert {
 #[cfg(not(test))]
 #[panic_handler]
 pub fn panic(info: &core::panic::PanicInfo) -> ! {
+    uart::Manager::set_polled();
     if let Some(loc) = info.location() {
         kprint!("{}:{}: {}\r\n", loc.file(), loc.line(), info.message());
     }
+    #[cfg(feature = "kernel-test")]
+    testing::Manager::on_panic(info);
+    #[cfg(not(feature = "kernel-test"))]
     abort();
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 extern "C" fn abort() -> ! {
     loop {
         unsafe {
@@ -118,36 +155,24 @@ impl core::fmt::Write for DebugSerial {
         Ok(())
     }
 }
+/// COM1 through the UART driver, which falls back to polling when it has to
+#[cfg(not(test))]
 impl DebugSerial {
     pub fn get_byte() -> Option<u8> {
-        #[allow(unused_assignments)]
-        let mut byte = 0;
-        unsafe {
-            core::arch::asm!(
-                "in al, dx",
-                out("al") byte,
-                in("dx") 0x3f8 + 5
-            );
-            if byte & 0x01 != 0 {
-                core::arch::asm!(
-                    "in al, dx",
-                    out("al") byte,
-                    in("dx") 0x3f8
-                );
-                Some(byte)
-            } else {
-                None
-            }
-        }
+        uart::Manager::get_byte(0)
     }
     pub fn put_byte(b: u8) {
-        unsafe {
-            core::arch::asm!(
-                "out dx, al",
-                in("al") b,
-                in("dx") 0x3f8
-            );
-        }
+        uart::Manager::put_byte(0, b);
+    }
+}
+/// No serial port on the host, output ends up in the test log instead
+#[cfg(test)]
+impl DebugSerial {
+    pub fn get_byte() -> Option<u8> {
+        None
+    }
+    pub fn put_byte(b: u8) {
+        std::print!("{}", b as char);
     }
 }
 
@@ -0,0 +1,464 @@
+//! Kernel log: levels, subsystem tags and a ring of recent records
+//!
+//! `klog!(Info, "pmm", ...)` stamps a fixed size `Record` with the TSC, keeps it
+//! in a ring of `RECORD_COUNT` (the oldest get overwritten) and echoes it to the
+//! serial console the way `kprint!("[pmm] ...")` used to. Every subsystem has a
+//! level, the default one until `loglevel` says otherwise, and anything less
+//! severe is dropped before being formatted. The ring is what `dmesg` and reads
+//! of `/mutable/logs/radian_core.log` show.
+
+use core::fmt::{self, Write};
+
+use crate::console::{ArgKind, ArgSpec, Command};
+use crate::containers::RingBuffer;
+use crate::{clock, console_commands, console_error, kprint};
+
+pub const RECORD_COUNT: usize = 256;
+pub const MESSAGE_LEN: usize = 120;
+pub const SUBSYSTEM_LEN: usize = 12;
+/// Subsystems the level table remembers, later ones follow the default
+pub const MAX_SUBSYSTEMS: usize = 32;
+pub const DEFAULT_LEVEL: Level = Level::Info;
+
+/// Logs to the kernel ring and the serial console, `$level` is a `Level` variant
+///
+/// ```ignore
+/// klog!(Warn, "pic", "unhandled irq {irq}");
+/// ```
+#[macro_export]
+macro_rules! klog {
+    ($level:ident, $subsystem:expr, $($args:tt)*) => {
+        $crate::log::Manager::log($crate::log::Level::$level, $subsystem, format_args!($($args)*))
+    };
+}
+
+/// Most severe first, a subsystem at `Info` keeps `Error`, `Warn` and `Info`
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+impl Level {
+    pub const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|l| l.as_str() == s)
+    }
+}
+
+/// Truncating fixed size text, unlike `StaticString` it is `Copy` and const
+#[derive(Debug, Clone, Copy)]
+pub struct Text<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+impl<const N: usize> Text<N> {
+    pub const fn new() -> Self {
+        Self { bytes: [0; N], len: 0 }
+    }
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+impl<const N: usize> Default for Text<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<const N: usize> From<&str> for Text<N> {
+    fn from(s: &str) -> Self {
+        let mut text = Self::new();
+        let _ = text.write_str(s);
+        text
+    }
+}
+impl<const N: usize> Write for Text<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(N - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    /// Raw TSC, see `clock::Manager::ticks_to_micros`
+    pub time: u64,
+    pub level: Level,
+    pub subsystem: Text<SUBSYSTEM_LEN>,
+    pub message: Text<MESSAGE_LEN>,
+}
+impl Record {
+    const EMPTY: Self = Self { time: 0, level: Level::Trace, subsystem: Text::new(), message: Text::new() };
+
+    pub fn new(time: u64, level: Level, subsystem: &str, args: fmt::Arguments) -> Self {
+        let mut message = Text::new();
+        let _ = message.write_fmt(args);
+        Self { time, level, subsystem: Text::from(subsystem), message }
+    }
+}
+/// `[    1.234567] warn  pic: unhandled irq 9`
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let us = clock::Manager::ticks_to_micros(self.time);
+        write!(f, "[{:5}.{:06}] {:<5} {}: {}", us / 1_000_000, us % 1_000_000, self.level.as_str(), self.subsystem.as_str(), self.message.as_str())
+    }
+}
+
+/// Which records `dmesg` shows: at least as severe as `level`, and only from
+/// `subsystem` if there is one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Filter<'a> {
+    pub level: Level,
+    pub subsystem: Option<&'a str>,
+}
+impl<'a> Filter<'a> {
+    pub const ALL: Filter<'static> = Filter { level: Level::Trace, subsystem: None };
+
+    /// Words in any order, level names set the level and anything else the subsystem
+    pub fn parse(words: &'a str) -> Option<Self> {
+        let mut filter = Filter { level: Level::Trace, subsystem: None };
+        for word in words.split_whitespace() {
+            if let Some(level) = Level::parse(word) {
+                filter.level = level;
+            } else if filter.subsystem.replace(word).is_some() {
+                return None;
+            }
+        }
+        Some(filter)
+    }
+    pub fn matches(&self, record: &Record) -> bool {
+        record.level <= self.level && self.subsystem.is_none_or(|s| s == record.subsystem.as_str())
+    }
+}
+
+/// The ring and the level table, `Manager` keeps the kernel's one
+pub struct Log<const N: usize> {
+    records: RingBuffer<Record, N>,
+    /// Records pushed out of the ring so far
+    overwritten: usize,
+    /// `None` follows `default_level`
+    levels: [(Text<SUBSYSTEM_LEN>, Option<Level>); MAX_SUBSYSTEMS],
+    level_count: usize,
+    default_level: Level,
+}
+impl<const N: usize> Log<N> {
+    pub const fn new() -> Self {
+        Self {
+            records: RingBuffer::new_with_default(Record::EMPTY),
+            overwritten: 0,
+            levels: [(Text::new(), None); MAX_SUBSYSTEMS],
+            level_count: 0,
+            default_level: DEFAULT_LEVEL,
+        }
+    }
+
+    fn find_subsystem(&self, subsystem: &str) -> Option<usize> {
+        self.levels[..self.level_count].iter().position(|(name, _)| name.as_str() == subsystem)
+    }
+    /// Known subsystems get a slot even without their own level, so `loglevel` can list them
+    fn add_subsystem(&mut self, subsystem: &str) -> Option<usize> {
+        if let Some(index) = self.find_subsystem(subsystem) {
+            return Some(index);
+        }
+        if self.level_count == MAX_SUBSYSTEMS {
+            return None;
+        }
+        self.levels[self.level_count] = (Text::from(subsystem), None);
+        self.level_count += 1;
+        Some(self.level_count - 1)
+    }
+
+    pub fn get_level(&self, subsystem: &str) -> Level {
+        self.find_subsystem(subsystem).and_then(|i| self.levels[i].1).unwrap_or(self.default_level)
+    }
+    pub fn get_default_level(&self) -> Level {
+        self.default_level
+    }
+    pub fn is_enabled(&self, level: Level, subsystem: &str) -> bool {
+        level <= self.get_level(subsystem)
+    }
+    /// `false` once `MAX_SUBSYSTEMS` are known and `subsystem` is not one of them
+    pub fn set_level(&mut self, subsystem: &str, level: Level) -> bool {
+        let Some(index) = self.add_subsystem(subsystem) else {
+            return false;
+        };
+        self.levels[index].1 = Some(level);
+        true
+    }
+    /// Also drops every subsystem's own level
+    pub fn set_default_level(&mut self, level: Level) {
+        self.default_level = level;
+        for (_, l) in self.levels[..self.level_count].iter_mut() {
+            *l = None;
+        }
+    }
+    /// `(subsystem, level, own)`, `own` if it does not just follow the default
+    pub fn get_subsystem(&self, index: usize) -> Option<(&str, Level, bool)> {
+        let (name, level) = self.levels[..self.level_count].get(index)?;
+        Some((name.as_str(), level.unwrap_or(self.default_level), level.is_some()))
+    }
+
+    /// Unfiltered, `is_enabled` is for the caller to check before formatting
+    pub fn push(&mut self, record: Record) {
+        self.add_subsystem(record.subsystem.as_str());
+        if self.records.push_overwrite(record).is_some() {
+            self.overwritten += 1;
+        }
+    }
+    /// 0 is the oldest still in the ring
+    pub fn get(&self, index: usize) -> Option<&Record> {
+        self.records.get(index)
+    }
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+    pub fn get_overwritten(&self) -> usize {
+        self.overwritten
+    }
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.overwritten = 0;
+    }
+
+    /// `f(line)` for the matching records, oldest first, as `dmesg` prints them
+    fn for_each_line<F: FnMut(&[u8])>(&self, filter: &Filter, mut f: F) {
+        for record in (0..self.len()).filter_map(|i| self.get(i)).filter(|r| filter.matches(r)) {
+            let mut line = Text::<{ MESSAGE_LEN + SUBSYSTEM_LEN + 32 }>::new();
+            let _ = writeln!(line, "{record}");
+            f(line.as_str().as_bytes());
+        }
+    }
+    /// The matching records as text, from `offset` bytes in
+    pub fn read(&self, filter: &Filter, offset: u64, out: &mut [u8]) -> usize {
+        let (mut pos, mut len) = (0, 0);
+        let offset = offset as usize;
+        self.for_each_line(filter, |line| {
+            let start = offset.max(pos).min(pos + line.len());
+            let n = (pos + line.len() - start).min(out.len() - len);
+            out[len..len + n].copy_from_slice(&line[start - pos..start - pos + n]);
+            len += n;
+            pos += line.len();
+        });
+        len
+    }
+    /// How long `read` would make it
+    pub fn get_size(&self, filter: &Filter) -> u64 {
+        let mut size = 0;
+        self.for_each_line(filter, |line| size += line.len() as u64);
+        size
+    }
+}
+
+impl<const N: usize> Default for Log<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static mut LOG: Log<RECORD_COUNT> = Log::new();
+
+fn get_log() -> &'static mut Log<RECORD_COUNT> {
+    unsafe { (&raw mut LOG).as_mut().unwrap() }
+}
+
+/// Interrupt handlers log too
+#[cfg(not(test))]
+fn locked<R, F: FnOnce(&mut Log<RECORD_COUNT>) -> R>(f: F) -> R {
+    crate::cpu::Manager::without_interrupts(|| f(get_log()))
+}
+/// No `cli` outside ring 0, but the host tests run on several threads
+#[cfg(test)]
+fn locked<R, F: FnOnce(&mut Log<RECORD_COUNT>) -> R>(f: F) -> R {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    f(get_log())
+}
+
+pub struct Manager;
+impl Manager {
+    /// Prefer `klog!`
+    pub fn log(level: Level, subsystem: &str, args: fmt::Arguments) {
+        let time = clock::Manager::now();
+        let record = locked(|log| {
+            if !log.is_enabled(level, subsystem) {
+                return None;
+            }
+            let record = Record::new(time, level, subsystem, args);
+            log.push(record);
+            Some(record)
+        });
+        // Outside the lock, so the UART can still use its interrupt
+        if let Some(record) = record {
+            let (subsystem, message) = (record.subsystem.as_str(), record.message.as_str());
+            match level {
+                Level::Info => kprint!("[{subsystem}] {message}\r\n"),
+                _ => kprint!("[{subsystem}] {}: {message}\r\n", level.as_str()),
+            }
+        }
+    }
+
+    /// One `Info` record per non-empty line of `text`, for the log node
+    pub fn log_lines(subsystem: &str, text: &str) {
+        for line in text.lines().map(|l| l.trim_end_matches('\r')).filter(|l| !l.is_empty()) {
+            Self::log(Level::Info, subsystem, format_args!("{line}"));
+        }
+    }
+
+    pub fn get(index: usize) -> Option<Record> {
+        locked(|log| log.get(index).copied())
+    }
+    pub fn read(filter: &Filter, offset: u64, out: &mut [u8]) -> usize {
+        locked(|log| log.read(filter, offset, out))
+    }
+    pub fn get_size(filter: &Filter) -> u64 {
+        locked(|log| log.get_size(filter))
+    }
+    pub fn set_level(subsystem: &str, level: Level) -> bool {
+        locked(|log| log.set_level(subsystem, level))
+    }
+    pub fn set_default_level(level: Level) {
+        locked(|log| log.set_default_level(level))
+    }
+}
+
+console_commands! {
+    Command {
+        name: "dmesg",
+        category: "log",
+        desc: "show the kernel log",
+        help: "Filters are a level (error, warn, info, debug, trace), which shows that and\n\
+               anything more severe, and/or a subsystem, e.g `dmesg warn` or `dmesg pmm debug`.\n\
+               `dmesg clear` empties the ring.",
+        args: &[ArgSpec::optional("filter", ArgKind::Rest)],
+        handler: |state, args| {
+            let words = args.get_rest(1);
+            if words.trim() == "clear" {
+                locked(|log| log.clear());
+                return;
+            }
+            let Some(filter) = Filter::parse(words) else {
+                console_error!(state, "at most one subsystem\r\n");
+                return;
+            };
+            let overwritten = locked(|log| log.get_overwritten());
+            if overwritten > 0 {
+                kprint!("({overwritten} older records overwritten)\r\n");
+            }
+            // One at a time, printing with interrupts off would poll the UART
+            for record in (0..).map_while(Manager::get).filter(|r| filter.matches(r)) {
+                kprint!("{record}\r\n");
+            }
+        },
+    },
+    Command {
+        name: "loglevel",
+        category: "log",
+        desc: "show or set log levels",
+        help: "Without arguments lists the default and every subsystem seen so far, `all` as\n\
+               the subsystem sets the default and drops the per-subsystem levels.",
+        args: &[ArgSpec::optional("subsystem", ArgKind::Word), ArgSpec::optional("level", ArgKind::Word)],
+        handler: |state, args| {
+            let Some(subsystem) = args.get(1) else {
+                let default = locked(|log| log.get_default_level());
+                kprint!("{:<SUBSYSTEM_LEN$} {}\r\n", "all", default.as_str());
+                for index in 0..MAX_SUBSYSTEMS {
+                    let found = locked(|log| log.get_subsystem(index).map(|(n, l, o)| (Text::<SUBSYSTEM_LEN>::from(n), l, o)));
+                    let Some((name, level, own)) = found else {
+                        break;
+                    };
+                    kprint!("{:<SUBSYSTEM_LEN$} {}{}\r\n", name.as_str(), level.as_str(), if own { "" } else { " (default)" });
+                }
+                return;
+            };
+            let Some(level) = args.get(2) else {
+                kprint!("{}\r\n", locked(|log| log.get_level(subsystem)).as_str());
+                return;
+            };
+            let Some(level) = Level::parse(level) else {
+                console_error!(state, "<level> is one of error, warn, info, debug, trace\r\n");
+                return;
+            };
+            if subsystem == "all" {
+                Manager::set_default_level(level);
+            } else if !Manager::set_level(subsystem, level) {
+                console_error!(state, "too many subsystems\r\n");
+            }
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(level: Level, subsystem: &str, message: &str) -> Record {
+        Record::new(0, level, subsystem, format_args!("{message}"))
+    }
+
+    #[test]
+    fn levels_per_subsystem() {
+        let mut log = Log::<4>::new();
+        assert!(log.is_enabled(Level::Info, "pmm"));
+        assert!(!log.is_enabled(Level::Debug, "pmm"));
+        assert!(log.set_level("pmm", Level::Debug));
+        assert!(log.is_enabled(Level::Debug, "pmm"));
+        assert!(!log.is_enabled(Level::Debug, "vmm"));
+        log.set_default_level(Level::Error);
+        assert!(!log.is_enabled(Level::Warn, "pmm"));
+    }
+
+    #[test]
+    fn ring_keeps_newest() {
+        let mut log = Log::<2>::new();
+        for message in ["a", "b", "c"] {
+            log.push(record(Level::Info, "task", message));
+        }
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.get_overwritten(), 1);
+        assert_eq!(log.get(0).unwrap().message.as_str(), "b");
+    }
+
+    #[test]
+    fn filter_and_read() {
+        let mut log = Log::<4>::new();
+        log.push(record(Level::Warn, "pic", "unhandled irq 9"));
+        log.push(record(Level::Info, "pmm", "add memory"));
+        let filter = Filter::parse("warn").unwrap();
+        let mut out = [0u8; 128];
+        let len = log.read(&filter, 0, &mut out);
+        assert_eq!(core::str::from_utf8(&out[..len]).unwrap(), "[    0.000000] warn  pic: unhandled irq 9\n");
+        assert_eq!(log.get_size(&filter), len as u64);
+        // Picks up mid-line and runs into the next record
+        let len = log.read(&Filter::ALL, 36, &mut out[..16]);
+        assert_eq!(core::str::from_utf8(&out[..len]).unwrap(), "irq 9\n[    0.000");
+        assert_eq!(Filter::parse("pmm debug"), Some(Filter { level: Level::Debug, subsystem: Some("pmm") }));
+        assert_eq!(Filter::parse("pmm vmm"), None);
+    }
+
+    #[test]
+    fn text_truncates_on_char_boundary() {
+        let text = Text::<4>::from("abcé");
+        assert_eq!(text.as_str(), "abc");
+    }
+}
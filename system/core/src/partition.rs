@@ -0,0 +1,495 @@
+//! Partition tables
+//!
+//! `Manager::scan` reads a disk's GPT, or its MBR when it has none, and
+//! registers each partition as a block device of its own under the disk's
+//! node, numbered like Linux does: `/devices/ata0/part1` for the first GPT
+//! entry or MBR slot, logical MBR partitions from `part5` on. A GPT header
+//! or entry array failing its CRC is read from the backup at the end of the
+//! disk instead. Partitions pass their requests on to the disk shifted by
+//! their start, so filesystems mount them like any other device.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt;
+
+use crate::{block, db, klog, kprint, vfs};
+use crate::console::{self, ArgKind, ArgSpec, Command};
+use crate::{console_commands, console_error};
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Logical partitions followed down an extended one before giving up on a loop
+const MAX_LOGICAL: usize = 64;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Of the header, what the CRC covers can be more
+const GPT_HEADER_SIZE: usize = 92;
+/// What a GPT entry array may take, 128 entries of 128 bytes is the usual
+const MAX_GPT_ENTRIES: u64 = 64 * 1024;
+/// UTF-16 units in a GPT entry's name
+const GPT_NAME: usize = 36;
+
+/// CRC-32 as GPT (and zlib) has it
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| (0..8).fold(crc ^ b as u32, |crc, _| (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())))
+}
+
+/// A boot sector ends in the MBR signature as well, only the boot indicator
+/// of every slot being 0x00 or 0x80 tells a partition table from boot code
+fn is_mbr(sector: &[u8]) -> bool {
+    u16::from_le_bytes([sector[510], sector[511]]) == MBR_SIGNATURE && (0..4).all(|i| matches!(sector[446 + i * 16], 0x00 | 0x80))
+}
+
+fn get32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+fn get64(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+/// As stored, the first three fields little endian
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+impl Guid {
+    pub const EFI_SYSTEM: Self = Self::new(0xc12a7328, 0xf81f, 0x11d2, [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]);
+    pub const BASIC_DATA: Self = Self::new(0xebd0a0a2, 0xb9e5, 0x4433, [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7]);
+    pub const LINUX_DATA: Self = Self::new(0x0fc63daf, 0x8483, 0x4772, [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4]);
+    pub const LINUX_SWAP: Self = Self::new(0x0657fd6d, 0xa4ab, 0x43c4, [0x84, 0xe5, 0x09, 0x33, 0xc8, 0x4b, 0x4f, 0x4f]);
+    /// From the way it is written, `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`
+    pub const fn new(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let (a, b, c) = (a.to_le_bytes(), b.to_le_bytes(), c.to_le_bytes());
+        Self([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]])
+    }
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = &self.0;
+        write!(f, "{:08X}-{:04X}-{:04X}-", get32(g, 0), u16::from_le_bytes([g[4], g[5]]), u16::from_le_bytes([g[6], g[7]]))?;
+        write!(f, "{:02X}{:02X}-", g[8], g[9])?;
+        g[10..].iter().try_for_each(|b| write!(f, "{b:02X}"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Gpt(Guid),
+    /// The MBR system id
+    Mbr(u8),
+}
+impl Kind {
+    /// What the type is usually for, `None` when not known here
+    pub fn describe(&self) -> Option<&'static str> {
+        match *self {
+            Kind::Gpt(Guid::EFI_SYSTEM) | Kind::Mbr(0xef) => Some("EFI system"),
+            Kind::Gpt(Guid::BASIC_DATA) => Some("basic data"),
+            Kind::Gpt(Guid::LINUX_DATA) | Kind::Mbr(0x83) => Some("Linux data"),
+            Kind::Gpt(Guid::LINUX_SWAP) | Kind::Mbr(0x82) => Some("Linux swap"),
+            Kind::Mbr(0x01) => Some("FAT12"),
+            Kind::Mbr(0x04 | 0x06 | 0x0e) => Some("FAT16"),
+            Kind::Mbr(0x0b | 0x0c) => Some("FAT32"),
+            Kind::Mbr(0x07) => Some("NTFS/exFAT"),
+            _ => None,
+        }
+    }
+}
+
+/// The driver data of a partition's device
+pub struct Partition {
+    disk: block::DeviceHandle,
+    /// First sector on the disk
+    start: u64,
+    kind: Kind,
+    /// From the GPT entry, empty for MBR
+    name: String,
+    /// What the disk is doing for the request the partition started
+    request: Option<block::RequestHandle>,
+}
+impl Partition {
+    pub fn get_disk(&self) -> block::DeviceHandle {
+        self.disk
+    }
+    pub fn get_start(&self) -> u64 {
+        self.start
+    }
+    pub fn get_kind(&self) -> Kind {
+        self.kind
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// What a table says before it is registered, `number` is the N of `partN`
+struct Found {
+    number: usize,
+    start: u64,
+    count: u64,
+    kind: Kind,
+    name: String,
+}
+
+pub struct Manager;
+impl Manager {
+    /// Registers the partitions of a disk under it, how many there were.
+    /// `Error::Exists` once the disk was scanned
+    pub fn scan(db: &mut db::Database, disk: block::DeviceHandle) -> Result<usize, block::Error> {
+        let node = block::Manager::get_device(db, disk).get_node();
+        if vfs::Manager::get_node(db, node).get_child_count() != 0 || Self::get_partition(db, disk).is_some() {
+            return Err(block::Error::Exists);
+        }
+        let sector_size = block::Manager::get_device(db, disk).get_sector_size() as usize;
+        let mut mbr = alloc::vec![0u8; sector_size.max(512)];
+        block::Manager::read_bytes(db, disk, 0, &mut mbr[..512])?;
+        let has_mbr = is_mbr(&mbr);
+        let protective = has_mbr && (0..4).any(|i| mbr[446 + i * 16 + 4] == MBR_PROTECTIVE);
+        // A GPT needs the protective MBR in front of it, but not every tool writes one
+        let found = match Self::read_gpt(db, disk)? {
+            Some(found) => found,
+            None if protective => {
+                klog!(Warn, "partition", "{}: protective MBR but no usable GPT", vfs::Manager::get_path(db, node));
+                Vec::new()
+            }
+            None if has_mbr => Self::read_mbr(db, disk, &mbr)?,
+            None => Vec::new(),
+        };
+        let sector_count = block::Manager::get_device(db, disk).get_sector_count();
+        let mut registered = 0;
+        for p in found {
+            if p.count == 0 || p.start.checked_add(p.count).is_none_or(|end| end > sector_count) {
+                klog!(Warn, "partition", "{}: part{} is past the end of the disk", vfs::Manager::get_path(db, node), p.number);
+                continue;
+            }
+            let driver = block::Driver { start: Self::start, poll: Self::poll, ..block::Driver::DEFAULT };
+            let data = Partition { disk, start: p.start, kind: p.kind, name: p.name, request: None };
+            let name = alloc::format!("part{}", p.number);
+            let part = block::Manager::register_at(db, node, &name, sector_size as u32, p.count, driver, Some(Box::new(data)))?;
+            block::Manager::set_parent(db, part, disk, p.start);
+            registered += 1;
+        }
+        Ok(registered)
+    }
+    /// What the partition's device registered with, `None` for any other device
+    pub fn get_partition(db: &db::Database, device: block::DeviceHandle) -> Option<&Partition> {
+        block::Manager::get_data::<Partition>(db, device)
+    }
+
+    /// The entries of the primary GPT, or the backup one's where the primary
+    /// fails a check, `None` with neither
+    fn read_gpt(db: &mut db::Database, disk: block::DeviceHandle) -> Result<Option<Vec<Found>>, block::Error> {
+        let last = block::Manager::get_device(db, disk).get_sector_count().saturating_sub(1);
+        for (lba, what) in [(1, "primary"), (last, "backup")] {
+            match Self::read_gpt_at(db, disk, lba)? {
+                Some(found) => {
+                    if lba != 1 {
+                        let node = block::Manager::get_device(db, disk).get_node();
+                        klog!(Warn, "partition", "{}: primary GPT is damaged, using the backup", vfs::Manager::get_path(db, node));
+                    }
+                    return Ok(Some(found));
+                }
+                None => klog!(Debug, "partition", "no valid {what} GPT header at {lba}"),
+            }
+        }
+        Ok(None)
+    }
+    fn read_gpt_at(db: &mut db::Database, disk: block::DeviceHandle, lba: u64) -> Result<Option<Vec<Found>>, block::Error> {
+        let d = block::Manager::get_device(db, disk);
+        let (sector_size, sector_count) = (d.get_sector_size() as usize, d.get_sector_count());
+        if lba == 0 || lba >= sector_count || sector_size < GPT_HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut header = alloc::vec![0u8; sector_size];
+        block::Manager::read(db, disk, lba, &mut header)?;
+        let size = get32(&header, 12) as usize;
+        if &header[..8] != GPT_SIGNATURE || !(GPT_HEADER_SIZE..=sector_size).contains(&size) || get64(&header, 24) != lba {
+            return Ok(None);
+        }
+        let crc = get32(&header, 16);
+        header[16..20].fill(0);
+        if crc32(&header[..size]) != crc {
+            return Ok(None);
+        }
+        let (entries_lba, count, entry_size) = (get64(&header, 72), get32(&header, 80) as u64, get32(&header, 84) as u64);
+        let bytes = count * entry_size;
+        if entry_size < 128 || !entry_size.is_multiple_of(8) || bytes > MAX_GPT_ENTRIES * 128 {
+            return Ok(None);
+        }
+        let sectors = bytes.div_ceil(sector_size as u64);
+        if entries_lba.checked_add(sectors).is_none_or(|end| end > sector_count) {
+            return Ok(None);
+        }
+        let mut entries = alloc::vec![0u8; (sectors as usize) * sector_size];
+        block::Manager::read(db, disk, entries_lba, &mut entries)?;
+        if crc32(&entries[..bytes as usize]) != get32(&header, 88) {
+            return Ok(None);
+        }
+        let mut found = Vec::new();
+        for (i, entry) in entries[..bytes as usize].chunks_exact(entry_size as usize).enumerate() {
+            let kind = Guid(entry[..16].try_into().unwrap());
+            if kind.is_nil() {
+                continue;
+            }
+            let (first, last) = (get64(entry, 32), get64(entry, 40));
+            let units = (0..GPT_NAME).map(|u| u16::from_le_bytes([entry[56 + u * 2], entry[57 + u * 2]])).take_while(|&u| u != 0);
+            let name = char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect();
+            let count = (last + 1).saturating_sub(first);
+            found.push(Found { number: i + 1, start: first, count, kind: Kind::Gpt(kind), name });
+        }
+        Ok(Some(found))
+    }
+
+    /// The four slots, then the logical partitions an extended one chains
+    fn read_mbr(db: &mut db::Database, disk: block::DeviceHandle, mbr: &[u8]) -> Result<Vec<Found>, block::Error> {
+        let slot = |sector: &[u8], i: usize| {
+            let at = 446 + i * 16;
+            (sector[at + 4], get32(sector, at + 8) as u64, get32(sector, at + 12) as u64)
+        };
+        let mut found = Vec::new();
+        let mut extended = None;
+        for i in 0..4 {
+            let (id, start, count) = slot(mbr, i);
+            match id {
+                0 => {}
+                id if MBR_EXTENDED.contains(&id) => extended = extended.or(Some(start)),
+                id => found.push(Found { number: i + 1, start, count, kind: Kind::Mbr(id), name: String::new() }),
+            }
+        }
+        // Each EBR has the logical partition relative to itself, then the
+        // next EBR relative to the extended partition
+        let Some(base) = extended else {
+            return Ok(found);
+        };
+        let mut ebr = base;
+        let mut sector = [0u8; 512];
+        for number in 5..5 + MAX_LOGICAL {
+            let sector_size = block::Manager::get_device(db, disk).get_sector_size() as u64;
+            if block::Manager::read_bytes(db, disk, ebr * sector_size, &mut sector).is_err() || !is_mbr(&sector) {
+                break;
+            }
+            let (id, start, count) = slot(&sector, 0);
+            if id != 0 {
+                found.push(Found { number, start: ebr + start, count, kind: Kind::Mbr(id), name: String::new() });
+            }
+            match slot(&sector, 1) {
+                (id, next, _) if MBR_EXTENDED.contains(&id) && next != 0 => ebr = base + next,
+                _ => break,
+            }
+        }
+        Ok(found)
+    }
+
+    /// Hands the request to the disk, moved by where the partition starts
+    fn start(db: &mut db::Database, device: block::DeviceHandle, request: &mut block::Request) -> Option<Result<(), block::Error>> {
+        let p = Self::get_partition(db, device)?;
+        let (disk, lba) = (p.disk, request.lba + p.start);
+        match block::Manager::submit(db, disk, request.op, lba, core::mem::take(&mut request.data)) {
+            Ok(handle) => {
+                block::Manager::get_data_mut::<Partition>(db, device)?.request = Some(handle);
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+    fn poll(db: &mut db::Database, device: block::DeviceHandle, request: &mut block::Request) -> Option<Result<(), block::Error>> {
+        let Some(handle) = Self::get_partition(db, device)?.request else {
+            return Some(Err(block::Error::Unknown));
+        };
+        let result = block::Manager::poll(db, handle)?;
+        block::Manager::get_data_mut::<Partition>(db, device)?.request = None;
+        Some(result.map(|data| request.data = data))
+    }
+}
+
+console_commands! {
+    Command {
+        name: "partitions",
+        category: "devices",
+        desc: "scan and list a disk's partitions",
+        help: "Reads the disk's GPT or MBR the first time, then lists what is under it: start and sectors, type and GPT name.",
+        args: &[ArgSpec::required("disk", ArgKind::Word)],
+        handler: |state, args| {
+            let node = console::Manager::resolve_path(state, args.get(1).unwrap());
+            let Some(disk) = node.ok().and_then(|node| block::Manager::find_by_node(state.db, node)) else {
+                console_error!(state, "not a block device\r\n");
+                return;
+            };
+            match Manager::scan(state.db, disk) {
+                Ok(_) | Err(block::Error::Exists) => {}
+                Err(e) => {
+                    console_error!(state, "{:?}\r\n", e);
+                    return;
+                }
+            }
+            block::Manager::for_each_device(state.db, |device, d| {
+                let Some(p) = Manager::get_partition(state.db, device).filter(|p| p.disk == disk) else {
+                    return;
+                };
+                let name = vfs::Manager::get_node(state.db, d.get_node()).get_name();
+                kprint!("{name} {}+{} ", p.start, d.get_sector_count());
+                match p.kind {
+                    Kind::Gpt(guid) => kprint!("{guid}"),
+                    Kind::Mbr(id) => kprint!("{id:#04x}"),
+                }
+                kprint!(" {} {:?}\r\n", p.kind.describe().unwrap_or("unknown"), p.name);
+            });
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::Subsystem::*;
+
+    fn path(db: &db::Database, path: &str) -> Result<vfs::NodeHandle, vfs::Error> {
+        vfs::Manager::resolve_path(db, db::ObjectHandle::default(), vfs::NodeHandle::default(), path)
+    }
+
+    fn put32(b: &mut [u8], at: usize, value: u32) {
+        b[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    fn put64(b: &mut [u8], at: usize, value: u64) {
+        b[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// A disk of `sectors` with a protective MBR and both GPT headers and
+    /// entry arrays, `(type, first, last, name)` in the entries
+    fn gpt_image(sectors: u64, parts: &[(Guid, u64, u64, &str)]) -> Vec<u8> {
+        let mut image = alloc::vec![0u8; sectors as usize * 512];
+        image[446 + 4] = MBR_PROTECTIVE;
+        put32(&mut image, 446 + 8, 1);
+        put32(&mut image, 446 + 12, (sectors - 1) as u32);
+        image[510..512].copy_from_slice(&MBR_SIGNATURE.to_le_bytes());
+        let mut entries = alloc::vec![0u8; 128 * 128];
+        for (i, (kind, first, last, name)) in parts.iter().enumerate() {
+            let entry = &mut entries[i * 128..(i + 1) * 128];
+            entry[..16].copy_from_slice(&kind.0);
+            entry[16] = i as u8 + 1;
+            put64(entry, 32, *first);
+            put64(entry, 40, *last);
+            for (u, unit) in name.encode_utf16().enumerate() {
+                entry[56 + u * 2..58 + u * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        for (lba, other, entries_lba) in [(1, sectors - 1, 2), (sectors - 1, 1, sectors - 33)] {
+            let mut header = [0u8; 92];
+            header[..8].copy_from_slice(GPT_SIGNATURE);
+            put32(&mut header, 8, 0x10000);
+            put32(&mut header, 12, 92);
+            put64(&mut header, 24, lba);
+            put64(&mut header, 32, other);
+            put64(&mut header, 40, 34);
+            put64(&mut header, 48, sectors - 34);
+            put64(&mut header, 72, entries_lba);
+            put32(&mut header, 80, 128);
+            put32(&mut header, 84, 128);
+            put32(&mut header, 88, crc32(&entries));
+            let crc = crc32(&header);
+            put32(&mut header, 16, crc);
+            image[lba as usize * 512..][..92].copy_from_slice(&header);
+            image[entries_lba as usize * 512..][..entries.len()].copy_from_slice(&entries);
+        }
+        image
+    }
+
+    #[test]
+    fn crc_and_guid_text() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(std::format!("{}", Guid::EFI_SYSTEM), "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+        assert_eq!(&Guid::EFI_SYSTEM.0[..4], &[0x28, 0x73, 0x2a, 0xc1]);
+    }
+
+    #[test]
+    fn gpt_partitions_sit_under_the_disk() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs, Block, Fat]);
+        let image = gpt_image(8192, &[(Guid::EFI_SYSTEM, 2048, 6143, "EFI system"), (Guid::LINUX_DATA, 6144, 8158, "data")]);
+        let disk = block::Manager::new_ram_disk(&mut db, "ram0", 512, image).unwrap();
+        assert_eq!(Manager::scan(&mut db, disk), Ok(2));
+        assert_eq!(Manager::scan(&mut db, disk), Err(block::Error::Exists));
+        let node = path(&db, "/devices/ram0/part2").unwrap();
+        let part = block::Manager::find_by_node(&db, node).unwrap();
+        let p = Manager::get_partition(&db, part).unwrap();
+        assert_eq!((p.get_start(), p.get_kind(), p.get_name()), (6144, Kind::Gpt(Guid::LINUX_DATA), "data"));
+        assert_eq!(block::Manager::get_device(&db, part).get_sector_count(), 2015);
+        assert_eq!(Manager::scan(&mut db, part), Err(block::Error::Exists));
+        // Lands on the disk where the partition starts
+        let actor = db::ObjectHandle::default();
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, node, 10, b"abc"), Ok(3));
+        block::Manager::flush(&mut db, part).unwrap();
+        let mut back = [0u8; 3];
+        block::Manager::read_bytes(&mut db, disk, 6144 * 512 + 10, &mut back).unwrap();
+        assert_eq!(&back, b"abc");
+        // Both have the sectors cached, neither reads the other's stale
+        block::Manager::write_bytes(&mut db, disk, 6144 * 512 + 10, b"xyz").unwrap();
+        block::Manager::read_bytes(&mut db, part, 10, &mut back).unwrap();
+        assert_eq!(&back, b"xyz");
+        block::Manager::write_bytes(&mut db, part, 11, b"!").unwrap();
+        block::Manager::read_bytes(&mut db, disk, 6144 * 512 + 10, &mut back).unwrap();
+        assert_eq!(&back, b"x!z");
+        assert_eq!(vfs::Manager::stat(&db, actor, path(&db, "/devices/ram0").unwrap()).unwrap().children, 2);
+        // And mounts on its own
+        let part1 = block::Manager::find_by_node(&db, path(&db, "/devices/ram0/part1").unwrap()).unwrap();
+        crate::fat::Manager::format(&mut db, part1, None).unwrap();
+        let (mount, source) = (path(&db, "/mount").unwrap(), path(&db, "/devices/ram0/part1").unwrap());
+        let dir = vfs::Manager::create(&mut db, actor, mount, "esp", vfs::NodeKind::Directory).unwrap();
+        let fat = vfs::Manager::find_fs(&db, "fat").unwrap();
+        vfs::Manager::mount(&mut db, actor, fat, dir, Some(source)).unwrap();
+        vfs::Manager::create(&mut db, actor, dir, "boot", vfs::NodeKind::Directory).unwrap();
+        vfs::Manager::unmount(&mut db, actor, dir).unwrap();
+        let mut boot = [0u8; 512];
+        block::Manager::read_bytes(&mut db, disk, 2048 * 512, &mut boot).unwrap();
+        assert_eq!(&boot[510..], &[0x55, 0xaa]);
+    }
+
+    #[test]
+    fn damaged_gpt_falls_back_to_the_backup() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs, Block, Fat]);
+        let mut image = gpt_image(4096, &[(Guid::BASIC_DATA, 34, 4000, "")]);
+        image[512 + 40] ^= 1;
+        let disk = block::Manager::new_ram_disk(&mut db, "ram0", 512, image.clone()).unwrap();
+        assert_eq!(Manager::scan(&mut db, disk), Ok(1));
+        // Entries failing their CRC are as bad as the header
+        image[4093 * 512] ^= 1;
+        let disk = block::Manager::new_ram_disk(&mut db, "ram1", 512, image).unwrap();
+        assert_eq!(Manager::scan(&mut db, disk), Ok(0));
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs, Block, Fat]);
+        let mut image = alloc::vec![0u8; 4096 * 512];
+        let mut slot = |sector: usize, i: usize, id: u8, start: u32, count: u32| {
+            let at = sector * 512 + 446 + i * 16;
+            image[at + 4] = id;
+            put32(&mut image, at + 8, start);
+            put32(&mut image, at + 12, count);
+            image[sector * 512 + 510..sector * 512 + 512].copy_from_slice(&MBR_SIGNATURE.to_le_bytes());
+        };
+        slot(0, 0, 0x0c, 63, 1000);
+        slot(0, 2, 0x05, 2000, 2000);
+        // Two logical partitions, each EBR right in front of its own
+        slot(2000, 0, 0x83, 1, 499);
+        slot(2000, 1, 0x05, 500, 1000);
+        slot(2500, 0, 0x82, 1, 999);
+        let disk = block::Manager::new_ram_disk(&mut db, "ram0", 512, image).unwrap();
+        assert_eq!(Manager::scan(&mut db, disk), Ok(3));
+        let start = |db: &db::Database, name: &str| {
+            let device = block::Manager::find_by_node(db, path(db, name).unwrap()).unwrap();
+            let p = Manager::get_partition(db, device).unwrap();
+            (p.get_start(), p.get_kind())
+        };
+        assert_eq!(start(&db, "/devices/ram0/part1"), (63, Kind::Mbr(0x0c)));
+        assert_eq!(start(&db, "/devices/ram0/part5"), (2001, Kind::Mbr(0x83)));
+        assert_eq!(start(&db, "/devices/ram0/part6"), (2501, Kind::Mbr(0x82)));
+        assert!(path(&db, "/devices/ram0/part3").is_err());
+        let blank = block::Manager::new_ram_disk(&mut db, "ram1", 512, alloc::vec![0; 4096]).unwrap();
+        assert_eq!(Manager::scan(&mut db, blank), Ok(0));
+        // A FAT volume without partitions ends its boot sector the same way
+        let volume = block::Manager::new_ram_disk(&mut db, "ram2", 512, alloc::vec![0; 4096 * 512]).unwrap();
+        crate::fat::Manager::format(&mut db, volume, None).unwrap();
+        // with boot code that would read as a slot from sector 1 on
+        block::Manager::write_bytes(&mut db, volume, 446, &[0xeb, 0x3c, 0x90, 0, 0x0c, 0, 0, 0, 1, 0, 0, 0, 16, 0, 0, 0]).unwrap();
+        assert_eq!(Manager::scan(&mut db, volume), Ok(0));
+    }
+}
@@ -0,0 +1,267 @@
+//! PCI configuration space through the legacy 0xcf8/0xcfc ports
+//!
+//! Enough for drivers to find their device by class, size and decode its
+//! BARs and let it master the bus. Every bus is walked by brute force, no
+//! bridge is looked at and nothing gets (re)assigned, the firmware already
+//! placed the BARs and picked the interrupt line.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::console::Command;
+use crate::{console_commands, cpu, kprint};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// Config space offsets, the same for every header type
+const VENDOR_ID: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0e;
+const BARS: u8 = 0x10;
+const INTERRUPT_LINE: u8 = 0x3c;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+/// Only header type 0 (a plain device) has six BARs, bridges have two
+const MAX_BARS: usize = 6;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+impl Address {
+    /// What goes into CONFIG_ADDRESS to reach the dword at `offset`
+    fn get_config(self, offset: u8) -> u32 {
+        0x8000_0000
+            | (self.bus as u32) << 16
+            | (self.device as u32 & 0x1f) << 11
+            | (self.function as u32 & 0x07) << 8
+            | (offset as u32 & 0xfc)
+    }
+}
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A function that answered, as its config header describes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Function {
+    pub address: Address,
+    pub vendor: u16,
+    pub device: u16,
+    /// Class, subclass and programming interface
+    pub class: (u8, u8, u8),
+    pub header_type: u8,
+    /// The PIC line the firmware routed INTx to, 0xff for none
+    pub interrupt_line: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { base: u64, size: u64, prefetchable: bool },
+    Io { base: u16, size: u16 },
+}
+impl Bar {
+    /// `value` is what the BAR holds and `mask` what it reads back after all
+    /// ones were written, with the upper dword of a 64 bit BAR on top of
+    /// both. `None` for an unimplemented BAR
+    fn decode(value: u64, mask: u64) -> Option<Self> {
+        if value & 1 != 0 {
+            let size = (!(mask as u32 & !0x3)).wrapping_add(1) as u16;
+            return (size != 0).then_some(Self::Io { base: (value as u32 & !0x3) as u16, size });
+        }
+        // A 32 bit BAR sizes as if all its upper bits were set
+        let (mask, implemented) = match Self::is_64bit(value as u32) {
+            true => (mask & !0xf, mask & !0xf != 0),
+            false => (0xffff_ffff_0000_0000 | mask & 0xffff_fff0, mask & 0xffff_fff0 != 0),
+        };
+        let size = (!mask).wrapping_add(1);
+        implemented.then_some(Self::Memory { base: value & !0xf, size, prefetchable: value & 0x8 != 0 })
+    }
+    fn is_64bit(low: u32) -> bool {
+        low & 1 == 0 && (low >> 1) & 0x3 == 0x2
+    }
+}
+
+pub struct Manager;
+impl Manager {
+    pub fn read_u32(address: Address, offset: u8) -> u32 {
+        cpu::Manager::port_write_u32(CONFIG_ADDRESS, address.get_config(offset));
+        cpu::Manager::port_read_u32(CONFIG_DATA)
+    }
+    pub fn write_u32(address: Address, offset: u8, value: u32) {
+        cpu::Manager::port_write_u32(CONFIG_ADDRESS, address.get_config(offset));
+        cpu::Manager::port_write_u32(CONFIG_DATA, value);
+    }
+    pub fn read_u16(address: Address, offset: u8) -> u16 {
+        cpu::Manager::port_write_u32(CONFIG_ADDRESS, address.get_config(offset));
+        cpu::Manager::port_read_u16(CONFIG_DATA + (offset & 2) as u16)
+    }
+    pub fn write_u16(address: Address, offset: u8, value: u16) {
+        cpu::Manager::port_write_u32(CONFIG_ADDRESS, address.get_config(offset));
+        cpu::Manager::port_write_u16(CONFIG_DATA + (offset & 2) as u16, value);
+    }
+    pub fn read_u8(address: Address, offset: u8) -> u8 {
+        cpu::Manager::port_write_u32(CONFIG_ADDRESS, address.get_config(offset));
+        cpu::Manager::port_read_u8(CONFIG_DATA + (offset & 3) as u16)
+    }
+    pub fn write_u8(address: Address, offset: u8, value: u8) {
+        cpu::Manager::port_write_u32(CONFIG_ADDRESS, address.get_config(offset));
+        cpu::Manager::port_write_u8(CONFIG_DATA + (offset & 3) as u16, value);
+    }
+
+    /// `None` when nothing answers at `address`
+    pub fn get_function(address: Address) -> Option<Function> {
+        let vendor = Self::read_u16(address, VENDOR_ID);
+        if vendor == 0xffff {
+            return None;
+        }
+        let class = Self::read_u32(address, CLASS);
+        Some(Function {
+            address,
+            vendor,
+            device: Self::read_u16(address, VENDOR_ID + 2),
+            class: ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8),
+            header_type: Self::read_u8(address, HEADER_TYPE),
+            interrupt_line: Self::read_u8(address, INTERRUPT_LINE),
+        })
+    }
+    /// Every function on every bus, in address order
+    pub fn for_each_function<F: FnMut(&Function)>(mut f: F) {
+        for bus in 0..=255u8 {
+            for device in 0..32 {
+                let Some(first) = Self::get_function(Address { bus, device, function: 0 }) else {
+                    continue;
+                };
+                f(&first);
+                if first.header_type & HEADER_MULTIFUNCTION == 0 {
+                    continue;
+                }
+                for function in 1..8 {
+                    if let Some(other) = Self::get_function(Address { bus, device, function }) {
+                        f(&other);
+                    }
+                }
+            }
+        }
+    }
+    pub fn find(class: (u8, u8, u8)) -> Vec<Function> {
+        let mut found = Vec::new();
+        Self::for_each_function(|f| {
+            if f.class == class {
+                found.push(*f);
+            }
+        });
+        found
+    }
+
+    /// BAR `index` with its size, which means writing all ones to it for a
+    /// moment, so decoding is off meanwhile
+    pub fn get_bar(address: Address, index: usize) -> Option<Bar> {
+        if index >= MAX_BARS {
+            return None;
+        }
+        let offset = BARS + index as u8 * 4;
+        let command = Self::read_u16(address, COMMAND);
+        Self::write_u16(address, COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+        let size_dword = |offset| {
+            let value = Self::read_u32(address, offset);
+            Self::write_u32(address, offset, u32::MAX);
+            let mask = Self::read_u32(address, offset);
+            Self::write_u32(address, offset, value);
+            (value, mask)
+        };
+        let (low, low_mask) = size_dword(offset);
+        let (high, high_mask) = match Bar::is_64bit(low) && index + 1 < MAX_BARS {
+            true => size_dword(offset + 4),
+            false => (0, 0),
+        };
+        Self::write_u16(address, COMMAND, command);
+        Bar::decode((high as u64) << 32 | low as u64, (high_mask as u64) << 32 | low_mask as u64)
+    }
+    /// Turns on memory decoding and DMA, and INTx in case the firmware masked it
+    pub fn enable_bus_master(address: Address) {
+        let command = Self::read_u16(address, COMMAND);
+        Self::write_u16(address, COMMAND, (command | COMMAND_MEMORY | COMMAND_BUS_MASTER) & !COMMAND_INTX_DISABLE);
+    }
+
+    fn describe(class: (u8, u8, u8)) -> &'static str {
+        match class {
+            (0x01, 0x01, _) => "IDE",
+            (0x01, 0x06, 0x01) => "SATA (AHCI)",
+            (0x01, 0x08, _) => "NVMe",
+            (0x01, _, _) => "storage",
+            (0x02, _, _) => "network",
+            (0x03, _, _) => "display",
+            (0x04, _, _) => "multimedia",
+            (0x06, 0x00, _) => "host bridge",
+            (0x06, 0x01, _) => "ISA bridge",
+            (0x06, 0x04, _) => "PCI bridge",
+            (0x06, _, _) => "bridge",
+            (0x0c, 0x03, _) => "USB",
+            (0x0c, 0x05, _) => "SMBus",
+            _ => "other",
+        }
+    }
+}
+
+console_commands! {
+    Command {
+        name: "pci",
+        category: "devices",
+        desc: "PCI functions",
+        help: "Every function that answers: address, vendor:device, class.subclass.interface and the interrupt line the firmware gave it.",
+        args: &[],
+        handler: |_state, _args| {
+            Manager::for_each_function(|f| {
+                let (class, subclass, interface) = f.class;
+                kprint!(
+                    "{} {:04x}:{:04x} {class:02x}.{subclass:02x}.{interface:02x} {}",
+                    f.address,
+                    f.vendor,
+                    f.device,
+                    Manager::describe(f.class)
+                );
+                match f.interrupt_line {
+                    line if line < 16 => kprint!(" irq {line}\r\n"),
+                    _ => kprint!("\r\n"),
+                }
+            });
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_address_packs_bus_device_function() {
+        let address = Address { bus: 0, device: 0x1f, function: 2 };
+        assert_eq!(address.get_config(0x24), 0x8000_fa24);
+        assert_eq!(Address { bus: 3, device: 1, function: 0 }.get_config(0x3e), 0x8003_083c);
+        assert_eq!(std::format!("{address}"), "00:1f.2");
+    }
+
+    #[test]
+    fn bars_decode_with_their_size() {
+        // 32 bit memory, 8 KiB
+        assert_eq!(Bar::decode(0xfebd_5000, 0xffff_e000), Some(Bar::Memory { base: 0xfebd_5000, size: 0x2000, prefetchable: false }));
+        // 64 bit prefetchable, 4 GiB up high
+        assert!(Bar::is_64bit(0x0000_000c));
+        let bar = Bar::decode(0x8_0000_000c, 0xffff_ffff_0000_000c);
+        assert_eq!(bar, Some(Bar::Memory { base: 0x8_0000_0000, size: 0x1_0000_0000, prefetchable: true }));
+        // 32 bytes of ports
+        assert_eq!(Bar::decode(0xc041, 0xffff_ffff_ffff_ffe1), Some(Bar::Io { base: 0xc040, size: 0x20 }));
+        assert_eq!(Bar::decode(0, 0), None);
+    }
+}
@@ -0,0 +1,96 @@
+//! Legacy 8259 pair, remapped above the exceptions
+//!
+//! Firmware leaves IRQ 0-7 on vectors 0x08-0x0f, right on top of #DF..#GP, so
+//! `init` moves them to `IRQ_BASE` and masks everything. Drivers unmask their
+//! line with `register`, anything else that still fires is logged and acked.
+
+use crate::{cpu, klog};
+
+pub const IRQ_BASE: u8 = 0x20;
+pub const IRQ_COUNT: u8 = 16;
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+const CASCADE_IRQ: u8 = 2;
+const END_OF_INTERRUPT: u8 = 0x20;
+const READ_ISR: u8 = 0x0b;
+
+pub struct Manager;
+impl Manager {
+    pub fn init() {
+        let io_wait = || cpu::Manager::port_write_u8(0x80, 0);
+        // ICW1 (init + ICW4), ICW2 (vector base), ICW3 (cascade wiring), ICW4 (8086 mode)
+        for (command, data, base, wiring) in [
+            (MASTER_COMMAND, MASTER_DATA, IRQ_BASE, 1 << CASCADE_IRQ),
+            (SLAVE_COMMAND, SLAVE_DATA, IRQ_BASE + 8, CASCADE_IRQ),
+        ] {
+            cpu::Manager::port_write_u8(command, 0x11);
+            io_wait();
+            cpu::Manager::port_write_u8(data, base);
+            io_wait();
+            cpu::Manager::port_write_u8(data, wiring);
+            io_wait();
+            cpu::Manager::port_write_u8(data, 0x01);
+            io_wait();
+        }
+        cpu::Manager::port_write_u8(MASTER_DATA, !(1 << CASCADE_IRQ));
+        cpu::Manager::port_write_u8(SLAVE_DATA, 0xff);
+        for irq in 0..IRQ_COUNT {
+            cpu::Manager::register_interrupt(Self::unhandled_entry as *const () as u64, (IRQ_BASE + irq) as usize);
+        }
+        klog!(Info, "pic", "remapped to {:#x}", IRQ_BASE);
+    }
+
+    /// Points `irq` at `addr` (an entry built with `standard_interrupt_body!`) and unmasks it,
+    /// the handler must call `end_of_interrupt`
+    pub fn register(addr: u64, irq: u8) {
+        cpu::Manager::register_interrupt(addr, (IRQ_BASE + irq) as usize);
+        Self::set_masked(irq, false);
+    }
+
+    pub fn set_masked(irq: u8, masked: bool) {
+        let port = if irq < 8 { MASTER_DATA } else { SLAVE_DATA };
+        let bit = 1 << (irq % 8);
+        let mask = cpu::Manager::port_read_u8(port);
+        cpu::Manager::port_write_u8(port, if masked { mask | bit } else { mask & !bit });
+    }
+
+    pub fn end_of_interrupt(irq: u8) {
+        if irq >= 8 {
+            cpu::Manager::port_write_u8(SLAVE_COMMAND, END_OF_INTERRUPT);
+        }
+        cpu::Manager::port_write_u8(MASTER_COMMAND, END_OF_INTERRUPT);
+    }
+
+    /// IRQ 7/15 with nothing in service, the PIC wants no EOI for those
+    /// (well, the master does for a spurious 15)
+    fn is_spurious(irq: u8) -> bool {
+        let (command, bit) = match irq {
+            7 => (MASTER_COMMAND, 7),
+            15 => (SLAVE_COMMAND, 7),
+            _ => return false,
+        };
+        cpu::Manager::port_write_u8(command, READ_ISR);
+        let spurious = cpu::Manager::port_read_u8(command) & (1 << bit) == 0;
+        if spurious && irq == 15 {
+            cpu::Manager::port_write_u8(MASTER_COMMAND, END_OF_INTERRUPT);
+        }
+        spurious
+    }
+
+    #[unsafe(naked)]
+    unsafe extern "C" fn unhandled_entry() {
+        #[unsafe(no_mangle)]
+        fn pic_unhandled_inner(rsp: u64) {
+            let frame = unsafe { (rsp as *mut cpu::InterruptStackFrame).as_mut() }.unwrap();
+            let irq = (frame.get_irq() as u8).wrapping_sub(IRQ_BASE);
+            if !Manager::is_spurious(irq) {
+                klog!(Warn, "pic", "unhandled irq {irq}");
+                Manager::end_of_interrupt(irq);
+            }
+        }
+        cpu::standard_interrupt_body!("call pic_unhandled_inner");
+    }
+}
@@ -1,4 +1,5 @@
-use crate::{containers::StaticVec, kprint, weak_typed_enum};
+use crate::console::Command;
+use crate::{console_commands, containers::StaticVec, klog, kprint, weak_typed_enum};
 
 type BitmapEntry = u64;
 const BITMAP_BYTES: usize = core::mem::size_of::<BitmapEntry>();
@@ -69,13 +70,19 @@ impl Arena {
         }
         None
     }
+    fn get_used_pages(&self) -> usize {
+        let heap = self.get_heap();
+        (0..self.get_num_pages().div_ceil(BITMAP_BITS))
+            .map(|i| unsafe { heap.add(i).read() }.count_ones() as usize)
+            .sum()
+    }
     pub fn free_page(&mut self, handle: RelativeHandle) {
         let heap = self.get_heap_mut();
-        let value = unsafe { heap.add(handle.0 as usize).read() };
+        let index = handle.0 as usize / BITMAP_BITS;
+        let value = unsafe { heap.add(index).read() };
         let mask = 1 << (handle.0 as usize % BITMAP_BITS);
         assert_ne!(value & mask, 0);
-        unsafe { heap.add(handle.0 as usize).write(value & !mask) };
-        unreachable!()
+        unsafe { heap.add(index).write(value & !mask) };
     }
 }
 
@@ -129,7 +136,7 @@ impl Manager {
             unsafe {
                 let e = entries.add(i).read();
                 if e.type_ == MemoryType::CONVENTIONAL {
-                    kprint!("[pmm] add memory {:016x} (len = {} bytes)\r\n", e.phys, e.page_count * 4096);
+                    klog!(Info, "pmm", "add memory {:016x} (len = {} bytes)", e.phys, e.page_count * 4096);
                     let mut arena = Arena{
                         base: e.phys as usize,
                         length: e.page_count as usize * 4096,
@@ -213,3 +220,24 @@ impl Handle {
         unreachable!()
     }
 }
+
+console_commands! {
+    Command {
+        name: "pages",
+        category: "memory",
+        desc: "print physical page usage",
+        help: "One line per arena, bitmap pages count as used.",
+        args: &[],
+        handler: |_state, _args| {
+            let arenas = unsafe { &(*&raw const PHYSICAL_ALLOCATOR).arenas };
+            let (mut total, mut used) = (0, 0);
+            for arena in arenas.iter().take(arenas.len()) {
+                let (pages, in_use) = (arena.get_num_pages(), arena.get_used_pages());
+                kprint!("{:016x} {:>8} pages {:>8} used\r\n", arena.base, pages, in_use);
+                total += pages;
+                used += in_use;
+            }
+            kprint!("total {total} pages, {used} used, {} KiB free\r\n", (total - used) * PAGE_SIZE / 1024);
+        },
+    },
+}
@@ -1,18 +1,24 @@
-use core::str;
+use core::{fmt, str};
+
+use radian_abi::capability;
 
 use crate::containers::StaticString;
+use crate::console::{ArgKind, ArgSpec, Command};
+use crate::{console_commands, console_error, kprint};
 use crate::db;
 use crate::dense_bitfield;
 use crate::policy;
 use crate::tagged_dense_bitfield;
 
 // Define system capabilities that can be granted to components or processes.
+// The bits are part of the syscall ABI, user programs ask for them by value
 dense_bitfield!(
     Capability u16
-    READ_FILESYSTEM = 0x01,
-    WRITE_LOG = 0x02,
-    SPAWN_TASK = 0x04,
-    NETWORK_ACCESS = 0x08,
+    READ_FILESYSTEM = capability::READ_FILESYSTEM,
+    WRITE_LOG = capability::WRITE_LOG,
+    SPAWN_TASK = capability::SPAWN_TASK,
+    NETWORK_ACCESS = capability::NETWORK_ACCESS,
+    DEBUG = capability::DEBUG,
 );
 impl Capability {
     pub fn new() -> Self {
@@ -28,6 +34,76 @@ pub Action : u16 {
     WRITE_TO = 0x04,
 });
 
+// Who may do what to a VFS node, by whether the worker's user owns it, is in
+// its group or neither. `STICKY` on a directory keeps its entries from being
+// removed or renamed by anyone but their owner
+dense_bitfield!(
+    Permissions u16
+    OWNER_READ = 0x01,
+    OWNER_WRITE = 0x02,
+    GROUP_READ = 0x04,
+    GROUP_WRITE = 0x08,
+    OTHER_READ = 0x10,
+    OTHER_WRITE = 0x20,
+    STICKY = 0x40,
+);
+impl Permissions {
+    /// Everyone reads, the owner writes
+    pub const fn public() -> Self {
+        Self(Self::OWNER_READ | Self::OWNER_WRITE | Self::GROUP_READ | Self::OTHER_READ)
+    }
+    /// Everyone reads and writes, like `/temp`
+    pub const fn shared() -> Self {
+        Self(Self::public().0 | Self::GROUP_WRITE | Self::OTHER_WRITE)
+    }
+    /// Everyone reads and writes but only removes or renames their own, `/temp`
+    pub const fn sticky() -> Self {
+        Self(Self::shared().0 | Self::STICKY)
+    }
+    pub fn is_sticky(self) -> bool {
+        self.0 & Self::STICKY != 0
+    }
+    /// `rw` pairs for owner, group and others with `-` for what is missing,
+    /// and a `t` after them for sticky
+    pub fn parse(s: &str) -> Option<Self> {
+        let (s, sticky) = match s.strip_suffix('t') {
+            Some(s) => (s, Self::STICKY),
+            None => (s, 0),
+        };
+        if s.len() != 6 {
+            return None;
+        }
+        s.bytes().enumerate().try_fold(Self(sticky), |p, (i, c)| match (c, i % 2) {
+            (b'r', 0) | (b'w', 1) => Some(Self(p.0 | 1 << i)),
+            (b'-', _) => Some(p),
+            _ => None,
+        })
+    }
+}
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for i in 0..6 {
+            let c = match (self.0 & 1 << i != 0, i % 2) {
+                (false, _) => '-',
+                (true, 0) => 'r',
+                (true, _) => 'w',
+            };
+            write!(f, "{c}")?;
+        }
+        match self.is_sticky() {
+            true => write!(f, "t"),
+            false => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    /// Writing a node, or changing a directory's children
+    Write,
+}
+
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PolicyRuleHandle(pub u16);
 #[derive(Default, Debug)]
@@ -46,11 +122,16 @@ pub struct PasswordHash {
 pub struct User {
     name: StaticString<16>,
     pass_hash: PasswordHash,
+    group: db::ObjectHandle,
 }
 impl User {
     pub fn get_name(&self) -> &str {
         self.name.as_str()
     }
+    /// Its one group, `ObjectHandle::default()` for none
+    pub fn get_group(&self) -> db::ObjectHandle {
+        self.group
+    }
 }
 
 #[derive(Default, Debug)]
@@ -67,8 +148,9 @@ impl Group {
 pub struct Manager;
 impl Manager {
     pub fn init(db: &mut db::Database) {
-        Self::new_user(db, "admin");
-        Self::new_group(db, "admin");
+        let admin = Self::new_user(db, "admin");
+        let group = Self::new_group(db, "admin");
+        Self::set_user_group(db, admin, group);
     }
     pub fn new_user(db: &mut db::Database, name: &str) -> db::ObjectHandle {
         let mut obj = User::default();
@@ -82,6 +164,22 @@ impl Manager {
         db.groups.push(obj);
         db::ObjectHandle::new::<{db::ObjectHandle::GROUP}>((db.groups.len() - 1) as u16)
     }
+    pub fn set_user_group(db: &mut db::Database, user: db::ObjectHandle, group: db::ObjectHandle) {
+        Self::get_user_mut(db, user).group = group;
+    }
+    /// The first user, who workers run as until told otherwise
+    pub fn get_admin() -> db::ObjectHandle {
+        db::ObjectHandle::new::<{ db::ObjectHandle::USER }>(0)
+    }
+    /// The user a worker runs as, or a user acting itself, `None` for anything
+    /// else like the kernel
+    pub fn get_actor_user(db: &db::Database, actor: db::ObjectHandle) -> Option<db::ObjectHandle> {
+        match actor.get_type() {
+            db::ObjectHandle::WORKER => db.workers.get(actor.get_id() as usize).map(|w| w.get_user()),
+            db::ObjectHandle::USER => Some(actor),
+            _ => None,
+        }
+    }
     pub fn add_rule(db: &mut db::Database, rule: PolicyRule) -> PolicyRuleHandle {
         for i in 1..db.policy_rule.len() {
             let r = db.policy_rule.get_mut(i).unwrap();
@@ -120,6 +218,38 @@ impl Manager {
         }
         false
     }
+    /// Whether `actor` may `access` something with that owner, group and
+    /// permissions. The kernel (`ObjectHandle::default()`) always may, `Capability::READ_FILESYSTEM` reads
+    /// and `Action::WRITE_TO` writes anything, otherwise the owner bits apply to
+    /// the owning user, the group bits to its group's users and the rest to others
+    pub fn check_access(
+        db: &db::Database,
+        actor: db::ObjectHandle,
+        owner: db::ObjectHandle,
+        group: db::ObjectHandle,
+        permissions: Permissions,
+        access: Access,
+    ) -> bool {
+        let Some(user) = Self::get_actor_user(db, actor) else {
+            return actor == db::ObjectHandle::default();
+        };
+        let granted = match access {
+            Access::Read => Self::check_capability(db, actor, Capability::default().with(Capability::READ_FILESYSTEM)),
+            Access::Write => Self::check_action(db, actor, Action::default().with(Action::WRITE_TO)),
+        };
+        let shift = if user == owner {
+            0
+        } else if group != db::ObjectHandle::default() && db.users.get(user.get_id() as usize).map(|u| u.group) == Some(group) {
+            2
+        } else {
+            4
+        };
+        let bit = match access {
+            Access::Read => Permissions::OWNER_READ,
+            Access::Write => Permissions::OWNER_WRITE,
+        };
+        granted || permissions.contains(Permissions(bit << shift))
+    }
     pub fn for_each_policy_rule<F: FnMut(&policy::PolicyRule)>(db: &db::Database, mut f: F) {
         for i in 1..db.policy_rule.len() {
             let r = &db.policy_rule[i];
@@ -134,6 +264,12 @@ impl Manager {
     pub fn get_user_mut<'a>(db: &'a mut db::Database, id: db::ObjectHandle) -> &'a mut User {
         db.users.get_mut(id.get_id() as usize).unwrap()
     }
+    pub fn find_user(db: &db::Database, name: &str) -> Option<db::ObjectHandle> {
+        (0..db.users.len()).find(|&i| db.users[i].get_name() == name).map(|i| db::ObjectHandle::new::<{ db::ObjectHandle::USER }>(i as u16))
+    }
+    pub fn find_group(db: &db::Database, name: &str) -> Option<db::ObjectHandle> {
+        (0..db.groups.len()).find(|&i| db.groups[i].get_name() == name).map(|i| db::ObjectHandle::new::<{ db::ObjectHandle::GROUP }>(i as u16))
+    }
     pub fn for_each_user<F: FnMut(&User)>(db: &db::Database, mut f: F) {
         for i in 0..db.users.len() {
             f(&db.users[i]);
@@ -145,3 +281,157 @@ impl Manager {
         }
     }
 }
+
+console_commands! {
+    Command {
+        name: "rule_list",
+        category: "policy",
+        desc: "list policy rules",
+        help: "",
+        args: &[],
+        handler: |state, _args| {
+            Manager::for_each_policy_rule(state.db, |rule| {
+                kprint!("- {:?}\r\n", rule);
+            });
+        },
+    },
+    Command {
+        name: "rule_remove",
+        category: "policy",
+        desc: "remove a policy rule",
+        help: "<index> is the position in rule_list.",
+        args: &[ArgSpec::required("index", ArgKind::Literal)],
+        handler: |state, args| {
+            if let Ok(index) = u16::try_from(args.get_literal(1).unwrap()) {
+                Manager::remove_rule(state.db, PolicyRuleHandle(index));
+            } else {
+                console_error!(state, "invalid number\r\n");
+            }
+        },
+    },
+    Command {
+        name: "users",
+        category: "policy",
+        desc: "list users",
+        help: "",
+        args: &[],
+        handler: |state, _args| {
+            Manager::for_each_user(state.db, |user| {
+                let group = state.db.groups.get(user.get_group().get_id() as usize).filter(|_| user.get_group() != db::ObjectHandle::default());
+                kprint!("- {} ({})\r\n", user.get_name(), group.map_or("no group", |g| g.get_name()));
+            });
+        },
+    },
+    Command {
+        name: "groups",
+        category: "policy",
+        desc: "list groups",
+        help: "",
+        args: &[],
+        handler: |state, _args| {
+            Manager::for_each_group(state.db, |group| {
+                kprint!("- {}\r\n", group.get_name());
+            });
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitfields() {
+        let caps = Capability::new().with(Capability::READ_FILESYSTEM).with(Capability::WRITE_LOG);
+        assert!(caps.contains(Capability::default().with(Capability::WRITE_LOG)));
+        assert!(!caps.contains(Capability::default().with(Capability::SPAWN_TASK)));
+        let action = Action::default().with(Action::START_TASK);
+        assert!(action.contains(Action::default().with(Action::START_TASK)));
+    }
+
+    #[test]
+    fn init_creates_admin() {
+        let db = db::Database::new_with(&[]);
+        let user = db.find_from_str("user_0").unwrap();
+        assert_eq!(Manager::get_user(&db, user).get_name(), "admin");
+        let mut groups = 0;
+        Manager::for_each_group(&db, |g| {
+            assert_eq!(g.get_name(), "admin");
+            groups += 1;
+        });
+        assert_eq!(groups, 1);
+    }
+
+    #[test]
+    fn rules_grant_and_revoke() {
+        let mut db = db::Database::new_with(&[]);
+        let user = Manager::new_user(&mut db, "guest");
+        assert!(!Manager::check_capability(&db, user, Capability::default().with(Capability::WRITE_LOG)));
+        let handle = Manager::add_rule(&mut db, PolicyRule {
+            subject: user,
+            allowed: Action::default().with(Action::START_TASK),
+            capabilities: Capability::default().with(Capability::WRITE_LOG),
+        });
+        assert!(Manager::check_capability(&db, user, Capability::default().with(Capability::WRITE_LOG)));
+        assert!(!Manager::check_capability(&db, user, Capability::default().with(Capability::SPAWN_TASK)));
+        assert!(Manager::check_action(&db, user, Action::default().with(Action::START_TASK)));
+        assert!(!Manager::check_action(&db, user, Action::default().with(Action::WRITE_TO)));
+        Manager::remove_rule(&mut db, handle);
+        assert!(!Manager::check_capability(&db, user, Capability::default().with(Capability::WRITE_LOG)));
+    }
+
+    #[test]
+    fn access_by_owner_group_and_others() {
+        let mut db = db::Database::new_with(&[]);
+        let admin = Manager::get_admin();
+        let group = db::ObjectHandle::new::<{ db::ObjectHandle::GROUP }>(0);
+        let guest = Manager::new_user(&mut db, "guest");
+        let owner = crate::task::Manager::new_worker(&mut db, Default::default());
+        let other = crate::task::Manager::new_worker(&mut db, Default::default());
+        db.workers[other.get_id() as usize].set_user(guest);
+        let public = Permissions::public();
+        let check = |db: &db::Database, actor, perms, access| Manager::check_access(db, actor, admin, group, perms, access);
+        assert!(check(&db, owner, public, Access::Write));
+        assert!(check(&db, other, public, Access::Read));
+        assert!(!check(&db, other, public, Access::Write));
+        assert!(check(&db, other, Permissions::shared(), Access::Write));
+        // Group members get the group bits
+        Manager::set_user_group(&mut db, guest, group);
+        let group_write = Permissions::default().with(Permissions::GROUP_WRITE);
+        assert!(check(&db, other, group_write, Access::Write));
+        assert!(!check(&db, other, Permissions::default(), Access::Read));
+        Manager::add_rule(&mut db, PolicyRule {
+            subject: other,
+            capabilities: Capability::default().with(Capability::READ_FILESYSTEM),
+            ..Default::default()
+        });
+        assert!(check(&db, other, Permissions::default(), Access::Read));
+        assert!(check(&db, db::ObjectHandle::default(), Permissions::default(), Access::Write));
+    }
+
+    #[test]
+    fn permissions_round_trip() {
+        assert_eq!(Permissions::public().to_string(), "rwr-r-");
+        assert_eq!(Permissions::parse("rwr-r-"), Some(Permissions::public()));
+        assert_eq!(Permissions::parse("rwrwrw"), Some(Permissions::shared()));
+        assert_eq!(Permissions::parse("wr----"), None);
+        assert_eq!(Permissions::parse("rw"), None);
+        assert_eq!(Permissions::sticky().to_string(), "rwrwrwt");
+        assert_eq!(Permissions::parse("rwrwrwt"), Some(Permissions::sticky()));
+        assert_eq!(Permissions::parse("trwrwrw"), None);
+    }
+
+    #[test]
+    fn removed_rule_slot_is_reused() {
+        let mut db = db::Database::new_with(&[]);
+        let user = Manager::new_user(&mut db, "guest");
+        let rule = || PolicyRule { subject: user, ..Default::default() };
+        let first = Manager::add_rule(&mut db, rule());
+        Manager::add_rule(&mut db, rule());
+        Manager::remove_rule(&mut db, first);
+        assert_eq!(Manager::add_rule(&mut db, rule()), first);
+        let mut count = 0;
+        Manager::for_each_policy_rule(&db, |_| count += 1);
+        assert_eq!(count, 2);
+    }
+}
@@ -0,0 +1,284 @@
+//! `int 0xf0` handler, the numbers and encoding live in `radian_abi`
+//!
+//! Syscalls run on the TSS stack with interrupts off, in the kernel address
+//! space, on behalf of whichever worker the scheduler has active. User buffers
+//! are never dereferenced directly: every page is looked up in the caller's
+//! page tables (present + user at all levels) and copied through its physical
+//! address, so a bad pointer is `Error::Fault` and not a kernel page fault.
+
+use alloc::vec::Vec;
+use radian_abi::{Error, id};
+
+use crate::{console, cpu, db, klog, pmm, policy, task, vfs, vmm, DebugSerial};
+
+/// Largest single read/write, bigger requests are cut short like a short read
+pub const MAX_TRANSFER: usize = 4096;
+
+type Result = core::result::Result<u64, Error>;
+
+/// Where to go once the worker that was started last exits, `None` halts
+static mut ON_EXIT: Option<fn(&mut db::Database) -> !> = None;
+
+pub struct Manager;
+impl Manager {
+    pub fn init(on_exit: Option<fn(&mut db::Database) -> !>) {
+        unsafe {
+            ON_EXIT = on_exit;
+        }
+        cpu::Manager::register_interrupt(Self::entry as *const () as u64, radian_abi::VECTOR as usize);
+        cpu::Manager::allow_user_interrupt(radian_abi::VECTOR as usize);
+        klog!(Info, "syscall", "ready on {:#x}", radian_abi::VECTOR);
+    }
+
+    #[unsafe(naked)]
+    unsafe extern "C" fn entry() {
+        #[unsafe(no_mangle)]
+        fn syscall_entry_inner(rsp: u64) {
+            let frame = unsafe { (rsp as *mut cpu::InterruptStackFrame).as_mut() }.unwrap();
+            Manager::handle(db::Database::get_mut(), frame);
+        }
+        cpu::standard_interrupt_body!("call syscall_entry_inner");
+    }
+
+    fn handle(db: &mut db::Database, frame: &mut cpu::InterruptStackFrame) {
+        use cpu::InterruptStackFrame as F;
+        let worker = task::Manager::get_current_worker(db);
+        let aspace = task::Manager::get_worker_aspace(db, worker);
+        let which = frame.get_register(F::RAX);
+        let args = [F::R9, F::R10, F::R11, F::R12].map(|r| frame.get_register(r));
+        vmm::Manager::switch_to(db, vmm::AddressSpaceHandle::get_kernel());
+        let result = Self::dispatch(db, worker, aspace, which, args);
+        vmm::Manager::switch_to(db, aspace);
+        frame.set_register(F::R15, radian_abi::encode_result(result));
+    }
+
+    fn dispatch(
+        db: &mut db::Database,
+        worker: db::ObjectHandle,
+        aspace: vmm::AddressSpaceHandle,
+        which: u64,
+        args: [u64; 4],
+    ) -> Result {
+        match which {
+            id::CONSOLE_WRITE => {
+                let data = Self::copy_from_user(db, aspace, args[0], args[1])?;
+                for &b in data.iter() {
+                    DebugSerial::put_byte(b);
+                }
+                Ok(data.len() as u64)
+            }
+            id::CONSOLE_READ => DebugSerial::get_byte().map(u64::from).ok_or(Error::WouldBlock),
+            id::ABORT => {
+                klog!(Warn, "syscall", "{:?} aborted", worker);
+                Self::exit_worker(db, worker)
+            }
+            id::EXIT => {
+                klog!(Info, "syscall", "{:?} exited with {}", worker, args[0] as i64);
+                Self::exit_worker(db, worker)
+            }
+            id::GETPID => Ok(worker.get_id() as u64),
+            // Nothing else to run yet
+            id::YIELD => Ok(0),
+            id::NEW_WORKER => {
+                Self::require(db, worker, policy::Capability::SPAWN_TASK)?;
+                let aspace = vmm::Manager::new_address_space(db, pmm::Manager::alloc_page_zeroed());
+                Ok(task::Manager::new_worker(db, aspace).get_id() as u64)
+            }
+            id::NEW_TASK => {
+                Self::require(db, worker, policy::Capability::SPAWN_TASK)?;
+                if args[0] >= db.workers.len() as u64 {
+                    return Err(Error::NotFound);
+                }
+                let target = db::ObjectHandle::new::<{ db::ObjectHandle::WORKER }>(args[0] as u16);
+                task::Manager::new_task(db, target).map(|t| t.get_id() as u64).ok_or(Error::Invalid)
+            }
+            id::WORKER_COUNT => Ok(db.workers.len() as u64),
+            id::VFS_LOOKUP => {
+                let from = Self::get_node(db, args[0])?;
+                let path = Self::copy_from_user(db, aspace, args[1], args[2])?;
+                let path = core::str::from_utf8(&path).map_err(|_| Error::Invalid)?;
+                vfs::Manager::resolve_path(db, worker, from, path).map(|n| n.get_raw()).map_err(Self::from_vfs)
+            }
+            id::VFS_OPEN => {
+                let node = Self::get_node(db, args[0])?;
+                vfs::Manager::open(db, worker, node).map(|f| f.get_id() as u64).map_err(Self::from_vfs)
+            }
+            id::VFS_CLOSE => {
+                vfs::Manager::close(db, worker, Self::get_file(args[0])?).map(|_| 0).map_err(Self::from_vfs)
+            }
+            id::VFS_READ => {
+                let file = Self::get_file(args[0])?;
+                let mut data = alloc::vec![0; (args[2] as usize).min(MAX_TRANSFER)];
+                let len = vfs::Manager::read(db, worker, file, &mut data).map_err(Self::from_vfs)?;
+                Self::copy_to_user(db, aspace, args[1], &data[..len.min(data.len())])
+            }
+            id::VFS_WRITE => {
+                let file = Self::get_file(args[0])?;
+                let data = Self::copy_from_user(db, aspace, args[1], args[2])?;
+                vfs::Manager::write(db, worker, file, &data).map(|n| n as u64).map_err(Self::from_vfs)
+            }
+            id::VFS_SEEK => {
+                let file = Self::get_file(args[0])?;
+                let to = match args[2] {
+                    radian_abi::seek::START => vfs::Seek::Start(args[1]),
+                    radian_abi::seek::CURRENT => vfs::Seek::Current(args[1] as i64),
+                    radian_abi::seek::END => vfs::Seek::End(args[1] as i64),
+                    _ => return Err(Error::Invalid),
+                };
+                vfs::Manager::seek(db, worker, file, to).map_err(Self::from_vfs)
+            }
+            id::VFS_STAT => {
+                let node = Self::get_node(db, args[0])?;
+                vfs::Manager::stat(db, worker, node).map(|s| s.size).map_err(Self::from_vfs)
+            }
+            id::VFS_CHILD => {
+                let node = Self::get_node(db, args[0])?;
+                vfs::Manager::readdir(db, worker, node, args[1] as usize).map(|n| n.get_raw()).ok_or(Error::NotFound)
+            }
+            id::VFS_NAME => {
+                let node = Self::get_node(db, args[0])?;
+                let name = vfs::Manager::get_node(db, node).get_name().as_bytes();
+                Self::copy_to_user(db, aspace, args[1], &name[..name.len().min(args[2] as usize)])
+            }
+            id::VFS_PATH => {
+                let node = Self::get_node(db, args[0])?;
+                let path = alloc::format!("{}", vfs::Manager::get_path(db, node));
+                let len = path.len().min(args[2] as usize);
+                Self::copy_to_user(db, aspace, args[1], &path.as_bytes()[..len])
+            }
+            id::VFS_PARENT => {
+                let node = Self::get_node(db, args[0])?;
+                Ok(vfs::Manager::get_node(db, node).get_parent().get_raw())
+            }
+            id::POLICY_HAS_CAPABILITY => {
+                let caps = policy::Capability::new().with(args[0] as u16);
+                Ok(policy::Manager::check_capability(db, worker, caps) as u64)
+            }
+            id::POLICY_USER_NAME | id::POLICY_GROUP_NAME => {
+                let mut name = [0u8; 16];
+                let mut len = None;
+                let mut index = 0;
+                let mut f = |s: &str| {
+                    if index == args[0] {
+                        len = Some(Self::copy_name(&mut name, s));
+                    }
+                    index += 1;
+                };
+                if which == id::POLICY_USER_NAME {
+                    policy::Manager::for_each_user(db, |u| f(u.get_name()));
+                } else {
+                    policy::Manager::for_each_group(db, |g| f(g.get_name()));
+                }
+                let len = len.ok_or(Error::NotFound)?;
+                Self::copy_to_user(db, aspace, args[1], &name[..len.min(args[2] as usize)])
+            }
+            id::POLICY_WHOAMI => {
+                let user = policy::Manager::get_actor_user(db, worker).ok_or(Error::NotFound)?;
+                let name = policy::Manager::get_user(db, user).get_name().as_bytes();
+                Self::copy_to_user(db, aspace, args[0], &name[..name.len().min(args[1] as usize)])
+            }
+            id::DEBUG_COMMAND => {
+                Self::require(db, worker, policy::Capability::DEBUG)?;
+                let line = Self::copy_from_user(db, aspace, args[0], args[1])?;
+                let line = core::str::from_utf8(&line).map_err(|_| Error::Invalid)?;
+                // Runs in the kernel space, that is what there is to debug
+                let mut state = console::State::new(db, vmm::AddressSpaceHandle::get_kernel(), worker);
+                if console::Manager::execute_line(&mut state, line) {
+                    Ok(0)
+                } else {
+                    Err(Error::Failed)
+                }
+            }
+            id::DEBUG_COMMAND_NAME => {
+                let command = console::Manager::get_commands().get(args[0] as usize).ok_or(Error::NotFound)?;
+                let name = command.name.as_bytes();
+                Self::copy_to_user(db, aspace, args[1], &name[..name.len().min(args[2] as usize)])
+            }
+            _ => Err(Error::NoSys),
+        }
+    }
+
+    fn require(db: &db::Database, worker: db::ObjectHandle, what: u16) -> core::result::Result<(), Error> {
+        if policy::Manager::check_capability(db, worker, policy::Capability::new().with(what)) {
+            Ok(())
+        } else {
+            Err(Error::Denied)
+        }
+    }
+
+    fn get_node(db: &db::Database, raw: u64) -> core::result::Result<vfs::NodeHandle, Error> {
+        vfs::Manager::find_node_by_raw(db, raw).ok_or(Error::NotFound)
+    }
+
+    /// Only a range check, the VFS tells whether it is open
+    fn get_file(raw: u64) -> core::result::Result<vfs::FileHandle, Error> {
+        u16::try_from(raw).map(vfs::FileHandle::from_id).map_err(|_| Error::Invalid)
+    }
+
+    fn from_vfs(e: vfs::Error) -> Error {
+        match e {
+            vfs::Error::Policy => Error::Denied,
+            vfs::Error::NotFound => Error::NotFound,
+            vfs::Error::BadHandle | vfs::Error::Unsupported | vfs::Error::Invalid | vfs::Error::Loop => Error::Invalid,
+            vfs::Error::TooMany => Error::Failed,
+            _ => Error::Io,
+        }
+    }
+
+    fn copy_name(out: &mut [u8], name: &str) -> usize {
+        let len = name.len().min(out.len());
+        out[..len].copy_from_slice(&name.as_bytes()[..len]);
+        len
+    }
+
+    fn exit_worker(db: &mut db::Database, worker: db::ObjectHandle) -> ! {
+        vfs::Manager::close_all(db, worker);
+        db.workers[worker.get_id() as usize].set_active(false);
+        match unsafe { ON_EXIT } {
+            Some(f) => f(db),
+            None => crate::abort(),
+        }
+    }
+
+    /// Calls `f(physical, offset, len)` for each page of `[addr, addr + len)`,
+    /// after checking all of them so nothing is half done on `Error::Fault`
+    fn for_each_user_page<F: FnMut(*mut u8, usize, usize)>(
+        db: &db::Database,
+        aspace: vmm::AddressSpaceHandle,
+        addr: u64,
+        len: u64,
+        flags: u64,
+        mut f: F,
+    ) -> core::result::Result<(), Error> {
+        let end = addr.checked_add(len).ok_or(Error::Fault)?;
+        let flags = vmm::Page::PRESENT | vmm::Page::USER_SUPERVISOR | flags;
+        let page_size = pmm::PAGE_SIZE as u64;
+        for pass in 0..2 {
+            let mut vaddr = addr;
+            while vaddr < end {
+                let chunk = (page_size - vaddr % page_size).min(end - vaddr);
+                let paddr = vmm::Manager::translate(db, aspace, vaddr, flags).ok_or(Error::Fault)?;
+                if pass == 1 {
+                    f(paddr as *mut u8, (vaddr - addr) as usize, chunk as usize);
+                }
+                vaddr += chunk;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_from_user(db: &db::Database, aspace: vmm::AddressSpaceHandle, addr: u64, len: u64) -> core::result::Result<Vec<u8>, Error> {
+        let mut data = alloc::vec![0; (len as usize).min(MAX_TRANSFER)];
+        Self::for_each_user_page(db, aspace, addr, data.len() as u64, 0, |p, offset, n| unsafe {
+            core::ptr::copy_nonoverlapping(p, data.as_mut_ptr().add(offset), n);
+        })?;
+        Ok(data)
+    }
+
+    fn copy_to_user(db: &db::Database, aspace: vmm::AddressSpaceHandle, addr: u64, data: &[u8]) -> Result {
+        Self::for_each_user_page(db, aspace, addr, data.len() as u64, vmm::Page::READ_WRITE, |p, offset, n| unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr().add(offset), p, n);
+        })?;
+        Ok(data.len() as u64)
+    }
+}
@@ -1,8 +1,13 @@
+use crate::cpu;
 use crate::db;
-use crate::kprint;
+use crate::{klog, kprint};
 use crate::pmm;
+use crate::policy;
+use crate::vfs;
 use crate::vmm;
 use crate::containers::StaticVec;
+use crate::console::{ArgKind, ArgSpec, Command};
+use crate::console_commands;
 
 #[derive(Debug)]
 pub struct Task {
@@ -25,12 +30,20 @@ impl Default for Task {
 
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TaskHandle(u8);
+impl TaskHandle {
+    pub const fn get_id(self) -> u8 {
+        self.0
+    }
+}
 
 #[derive(Debug)]
 pub struct Worker {
     aspace: vmm::AddressSpaceHandle,
     entry_point: u64,
     tasks: StaticVec<Task, 4>,
+    files: [Option<vfs::OpenFile>; vfs::MAX_OPEN_FILES],
+    /// Who the VFS checks permissions as
+    user: db::ObjectHandle,
     flags: u8,
 }
 impl Worker {
@@ -42,12 +55,32 @@ impl Worker {
             aspace,
             entry_point: 0,
             tasks: StaticVec::new(),
+            files: [None; vfs::MAX_OPEN_FILES],
+            user: policy::Manager::get_admin(),
             flags: 0,
         }
     }
     const fn set_flag<const FLAG: u8>(&mut self, v: bool) {
         self.flags = (self.flags & !FLAG) | [0, FLAG][v as usize];
     }
+    pub const fn get_aspace(&self) -> vmm::AddressSpaceHandle {
+        self.aspace
+    }
+    pub const fn get_entry_point(&self) -> u64 {
+        self.entry_point
+    }
+    pub fn get_files(&self) -> &[Option<vfs::OpenFile>; vfs::MAX_OPEN_FILES] {
+        &self.files
+    }
+    pub fn get_files_mut(&mut self) -> &mut [Option<vfs::OpenFile>; vfs::MAX_OPEN_FILES] {
+        &mut self.files
+    }
+    pub const fn get_user(&self) -> db::ObjectHandle {
+        self.user
+    }
+    pub const fn set_user(&mut self, user: db::ObjectHandle) {
+        self.user = user;
+    }
     pub const fn is_active(&self) -> bool {
         self.flags & Self::ACTIVE != 0
     }
@@ -56,8 +89,11 @@ impl Worker {
     }
 }
 
-/// Default stack base
+/// Default stack base, task N gets the N-th `TASK_STACK_SPACING` window above it
 pub const TASK_STACK_BASE: u64 = 0x1100_0000;
+pub const TASK_STACK_PAGES: usize = 4;
+/// Leaves an unmapped guard below every stack
+pub const TASK_STACK_SPACING: u64 = 2 * (TASK_STACK_PAGES * pmm::PAGE_SIZE) as u64;
 pub type EntryFn = unsafe extern "C" fn() -> ();
 /// Only used for shit like .bin or a.out
 pub const PROGRAM_IMAGE_BASE: u64 = 0x1000_0000;
@@ -92,24 +128,83 @@ impl Manager {
         db::ObjectHandle::new::<{db::ObjectHandle::WORKER}>((db.workers.len() - 1) as u16)
     }
 
-    fn get_worker_aspace(db: &db::Database, id: db::ObjectHandle) -> vmm::AddressSpaceHandle {
+    pub fn get_worker_aspace(db: &db::Database, id: db::ObjectHandle) -> vmm::AddressSpaceHandle {
         db.workers.get(id.get_id() as usize).map(|w| w.aspace).unwrap_or_default()
     }
 
     pub fn new_task(db: &mut db::Database, id: db::ObjectHandle) -> Option<TaskHandle> {
+        if id.get_id() as usize >= db.workers.len() {
+            return None;
+        }
         if let Some(worker) = db.workers.get_mut(id.get_id() as usize) {
+            if worker.tasks.len() == worker.tasks.max_len() {
+                return None;
+            }
             worker.tasks.push(Task::new());
             let task_id = TaskHandle((worker.tasks.len() - 1) as u8);
-            // Map the stack (default)
+            // Map the stack (default), user accessible since that is who runs on it
             let aspace = Self::get_worker_aspace(db, id);
-            let stack_page = pmm::Manager::alloc_page_zeroed();
-            vmm::Manager::map(db, aspace, stack_page.get() as u64, TASK_STACK_BASE, 1, vmm::Page::PRESENT | vmm::Page::READ_WRITE);
+            let base = Self::get_stack_top(task_id) - (TASK_STACK_PAGES * pmm::PAGE_SIZE) as u64;
+            for i in 0..TASK_STACK_PAGES {
+                let stack_page = pmm::Manager::alloc_page_zeroed();
+                if i == 0 {
+                    db.workers[id.get_id() as usize].tasks[task_id.0 as usize].stack_page = stack_page;
+                }
+                vmm::Manager::map_single(
+                    db,
+                    aspace,
+                    stack_page.get() as u64,
+                    base + (i * pmm::PAGE_SIZE) as u64,
+                    vmm::Page::PRESENT | vmm::Page::READ_WRITE | vmm::Page::USER_SUPERVISOR,
+                );
+            }
             Some(task_id)
         } else {
             None
         }
     }
 
+    pub const fn get_stack_top(task: TaskHandle) -> u64 {
+        TASK_STACK_BASE + TASK_STACK_SPACING * (task.0 as u64 + 1)
+    }
+
+    /// The worker the scheduler marked active, `worker_0` before anything ran
+    pub fn get_current_worker(db: &db::Database) -> db::ObjectHandle {
+        let index = (0..db.workers.len()).find(|&i| db.workers[i].is_active()).unwrap_or(0);
+        db::ObjectHandle::new::<{db::ObjectHandle::WORKER}>(index as u16)
+    }
+
+    /// Makes `id` the only active worker and runs its first task from the ELF entry point
+    pub fn start_worker(db: &mut db::Database, id: db::ObjectHandle) -> ! {
+        for i in 0..db.workers.len() {
+            db.workers[i].set_active(i == id.get_id() as usize);
+        }
+        let entry = db.workers[id.get_id() as usize].entry_point;
+        klog!(Info, "task", "starting {:?} at {entry:016x}", id);
+        vmm::Manager::reload_cr3(db, Self::get_worker_aspace(db, id));
+        Self::enter_usermode(entry, Self::get_stack_top(TaskHandle(0)))
+    }
+
+    /// `iretq` into ring 3 with interrupts on, unlike `switch_to_usermode` this
+    /// sets up the user segments and a stack
+    pub fn enter_usermode(rip: u64, rsp: u64) -> ! {
+        unsafe {
+            core::arch::asm!(
+                "push {ss}",
+                "push {rsp}",
+                "push 0x202", //rflags, IF=1
+                "push {cs}",
+                "push {rip}",
+                "iretq",
+                ss = in(reg) (cpu::USER_DATA_SEGMENT | 3) as u64,
+                cs = in(reg) (cpu::USER_CODE_SEGMENT | 3) as u64,
+                rsp = in(reg) rsp,
+                rip = in(reg) rip,
+                options(noreturn),
+            );
+        }
+    }
+
     #[unsafe(no_mangle)]
     pub fn switch_to_usermode(next_rip: u64) {
         unsafe {
@@ -127,11 +222,11 @@ impl Manager {
         use xmas_elf::{program, ElfFile};
         let elf = ElfFile::new(&bytes).expect("Failed to parse ELF file");
         let aspace = Self::get_worker_aspace(db, id);
-        kprint!("[task] using aspace = {:?}\r\n", aspace);
+        klog!(Info, "task", "using aspace = {:?}", aspace);
 
         for ph in elf.program_iter() {
             if ph.get_type().unwrap() == program::Type::Dynamic {
-                kprint!("[task] Skipping dynamic segment\r\n");
+                klog!(Info, "task", "Skipping dynamic segment");
             }
             if ph.get_type().expect("Failed to get header type") != program::Type::Load {
                 continue;
@@ -144,36 +239,35 @@ impl Manager {
             let page_offset = virt_addr - aligned_virt_addr;
             let total_size = page_offset + mem_size;
             let num_pages = total_size.div_ceil(0x1000);
-            kprint!("[task] Using {num_pages} pages, addr = {virt_addr:0x}, align {aligned_virt_addr:0x} with type {:0x}\r\n", ph.physical_addr());
+            klog!(Debug, "task", "Using {num_pages} pages, addr = {virt_addr:0x}, align {aligned_virt_addr:0x} with type {:0x}", ph.physical_addr());
+            let file_end = virt_addr + ph.file_size() as usize;
             for i in 0..num_pages {
-                let handle = pmm::Manager::alloc_page();
+                // Zeroed so whatever the file does not cover (.bss, padding) is 0
+                let handle = pmm::Manager::alloc_page_zeroed();
                 let ptr = handle.get_mut();
-                let file_offset = (ph.offset() as usize - i * pmm::PAGE_SIZE).min(pmm::PAGE_SIZE);
-                let file_size = (ph.file_size() as usize - i * pmm::PAGE_SIZE).min(pmm::PAGE_SIZE);
-                let mem_size = (ph.mem_size() as usize - i * pmm::PAGE_SIZE).min(pmm::PAGE_SIZE);
-                unsafe {
-                    core::ptr::copy_nonoverlapping(
-                        bytes[file_offset..].as_ptr(),
-                        ptr.add(page_offset),
-                        file_size
-                    );
-                    if mem_size > file_size {
-                        core::ptr::write_bytes(
-                            ptr.add(page_offset + file_size),
-                            0,
-                            mem_size - file_size
+                let page_vaddr = aligned_virt_addr + i * pmm::PAGE_SIZE;
+                let start = page_vaddr.max(virt_addr);
+                let end = (page_vaddr + pmm::PAGE_SIZE).min(file_end);
+                let file_offset = ph.offset() as usize + start.saturating_sub(virt_addr);
+                let file_size = end.saturating_sub(start);
+                if file_size > 0 {
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            bytes[file_offset..].as_ptr(),
+                            ptr.add(start - page_vaddr),
+                            file_size
                         );
                     }
                 }
-                kprint!("[task] {:016x} => {virt_addr:016x}; file_size={file_size}, file_offset={file_offset}, mem_size={mem_size}\r\n", ptr as u64);
-                vmm::Manager::map_single(db, aspace, ptr as u64, (virt_addr + i * pmm::PAGE_SIZE) as u64, vmm::Page::PRESENT | vmm::Page::READ_WRITE | vmm::Page::USER_SUPERVISOR);
+                klog!(Debug, "task", "{:016x} => {page_vaddr:016x}; file_size={file_size}, file_offset={file_offset}", ptr as u64);
+                vmm::Manager::map_single(db, aspace, ptr as u64, page_vaddr as u64, vmm::Page::PRESENT | vmm::Page::READ_WRITE | vmm::Page::USER_SUPERVISOR);
             }
         }
         //let entry_function: EntryFn = unsafe { core::mem::transmute(entry_point) };
         if main {
             if let Some(worker) = db.workers.get_mut(id.get_id() as usize) {
                 worker.entry_point = elf.header.pt2.entry_point();
-                kprint!("[task] entry point at {:016x}\r\n", worker.entry_point);
+                klog!(Info, "task", "entry point at {:016x}", worker.entry_point);
             }
         }
     }
@@ -196,3 +290,47 @@ impl Manager {
         db::ObjectHandle::new::<{db::ObjectHandle::WORKER}>(next_index as u16)
     }
 }
+
+console_commands! {
+    Command {
+        name: "worker",
+        category: "task",
+        desc: "make new worker or set",
+        help: "Without <id> a worker is made in the current address space. Either way\n\
+               it becomes the actor for write, source, new_task...",
+        args: &[ArgSpec::optional("id", ArgKind::Literal)],
+        handler: |state, args| {
+            if let Some(index) = args.get_literal(1) {
+                state.current_actor = db::ObjectHandle::new::<{ db::ObjectHandle::WORKER }>(index as u16);
+                kprint!("set {:?}\r\n", state.current_actor);
+            } else {
+                state.current_actor = Manager::new_worker(state.db, state.current_aspace);
+                kprint!("new {:?}\r\n", state.current_actor);
+            }
+        },
+    },
+    Command {
+        name: "new_task",
+        category: "task",
+        desc: "make new task in worker",
+        help: "",
+        args: &[],
+        handler: |state, _args| {
+            let handle = Manager::new_task(state.db, state.current_actor).unwrap();
+            state.current_task = handle;
+            kprint!("new {:?}\r\n", state.current_task);
+        },
+    },
+    Command {
+        name: "rip3_to",
+        category: "task",
+        desc: "jump to usermode",
+        help: "",
+        args: &[ArgSpec::required("addr", ArgKind::Literal)],
+        handler: |_state, args| {
+            let rip = args.get_literal(1).unwrap();
+            kprint!("jumping to {:016x}\r\n", rip);
+            Manager::switch_to_usermode(rip as u64);
+        },
+    },
+}
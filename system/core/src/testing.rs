@@ -0,0 +1,180 @@
+//! In-kernel integration test runner, enabled with the `kernel-test` feature
+//!
+//! Functions marked `#[kernel_test]` land in the `.kernel_tests` section (see
+//! `kernel.ld`), `rust_start` runs all of them instead of the console and exits
+//! QEMU through `isa-debug-exit`, see `make test-kernel`. One line per event
+//! goes to `DebugSerial`, nothing else the kernel prints starts with `[test]`:
+//!
+//! ```text
+//! [test] begin <count>
+//! [test] pass <name>
+//! [test] note <name> <free form>
+//! [test] fail <name> <file>:<line>: <what>
+//! [test] end <passed> <failed>
+//! ```
+//!
+//! A panic inside a test is reported as a failure of that test and ends the run.
+
+use crate::{db, kprint};
+
+/// Must match `-device isa-debug-exit,iobase=...` in the Makefile
+pub const DEBUG_EXIT_PORT: u16 = 0xf4;
+/// QEMU exits with `(code << 1) | 1`, so 33 and 35
+pub const EXIT_SUCCESS: u32 = 0x10;
+pub const EXIT_FAILURE: u32 = 0x11;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Failure {
+    pub file: &'static str,
+    pub line: u32,
+    pub what: &'static str,
+}
+pub type Result = core::result::Result<(), Failure>;
+
+/// Registered by `#[kernel_test]`, do not build these by hand
+pub struct KernelTest {
+    pub name: &'static str,
+    pub func: fn(&mut db::Database) -> Result,
+}
+
+unsafe extern "C" {
+    unsafe static KERNEL_TESTS_START: u8;
+    unsafe static KERNEL_TESTS_END: u8;
+}
+
+/// Registers a `fn(&mut db::Database) -> testing::Result` with the runner
+#[macro_export]
+macro_rules! kernel_test {
+    attr() ($(#[$meta:meta])* $vis:vis fn $name:ident $($rest:tt)*) => {
+        $(#[$meta])* $vis fn $name $($rest)*
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".kernel_tests")]
+            static TEST: $crate::testing::KernelTest = $crate::testing::KernelTest {
+                name: concat!(module_path!(), "::", stringify!($name)),
+                func: $name,
+            };
+        };
+    };
+}
+
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr) => {
+        if !$cond {
+            return Err($crate::testing::Failure {
+                file: file!(),
+                line: line!(),
+                what: stringify!($cond),
+            });
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr) => {
+        match (&$left, &$right) {
+            (l, r) => {
+                if *l != *r {
+                    $crate::testing::Manager::note(format_args!("left={:?} right={:?}", l, r));
+                    return Err($crate::testing::Failure {
+                        file: file!(),
+                        line: line!(),
+                        what: concat!(stringify!($left), " == ", stringify!($right)),
+                    });
+                }
+            }
+        }
+    };
+}
+
+struct Runner {
+    current: Option<&'static str>,
+    passed: usize,
+    failed: usize,
+}
+static mut RUNNER: Runner = Runner {
+    current: None,
+    passed: 0,
+    failed: 0,
+};
+
+pub struct Manager;
+impl Manager {
+    fn get_tests() -> &'static [KernelTest] {
+        unsafe {
+            let start = &raw const KERNEL_TESTS_START as *const KernelTest;
+            let end = &raw const KERNEL_TESTS_END as *const KernelTest;
+            core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+        }
+    }
+
+    /// Runs every registered test and exits QEMU, never returns
+    pub fn run(db: &mut db::Database) -> ! {
+        let tests = Self::get_tests();
+        let runner = unsafe { (&raw mut RUNNER).as_mut().unwrap() };
+        kprint!("[test] begin {}\r\n", tests.len());
+        for test in tests {
+            runner.current = Some(test.name);
+            #[cfg(feature = "kasan")]
+            let reports = crate::kasan::Manager::get_report_count();
+            let result = (test.func)(db);
+            #[cfg(feature = "kasan")]
+            let result = result.and_then(|_| {
+                if crate::kasan::Manager::get_report_count() != reports {
+                    Err(Failure { file: file!(), line: line!(), what: "kasan report" })
+                } else {
+                    Ok(())
+                }
+            });
+            match result {
+                Ok(()) => {
+                    runner.passed += 1;
+                    kprint!("[test] pass {}\r\n", test.name);
+                }
+                Err(f) => {
+                    runner.failed += 1;
+                    kprint!("[test] fail {} {}:{}: {}\r\n", test.name, f.file, f.line, f.what);
+                }
+            }
+            runner.current = None;
+        }
+        Self::finish()
+    }
+
+    /// Extra context for the current test, e.g the values of a failed comparison
+    pub fn note(args: core::fmt::Arguments) {
+        let name = unsafe { (*&raw const RUNNER).current.unwrap_or("-") };
+        kprint!("[test] note {name} {args}\r\n");
+    }
+
+    /// Called by the panic handler, whatever was running failed
+    pub fn on_panic(info: &core::panic::PanicInfo) -> ! {
+        let runner = unsafe { (&raw mut RUNNER).as_mut().unwrap() };
+        let name = runner.current.take().unwrap_or("-");
+        let (file, line) = info.location().map(|l| (l.file(), l.line())).unwrap_or(("?", 0));
+        runner.failed += 1;
+        kprint!("[test] fail {name} {file}:{line}: panic: {}\r\n", info.message());
+        Self::finish()
+    }
+
+    fn finish() -> ! {
+        let runner = unsafe { &*&raw const RUNNER };
+        let (passed, failed) = (runner.passed, runner.failed);
+        kprint!("[test] end {passed} {failed}\r\n");
+        Self::exit_qemu([EXIT_SUCCESS, EXIT_FAILURE][(failed > 0) as usize]);
+    }
+
+    pub fn exit_qemu(code: u32) -> ! {
+        unsafe {
+            core::arch::asm!(
+                "out dx, eax",
+                in("eax") code,
+                in("dx") DEBUG_EXIT_PORT
+            );
+        }
+        // Not running under QEMU (or without the device)
+        crate::abort()
+    }
+}
@@ -0,0 +1,275 @@
+//! RAM backed files and directories
+//!
+//! Registered as the `tmpfs` filesystem type, every instance shares one
+//! provider and whatever gets created under a mount has it too. Contents are
+//! heap `Vec`s kept in
+//! `db::Database::tmpfs_inodes` by node id and grow on write, so nothing is
+//! allocated until a file gets data, a symlink keeps its target there the same
+//! way. `init` mounts it on `/temp`,
+//! `/mutable/runtime`, `/mount` and every `/user/*/home`.
+
+use alloc::vec::Vec;
+
+use crate::{db, klog, vfs};
+
+/// Past this a write fails, the heap is not that big
+pub const MAX_FILE_SIZE: usize = 1 << 20;
+
+#[repr(u8)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Not a tmpfs node, all-zero
+    #[default]
+    Unused,
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Default, Debug)]
+pub struct Inode {
+    kind: Kind,
+    /// `None` until the first write, zeroed memory reads as that too, the
+    /// target for symlinks
+    data: Option<Vec<u8>>,
+}
+impl Inode {
+    pub fn get_kind(&self) -> Kind {
+        self.kind
+    }
+    pub fn get_size(&self) -> usize {
+        self.data.as_ref().map_or(0, |d| d.len())
+    }
+}
+
+pub const FS_TYPE: vfs::FsType = vfs::FsType {
+    name: "tmpfs",
+    provider: PROVIDER,
+    // The root may already be a tmpfs directory (mounted on under `/mount`),
+    // its inode is kept as the instance's state and put back on unmount
+    mount: |db, _, root, _| {
+        let previous = core::mem::replace(Manager::get_slot(db, root), Inode { kind: Kind::Directory, data: None });
+        Ok(Some(alloc::boxed::Box::new(previous)))
+    },
+    unmount: |db, _, root| {
+        let mut stack = Vec::new();
+        vfs::Manager::for_each_children(db, root, |child| stack.push(child));
+        while let Some(node) = stack.pop() {
+            *Manager::get_slot(db, node) = Inode::default();
+            vfs::Manager::for_each_children(db, node, |child| stack.push(child));
+        }
+        let previous = vfs::Manager::get_superblock_mut::<Inode>(db, root).map(core::mem::take).unwrap_or_default();
+        *Manager::get_slot(db, root) = previous;
+    },
+};
+
+pub const PROVIDER: vfs::Provider = vfs::Provider {
+    read: |db, _, node, offset, data| {
+        let inode = Manager::get_inode(db, node, Kind::File)?;
+        let contents = inode.data.as_deref().unwrap_or_default();
+        let contents = contents.get(offset as usize..).unwrap_or_default();
+        let len = contents.len().min(data.len());
+        data[..len].copy_from_slice(&contents[..len]);
+        Ok(len)
+    },
+    write: |db, _, node, offset, data| {
+        let end = (offset as usize).checked_add(data.len()).filter(|&e| e <= MAX_FILE_SIZE).ok_or(vfs::Error::Invalid)?;
+        let contents = Manager::get_inode_mut(db, node, Kind::File)?.data.get_or_insert_default();
+        // Writing past the end leaves a hole of zeroes
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[offset as usize..end].copy_from_slice(data);
+        Ok(data.len())
+    },
+    stat: |db, _, node| {
+        let size = Manager::get_any_inode(db, node).get_size() as u64;
+        Ok(vfs::Stat { size, children: vfs::Manager::get_node(db, node).get_child_count() })
+    },
+    create: |db, _, parent, name, kind| {
+        let kind = match kind {
+            vfs::NodeKind::File => Kind::File,
+            vfs::NodeKind::Directory => Kind::Directory,
+        };
+        Manager::new_inode(db, parent, name, Inode { kind, data: None })
+    },
+    remove: |db, _, node| {
+        vfs::Manager::remove_node(db, node)?;
+        *Manager::get_slot(db, node) = Inode::default();
+        Ok(())
+    },
+    rename: |db, _, node, parent, name| {
+        // Contents stay in this provider's table, so only within tmpfs
+        if *vfs::Manager::get_node(db, parent).get_provider() != *vfs::Manager::get_node(db, node).get_provider() {
+            return Err(vfs::Error::Unsupported);
+        }
+        Manager::get_inode(db, parent, Kind::Directory)?;
+        vfs::Manager::move_node(db, node, parent, name);
+        Ok(())
+    },
+    truncate: |db, _, node, size| {
+        let size = usize::try_from(size).ok().filter(|&s| s <= MAX_FILE_SIZE).ok_or(vfs::Error::Invalid)?;
+        Manager::get_inode_mut(db, node, Kind::File)?.data.get_or_insert_default().resize(size, 0);
+        Ok(())
+    },
+    readlink: |db, _, node, out| {
+        let target = Manager::get_inode(db, node, Kind::Symlink)?.data.as_deref().unwrap_or_default();
+        let len = target.len().min(out.len());
+        out[..len].copy_from_slice(&target[..len]);
+        Ok(len)
+    },
+    symlink: |db, _, parent, name, target| {
+        Manager::new_inode(db, parent, name, Inode { kind: Kind::Symlink, data: Some(target.as_bytes().to_vec()) })
+    },
+    ..vfs::Provider::DEFAULT
+};
+
+pub struct Manager;
+impl Manager {
+    /// Needs the VFS tree, registers the type and mounts the usual places
+    pub fn init(db: &mut db::Database) -> vfs::FsTypeHandle {
+        let fs = vfs::Manager::register_fs(db, FS_TYPE);
+        let (actor, root) = (db::ObjectHandle::default(), vfs::NodeHandle::default());
+        let mut nodes: Vec<_> = ["/temp", "/mutable/runtime", "/mount"]
+            .iter()
+            .map(|path| vfs::Manager::resolve_path(db, actor, root, path).ok())
+            .collect();
+        if let Ok(users) = vfs::Manager::resolve_path(db, actor, root, "/user") {
+            vfs::Manager::for_each_children(db, users, |user| nodes.push(vfs::Manager::find_children(db, user, "home")));
+        }
+        for node in nodes.into_iter().flatten() {
+            if let Err(e) = vfs::Manager::mount(db, actor, fs, node, None) {
+                klog!(Warn, "tmpfs", "mounting on node {} failed: {:?}", node.get_id(), e);
+            }
+        }
+        fs
+    }
+
+    fn new_inode(db: &mut db::Database, parent: vfs::NodeHandle, name: &str, inode: Inode) -> Result<vfs::NodeHandle, vfs::Error> {
+        Self::get_inode(db, parent, Kind::Directory)?;
+        let provider = *vfs::Manager::get_node(db, parent).get_provider();
+        let node = vfs::Manager::new_node_with_provider(db, name, parent, provider);
+        *Self::get_slot(db, node) = inode;
+        Ok(node)
+    }
+
+    /// Past the end of the table reads as an unused one
+    fn get_any_inode(db: &db::Database, node: vfs::NodeHandle) -> &Inode {
+        static UNUSED: Inode = Inode { kind: Kind::Unused, data: None };
+        db.tmpfs_inodes.as_ref().and_then(|t| t.get(node.get_id() as usize)).unwrap_or(&UNUSED)
+    }
+    /// Grows the table up to `node`
+    fn get_slot(db: &mut db::Database, node: vfs::NodeHandle) -> &mut Inode {
+        let table = db.tmpfs_inodes.get_or_insert_default();
+        let id = node.get_id() as usize;
+        if table.len() <= id {
+            table.resize_with(id + 1, Inode::default);
+        }
+        &mut table[id]
+    }
+    /// `Error::Invalid` if it is not of that `kind`
+    fn get_inode(db: &db::Database, node: vfs::NodeHandle, kind: Kind) -> Result<&Inode, vfs::Error> {
+        let inode = Self::get_any_inode(db, node);
+        if inode.kind != kind {
+            return Err(vfs::Error::Invalid);
+        }
+        Ok(inode)
+    }
+    fn get_inode_mut(db: &mut db::Database, node: vfs::NodeHandle, kind: Kind) -> Result<&mut Inode, vfs::Error> {
+        Self::get_inode(db, node, kind)?;
+        Ok(Self::get_slot(db, node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::Subsystem::*;
+    use vfs::{Error, NodeKind};
+
+    fn path(db: &db::Database, path: &str) -> vfs::NodeHandle {
+        vfs::Manager::resolve_path(db, db::ObjectHandle::default(), vfs::NodeHandle::default(), path).unwrap()
+    }
+
+    #[test]
+    fn files_grow_and_truncate() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs]);
+        let actor = db::ObjectHandle::default();
+        let temp = path(&db, "/temp");
+        let file = vfs::Manager::create(&mut db, actor, temp, "notes.txt", NodeKind::File).unwrap();
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 0, b"hello"), Ok(5));
+        // Past the end, the gap reads as zeroes
+        assert_eq!(vfs::Manager::write_node(&mut db, actor, file, 7, b"!"), Ok(1));
+        let mut buf = [0xffu8; 16];
+        assert_eq!(vfs::Manager::read_node(&mut db, actor, file, 0, &mut buf), Ok(8));
+        assert_eq!(&buf[..8], b"hello\0\0!");
+        assert_eq!(vfs::Manager::truncate(&mut db, actor, file, 2), Ok(()));
+        assert_eq!(vfs::Manager::stat(&db, actor, file).unwrap().size, 2);
+        assert_eq!(vfs::Manager::read_node(&mut db, actor, file, 1, &mut buf), Ok(1));
+        assert_eq!(buf[0], b'e');
+    }
+
+    #[test]
+    fn directories_and_removal() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs]);
+        let actor = db::ObjectHandle::default();
+        let home = path(&db, "/user/admin/home");
+        let dir = vfs::Manager::create(&mut db, actor, home, "src", NodeKind::Directory).unwrap();
+        let file = vfs::Manager::create(&mut db, actor, dir, "main.rs", NodeKind::File).unwrap();
+        assert_eq!(vfs::Manager::create(&mut db, actor, dir, "main.rs", NodeKind::File), Err(Error::Exists));
+        assert_eq!(vfs::Manager::create(&mut db, actor, file, "x", NodeKind::File), Err(Error::Invalid));
+        assert_eq!(vfs::Manager::remove(&mut db, actor, dir), Err(Error::NotEmpty));
+        assert_eq!(vfs::Manager::remove(&mut db, actor, file), Ok(()));
+        assert_eq!(vfs::Manager::find_children(&db, dir, "main.rs"), None);
+        assert_eq!(vfs::Manager::remove(&mut db, actor, dir), Ok(()));
+        assert_eq!(vfs::Manager::remove(&mut db, actor, home), Err(Error::Busy));
+        // Plain nodes outside tmpfs stay read-only
+        let system = path(&db, "/system");
+        assert_eq!(vfs::Manager::create(&mut db, actor, system, "x", NodeKind::File), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn rename_stays_inside() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs]);
+        let actor = db::ObjectHandle::default();
+        let temp = path(&db, "/temp");
+        let a = vfs::Manager::create(&mut db, actor, temp, "a", NodeKind::Directory).unwrap();
+        let b = vfs::Manager::create(&mut db, actor, a, "b", NodeKind::Directory).unwrap();
+        assert_eq!(vfs::Manager::rename(&mut db, actor, a, b, "a"), Err(Error::Invalid));
+        assert_eq!(vfs::Manager::rename(&mut db, actor, b, temp, "c"), Ok(()));
+        assert_eq!(path(&db, "/temp/c"), b);
+        let system = path(&db, "/system");
+        assert_eq!(vfs::Manager::rename(&mut db, actor, b, system, "c"), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn open_files_cannot_be_removed() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs]);
+        let worker = crate::task::Manager::new_worker(&mut db, Default::default());
+        let temp = path(&db, "/temp");
+        let file = vfs::Manager::create(&mut db, worker, temp, "f", NodeKind::File).unwrap();
+        let handle = vfs::Manager::open(&mut db, worker, file).unwrap();
+        assert_eq!(vfs::Manager::remove(&mut db, worker, file), Err(Error::Busy));
+        vfs::Manager::close(&mut db, worker, handle).unwrap();
+        assert_eq!(vfs::Manager::remove(&mut db, worker, file), Ok(()));
+        // The slot is reused, the old handle does not find it
+        let reused = vfs::Manager::create(&mut db, worker, temp, "g", NodeKind::File).unwrap();
+        assert_eq!(reused.get_id(), file.get_id());
+        assert!(!vfs::Manager::is_valid(&db, file));
+        assert_eq!(vfs::Manager::find_node_by_raw(&db, file.get_raw()), None);
+        assert_eq!(vfs::Manager::find_node_by_raw(&db, reused.get_raw()), Some(reused));
+    }
+
+    #[test]
+    fn unreadable_directories_are_still_not_empty() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs]);
+        let worker = crate::task::Manager::new_worker(&mut db, Default::default());
+        let temp = path(&db, "/temp");
+        let dir = vfs::Manager::create(&mut db, worker, temp, "d", NodeKind::Directory).unwrap();
+        vfs::Manager::create(&mut db, worker, dir, "f", NodeKind::File).unwrap();
+        vfs::Manager::chmod(&mut db, worker, dir, crate::policy::Permissions::parse("-w----").unwrap()).unwrap();
+        assert_eq!(vfs::Manager::readdir(&db, worker, dir, 0), None);
+        assert_eq!(vfs::Manager::remove(&mut db, worker, dir), Err(Error::NotEmpty));
+        assert_eq!(vfs::Manager::remove_node(&mut db, dir), Err(Error::NotEmpty));
+    }
+}
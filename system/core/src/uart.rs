@@ -0,0 +1,329 @@
+//! 16550 UART driver for COM1-COM4
+//!
+//! Ports that pass the loopback probe get 8N1 at `DEFAULT_BAUD`, their FIFOs
+//! on, an interrupt on IRQ 4 (COM1/3) or IRQ 3 (COM2/4) and a `/devices/comN`
+//! node. Received bytes wait in a ring until read, written bytes go into a ring
+//! the THR-empty interrupt drains. With interrupts off (boot, syscalls, other
+//! handlers) or once `set_polled` was called, which the panic handler does,
+//! bytes go straight to the hardware instead so nothing waits on an IRQ that
+//! cannot come.
+
+use crate::console::{ArgKind, ArgSpec, Command};
+use crate::containers::RingBuffer;
+use crate::{console_commands, console_error};
+use crate::{cpu, db, klog, kprint, pic, vfs};
+
+pub const COM_BASES: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+pub const COM_IRQS: [u8; 4] = [4, 3, 4, 3];
+pub const DEFAULT_BAUD: u32 = 115200;
+pub const BUFFER_SIZE: usize = 1024;
+/// Under `/devices`
+pub const NODE_NAMES: [&str; 4] = ["com1", "com2", "com3", "com4"];
+/// Input clock / 16, the divisor for a given baud is `CLOCK / baud`
+const CLOCK: u32 = 115200;
+const FIFO_DEPTH: usize = 16;
+/// Give up waiting on a transmitter that never empties (no UART at all)
+const POLL_SPINS: usize = 100_000;
+
+// Register offsets from the base port
+const DATA: u16 = 0; // RBR/THR, DLL with DLAB set
+const INTERRUPT_ENABLE: u16 = 1; // DLM with DLAB set
+const INTERRUPT_ID: u16 = 2; // FCR on write
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+const IER_RX: u8 = 0x01;
+const IER_TX: u8 = 0x02;
+const IER_LINE: u8 = 0x04;
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+/// Enable, clear both FIFOs, interrupt at 14 bytes
+const FCR_ENABLE: u8 = 0xc7;
+/// DTR, RTS and OUT2 (which gates the IRQ line)
+const MCR_NORMAL: u8 = 0x0b;
+const MCR_LOOPBACK: u8 = 0x1e;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+
+struct Port {
+    present: bool,
+    baud: u32,
+    ier: u8,
+    rx: RingBuffer<u8, BUFFER_SIZE>,
+    tx: RingBuffer<u8, BUFFER_SIZE>,
+    rx_dropped: usize,
+}
+impl Port {
+    const fn new() -> Self {
+        Self {
+            present: false,
+            baud: DEFAULT_BAUD,
+            ier: 0,
+            rx: RingBuffer::new_with_default(0),
+            tx: RingBuffer::new_with_default(0),
+            rx_dropped: 0,
+        }
+    }
+}
+
+static mut PORTS: [Port; 4] = [const { Port::new() }; 4];
+static mut POLLED: bool = false;
+
+fn get_ports() -> &'static mut [Port; 4] {
+    unsafe { (&raw mut PORTS).as_mut().unwrap() }
+}
+
+fn read(index: usize, reg: u16) -> u8 {
+    cpu::Manager::port_read_u8(COM_BASES[index] + reg)
+}
+fn write(index: usize, reg: u16, value: u8) {
+    cpu::Manager::port_write_u8(COM_BASES[index] + reg, value)
+}
+
+pub struct Manager;
+impl Manager {
+    /// Needs `pic::Manager::init` and the VFS (for `/devices`)
+    pub fn init(db: &mut db::Database) {
+        let devices = vfs::Manager::find_children(db, vfs::NodeHandle::default(), "devices").unwrap();
+        let mut irqs = [false; pic::IRQ_COUNT as usize];
+        // One for all ports, it tells them apart by node name
+        let provider = vfs::Manager::new_provider(
+            db,
+            vfs::Provider { write: Self::node_write, read: Self::node_read, ..vfs::Provider::DEFAULT },
+        );
+        for index in 0..COM_BASES.len() {
+            if !Self::probe(index) {
+                continue;
+            }
+            Self::configure(index, DEFAULT_BAUD);
+            irqs[COM_IRQS[index] as usize] = true;
+            let name = NODE_NAMES[index];
+            vfs::Manager::new_node_with_provider(db, name, devices, provider);
+            klog!(Info, "uart", "{name} at {:#x}, irq {}", COM_BASES[index], COM_IRQS[index]);
+        }
+        for (irq, used) in irqs.iter().enumerate() {
+            if *used {
+                pic::Manager::register(Self::entry as *const () as u64, irq as u8);
+            }
+        }
+    }
+
+    /// Loopback test, a missing port reads back 0xff
+    fn probe(index: usize) -> bool {
+        write(index, INTERRUPT_ENABLE, 0);
+        write(index, MODEM_CONTROL, MCR_LOOPBACK);
+        write(index, DATA, 0xae);
+        let ok = read(index, DATA) == 0xae;
+        write(index, MODEM_CONTROL, MCR_NORMAL);
+        ok
+    }
+
+    fn configure(index: usize, baud: u32) {
+        let port = &mut get_ports()[index];
+        let divisor = (CLOCK / baud) as u16;
+        cpu::Manager::without_interrupts(|| {
+            // Let whatever is queued out at the old rate first
+            while let Some(b) = port.tx.pop() {
+                Self::put_byte_polled(index, b);
+            }
+            write(index, INTERRUPT_ENABLE, 0);
+            write(index, LINE_CONTROL, LCR_DLAB);
+            write(index, DATA, divisor as u8);
+            write(index, INTERRUPT_ENABLE, (divisor >> 8) as u8);
+            write(index, LINE_CONTROL, LCR_8N1);
+            write(index, FIFO_CONTROL, FCR_ENABLE);
+            write(index, MODEM_CONTROL, MCR_NORMAL);
+            port.ier = IER_RX | IER_LINE;
+            write(index, INTERRUPT_ENABLE, port.ier);
+            port.baud = baud;
+            port.present = true;
+        });
+    }
+
+    /// `false` if the port is missing or the rate is not a divisor of 115200
+    pub fn set_baud(index: usize, baud: u32) -> bool {
+        let present = get_ports().get(index).is_some_and(|p| p.present);
+        if !present || baud == 0 || baud > CLOCK || !CLOCK.is_multiple_of(baud) {
+            return false;
+        }
+        Self::configure(index, baud);
+        true
+    }
+
+    /// From here on every byte is written synchronously, for panics
+    pub fn set_polled() {
+        unsafe {
+            POLLED = true;
+        }
+        for index in 0..COM_BASES.len() {
+            while let Some(b) = get_ports()[index].tx.pop() {
+                Self::put_byte_polled(index, b);
+            }
+        }
+    }
+
+    pub fn put_byte_polled(index: usize, b: u8) {
+        for _ in 0..POLL_SPINS {
+            if read(index, LINE_STATUS) & LSR_THR_EMPTY != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        write(index, DATA, b);
+    }
+
+    pub fn put_byte(index: usize, b: u8) {
+        let interrupts = cpu::Manager::are_interrupts_enabled();
+        cpu::Manager::without_interrupts(|| {
+            let port = &mut get_ports()[index];
+            if !port.present || !interrupts || unsafe { POLLED } {
+                // Keep the order with what is still queued
+                while let Some(b) = port.tx.pop() {
+                    Self::put_byte_polled(index, b);
+                }
+                Self::put_byte_polled(index, b);
+                return;
+            }
+            if let Err(b) = port.tx.push(b) {
+                Self::put_byte_polled(index, port.tx.pop().unwrap());
+                let _ = port.tx.push(b);
+            }
+            if port.ier & IER_TX == 0 {
+                // Fires right away if the transmitter is already empty
+                port.ier |= IER_TX;
+                write(index, INTERRUPT_ENABLE, port.ier);
+            }
+        });
+    }
+
+    /// Whatever the interrupt buffered, or straight from the port
+    pub fn get_byte(index: usize) -> Option<u8> {
+        cpu::Manager::without_interrupts(|| {
+            if let Some(b) = get_ports()[index].rx.pop() {
+                Some(b)
+            } else if read(index, LINE_STATUS) & LSR_DATA_READY != 0 {
+                Some(read(index, DATA))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn handle_irq(irq: u8) {
+        for index in (0..COM_BASES.len()).filter(|&i| COM_IRQS[i] == irq) {
+            let port = &mut get_ports()[index];
+            if !port.present {
+                continue;
+            }
+            loop {
+                let iir = read(index, INTERRUPT_ID);
+                if iir & 0x01 != 0 {
+                    break; // nothing pending
+                }
+                match (iir >> 1) & 0x07 {
+                    // Receiver line status (overrun, framing...), reading clears it
+                    0b011 => {
+                        read(index, LINE_STATUS);
+                    }
+                    // Data available or character timeout
+                    0b010 | 0b110 => {
+                        while read(index, LINE_STATUS) & LSR_DATA_READY != 0 {
+                            if port.rx.push(read(index, DATA)).is_err() {
+                                port.rx_dropped += 1;
+                            }
+                        }
+                    }
+                    // Transmitter empty, refill the FIFO or stop asking
+                    0b001 => {
+                        for _ in 0..FIFO_DEPTH {
+                            let Some(b) = port.tx.pop() else {
+                                port.ier &= !IER_TX;
+                                write(index, INTERRUPT_ENABLE, port.ier);
+                                break;
+                            };
+                            write(index, DATA, b);
+                        }
+                    }
+                    _ => {
+                        read(index, MODEM_STATUS);
+                    }
+                }
+            }
+        }
+        pic::Manager::end_of_interrupt(irq);
+    }
+
+    #[unsafe(naked)]
+    unsafe extern "C" fn entry() {
+        #[unsafe(no_mangle)]
+        fn uart_irq_inner(rsp: u64) {
+            let frame = unsafe { (rsp as *mut cpu::InterruptStackFrame).as_mut() }.unwrap();
+            Manager::handle_irq((frame.get_irq() as u8).wrapping_sub(pic::IRQ_BASE));
+        }
+        cpu::standard_interrupt_body!("call uart_irq_inner");
+    }
+
+    fn get_node_port(db: &db::Database, node: vfs::NodeHandle) -> Result<usize, vfs::Error> {
+        let name = vfs::Manager::get_node(db, node).get_name();
+        NODE_NAMES.iter().position(|&n| n == name).ok_or(vfs::Error::NotFound)
+    }
+
+    /// A stream, offsets mean nothing
+    fn node_write(db: &mut db::Database, _actor: db::ObjectHandle, node: vfs::NodeHandle, _offset: u64, data: &[u8]) -> vfs::Result {
+        let index = Self::get_node_port(db, node)?;
+        for &b in data {
+            Self::put_byte(index, b);
+        }
+        Ok(data.len())
+    }
+
+    fn node_read(db: &mut db::Database, _actor: db::ObjectHandle, node: vfs::NodeHandle, _offset: u64, data: &mut [u8]) -> vfs::Result {
+        let index = Self::get_node_port(db, node)?;
+        let mut len = 0;
+        while len < data.len() {
+            let Some(b) = Self::get_byte(index) else {
+                break;
+            };
+            data[len] = b;
+            len += 1;
+        }
+        Ok(len)
+    }
+}
+
+console_commands! {
+    Command {
+        name: "uart",
+        category: "devices",
+        desc: "serial ports",
+        help: "Without arguments lists COM1-COM4 with their buffers, with <port> (1-4)\n\
+               and <baud> (a divisor of 115200) changes the rate.",
+        args: &[ArgSpec::optional("port", ArgKind::Literal), ArgSpec::optional("baud", ArgKind::Literal)],
+        handler: |state, args| {
+            if let Some(port) = args.get_literal(1) {
+                let Some(baud) = args.get_literal(2) else {
+                    console_error!(state, "missing <baud>\r\n");
+                    return;
+                };
+                if !(1..=COM_BASES.len()).contains(&port) || !Manager::set_baud(port - 1, baud as u32) {
+                    console_error!(state, "no such port or bad rate\r\n");
+                }
+                return;
+            }
+            for index in 0..COM_BASES.len() {
+                // Copied out, printing goes through COM1's buffers
+                let port = &get_ports()[index];
+                let (present, baud, rx, dropped, tx) = (port.present, port.baud, port.rx.len(), port.rx_dropped, port.tx.len());
+                kprint!("com{} {:#x} irq {} ", index + 1, COM_BASES[index], COM_IRQS[index]);
+                if present {
+                    kprint!("{baud} baud, rx {rx}/{BUFFER_SIZE} ({dropped} dropped), tx {tx}/{BUFFER_SIZE}\r\n");
+                } else {
+                    kprint!("absent\r\n");
+                }
+            }
+        },
+    },
+}
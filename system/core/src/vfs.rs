@@ -1,46 +1,282 @@
-use core::str;
+//! Node tree, providers and open files
+//!
+//! Nodes are names in a tree, what reading or writing one does is up to its
+//! provider, which is handed the node and an offset so one provider can serve
+//! many nodes (every COM port, every file of a filesystem). Workers get up to
+//! `MAX_OPEN_FILES` open files each, a `FileHandle` is an index into that
+//! worker's table and carries the offset `read`, `write` and `seek` move.
+//!
+//! A filesystem type is registered by name with its provider, `mount` grafts
+//! an instance onto an empty node, which becomes the root of that filesystem
+//! and gets the type's provider until `unmount` gives it back.
+//!
+//! Paths are resolved a name at a time by the provider of the directory they
+//! are in, so below a mount point the mounted provider answers, and symlinks
+//! are whatever a provider's `readlink` returns a target for.
 
-use crate::{db, kprint, policy};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{any::Any, fmt, str};
 
-#[derive(Default, Debug)]
+use crate::{db, klog, kprint, log, policy};
+use crate::console::{self, ArgKind, ArgSpec, Command};
+use crate::{console_commands, console_error};
+
+pub const MAX_OPEN_FILES: usize = 16;
+pub const MAX_NAME: usize = 255;
+/// Longest path `resolve_path` takes, symlink targets included
+pub const MAX_PATH: usize = 256;
+/// Symlinks followed in one resolution before giving up with `Error::Loop`
+pub const MAX_LINKS: usize = 8;
+pub const MAX_FS_TYPES: usize = 8;
+pub const MAX_MOUNTS: usize = 16;
+
+#[derive(Default, Debug, PartialEq, Eq)]
 pub enum Error {
     #[default]
     Unknown,
     Policy,
+    NotFound,
+    /// Not an open file of this worker, or the actor is no worker at all
+    BadHandle,
+    /// The provider does not do that
+    Unsupported,
+    Invalid,
+    /// Out of open file or node slots
+    TooMany,
+    /// Something by that name is already there
+    Exists,
+    /// A directory with children in the way
+    NotEmpty,
+    /// Open by some worker
+    Busy,
+    /// Too many symlinks on the way, see `MAX_LINKS`
+    Loop,
+    /// The device under the filesystem failed
+    Io,
+    /// The filesystem is full
+    NoSpace,
     Custom(u32),
 }
 pub type Result = core::result::Result<usize, Error>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub size: u64,
+    pub children: usize,
+}
+
+pub type OpenFn = fn(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle) -> core::result::Result<(), Error>;
+pub type CloseFn = fn(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle);
+pub type ReadFn = fn(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, offset: u64, data: &mut [u8]) -> Result;
+pub type WriteFn = fn(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, offset: u64, data: &[u8]) -> Result;
+pub type StatFn = fn(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle) -> core::result::Result<Stat, Error>;
+/// The `index`th child, `None` past the last one
+pub type ReaddirFn = fn(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle, index: usize) -> Option<NodeHandle>;
+/// Called on the provider of `parent`, the name is checked to be free and valid already
+pub type CreateFn = fn(db: &mut db::Database, actor: db::ObjectHandle, parent: NodeHandle, name: &str, kind: NodeKind) -> core::result::Result<NodeHandle, Error>;
+/// The node is not open anywhere, the provider removes it from the tree once it let go of it
+pub type RemoveFn = fn(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle) -> core::result::Result<(), Error>;
+/// Called on the provider of `node`, the new name is checked to be free and valid already
+pub type RenameFn = fn(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, parent: NodeHandle, name: &str) -> core::result::Result<(), Error>;
+pub type TruncateFn = fn(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, size: u64) -> core::result::Result<(), Error>;
+/// The child of directory `node` called `name`, never `.` or `..`
+pub type LookupFn = fn(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle, name: &str) -> Option<NodeHandle>;
+/// Copies a symlink's target into `out`, `Error::Invalid` if `node` is no symlink
+pub type ReadlinkFn = fn(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle, out: &mut [u8]) -> Result;
+/// Like `CreateFn`, `target` is stored as is and only resolved when followed
+pub type SymlinkFn = fn(db: &mut db::Database, actor: db::ObjectHandle, parent: NodeHandle, name: &str, target: &str) -> core::result::Result<NodeHandle, Error>;
+
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ProviderHandle(u16);
+/// Fill in what the provider does, `..Provider::DEFAULT` for the rest:
+///
+/// ```ignore
+/// Provider { read: my_read, ..Provider::DEFAULT }
+/// ```
+#[derive(Clone, Copy)]
 pub struct Provider {
-    write: fn(db: &mut db::Database, actor: db::ObjectHandle, data: &[u8]) -> Result,
-    #[allow(dead_code)]
-    read: fn(db: &mut db::Database, actor: db::ObjectHandle, data: &mut [u8]) -> Result,
+    /// Can refuse the open, e.g for policy, `close` is only called after an `Ok`
+    pub open: OpenFn,
+    pub close: CloseFn,
+    pub read: ReadFn,
+    pub write: WriteFn,
+    pub stat: StatFn,
+    pub readdir: ReaddirFn,
+    pub create: CreateFn,
+    pub remove: RemoveFn,
+    pub rename: RenameFn,
+    pub truncate: TruncateFn,
+    pub lookup: LookupFn,
+    pub readlink: ReadlinkFn,
+    pub symlink: SymlinkFn,
+}
+impl Provider {
+    /// Opens, but neither reads nor writes nor changes the tree, children are the ones in the node tree
+    /// and there are no symlinks
+    pub const DEFAULT: Self = Self {
+        open: |_, _, _| Ok(()),
+        close: |_, _, _| {},
+        read: |_, _, _, _, _| Err(Error::Unsupported),
+        write: |_, _, _, _, _| Err(Error::Unsupported),
+        stat: |db, _, node| Ok(Stat { size: 0, children: Manager::get_node(db, node).get_child_count() }),
+        readdir: |db, _, node, index| Manager::get_child(db, node, index),
+        create: |_, _, _, _, _| Err(Error::Unsupported),
+        remove: |_, _, _| Err(Error::Unsupported),
+        rename: |_, _, _, _, _| Err(Error::Unsupported),
+        truncate: |_, _, _, _| Err(Error::Unsupported),
+        lookup: |db, _, node, name| Manager::find_children(db, node, name),
+        readlink: |_, _, _, _| Err(Error::Invalid),
+        symlink: |_, _, _, _, _| Err(Error::Unsupported),
+    };
 }
 
+/// Sets up a new instance on `root`, which already has the type's provider,
+/// from the `source` device node if the type reads one. What it returns is
+/// the instance's state, see `Manager::get_superblock`
+pub type MountFn = fn(
+    db: &mut db::Database,
+    actor: db::ObjectHandle,
+    root: NodeHandle,
+    source: Option<NodeHandle>,
+) -> core::result::Result<Option<Box<dyn Any>>, Error>;
+/// Flushes and lets go of everything below `root` while the superblock is
+/// still there, the nodes themselves are removed by `Manager::unmount` afterwards
+pub type UnmountFn = fn(db: &mut db::Database, actor: db::ObjectHandle, root: NodeHandle);
+
+/// A kind of filesystem, `Manager::register_fs` makes it mountable by `name`
+#[derive(Clone, Copy)]
+pub struct FsType {
+    pub name: &'static str,
+    pub provider: Provider,
+    pub mount: MountFn,
+    pub unmount: UnmountFn,
+}
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct NodeHandle(u16);
+pub struct FsTypeHandle(u16);
+/// What `Manager::register_fs` keeps of an `FsType`
+#[derive(Clone, Copy)]
+pub struct RegisteredFs {
+    name: &'static str,
+    provider: ProviderHandle,
+    mount: MountFn,
+    unmount: UnmountFn,
+}
+
+#[derive(Default)]
+pub struct Mount {
+    root: NodeHandle,
+    fs: FsTypeHandle,
+    source: Option<NodeHandle>,
+    /// What `root` goes back to on unmount
+    previous: ProviderHandle,
+    superblock: Option<Box<dyn Any>>,
+}
+impl Mount {
+    pub fn get_root(&self) -> NodeHandle {
+        self.root
+    }
+    pub fn get_fs(&self) -> FsTypeHandle {
+        self.fs
+    }
+    pub fn get_source(&self) -> Option<NodeHandle> {
+        self.source
+    }
+}
+
+/// Slot and generation in `db::Database::vfs_nodes`, once the node is removed
+/// the handle no longer finds anything even if the slot is reused
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeHandle {
+    id: u32,
+    generation: u16,
+}
 impl NodeHandle {
     pub fn is_root(self) -> bool {
-        self.0 == 0
+        self.id == 0
+    }
+    /// The slot, shared with whatever was there before, for per-node tables
+    pub const fn get_id(self) -> u32 {
+        self.id
+    }
+    /// Slot and generation in one for outside the kernel, the root is 0, see
+    /// `Manager::find_node_by_raw`
+    pub const fn get_raw(self) -> u64 {
+        self.id as u64 | (self.generation as u64) << 32
     }
 }
+
+/// A directory's children in creation order, plus once there are enough of
+/// them buckets by name hash so `find_children` does not compare every name
+#[derive(Default)]
+struct Children {
+    order: Vec<(u32, NodeHandle)>,
+    buckets: Vec<Vec<(u32, NodeHandle)>>,
+}
+impl Children {
+    /// Below this a scan of `order` is as quick
+    const MIN_HASHED: usize = 8;
+
+    fn hash(name: &str) -> u32 {
+        // FNV-1a
+        name.bytes().fold(0x811c_9dc5, |h, b| (h ^ b as u32).wrapping_mul(0x0100_0193))
+    }
+    fn bucket(&self, hash: u32) -> usize {
+        hash as usize % self.buckets.len()
+    }
+    fn insert(&mut self, name: &str, node: NodeHandle) {
+        let hash = Self::hash(name);
+        self.order.push((hash, node));
+        if self.order.len() > self.buckets.len() * 2 && self.order.len() >= Self::MIN_HASHED {
+            // Rehash into as many as there are children, about two deep by the next one
+            self.buckets = (0..self.order.len()).map(|_| Vec::new()).collect();
+            for i in 0..self.order.len() {
+                let bucket = self.bucket(self.order[i].0);
+                self.buckets[bucket].push(self.order[i]);
+            }
+        } else if !self.buckets.is_empty() {
+            let bucket = self.bucket(hash);
+            self.buckets[bucket].push((hash, node));
+        }
+    }
+    fn remove(&mut self, name: &str, node: NodeHandle) {
+        self.order.retain(|&(_, c)| c != node);
+        if !self.buckets.is_empty() {
+            let bucket = self.bucket(Self::hash(name));
+            self.buckets[bucket].retain(|&(_, c)| c != node);
+        }
+    }
+    /// Every child that might be called `name`
+    fn candidates(&self, name: &str) -> impl Iterator<Item = NodeHandle> + '_ {
+        let hash = Self::hash(name);
+        let list = match self.buckets.is_empty() {
+            true => &self.order[..],
+            false => &self.buckets[self.bucket(hash)][..],
+        };
+        list.iter().filter(move |&&(h, _)| h == hash).map(|&(_, c)| c)
+    }
+    fn get(&self, index: usize) -> Option<NodeHandle> {
+        self.order.get(index).map(|&(_, c)| c)
+    }
+}
+
 pub struct Node {
-    name: [u8; 24],
+    name: String,
     parent: NodeHandle,
     provider: ProviderHandle,
+    children: Children,
+    owner: db::ObjectHandle,
+    group: db::ObjectHandle,
+    permissions: policy::Permissions,
 }
 impl Node {
     pub fn get_name(&self) -> &str {
-        let len = self
-            .name
-            .iter()
-            .enumerate()
-            .find(|&(_, c)| *c == 0)
-            .map(|(i, _)| i)
-            .unwrap_or(self.name.len());
-        unsafe { str::from_raw_parts(self.name.as_ptr(), len) }
+        &self.name
     }
     /// You fucking better dont clone/copy this around without understanding its consequences
     pub fn get_parent(&self) -> &NodeHandle {
@@ -49,6 +285,57 @@ impl Node {
     pub fn get_provider(&self) -> &ProviderHandle {
         &self.provider
     }
+    pub fn get_child_count(&self) -> usize {
+        self.children.order.len()
+    }
+    /// Hands the node (not its children) to another provider, e.g to mount on it
+    pub fn set_provider(&mut self, provider: ProviderHandle) {
+        self.provider = provider;
+    }
+    pub fn get_owner(&self) -> db::ObjectHandle {
+        self.owner
+    }
+    pub fn get_group(&self) -> db::ObjectHandle {
+        self.group
+    }
+    pub fn get_permissions(&self) -> policy::Permissions {
+        self.permissions
+    }
+    /// Unchecked, see `Manager::chown`
+    pub fn set_owner(&mut self, owner: db::ObjectHandle, group: db::ObjectHandle) {
+        self.owner = owner;
+        self.group = group;
+    }
+    /// Unchecked, see `Manager::chmod`
+    pub fn set_permissions(&mut self, permissions: policy::Permissions) {
+        self.permissions = permissions;
+    }
+}
+
+/// Index into the owning worker's open files
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileHandle(u16);
+impl FileHandle {
+    pub const fn get_id(self) -> u16 {
+        self.0
+    }
+    /// For raw ids from outside the kernel, checked on use
+    pub const fn from_id(id: u16) -> Self {
+        Self(id)
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFile {
+    node: NodeHandle,
+    offset: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seek {
+    Start(u64),
+    Current(i64),
+    /// From `Stat::size`
+    End(i64),
 }
 
 pub struct Manager;
@@ -56,28 +343,26 @@ impl Manager {
     pub fn init(db: &mut db::Database) {
         let root_handle = NodeHandle::default();
         Self::new_node(db, "", root_handle); //root node do not touch ignore please
-        Self::new_provider(
-            db,
-            Provider {
-                write: |_, _, _| Err(Error::Unknown),
-                read: |_, _, _| Err(Error::Unknown),
-            },
-        ); //default provider
+        Self::new_provider(db, Provider::DEFAULT); //default provider
 
         let log_provider = Self::new_provider(
             db,
             Provider {
-                write: |db, actor, data| {
+                // Appends whatever the offset, one record per line
+                write: |db, actor, _node, _offset, data| {
                     let caps = policy::Capability::default().with(policy::Capability::WRITE_LOG);
                     if policy::Manager::check_capability(db, actor, caps) {
-                        let s = unsafe { str::from_raw_parts(data.as_ptr(), data.len()) };
-                        kprint!("{}", s);
+                        let s = str::from_utf8(data).map_err(|_| Error::Invalid)?;
+                        log::Manager::log_lines("user", s);
                         Ok(data.len())
                     } else {
                         Err(Error::Policy)
                     }
                 },
-                read: |_db, _actor, _data| Err(Error::Policy),
+                // The ring as `dmesg` prints it, which moves under the offset as records come in
+                read: |_db, _actor, _node, offset, data| Ok(log::Manager::read(&log::Filter::ALL, offset, data)),
+                stat: |_db, _actor, _node| Ok(Stat { size: log::Manager::get_size(&log::Filter::ALL), children: 0 }),
+                ..Provider::DEFAULT
             },
         );
 
@@ -93,7 +378,9 @@ impl Manager {
         Self::new_node(db, "cache", mutable_dir);
         Self::new_node(db, "runtime", mutable_dir);
         let mutable_logs_dir = Self::new_node(db, "logs", mutable_dir);
-        Self::new_node_with_provider(db, "radian_core.log", mutable_logs_dir, log_provider);
+        let log_node = Self::new_node_with_provider(db, "radian_core.log", mutable_logs_dir, log_provider);
+        // Open to all, `WRITE_LOG` is what decides
+        Self::get_node_mut(db, log_node).set_permissions(policy::Permissions::shared());
 
         let systen_dir = Self::new_node(db, "system", root_handle);
         Self::new_node(db, "include", systen_dir);
@@ -101,7 +388,8 @@ impl Manager {
         Self::new_node(db, "opt", systen_dir);
         Self::new_node(db, "run", systen_dir);
 
-        Self::new_node(db, "temp", root_handle);
+        let temp_dir = Self::new_node(db, "temp", root_handle);
+        Self::get_node_mut(db, temp_dir).set_permissions(policy::Permissions::sticky());
 
         let user_dir = Self::new_node(db, "user", root_handle);
         let default_user_dir = Self::new_node(db, "admin", user_dir);
@@ -125,53 +413,1204 @@ impl Manager {
         parent: NodeHandle,
         provider: ProviderHandle,
     ) -> NodeHandle {
-        let bytes = core::array::from_fn(|i| name.as_bytes().get(i).copied().unwrap_or(0));
-        db.vfs_nodes.push(Node {
-            name: bytes,
-            parent,
-            provider,
-        });
-        NodeHandle((db.vfs_nodes.len() - 1) as u16)
+        // Owner and permissions come from the parent, the root's from nowhere
+        let (owner, group, permissions) = match db.vfs_nodes.is_empty() {
+            true => (policy::Manager::get_admin(), db::ObjectHandle::new::<{ db::ObjectHandle::GROUP }>(0), policy::Permissions::public()),
+            false => {
+                let parent = Self::get_node(db, parent);
+                (parent.owner, parent.group, parent.permissions)
+            }
+        };
+        let children = Children::default();
+        let node = Node { name: String::from(name), parent, provider, children, owner, group, permissions };
+        let (id, generation) = db.vfs_nodes.insert(node);
+        let handle = NodeHandle { id, generation };
+        // The root is its own parent but no child of itself
+        if handle != parent {
+            Self::get_node_mut(db, parent).children.insert(name, handle);
+        }
+        handle
+    }
+    /// Takes a node with no children out of the tree, for providers, see `remove`
+    /// for the checked way. Its handle stops working
+    pub fn remove_node(db: &mut db::Database, handle: NodeHandle) -> core::result::Result<(), Error> {
+        assert!(!handle.is_root());
+        if Self::get_node(db, handle).get_child_count() != 0 {
+            return Err(Error::NotEmpty);
+        }
+        let node = db.vfs_nodes.remove(handle.id, handle.generation).expect("stale node handle");
+        Self::get_node_mut(db, node.parent).children.remove(&node.name, handle);
+        Ok(())
+    }
+    /// Puts `handle` under `parent` as `name`, for providers, see `rename` for
+    /// the checked way
+    pub fn move_node(db: &mut db::Database, handle: NodeHandle, parent: NodeHandle, name: &str) {
+        assert!(!handle.is_root());
+        let node = Self::get_node_mut(db, handle);
+        let (old_parent, old_name) = (node.parent, core::mem::replace(&mut node.name, String::from(name)));
+        node.parent = parent;
+        Self::get_node_mut(db, old_parent).children.remove(&old_name, handle);
+        Self::get_node_mut(db, parent).children.insert(name, handle);
     }
     pub fn for_each_children<F: FnMut(NodeHandle)>(db: &db::Database, which: NodeHandle, mut f: F) {
-        for i in 1..db.vfs_nodes.len() {
-            let node = &db.vfs_nodes[i];
-            if node.parent == which {
-                f(NodeHandle(i as u16));
-            }
+        for &(_, child) in &Self::get_node(db, which).children.order {
+            f(child);
         }
     }
+    /// The `index`th child in the tree, in creation order
+    pub fn get_child(db: &db::Database, which: NodeHandle, index: usize) -> Option<NodeHandle> {
+        Self::get_node(db, which).children.get(index)
+    }
     pub fn find_children(db: &db::Database, from: NodeHandle, name: &str) -> Option<NodeHandle> {
-        for i in 1..db.vfs_nodes.len() {
-            let node = &db.vfs_nodes[i];
-            if node.parent == from && node.get_name() == name {
-                return Some(NodeHandle(i as u16));
+        Self::get_node(db, from).children.candidates(name).find(|&c| Self::get_node(db, c).get_name() == name)
+    }
+    /// Walks `path` from `from`, or from the root when it starts with `/`.
+    /// Empty names and `.` stay put, `..` of the root is the root, symlinks
+    /// are followed from the directory they are in, the last one too
+    pub fn resolve_path(db: &db::Database, actor: db::ObjectHandle, from: NodeHandle, path: &str) -> core::result::Result<NodeHandle, Error> {
+        Self::resolve(db, actor, from, path, true, &mut 0)
+    }
+    /// Like `resolve_path` but a symlink at the end is returned rather than
+    /// followed, for removing or renaming the link itself
+    pub fn resolve_link(db: &db::Database, actor: db::ObjectHandle, from: NodeHandle, path: &str) -> core::result::Result<NodeHandle, Error> {
+        Self::resolve(db, actor, from, path, false, &mut 0)
+    }
+    /// The directory `path` would be in and its last name, which need not exist
+    pub fn resolve_parent<'a>(
+        db: &db::Database,
+        actor: db::ObjectHandle,
+        from: NodeHandle,
+        path: &'a str,
+    ) -> core::result::Result<(NodeHandle, &'a str), Error> {
+        let trimmed = path.trim_end_matches('/');
+        let (dir, name) = match trimmed.rfind('/') {
+            Some(i) => (&trimmed[..i + 1], &trimmed[i + 1..]),
+            None => ("", trimmed),
+        };
+        Ok((Self::resolve_path(db, actor, from, dir)?, name))
+    }
+    /// `links` counts the symlinks followed so far, across the recursion.
+    /// Every directory looked into has to be readable by `actor`, a `from`
+    /// that was removed meanwhile is `Error::NotFound`
+    fn resolve(
+        db: &db::Database,
+        actor: db::ObjectHandle,
+        from: NodeHandle,
+        path: &str,
+        follow_last: bool,
+        links: &mut usize,
+    ) -> core::result::Result<NodeHandle, Error> {
+        if path.len() > MAX_PATH {
+            return Err(Error::Invalid);
+        }
+        let mut node = if path.starts_with('/') { NodeHandle::default() } else { from };
+        if !Self::is_valid(db, node) {
+            return Err(Error::NotFound);
+        }
+        let buf = db::PathBuf::from_str(path);
+        let mut names = buf.path().components().filter(|&c| !c.is_empty() && c != ".").peekable();
+        while let Some(name) = names.next() {
+            if name == ".." {
+                node = *Self::get_node(db, node).get_parent();
+                continue;
+            }
+            Self::check_access(db, actor, node, policy::Access::Read)?;
+            let child = (Self::get_provider(db, node).lookup)(db, actor, node, name).ok_or(Error::NotFound)?;
+            if names.peek().is_none() && !follow_last {
+                return Ok(child);
             }
+            let mut target = [0u8; MAX_PATH];
+            node = match Self::readlink(db, actor, child, &mut target) {
+                Ok(len) => {
+                    *links += 1;
+                    if *links > MAX_LINKS {
+                        return Err(Error::Loop);
+                    }
+                    let target = str::from_utf8(&target[..len]).map_err(|_| Error::Invalid)?;
+                    Self::resolve(db, actor, node, target, true, links)?
+                }
+                Err(_) => child,
+            };
         }
-        None
+        Ok(node)
+    }
+    /// Displays as the absolute path of `node`, symlinks are not looked into
+    pub fn get_path(db: &db::Database, node: NodeHandle) -> NodePath<'_> {
+        NodePath { db, node }
+    }
+    /// Handle for a `NodeHandle::get_raw` coming from outside the kernel,
+    /// `None` if that node is gone
+    pub fn find_node_by_raw(db: &db::Database, raw: u64) -> Option<NodeHandle> {
+        let handle = NodeHandle { id: u32::try_from(raw & 0xffff_ffff).ok()?, generation: u16::try_from(raw >> 32).ok()? };
+        Self::is_valid(db, handle).then_some(handle)
     }
+    /// False once the node was removed
+    pub fn is_valid(db: &db::Database, handle: NodeHandle) -> bool {
+        db.vfs_nodes.get(handle.id, handle.generation).is_some()
+    }
+    /// Panics on a removed node, `is_valid` first when unsure
     pub fn get_node(db: &db::Database, handle: NodeHandle) -> &Node {
-        &db.vfs_nodes[handle.0 as usize]
+        db.vfs_nodes.get(handle.id, handle.generation).expect("stale node handle")
     }
     pub fn get_node_mut(db: &mut db::Database, handle: NodeHandle) -> &mut Node {
-        db.vfs_nodes.get_mut(handle.0 as usize).unwrap()
+        db.vfs_nodes.get_mut(handle.id, handle.generation).expect("stale node handle")
     }
-    pub fn invoke_provider_write(
-        db: &mut db::Database,
-        handle: ProviderHandle,
-        actor: db::ObjectHandle,
-        data: &[u8],
-    ) -> Result {
-        let f = db.vfs_providers[handle.0 as usize].write;
-        f(db, actor, data)
+    fn get_provider(db: &db::Database, node: NodeHandle) -> Provider {
+        db.vfs_providers[Self::get_node(db, node).provider.0 as usize]
+    }
+
+    pub fn stat(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle) -> core::result::Result<Stat, Error> {
+        Self::check_access(db, actor, node, policy::Access::Read)?;
+        (Self::get_provider(db, node).stat)(db, actor, node)
+    }
+    /// The `index`th child as the node's provider sees it, `None` also when
+    /// `actor` may not read the directory
+    pub fn readdir(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle, index: usize) -> Option<NodeHandle> {
+        Self::check_access(db, actor, node, policy::Access::Read).ok()?;
+        (Self::get_provider(db, node).readdir)(db, actor, node, index)
+    }
+    /// `Error::Policy` unless the policy engine lets `actor` at `node` that way
+    pub fn check_access(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle, access: policy::Access) -> core::result::Result<(), Error> {
+        let n = Self::get_node(db, node);
+        match policy::Manager::check_access(db, actor, n.owner, n.group, n.permissions, access) {
+            true => Ok(()),
+            false => Err(Error::Policy),
+        }
+    }
+    /// Only the owner changes permissions, or whoever may write anything
+    fn check_owner(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle) -> core::result::Result<(), Error> {
+        let owner = Self::get_node(db, node).owner;
+        let is_owner = policy::Manager::get_actor_user(db, actor).map_or(actor == db::ObjectHandle::default(), |u| u == owner);
+        match is_owner || policy::Manager::check_action(db, actor, policy::Action::default().with(policy::Action::WRITE_TO)) {
+            true => Ok(()),
+            false => Err(Error::Policy),
+        }
+    }
+    /// In a sticky directory only the owner of `node` removes or renames it,
+    /// see `check_owner`
+    fn check_sticky(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle) -> core::result::Result<(), Error> {
+        let parent = *Self::get_node(db, node).get_parent();
+        match Self::get_node(db, parent).permissions.is_sticky() {
+            true => Self::check_owner(db, actor, node),
+            false => Ok(()),
+        }
+    }
+    /// Giving a node away is for the kernel and the admin, an owner could
+    /// otherwise hand files to users who never asked for them
+    pub fn chown(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, owner: db::ObjectHandle, group: db::ObjectHandle) -> core::result::Result<(), Error> {
+        let is_admin = policy::Manager::get_actor_user(db, actor) == Some(policy::Manager::get_admin());
+        if actor != db::ObjectHandle::default() && !is_admin {
+            return Err(Error::Policy);
+        }
+        Self::get_node_mut(db, node).set_owner(owner, group);
+        Ok(())
+    }
+    pub fn chmod(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, permissions: policy::Permissions) -> core::result::Result<(), Error> {
+        Self::check_owner(db, actor, node)?;
+        Self::get_node_mut(db, node).set_permissions(permissions);
+        Ok(())
     }
-    pub fn invoke_provider_read(
+    /// One-shot read without a file, still opened and closed on the provider
+    pub fn read_node(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, offset: u64, data: &mut [u8]) -> Result {
+        Self::check_access(db, actor, node, policy::Access::Read)?;
+        let provider = Self::get_provider(db, node);
+        (provider.open)(db, actor, node)?;
+        let result = (provider.read)(db, actor, node, offset, data);
+        (provider.close)(db, actor, node);
+        result
+    }
+    /// One-shot write without a file, see `read_node`
+    pub fn write_node(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, offset: u64, data: &[u8]) -> Result {
+        Self::check_access(db, actor, node, policy::Access::Write)?;
+        let provider = Self::get_provider(db, node);
+        (provider.open)(db, actor, node)?;
+        let result = (provider.write)(db, actor, node, offset, data);
+        (provider.close)(db, actor, node);
+        result
+    }
+
+    fn get_files(db: &mut db::Database, actor: db::ObjectHandle) -> core::result::Result<&mut [Option<OpenFile>; MAX_OPEN_FILES], Error> {
+        if actor.get_type() != db::ObjectHandle::WORKER {
+            return Err(Error::BadHandle);
+        }
+        db.workers.get_mut(actor.get_id() as usize).map(|w| w.get_files_mut()).ok_or(Error::BadHandle)
+    }
+    fn get_file(db: &mut db::Database, actor: db::ObjectHandle, file: FileHandle) -> core::result::Result<&mut OpenFile, Error> {
+        Self::get_files(db, actor)?.get_mut(file.0 as usize).and_then(|f| f.as_mut()).ok_or(Error::BadHandle)
+    }
+
+    /// `actor` must be a worker that may read or write `node`, which of the
+    /// two is checked on each `read` and `write`. The file starts at offset 0
+    pub fn open(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle) -> core::result::Result<FileHandle, Error> {
+        let slot = Self::get_files(db, actor)?.iter().position(|f| f.is_none()).ok_or(Error::TooMany)?;
+        Self::check_access(db, actor, node, policy::Access::Read).or_else(|_| Self::check_access(db, actor, node, policy::Access::Write))?;
+        (Self::get_provider(db, node).open)(db, actor, node)?;
+        Self::get_files(db, actor)?[slot] = Some(OpenFile { node, offset: 0 });
+        Ok(FileHandle(slot as u16))
+    }
+    pub fn close(db: &mut db::Database, actor: db::ObjectHandle, file: FileHandle) -> core::result::Result<(), Error> {
+        let node = Self::get_file(db, actor, file)?.node;
+        Self::get_files(db, actor)?[file.0 as usize] = None;
+        (Self::get_provider(db, node).close)(db, actor, node);
+        Ok(())
+    }
+    /// Everything `actor` still has open, for when a worker goes away
+    pub fn close_all(db: &mut db::Database, actor: db::ObjectHandle) {
+        for id in 0..MAX_OPEN_FILES {
+            let _ = Self::close(db, actor, FileHandle(id as u16));
+        }
+    }
+    pub fn get_file_node(db: &mut db::Database, actor: db::ObjectHandle, file: FileHandle) -> core::result::Result<NodeHandle, Error> {
+        Self::get_file(db, actor, file).map(|f| f.node)
+    }
+    /// At the file's offset, which moves past what was read
+    pub fn read(db: &mut db::Database, actor: db::ObjectHandle, file: FileHandle, data: &mut [u8]) -> Result {
+        let OpenFile { node, offset } = *Self::get_file(db, actor, file)?;
+        Self::check_access(db, actor, node, policy::Access::Read)?;
+        let len = (Self::get_provider(db, node).read)(db, actor, node, offset, data)?;
+        Self::get_file(db, actor, file)?.offset = offset + len as u64;
+        Ok(len)
+    }
+    /// At the file's offset, which moves past what was written
+    pub fn write(db: &mut db::Database, actor: db::ObjectHandle, file: FileHandle, data: &[u8]) -> Result {
+        let OpenFile { node, offset } = *Self::get_file(db, actor, file)?;
+        Self::check_access(db, actor, node, policy::Access::Write)?;
+        let len = (Self::get_provider(db, node).write)(db, actor, node, offset, data)?;
+        Self::get_file(db, actor, file)?.offset = offset + len as u64;
+        Ok(len)
+    }
+    /// Returns the new offset, which may be past the end but not before the start
+    pub fn seek(db: &mut db::Database, actor: db::ObjectHandle, file: FileHandle, to: Seek) -> core::result::Result<u64, Error> {
+        let OpenFile { node, offset } = *Self::get_file(db, actor, file)?;
+        let offset = match to {
+            Seek::Start(n) => Some(n),
+            Seek::Current(n) => offset.checked_add_signed(n),
+            Seek::End(n) => Self::stat(db, actor, node)?.size.checked_add_signed(n),
+        }
+        .ok_or(Error::Invalid)?;
+        Self::get_file(db, actor, file)?.offset = offset;
+        Ok(offset)
+    }
+
+    /// Whether any worker has `node` open
+    pub fn is_open(db: &db::Database, node: NodeHandle) -> bool {
+        db.workers.iter().take(db.workers.len()).any(|w| w.get_files().iter().flatten().any(|f| f.node == node))
+    }
+    /// Whether `node` is `ancestor` or somewhere under it
+    pub fn is_within(db: &db::Database, mut node: NodeHandle, ancestor: NodeHandle) -> bool {
+        loop {
+            if node == ancestor {
+                return true;
+            }
+            if node.is_root() {
+                return false;
+            }
+            node = *Self::get_node(db, node).get_parent();
+        }
+    }
+    fn check_name(db: &db::Database, parent: NodeHandle, name: &str) -> core::result::Result<(), Error> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.len() > MAX_NAME {
+            return Err(Error::Invalid);
+        }
+        if Self::find_children(db, parent, name).is_some() {
+            return Err(Error::Exists);
+        }
+        Ok(())
+    }
+    /// A new empty file or directory in `parent`, if its provider supports
+    /// that. It belongs to `actor`'s user and the parent's group, readable by
+    /// all and writable by the owner whatever the parent allows
+    pub fn create(db: &mut db::Database, actor: db::ObjectHandle, parent: NodeHandle, name: &str, kind: NodeKind) -> core::result::Result<NodeHandle, Error> {
+        Self::check_name(db, parent, name)?;
+        Self::check_access(db, actor, parent, policy::Access::Write)?;
+        let node = (Self::get_provider(db, parent).create)(db, actor, parent, name, kind)?;
+        Self::set_creator(db, actor, node);
+        Ok(node)
+    }
+    fn set_creator(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle) {
+        let user = policy::Manager::get_actor_user(db, actor);
+        let n = Self::get_node_mut(db, node);
+        n.set_permissions(policy::Permissions::public());
+        if let Some(user) = user {
+            n.owner = user;
+        }
+    }
+    /// A file or an empty directory that nobody has open and nothing is mounted
+    /// on, `actor` needs to be able to write its directory and, if that is
+    /// sticky, own it
+    pub fn remove(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle) -> core::result::Result<(), Error> {
+        if node.is_root() {
+            return Err(Error::Invalid);
+        }
+        // Not `readdir`, a directory `actor` cannot list still has children
+        if Self::get_child(db, node, 0).is_some() || (Self::get_provider(db, node).readdir)(db, actor, node, 0).is_some() {
+            return Err(Error::NotEmpty);
+        }
+        if Self::is_open(db, node) || Self::is_mount_point(db, node) {
+            return Err(Error::Busy);
+        }
+        Self::check_access(db, actor, *Self::get_node(db, node).get_parent(), policy::Access::Write)?;
+        Self::check_sticky(db, actor, node)?;
+        (Self::get_provider(db, node).remove)(db, actor, node)
+    }
+    /// Moves `node` under `parent` as `name`, never into itself, both
+    /// directories need to be writable by `actor` and a sticky one it leaves
+    /// needs `actor` to own it
+    pub fn rename(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, parent: NodeHandle, name: &str) -> core::result::Result<(), Error> {
+        Self::check_name(db, parent, name)?;
+        if Self::is_within(db, parent, node) {
+            return Err(Error::Invalid);
+        }
+        if Self::is_mount_point(db, node) {
+            return Err(Error::Busy);
+        }
+        Self::check_access(db, actor, *Self::get_node(db, node).get_parent(), policy::Access::Write)?;
+        Self::check_access(db, actor, parent, policy::Access::Write)?;
+        Self::check_sticky(db, actor, node)?;
+        (Self::get_provider(db, node).rename)(db, actor, node, parent, name)
+    }
+    pub fn truncate(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle, size: u64) -> core::result::Result<(), Error> {
+        Self::check_access(db, actor, node, policy::Access::Write)?;
+        (Self::get_provider(db, node).truncate)(db, actor, node, size)
+    }
+    /// The target of symlink `node`, `Error::Invalid` for anything else
+    pub fn readlink(db: &db::Database, actor: db::ObjectHandle, node: NodeHandle, out: &mut [u8]) -> Result {
+        (Self::get_provider(db, node).readlink)(db, actor, node, out)
+    }
+    /// A new symlink in `parent` pointing at `target`, which need not exist
+    pub fn symlink(db: &mut db::Database, actor: db::ObjectHandle, parent: NodeHandle, name: &str, target: &str) -> core::result::Result<NodeHandle, Error> {
+        Self::check_name(db, parent, name)?;
+        if target.is_empty() || target.len() > MAX_PATH {
+            return Err(Error::Invalid);
+        }
+        Self::check_access(db, actor, parent, policy::Access::Write)?;
+        let node = (Self::get_provider(db, parent).symlink)(db, actor, parent, name, target)?;
+        Self::set_creator(db, actor, node);
+        Ok(node)
+    }
+
+    pub fn register_fs(db: &mut db::Database, fs: FsType) -> FsTypeHandle {
+        assert!(Self::find_fs(db, fs.name).is_none(), "{} registered twice", fs.name);
+        let provider = Self::new_provider(db, fs.provider);
+        db.vfs_fs_types.push(RegisteredFs { name: fs.name, provider, mount: fs.mount, unmount: fs.unmount });
+        FsTypeHandle((db.vfs_fs_types.len() - 1) as u16)
+    }
+    pub fn find_fs(db: &db::Database, name: &str) -> Option<FsTypeHandle> {
+        db.vfs_fs_types.iter().take(db.vfs_fs_types.len()).position(|f| f.name == name).map(|i| FsTypeHandle(i as u16))
+    }
+    pub fn get_fs_name(db: &db::Database, fs: FsTypeHandle) -> &'static str {
+        db.vfs_fs_types[fs.0 as usize].name
+    }
+    /// The provider every instance of `fs` shares
+    pub fn get_fs_provider(db: &db::Database, fs: FsTypeHandle) -> ProviderHandle {
+        db.vfs_fs_types[fs.0 as usize].provider
+    }
+
+    /// Mounts a new `fs` instance on `node`, which must be an unused node
+    /// without children that `actor` may write
+    pub fn mount(
         db: &mut db::Database,
-        handle: ProviderHandle,
         actor: db::ObjectHandle,
-        data: &mut [u8],
-    ) -> Result {
-        let f = db.vfs_providers[handle.0 as usize].write;
-        f(db, actor, data)
+        fs: FsTypeHandle,
+        node: NodeHandle,
+        source: Option<NodeHandle>,
+    ) -> core::result::Result<(), Error> {
+        if node.is_root() {
+            return Err(Error::Invalid);
+        }
+        if db.vfs_mounts.len() == db.vfs_mounts.max_len() {
+            return Err(Error::TooMany);
+        }
+        if Self::is_mount_point(db, node) || Self::is_open(db, node) {
+            return Err(Error::Busy);
+        }
+        Self::check_access(db, actor, node, policy::Access::Write)?;
+        if Self::get_child(db, node, 0).is_some() || (Self::get_provider(db, node).readdir)(db, actor, node, 0).is_some() {
+            return Err(Error::NotEmpty);
+        }
+        let RegisteredFs { provider, mount, .. } = db.vfs_fs_types[fs.0 as usize];
+        let previous = *Self::get_node(db, node).get_provider();
+        Self::get_node_mut(db, node).set_provider(provider);
+        match mount(db, actor, node, source) {
+            Ok(superblock) => {
+                db.vfs_mounts.push(Mount { root: node, fs, source, previous, superblock });
+                klog!(Info, "vfs", "mounted {} on {}", Self::get_fs_name(db, fs), Self::get_path(db, node));
+                Ok(())
+            }
+            Err(e) => {
+                // Whatever the type made of it before failing is its own to clean up
+                Self::get_node_mut(db, node).set_provider(previous);
+                Err(e)
+            }
+        }
+    }
+    /// Undoes `mount`, refused while anything below is open or mounted on
+    pub fn unmount(db: &mut db::Database, actor: db::ObjectHandle, node: NodeHandle) -> core::result::Result<(), Error> {
+        let index = Self::find_mount(db, node).ok_or(Error::Invalid)?;
+        Self::check_access(db, actor, node, policy::Access::Write)?;
+        let open = db.workers.iter().take(db.workers.len()).any(|w| {
+            w.get_files().iter().flatten().any(|f| Self::is_within(db, f.node, node))
+        });
+        let nested = db.vfs_mounts.iter().take(db.vfs_mounts.len()).any(|m| m.root != node && Self::is_within(db, m.root, node));
+        if open || nested {
+            return Err(Error::Busy);
+        }
+        (db.vfs_fs_types[db.vfs_mounts[index].fs.0 as usize].unmount)(db, actor, node);
+        let mount = db.vfs_mounts.remove(index);
+        Self::remove_subtree(db, node);
+        Self::get_node_mut(db, node).set_provider(mount.previous);
+        klog!(Info, "vfs", "unmounted {} from {}", Self::get_fs_name(db, mount.fs), Self::get_path(db, node));
+        Ok(())
+    }
+    /// Children first, `node` itself stays
+    fn remove_subtree(db: &mut db::Database, node: NodeHandle) {
+        while let Some(child) = Self::get_child(db, node, 0) {
+            Self::remove_subtree(db, child);
+            Self::remove_node(db, child).expect("children go first");
+        }
+    }
+    pub fn is_mount_point(db: &db::Database, node: NodeHandle) -> bool {
+        Self::find_mount(db, node).is_some()
+    }
+    fn find_mount(db: &db::Database, root: NodeHandle) -> Option<usize> {
+        db.vfs_mounts.iter().take(db.vfs_mounts.len()).position(|m| m.root == root)
+    }
+    /// The mount `node` is part of, the innermost if they nest
+    pub fn get_mount(db: &db::Database, mut node: NodeHandle) -> Option<&Mount> {
+        loop {
+            if let Some(index) = Self::find_mount(db, node) {
+                return Some(&db.vfs_mounts[index]);
+            }
+            if node.is_root() {
+                return None;
+            }
+            node = *Self::get_node(db, node).get_parent();
+        }
+    }
+    /// The state the type's `mount` returned for the instance `node` is in
+    pub fn get_superblock<T: 'static>(db: &db::Database, node: NodeHandle) -> Option<&T> {
+        Self::get_mount(db, node)?.superblock.as_ref()?.downcast_ref()
+    }
+    pub fn get_superblock_mut<T: 'static>(db: &mut db::Database, node: NodeHandle) -> Option<&mut T> {
+        let root = Self::get_mount(db, node)?.root;
+        let index = Self::find_mount(db, root)?;
+        db.vfs_mounts[index].superblock.as_mut()?.downcast_mut()
+    }
+    pub fn for_each_mount<F: FnMut(&Mount)>(db: &db::Database, mut f: F) {
+        for mount in db.vfs_mounts.iter().take(db.vfs_mounts.len()) {
+            f(mount);
+        }
+    }
+}
+
+/// See `Manager::get_path`
+pub struct NodePath<'a> {
+    db: &'a db::Database,
+    node: NodeHandle,
+}
+impl fmt::Display for NodePath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn write_node(f: &mut fmt::Formatter<'_>, db: &db::Database, node: NodeHandle) -> fmt::Result {
+            if node.is_root() {
+                return Ok(());
+            }
+            let node = Manager::get_node(db, node);
+            write_node(f, db, *node.get_parent())?;
+            write!(f, "/{}", node.get_name())
+        }
+        if self.node.is_root() {
+            return f.write_str("/");
+        }
+        write_node(f, self.db, self.node)
+    }
+}
+
+fn create_command(state: &mut console::State, path: &str, kind: NodeKind) {
+    let (parent, name) = match Manager::resolve_parent(state.db, state.current_actor, state.current_node, path) {
+        Ok(found) => found,
+        Err(e) => {
+            console_error!(state, "{}: {:?}\r\n", path, e);
+            return;
+        }
+    };
+    if kind == NodeKind::File && Manager::find_children(state.db, parent, name).is_some() {
+        return;
+    }
+    if let Err(e) = Manager::create(state.db, state.current_actor, parent, name, kind) {
+        console_error!(state, "{:?}\r\n", e);
+    }
+}
+
+/// Fine have your stack overhead
+fn print_tree(db: &db::Database, handle: NodeHandle, level: usize) -> bool {
+    let tree_print_node = |handle, level| {
+        let node = Manager::get_node(db, handle);
+        let name = node.get_name();
+        if level > 0 {
+            for i in 0..level - 1 {
+                kprint!("│   ");
+            }
+            kprint!("└── ");
+        }
+        kprint!("{}\r\n", name);
+    };
+    let mut walk_had_children = false;
+    Manager::for_each_children(db, handle, |handle| {
+        tree_print_node(handle, level);
+        print_tree(db, handle, level + 1);
+        walk_had_children = true;
+    });
+    walk_had_children
+}
+
+console_commands! {
+    Command {
+        name: "list",
+        category: "vfs",
+        desc: "list all nodes",
+        help: "",
+        args: &[],
+        handler: |state, _args| {
+            Manager::for_each_children(state.db, state.current_node, |handle| {
+                let node = Manager::get_node(state.db, handle);
+                let name = node.get_name();
+                kprint!("- {}\r\n", name);
+            });
+        },
+    },
+    Command {
+        name: "cd",
+        category: "vfs",
+        desc: "change node or print current",
+        help: "Paths are `/` separated, absolute from the root or relative, `..` works\n\
+               and symlinks are followed. Without a path prints the current one.",
+        args: &[ArgSpec::optional("path", ArgKind::Word)],
+        handler: |state, args| {
+            if let Some(name) = args.get(1) {
+                match console::Manager::resolve_path(state, name) {
+                    Ok(handle) => state.current_node = handle,
+                    Err(e) => console_error!(state, "{}: {:?}\r\n", name, e),
+                }
+            } else {
+                kprint!("{}\r\n", Manager::get_path(state.db, state.current_node));
+            }
+        },
+    },
+    Command {
+        name: "write",
+        category: "vfs",
+        desc: "write to current node",
+        help: "Goes through the node's provider as the current worker, policy applies.",
+        args: &[ArgSpec::required("data", ArgKind::Rest)],
+        handler: |state, args| {
+            let res = Manager::write_node(state.db, state.current_actor, state.current_node, 0, args.get_rest(1).as_bytes());
+            kprint!("\r\n{:?}\r\n", res);
+            state.failed |= res.is_err();
+        },
+    },
+    Command {
+        name: "stat",
+        category: "vfs",
+        desc: "size, children, permissions and owner of a node",
+        help: "Size and children as the node's provider reports them to the current worker.\n\
+               Permissions are read/write for the owner, its group and everyone else.",
+        args: &[ArgSpec::optional("path", ArgKind::Word)],
+        handler: |state, args| {
+            let node = match console::Manager::resolve_path(state, args.get(1).unwrap_or(".")) {
+                Ok(node) => node,
+                Err(e) => {
+                    console_error!(state, "{:?}\r\n", e);
+                    return;
+                }
+            };
+            match Manager::stat(state.db, state.current_actor, node) {
+                Ok(stat) => {
+                    let n = Manager::get_node(state.db, node);
+                    let owner = policy::Manager::get_user(state.db, n.get_owner()).get_name();
+                    let group = state.db.groups.get(n.get_group().get_id() as usize).map_or("?", |g| g.get_name());
+                    kprint!("node {} size {} children {} {} {}:{}\r\n", node.get_id(), stat.size, stat.children, n.get_permissions(), owner, group);
+                }
+                Err(e) => console_error!(state, "{:?}\r\n", e),
+            }
+        },
+    },
+    Command {
+        name: "cat",
+        category: "vfs",
+        desc: "print a node",
+        help: "Reads through the node's provider until it returns nothing.",
+        args: &[ArgSpec::required("path", ArgKind::Word)],
+        handler: |state, args| {
+            let node = match console::Manager::resolve_path(state, args.get(1).unwrap()) {
+                Ok(node) => node,
+                Err(e) => {
+                    console_error!(state, "{:?}\r\n", e);
+                    return;
+                }
+            };
+            let mut buffer = [0u8; 256];
+            let mut offset = 0;
+            loop {
+                match Manager::read_node(state.db, state.current_actor, node, offset, &mut buffer) {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        kprint!("{}", str::from_utf8(&buffer[..len]).unwrap_or("<binary>"));
+                        offset += len as u64;
+                    }
+                    Err(e) => {
+                        console_error!(state, "{:?}\r\n", e);
+                        return;
+                    }
+                }
+            }
+            kprint!("\r\n");
+        },
+    },
+    Command {
+        name: "touch",
+        category: "vfs",
+        desc: "create an empty file",
+        help: "Does nothing if it exists, the directory must be on a provider that can\n\
+               create (tmpfs: /temp, /mutable/runtime, /mount, /user/*/home).",
+        args: &[ArgSpec::required("path", ArgKind::Word)],
+        handler: |state, args| create_command(state, args.get(1).unwrap(), NodeKind::File),
+    },
+    Command {
+        name: "mkdir",
+        category: "vfs",
+        desc: "create a directory",
+        help: "Same places as `touch`.",
+        args: &[ArgSpec::required("path", ArgKind::Word)],
+        handler: |state, args| create_command(state, args.get(1).unwrap(), NodeKind::Directory),
+    },
+    Command {
+        name: "rm",
+        category: "vfs",
+        desc: "remove a file or an empty directory",
+        help: "Refused while any worker has it open, a symlink is removed, not its target.",
+        args: &[ArgSpec::required("path", ArgKind::Word)],
+        handler: |state, args| {
+            let node = match Manager::resolve_link(state.db, state.current_actor, state.current_node, args.get(1).unwrap()) {
+                Ok(node) => node,
+                Err(e) => {
+                    console_error!(state, "{:?}\r\n", e);
+                    return;
+                }
+            };
+            let parent = *Manager::get_node(state.db, node).get_parent();
+            match Manager::remove(state.db, state.current_actor, node) {
+                Ok(()) if state.current_node == node => state.current_node = parent,
+                Ok(()) => {}
+                Err(e) => console_error!(state, "{:?}\r\n", e),
+            }
+        },
+    },
+    Command {
+        name: "mv",
+        category: "vfs",
+        desc: "move or rename a node",
+        help: "<to> is the new path including the name, within one provider.",
+        args: &[ArgSpec::required("from", ArgKind::Word), ArgSpec::required("to", ArgKind::Word)],
+        handler: |state, args| {
+            let from = Manager::resolve_link(state.db, state.current_actor, state.current_node, args.get(1).unwrap());
+            let to = Manager::resolve_parent(state.db, state.current_actor, state.current_node, args.get(2).unwrap());
+            let (node, (parent, name)) = match (from, to) {
+                (Ok(node), Ok(to)) => (node, to),
+                (Err(e), _) | (_, Err(e)) => {
+                    console_error!(state, "{:?}\r\n", e);
+                    return;
+                }
+            };
+            if let Err(e) = Manager::rename(state.db, state.current_actor, node, parent, name) {
+                console_error!(state, "{:?}\r\n", e);
+            }
+        },
+    },
+    Command {
+        name: "ln",
+        category: "vfs",
+        desc: "create a symlink",
+        help: "<target> is stored as given and resolved from the link's directory when\n\
+               followed, it need not exist. Same places as `touch`.",
+        args: &[ArgSpec::required("target", ArgKind::Word), ArgSpec::required("path", ArgKind::Word)],
+        handler: |state, args| {
+            let (target, path) = (args.get(1).unwrap(), args.get(2).unwrap());
+            let result = Manager::resolve_parent(state.db, state.current_actor, state.current_node, path)
+                .and_then(|(parent, name)| Manager::symlink(state.db, state.current_actor, parent, name, target));
+            if let Err(e) = result {
+                console_error!(state, "{}: {:?}\r\n", path, e);
+            }
+        },
+    },
+    Command {
+        name: "chmod",
+        category: "vfs",
+        desc: "change who may read and write a node",
+        help: "<permissions> is `rw` for owner, group and others in that order with `-`\n\
+               for what is not allowed, e.g `rwr-r-`, and a `t` after it makes a directory\n\
+               sticky: its entries are only removed or renamed by their owner. Only for the owner.",
+        args: &[ArgSpec::required("permissions", ArgKind::Word), ArgSpec::required("path", ArgKind::Word)],
+        handler: |state, args| {
+            let Some(permissions) = policy::Permissions::parse(args.get(1).unwrap()) else {
+                console_error!(state, "expected something like rwr-r-\r\n");
+                return;
+            };
+            let result = console::Manager::resolve_path(state, args.get(2).unwrap())
+                .and_then(|node| Manager::chmod(state.db, state.current_actor, node, permissions));
+            if let Err(e) = result {
+                console_error!(state, "{:?}\r\n", e);
+            }
+        },
+    },
+    Command {
+        name: "chown",
+        category: "vfs",
+        desc: "give a node to another user",
+        help: "<user> and [group] are names, the group stays if not given. Only for the admin.",
+        args: &[
+            ArgSpec::required("user", ArgKind::Word),
+            ArgSpec::required("path", ArgKind::Word),
+            ArgSpec::optional("group", ArgKind::Word),
+        ],
+        handler: |state, args| {
+            let user = policy::Manager::find_user(state.db, args.get(1).unwrap());
+            let group = args.get(3).map(|name| policy::Manager::find_group(state.db, name).ok_or(name));
+            let node = console::Manager::resolve_path(state, args.get(2).unwrap());
+            match (user, group.transpose(), node) {
+                (None, _, _) => console_error!(state, "no user {}\r\n", args.get(1).unwrap()),
+                (_, Err(name), _) => console_error!(state, "no group {}\r\n", name),
+                (_, _, Err(e)) => console_error!(state, "{:?}\r\n", e),
+                (Some(user), Ok(group), Ok(node)) => {
+                    let group = group.unwrap_or(Manager::get_node(state.db, node).get_group());
+                    if let Err(e) = Manager::chown(state.db, state.current_actor, node, user, group) {
+                        console_error!(state, "{:?}\r\n", e);
+                    }
+                }
+            }
+        },
+    },
+    Command {
+        name: "mount",
+        category: "vfs",
+        desc: "mount a filesystem on an empty node",
+        help: "<type> is a registered filesystem type (see `mounts`), [source] the device\n\
+               node it reads if it needs one. `mkdir /mount/<name>` for somewhere to put it.",
+        args: &[
+            ArgSpec::required("type", ArgKind::Word),
+            ArgSpec::required("path", ArgKind::Word),
+            ArgSpec::optional("source", ArgKind::Word),
+        ],
+        handler: |state, args| {
+            let Some(fs) = Manager::find_fs(state.db, args.get(1).unwrap()) else {
+                console_error!(state, "no filesystem type {}\r\n", args.get(1).unwrap());
+                return;
+            };
+            let source = args.get(3).map(|path| console::Manager::resolve_path(state, path)).transpose();
+            let result = console::Manager::resolve_path(state, args.get(2).unwrap())
+                .and_then(|node| Ok((node, source?)))
+                .and_then(|(node, source)| Manager::mount(state.db, state.current_actor, fs, node, source));
+            if let Err(e) = result {
+                console_error!(state, "{:?}\r\n", e);
+            }
+        },
+    },
+    Command {
+        name: "umount",
+        category: "vfs",
+        desc: "unmount a filesystem",
+        help: "Refused while a worker has anything below it open or something is mounted\n\
+               further down. The node goes back to what it was before `mount`.",
+        args: &[ArgSpec::required("path", ArgKind::Word)],
+        handler: |state, args| {
+            let node = match console::Manager::resolve_path(state, args.get(1).unwrap()) {
+                Ok(node) => node,
+                Err(e) => {
+                    console_error!(state, "{:?}\r\n", e);
+                    return;
+                }
+            };
+            // The console may be somewhere inside
+            let inside = Manager::is_within(state.db, state.current_node, node);
+            match Manager::unmount(state.db, state.current_actor, node) {
+                Ok(()) if inside => state.current_node = node,
+                Ok(()) => {}
+                Err(e) => console_error!(state, "{:?}\r\n", e),
+            }
+        },
+    },
+    Command {
+        name: "mounts",
+        category: "vfs",
+        desc: "list mounts and filesystem types",
+        help: "",
+        args: &[],
+        handler: |state, _args| {
+            Manager::for_each_mount(state.db, |mount| {
+                kprint!("{} on {}", Manager::get_fs_name(state.db, mount.get_fs()), Manager::get_path(state.db, mount.get_root()));
+                if let Some(source) = mount.get_source() {
+                    kprint!(" from {}", Manager::get_path(state.db, source));
+                }
+                kprint!("\r\n");
+            });
+            kprint!("types:");
+            for fs in state.db.vfs_fs_types.iter().take(state.db.vfs_fs_types.len()) {
+                kprint!(" {}", fs.name);
+            }
+            kprint!("\r\n");
+        },
+    },
+    Command {
+        name: "tree",
+        category: "vfs",
+        desc: "list node tree",
+        help: "",
+        args: &[],
+        handler: |state, _args| {
+            print_tree(state.db, state.current_node, 0);
+        },
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::Subsystem::*;
+
+    /// Walk a `/` separated path from the root, the way the console does
+    fn lookup(db: &db::Database, path: &str) -> Option<NodeHandle> {
+        let buf = db::PathBuf::from_str(path);
+        let mut node = NodeHandle::default();
+        for name in buf.path().components().filter(|c| !c.is_empty()) {
+            node = Manager::find_children(db, node, name)?;
+        }
+        Some(node)
+    }
+
+    #[test]
+    fn init_builds_hierarchy() {
+        let db = db::Database::new_with(&[Vfs]);
+        let logs = lookup(&db, "/mutable/logs").unwrap();
+        let log = lookup(&db, "/mutable/logs/radian_core.log").unwrap();
+        assert_eq!(*Manager::get_node(&db, log).get_parent(), logs);
+        assert_eq!(Manager::get_node(&db, log).get_name(), "radian_core.log");
+        assert!(lookup(&db, "/user/admin/home").is_some());
+        assert!(lookup(&db, "/user/nobody").is_none());
+        assert!(lookup(&db, "/").unwrap().is_root());
+    }
+
+    #[test]
+    fn children_of_root() {
+        let db = db::Database::new_with(&[Vfs]);
+        let mut names = std::vec::Vec::new();
+        Manager::for_each_children(&db, NodeHandle::default(), |h| {
+            names.push(Manager::get_node(&db, h).get_name())
+        });
+        assert!(names.contains(&"devices"));
+        assert!(names.contains(&"temp"));
+        assert!(!names.contains(&"logs"));
+    }
+
+    #[test]
+    fn big_directories_stay_indexed() {
+        let mut db = db::Database::new_with(&[Vfs]);
+        let temp = lookup(&db, "/temp").unwrap();
+        let nodes: std::vec::Vec<_> = (0..100).map(|i| Manager::new_node(&mut db, &std::format!("n{i}"), temp)).collect();
+        assert_eq!(Manager::find_children(&db, temp, "n57"), Some(nodes[57]));
+        assert_eq!(Manager::get_child(&db, temp, 3), Some(nodes[3]));
+        Manager::remove_node(&mut db, nodes[57]).unwrap();
+        assert_eq!(Manager::find_children(&db, temp, "n57"), None);
+        assert_eq!(Manager::get_node(&db, temp).get_child_count(), 99);
+        let misc = lookup(&db, "/misc").unwrap();
+        Manager::move_node(&mut db, nodes[10], misc, "moved");
+        assert_eq!(Manager::find_children(&db, temp, "n10"), None);
+        assert_eq!(Manager::find_children(&db, misc, "moved"), Some(nodes[10]));
+        assert_eq!(Manager::get_path(&db, nodes[10]).to_string(), "/misc/moved");
+    }
+
+    #[test]
+    fn long_names_are_kept() {
+        let mut db = db::Database::new_with(&[Vfs]);
+        let temp = lookup(&db, "/temp").unwrap();
+        let name = "a_name_that_used_to_be_way_too_long_for_a_node";
+        let handle = Manager::new_node(&mut db, name, temp);
+        assert_eq!(Manager::get_node(&db, handle).get_name(), name);
+        assert_eq!(Manager::find_children(&db, temp, name), Some(handle));
+        let actor = db::ObjectHandle::default();
+        assert_eq!(Manager::create(&mut db, actor, temp, &"x".repeat(MAX_NAME + 1), NodeKind::File), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn log_write_needs_capability() {
+        let mut db = db::Database::new_with(&[Vfs]);
+        let log = lookup(&db, "/mutable/logs/radian_core.log").unwrap();
+        let user = policy::Manager::new_user(&mut db, "guest");
+        assert_eq!(Manager::write_node(&mut db, user, log, 0, b"hi"), Err(Error::Policy));
+        policy::Manager::add_rule(&mut db, policy::PolicyRule {
+            subject: user,
+            capabilities: policy::Capability::default().with(policy::Capability::WRITE_LOG),
+            ..Default::default()
+        });
+        assert_eq!(Manager::write_node(&mut db, user, log, 0, b"hi"), Ok(2));
+    }
+
+    #[test]
+    fn log_reads_back_records() {
+        let mut db = db::Database::new_with(&[Vfs]);
+        let log = lookup(&db, "/mutable/logs/radian_core.log").unwrap();
+        let user = policy::Manager::new_user(&mut db, "logger");
+        policy::Manager::add_rule(&mut db, policy::PolicyRule {
+            subject: user,
+            capabilities: policy::Capability::default().with(policy::Capability::WRITE_LOG),
+            ..Default::default()
+        });
+        Manager::write_node(&mut db, user, log, 0, b"first line\r\nsecond line\r\n").unwrap();
+        let mut data = alloc::vec![0u8; 64 * 1024];
+        let len = Manager::read_node(&mut db, user, log, 0, &mut data).unwrap();
+        let text = str::from_utf8(&data[..len]).unwrap();
+        assert!(text.contains(" user: first line\n"));
+        assert!(text.contains(" user: second line\n"));
+    }
+
+    /// Serves its node's name, so offsets and which node was asked both show
+    fn name_provider() -> Provider {
+        Provider {
+            read: |db, _, node, offset, data| {
+                let name = Manager::get_node(db, node).get_name().as_bytes();
+                let name = name.get(offset as usize..).unwrap_or_default();
+                let len = name.len().min(data.len());
+                data[..len].copy_from_slice(&name[..len]);
+                Ok(len)
+            },
+            stat: |db, _, node| Ok(Stat { size: Manager::get_node(db, node).get_name().len() as u64, children: 0 }),
+            ..Provider::DEFAULT
+        }
+    }
+
+    #[test]
+    fn files_keep_offsets_per_worker() {
+        let mut db = db::Database::new_with(&[Vfs]);
+        let provider = Manager::new_provider(&mut db, name_provider());
+        let node = Manager::new_node_with_provider(&mut db, "abcdef", NodeHandle::default(), provider);
+        let worker = crate::task::Manager::new_worker(&mut db, Default::default());
+        let other = crate::task::Manager::new_worker(&mut db, Default::default());
+        let file = Manager::open(&mut db, worker, node).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(Manager::read(&mut db, worker, file, &mut buf), Ok(4));
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(Manager::read(&mut db, worker, file, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"ef");
+        assert_eq!(Manager::read(&mut db, worker, file, &mut buf), Ok(0));
+        assert_eq!(Manager::seek(&mut db, worker, file, Seek::End(-3)), Ok(3));
+        assert_eq!(Manager::read(&mut db, worker, file, &mut buf), Ok(3));
+        assert_eq!(Manager::seek(&mut db, worker, file, Seek::Current(-10)), Err(Error::Invalid));
+        // Not the other worker's, and not writable
+        assert_eq!(Manager::read(&mut db, other, file, &mut buf), Err(Error::BadHandle));
+        assert_eq!(Manager::write(&mut db, worker, file, b"x"), Err(Error::Unsupported));
+        assert_eq!(Manager::close(&mut db, worker, file), Ok(()));
+        assert_eq!(Manager::close(&mut db, worker, file), Err(Error::BadHandle));
+    }
+
+    #[test]
+    fn open_files_are_limited() {
+        let mut db = db::Database::new_with(&[Vfs]);
+        let worker = crate::task::Manager::new_worker(&mut db, Default::default());
+        for _ in 0..MAX_OPEN_FILES {
+            Manager::open(&mut db, worker, NodeHandle::default()).unwrap();
+        }
+        assert_eq!(Manager::open(&mut db, worker, NodeHandle::default()), Err(Error::TooMany));
+        Manager::close_all(&mut db, worker);
+        assert!(Manager::open(&mut db, worker, NodeHandle::default()).is_ok());
+        // Users are no workers, they have no file table
+        let user = policy::Manager::new_user(&mut db, "guest");
+        assert_eq!(Manager::open(&mut db, user, NodeHandle::default()), Err(Error::BadHandle));
+    }
+
+    #[test]
+    fn readdir_and_stat_default_to_the_tree() {
+        let db = db::Database::new_with(&[Vfs]);
+        let logs = lookup(&db, "/mutable/logs").unwrap();
+        let log = lookup(&db, "/mutable/logs/radian_core.log").unwrap();
+        let actor = db::ObjectHandle::default();
+        assert_eq!(Manager::readdir(&db, actor, logs, 0), Some(log));
+        assert_eq!(Manager::readdir(&db, actor, logs, 1), None);
+        assert_eq!(Manager::stat(&db, actor, logs).unwrap().children, 1);
+    }
+
+    #[test]
+    fn resolve_handles_dots_and_slashes() {
+        let db = db::Database::new_with(&[Vfs]);
+        let actor = db::ObjectHandle::default();
+        let logs = lookup(&db, "/mutable/logs").unwrap();
+        let resolve = |from, path| Manager::resolve_path(&db, actor, from, path);
+        assert_eq!(resolve(NodeHandle::default(), "//mutable/./logs/"), Ok(logs));
+        assert_eq!(resolve(logs, "../../mutable//logs"), Ok(logs));
+        assert_eq!(resolve(logs, "/../.."), Ok(NodeHandle::default()));
+        assert_eq!(resolve(logs, ""), Ok(logs));
+        assert_eq!(resolve(logs, "nothing/here"), Err(Error::NotFound));
+        assert_eq!(Manager::resolve_parent(&db, actor, logs, "../new.txt"), Ok((lookup(&db, "/mutable").unwrap(), "new.txt")));
+        let long = "a/".repeat(MAX_PATH);
+        assert_eq!(resolve(logs, &long), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn resolve_follows_symlinks() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs]);
+        let actor = db::ObjectHandle::default();
+        let temp = lookup(&db, "/temp").unwrap();
+        let logs = lookup(&db, "/mutable/logs").unwrap();
+        let abs = Manager::symlink(&mut db, actor, temp, "logs", "/mutable/logs").unwrap();
+        let rel = Manager::symlink(&mut db, actor, temp, "log", "logs/radian_core.log").unwrap();
+        assert_eq!(Manager::resolve_path(&db, actor, temp, "logs/.."), Ok(lookup(&db, "/mutable").unwrap()));
+        assert_eq!(Manager::resolve_path(&db, actor, temp, "log"), lookup(&db, "/mutable/logs/radian_core.log").ok_or(Error::NotFound));
+        assert_eq!(Manager::resolve_path(&db, actor, temp, "logs"), Ok(logs));
+        assert_eq!(Manager::resolve_link(&db, actor, temp, "logs"), Ok(abs));
+        assert_eq!(Manager::resolve_link(&db, actor, temp, "/temp/log"), Ok(rel));
+        let mut target = [0u8; MAX_PATH];
+        let len = Manager::readlink(&db, actor, rel, &mut target).unwrap();
+        assert_eq!(&target[..len], b"logs/radian_core.log");
+        assert_eq!(Manager::readlink(&db, actor, temp, &mut target), Err(Error::Invalid));
+        // A dangling link is fine until followed, a loop is not
+        Manager::symlink(&mut db, actor, temp, "a", "b").unwrap();
+        Manager::symlink(&mut db, actor, temp, "b", "a").unwrap();
+        Manager::symlink(&mut db, actor, temp, "gone", "/nowhere").unwrap();
+        assert_eq!(Manager::resolve_path(&db, actor, temp, "a"), Err(Error::Loop));
+        assert_eq!(Manager::resolve_path(&db, actor, temp, "gone"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn path_of_node() {
+        let mut db = db::Database::new_with(&[Vfs]);
+        let log = lookup(&db, "/mutable/logs/radian_core.log").unwrap();
+        assert_eq!(Manager::get_path(&db, log).to_string(), "/mutable/logs/radian_core.log");
+        assert_eq!(Manager::get_path(&db, NodeHandle::default()).to_string(), "/");
+        let system = lookup(&db, "/system").unwrap();
+        let node = Manager::new_node(&mut db, "x.tar.gz", system);
+        assert_eq!(Manager::get_path(&db, node).to_string(), "/system/x.tar.gz");
+    }
+
+    struct Counter(u32);
+    const COUNTER_FS: FsType = FsType {
+        name: "counter",
+        provider: Provider {
+            read: |db, _, node, _, data| {
+                data[0] = Manager::get_superblock::<Counter>(db, node).ok_or(Error::Unknown)?.0 as u8;
+                Ok(1)
+            },
+            write: |db, _, node, _, data| {
+                Manager::get_superblock_mut::<Counter>(db, node).ok_or(Error::Unknown)?.0 += data.len() as u32;
+                Ok(data.len())
+            },
+            ..Provider::DEFAULT
+        },
+        mount: |_, _, _, source| match source {
+            Some(_) => Ok(Some(Box::new(Counter(0)))),
+            None => Err(Error::Invalid),
+        },
+        unmount: |_, _, _| {},
+    };
+
+    #[test]
+    fn mount_and_unmount() {
+        let mut db = db::Database::new_with(&[Vfs]);
+        let worker = crate::task::Manager::new_worker(&mut db, Default::default());
+        let fs = Manager::register_fs(&mut db, COUNTER_FS);
+        assert_eq!(Manager::find_fs(&db, "counter"), Some(fs));
+        let temp = lookup(&db, "/temp").unwrap();
+        let source = lookup(&db, "/devices").unwrap();
+        let previous = *Manager::get_node(&db, temp).get_provider();
+        assert_eq!(Manager::mount(&mut db, worker, fs, temp, None), Err(Error::Invalid));
+        assert_eq!(*Manager::get_node(&db, temp).get_provider(), previous);
+        let user = lookup(&db, "/user").unwrap();
+        assert_eq!(Manager::mount(&mut db, worker, fs, user, Some(source)), Err(Error::NotEmpty));
+        assert_eq!(Manager::mount(&mut db, worker, fs, temp, Some(source)), Ok(()));
+        assert_eq!(Manager::mount(&mut db, worker, fs, temp, Some(source)), Err(Error::Busy));
+        assert_eq!(Manager::get_mount(&db, temp).unwrap().get_source(), Some(source));
+        assert!(Manager::get_mount(&db, source).is_none());
+        // Each instance keeps its own state
+        let file = Manager::open(&mut db, worker, temp).unwrap();
+        Manager::write(&mut db, worker, file, b"abc").unwrap();
+        let mut count = [0u8];
+        assert_eq!(Manager::read_node(&mut db, worker, temp, 0, &mut count), Ok(1));
+        assert_eq!(count[0], 3);
+        assert_eq!(Manager::remove(&mut db, worker, temp), Err(Error::Busy));
+        assert_eq!(Manager::unmount(&mut db, worker, temp), Err(Error::Busy));
+        Manager::close(&mut db, worker, file).unwrap();
+        assert_eq!(Manager::unmount(&mut db, worker, temp), Ok(()));
+        assert_eq!(Manager::unmount(&mut db, worker, temp), Err(Error::Invalid));
+        assert_eq!(*Manager::get_node(&db, temp).get_provider(), previous);
+    }
+
+    #[test]
+    fn unmount_drops_the_subtree() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs]);
+        let actor = db::ObjectHandle::default();
+        let tmpfs = Manager::find_fs(&db, "tmpfs").unwrap();
+        let mount = lookup(&db, "/mount").unwrap();
+        let usb = Manager::create(&mut db, actor, mount, "usb", NodeKind::Directory).unwrap();
+        assert_eq!(Manager::mount(&mut db, actor, tmpfs, usb, None), Ok(()));
+        let dir = Manager::create(&mut db, actor, usb, "dir", NodeKind::Directory).unwrap();
+        Manager::create(&mut db, actor, dir, "file", NodeKind::File).unwrap();
+        // Nested below /mount, so that one has to wait
+        assert_eq!(Manager::unmount(&mut db, actor, mount), Err(Error::Busy));
+        assert_eq!(Manager::unmount(&mut db, actor, usb), Ok(()));
+        assert_eq!(Manager::find_children(&db, usb, "dir"), None);
+        // Back to the directory of the outer tmpfs it was before
+        let file = Manager::create(&mut db, actor, usb, "after", NodeKind::File).unwrap();
+        assert_eq!(Manager::write_node(&mut db, actor, file, 0, b"ok"), Ok(2));
+        assert_eq!(Manager::remove(&mut db, actor, file), Ok(()));
+        assert_eq!(Manager::remove(&mut db, actor, usb), Ok(()));
+        assert_eq!(Manager::unmount(&mut db, actor, mount), Ok(()));
+    }
+
+    #[test]
+    fn permissions_are_enforced() {
+        let mut db = db::Database::new_with(&[Vfs, Tmpfs]);
+        let admin = crate::task::Manager::new_worker(&mut db, Default::default());
+        let guest = crate::task::Manager::new_worker(&mut db, Default::default());
+        let guest_user = policy::Manager::new_user(&mut db, "guest");
+        db.workers[guest.get_id() as usize].set_user(guest_user);
+        let home = lookup(&db, "/user/admin/home").unwrap();
+        let temp = lookup(&db, "/temp").unwrap();
+        let private = Manager::create(&mut db, admin, home, "notes", NodeKind::File).unwrap();
+        Manager::write_node(&mut db, admin, private, 0, b"hi").unwrap();
+        // Readable by all, writable by the owner only
+        let mut buf = [0u8; 2];
+        assert_eq!(Manager::read_node(&mut db, guest, private, 0, &mut buf), Ok(2));
+        assert_eq!(Manager::write_node(&mut db, guest, private, 0, b"no"), Err(Error::Policy));
+        assert_eq!(Manager::create(&mut db, guest, home, "x", NodeKind::File), Err(Error::Policy));
+        assert_eq!(Manager::remove(&mut db, guest, private), Err(Error::Policy));
+        assert_eq!(Manager::chmod(&mut db, guest, private, policy::Permissions::shared()), Err(Error::Policy));
+        Manager::chmod(&mut db, admin, private, policy::Permissions::default()).unwrap();
+        assert_eq!(Manager::open(&mut db, guest, private), Err(Error::Policy));
+        // What the guest makes in /temp is the guest's
+        let mine = Manager::create(&mut db, guest, temp, "mine", NodeKind::File).unwrap();
+        assert_eq!(Manager::get_node(&db, mine).get_owner(), guest_user);
+        let file = Manager::open(&mut db, guest, mine).unwrap();
+        assert_eq!(Manager::write(&mut db, guest, file, b"ok"), Ok(2));
+        assert_eq!(Manager::write_node(&mut db, admin, mine, 0, b"no"), Err(Error::Policy));
+        // /temp is sticky, everyone creates there but only removes their own
+        let theirs = Manager::create(&mut db, admin, temp, "theirs", NodeKind::File).unwrap();
+        assert_eq!(Manager::remove(&mut db, guest, theirs), Err(Error::Policy));
+        assert_eq!(Manager::rename(&mut db, guest, theirs, temp, "stolen"), Err(Error::Policy));
+        let gone = Manager::create(&mut db, guest, temp, "gone", NodeKind::File).unwrap();
+        assert_eq!(Manager::rename(&mut db, guest, gone, temp, "going"), Ok(()));
+        assert_eq!(Manager::remove(&mut db, guest, gone), Ok(()));
+        // Nothing below a directory the guest may not read, not even its stat
+        assert_eq!(Manager::stat(&db, guest, private), Err(Error::Policy));
+        Manager::chmod(&mut db, admin, home, policy::Permissions::parse("rw----").unwrap()).unwrap();
+        assert_eq!(Manager::resolve_path(&db, guest, NodeHandle::default(), "/user/admin/home/notes"), Err(Error::Policy));
+        assert!(Manager::resolve_path(&db, admin, NodeHandle::default(), "/user/admin/home/notes").is_ok());
+        // Only the admin gives nodes away, even the owner may not
+        let group = Manager::get_node(&db, mine).get_group();
+        assert_eq!(Manager::chown(&mut db, guest, mine, policy::Manager::get_admin(), group), Err(Error::Policy));
+        assert_eq!(Manager::chown(&mut db, admin, theirs, guest_user, group), Ok(()));
+        assert_eq!(Manager::remove(&mut db, guest, theirs), Ok(()));
+        // Unless the policy says otherwise
+        policy::Manager::add_rule(&mut db, policy::PolicyRule {
+            subject: admin,
+            allowed: policy::Action::default().with(policy::Action::WRITE_TO),
+            ..Default::default()
+        });
+        assert_eq!(Manager::write_node(&mut db, admin, mine, 0, b"ok"), Ok(2));
     }
 }
@@ -1,4 +1,6 @@
-use crate::{db, kprint, pmm};
+use crate::{db, klog, kprint, pmm};
+use crate::console::{ArgKind, ArgSpec, Command};
+use crate::{console_commands, console_error};
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page(u64);
@@ -9,6 +11,7 @@ impl Page {
     pub const READ_WRITE: u64 = 0x02;
     pub const USER_SUPERVISOR: u64 = 0x04; //shared
     pub const WRITE_THROUGH: u64 = 0x08;
+    pub const CACHE_DISABLE: u64 = 0x10; //for MMIO
 
     pub fn is_present(self) -> bool {
         self.0 & Page::PRESENT != 0
@@ -31,7 +34,7 @@ const NUM_ENTRIES: usize = 512;
 pub struct AddressSpaceHandle(u16);
 impl AddressSpaceHandle {
     /// Kernel address space is always #1
-    pub fn get_kernel() -> Self {
+    pub const fn get_kernel() -> Self {
         Self(1)
     }
 }
@@ -110,7 +113,7 @@ impl Manager {
     }
 
     /// Maps a single page, if the page already exists it will simply go into the next level
-    /// if the flags of the page differ, the flags will be updated but the TLB will not be flushed
+    /// if the flags of the page differ, the flags will be added but the TLB will not be flushed
     /// so the changes wont be reflected globally
     pub fn map_single(
         db: &mut db::Database,
@@ -135,9 +138,11 @@ impl Manager {
                     *entry = Page((paddr & !Page::FLAG_MASK) | flags);
                 } else {
                     table = if (*entry).is_present() {
-                        // Always override flags
-                        if !(*entry).contains_flags(flags) {
-                            *entry = (*entry).override_flags(flags);
+                        // Upper levels keep the union of everything below them, otherwise a
+                        // supervisor mapping takes USER away from its user neighbours
+                        let merged = ((*entry).0 & Page::FLAG_MASK) | flags;
+                        if !(*entry).contains_flags(merged) {
+                            *entry = (*entry).override_flags(merged);
                         }
                         ((*entry).get_physaddr()) as *mut Page
                     } else {
@@ -193,6 +198,20 @@ impl Manager {
         }
     }
 
+    /// Physical address behind `vaddr`, only if every level is present and has `flags`
+    /// (e.g `Page::USER_SUPERVISOR` to check what ring 3 can touch)
+    pub fn translate(db: &db::Database, aspace: AddressSpaceHandle, vaddr: u64, flags: u64) -> Option<u64> {
+        let mut levels = 0;
+        let mut leaf = Page::default();
+        Self::traverse_page_table(db, aspace, vaddr, |p| {
+            if p.is_present() && p.0 & flags == flags {
+                levels += 1;
+            }
+            leaf = *p;
+        });
+        (levels == 4).then(|| leaf.get_physaddr() | (vaddr & Page::FLAG_MASK))
+    }
+
     pub fn invalidate_single(addr: u64) {
         unsafe {
             core::arch::asm!(
@@ -206,7 +225,13 @@ impl Manager {
     /// Reloads entire TLB because fuck you
     pub fn reload_cr3(db: &db::Database, aspace: AddressSpaceHandle) {
         let table = db.aspaces[aspace.0 as usize].get_mut() as u64;
-        kprint!("[vmm] loading table at {table:016x}\r\n");
+        klog!(Info, "vmm", "loading table at {table:016x}");
+        Self::switch_to(db, aspace);
+    }
+
+    /// Same as `reload_cr3` without the noise, for the syscall path
+    pub fn switch_to(db: &db::Database, aspace: AddressSpaceHandle) {
+        let table = db.aspaces[aspace.0 as usize].get_mut() as u64;
         unsafe {
             core::arch::asm!(
                 "mov cr3, {}",
@@ -216,3 +241,126 @@ impl Manager {
         }
     }
 }
+
+console_commands! {
+    Command {
+        name: "mapl",
+        category: "memory",
+        desc: "identity map",
+        help: "Same as map with vaddr = paddr.",
+        args: &[
+            ArgSpec::required("vaddr/paddr", ArgKind::Literal),
+            ArgSpec::required("count", ArgKind::Literal),
+            ArgSpec::required("flags", ArgKind::Literal),
+        ],
+        handler: |state, args| {
+            let addr = args.get_literal(1).unwrap();
+            Manager::map(
+                state.db,
+                state.current_aspace,
+                addr as u64,
+                addr as u64,
+                args.get_literal(2).unwrap(),
+                args.get_literal(3).unwrap() as u64,
+            );
+            Manager::reload_cr3(state.db, state.current_aspace);
+        },
+    },
+    Command {
+        name: "map",
+        category: "memory",
+        desc: "map physical pages",
+        help: "Maps <count> pages in the current address space, <flags> are the raw\n\
+               page table bits: 0x1 present, 0x2 writable, 0x4 user.",
+        args: &[
+            ArgSpec::required("vaddr", ArgKind::Literal),
+            ArgSpec::required("paddr", ArgKind::Literal),
+            ArgSpec::required("count", ArgKind::Literal),
+            ArgSpec::required("flags", ArgKind::Literal),
+        ],
+        handler: |state, args| {
+            Manager::map(
+                state.db,
+                state.current_aspace,
+                args.get_literal(2).unwrap() as u64,
+                args.get_literal(1).unwrap() as u64,
+                args.get_literal(3).unwrap(),
+                args.get_literal(4).unwrap() as u64,
+            );
+            Manager::reload_cr3(state.db, state.current_aspace);
+        },
+    },
+    Command {
+        name: "tlb_reload",
+        category: "memory",
+        desc: "reload tlb",
+        help: "",
+        args: &[],
+        handler: |state, _args| {
+            Manager::reload_cr3(state.db, state.current_aspace);
+        },
+    },
+    Command {
+        name: "aspace",
+        category: "memory",
+        desc: "make new address space",
+        help: "The new space becomes the current one for map, peek, poke...",
+        args: &[],
+        handler: |state, _args| {
+            state.current_aspace = Manager::new_address_space(state.db, pmm::Manager::alloc_page_zeroed());
+            kprint!("new aspace {:?}\r\n", state.current_aspace);
+        },
+    },
+    Command {
+        name: "poke",
+        category: "memory",
+        desc: "poke address (byte)",
+        help: "Writes <value> to <count> bytes (1 by default), only the first page is checked.",
+        args: &[
+            ArgSpec::required("addr", ArgKind::Literal),
+            ArgSpec::required("value", ArgKind::Literal),
+            ArgSpec::optional("count", ArgKind::Literal),
+        ],
+        handler: |state, args| {
+            let addr = args.get_literal(1).unwrap();
+            let value = args.get_literal(2).unwrap();
+            let len = args.get_literal(3).unwrap_or(1);
+            if Manager::has_mapping_present(state.db, state.current_aspace, addr as u64) {
+                let ptr = addr as *mut u8;
+                unsafe {
+                    ptr.write_bytes(value as u8, len);
+                }
+            } else {
+                console_error!(state, "area not mapped\r\n");
+            }
+        },
+    },
+    Command {
+        name: "peek",
+        category: "memory",
+        desc: "peek address",
+        help: "Hex dump of <count> bytes (1 by default), only the first page is checked.",
+        args: &[ArgSpec::required("addr", ArgKind::Literal), ArgSpec::optional("count", ArgKind::Literal)],
+        handler: |state, args| {
+            let addr = args.get_literal(1).unwrap();
+            let len = args.get_literal(2).unwrap_or(1);
+            if Manager::has_mapping_present(state.db, state.current_aspace, addr as u64) {
+                let ptr = addr as *const u8;
+                unsafe {
+                    for i in 0..len {
+                        if i == 0 || i % 8 == 0 {
+                            if i != 0 {
+                                kprint!("\r\n");
+                            }
+                            kprint!("{:016x} ", ptr as usize + i);
+                        }
+                        kprint!("{:02x} ", ptr.add(i).read());
+                    }
+                    kprint!("\r\n");
+                }
+            } else {
+                console_error!(state, "area not mapped\r\n");
+            }
+        },
+    },
+}
@@ -1,3 +1,7 @@
+//! The ATA driver as a loadable binary, linked against `driver.ld` by `make
+//! build-drivers` as DRIVER_COMPILANCE.md lays out. Only a stub that halts:
+//! until drivers can be loaded the real one is built into the kernel as
+//! `radian_core::ata`, next to the block layer it registers with.
 #![no_std]
 #![no_main]
 #![feature(lang_items)]
@@ -0,0 +1,324 @@
+//! The debug shell as a ring 3 program, started by the kernel as the first worker
+//!
+//! Everything goes through `radian_abi::sys`. The plain commands only need what
+//! any worker gets (console, VFS, worker and policy queries), any other name
+//! the kernel console has registered is forwarded as-is and needs the `debug`
+//! capability, the kernel refuses them otherwise. Lines are edited and split
+//! by `radian_abi::line` like on the kernel console, without completion.
+#![no_std]
+#![no_main]
+
+use core::fmt::Write;
+use radian_abi::line::{Args, Event, History, LineEditor, MAX_LINE};
+use radian_abi::{Error, capability, sys};
+
+const CAPABILITIES: &[(&str, u16)] = &[
+    ("read_filesystem", capability::READ_FILESYSTEM),
+    ("write_log", capability::WRITE_LOG),
+    ("spawn_task", capability::SPAWN_TASK),
+    ("network_access", capability::NETWORK_ACCESS),
+    ("debug", capability::DEBUG),
+];
+
+struct Console;
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        sys::console_write(s.as_bytes()).map(|_| ()).map_err(|_| core::fmt::Error)
+    }
+}
+
+macro_rules! print {
+    ($($args:tt)*) => ({
+        let _ = write!(Console, $($args)*);
+    });
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    print!("shell: {}\r\n", info.message());
+    sys::exit(101)
+}
+
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+unsafe extern "C" fn _start() -> ! {
+    core::arch::naked_asm!("and rsp, -16", "call {}", "ud2", sym main);
+}
+
+struct Builtin {
+    name: &'static str,
+    usage: &'static str,
+    desc: &'static str,
+    run: fn(&mut Shell, &Args) -> Result<(), Error>,
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin { name: "help", usage: "", desc: "list commands", run: |_, _| {
+        for b in BUILTINS {
+            print!("{:<8} {:<16} {}\r\n", b.name, b.usage, b.desc);
+        }
+        print!("kernel (needs debug):");
+        for_each_kernel_command(|name| {
+            if !BUILTINS.iter().any(|b| b.name == name) {
+                print!(" {name}");
+            }
+        });
+        print!("\r\nk <line> runs any kernel console line, `k help` explains them\r\n");
+        Ok(())
+    }},
+    Builtin { name: "echo", usage: "[text...]", desc: "print arguments", run: |_, args| {
+        for i in 1..args.len() {
+            print!("{}{}", if i > 1 { " " } else { "" }, args.get(i).unwrap_or(""));
+        }
+        print!("\r\n");
+        Ok(())
+    }},
+    Builtin { name: "clear", usage: "", desc: "clear the screen", run: |_, _| {
+        print!("\x1b[2J\x1b[H");
+        Ok(())
+    }},
+    Builtin { name: "exit", usage: "[code]", desc: "leave the shell", run: |_, args| {
+        let code = args.get(1).and_then(|s| s.parse().ok()).unwrap_or(0);
+        sys::exit(code)
+    }},
+    Builtin { name: "pwd", usage: "", desc: "print current node", run: |shell, _| {
+        print_path(shell.cwd)?;
+        print!("\r\n");
+        Ok(())
+    }},
+    Builtin { name: "cd", usage: "[path]", desc: "change current node", run: |shell, args| {
+        shell.cwd = sys::vfs_lookup(shell.cwd, args.get(1).unwrap_or("/"))?;
+        Ok(())
+    }},
+    Builtin { name: "ls", usage: "[path]", desc: "list children", run: |shell, args| {
+        let node = shell.lookup(args.get(1).unwrap_or("."))?;
+        let mut buf = [0u8; 32];
+        for_each_child(node, |child| {
+            print!("{}\r\n", sys::vfs_name(child, &mut buf)?);
+            Ok(())
+        })
+    }},
+    Builtin { name: "tree", usage: "[path]", desc: "print nodes recursively", run: |shell, args| {
+        let node = shell.lookup(args.get(1).unwrap_or("."))?;
+        print_tree(node, 0)
+    }},
+    Builtin { name: "cat", usage: "<path>", desc: "read a node", run: |shell, args| {
+        let node = shell.lookup(args.get(1).ok_or(Error::Invalid)?)?;
+        with_file(node, |file| {
+            let mut buf = [0u8; 512];
+            loop {
+                let len = sys::vfs_read(file, &mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                sys::console_write(&buf[..len])?;
+            }
+            print!("\r\n");
+            Ok(())
+        })
+    }},
+    Builtin { name: "write", usage: "<path> <text...>", desc: "write to a node", run: |shell, args| {
+        let node = shell.lookup(args.get(1).ok_or(Error::Invalid)?)?;
+        // One write, nodes like the kernel log take each as a record
+        let mut buf = [0u8; MAX_LINE + 2];
+        let mut len = 0;
+        for i in 2..args.len() {
+            let sep: &[u8] = if i > 2 { b" " } else { b"" };
+            for &b in sep.iter().chain(args.get(i).unwrap_or("").as_bytes()) {
+                buf[len] = b;
+                len += 1;
+            }
+        }
+        buf[len..len + 2].copy_from_slice(b"\r\n");
+        with_file(node, |file| sys::vfs_write(file, &buf[..len + 2]).map(|_| ()))
+    }},
+    Builtin { name: "stat", usage: "<path>", desc: "size of a node", run: |shell, args| {
+        let node = shell.lookup(args.get(1).ok_or(Error::Invalid)?)?;
+        print!("{} bytes\r\n", sys::vfs_stat(node)?);
+        Ok(())
+    }},
+    Builtin { name: "pid", usage: "", desc: "this worker", run: |_, _| {
+        print!("worker_{}\r\n", sys::getpid());
+        Ok(())
+    }},
+    Builtin { name: "workers", usage: "", desc: "count workers", run: |_, _| {
+        print!("{}\r\n", sys::worker_count());
+        Ok(())
+    }},
+    Builtin { name: "worker", usage: "", desc: "new worker (spawn_task)", run: |_, _| {
+        print!("worker_{}\r\n", sys::new_worker()?);
+        Ok(())
+    }},
+    Builtin { name: "task", usage: "<worker>", desc: "new task in worker (spawn_task)", run: |_, args| {
+        let worker = args.get(1).and_then(|s| s.parse().ok()).ok_or(Error::Invalid)?;
+        print!("task {}\r\n", sys::new_task(worker)?);
+        Ok(())
+    }},
+    Builtin { name: "users", usage: "", desc: "list users", run: |_, _| {
+        list_names(sys::user_name)
+    }},
+    Builtin { name: "groups", usage: "", desc: "list groups", run: |_, _| {
+        list_names(sys::group_name)
+    }},
+    Builtin { name: "can", usage: "[capability]", desc: "check capabilities", run: |_, args| {
+        for &(name, bits) in CAPABILITIES {
+            if args.get(1).is_none_or(|a| a == name) {
+                print!("{name:<16} {}\r\n", if sys::has_capability(bits) { "yes" } else { "no" });
+            }
+        }
+        Ok(())
+    }},
+    Builtin { name: "k", usage: "<line...>", desc: "run a kernel console line (debug)", run: |_, args| {
+        sys::debug_command(args.get_rest(1)).map(|_| ())
+    }},
+];
+
+/// Opens `node` for `f` and closes it again whatever `f` returns
+fn with_file<F: FnOnce(u64) -> Result<(), Error>>(node: u64, f: F) -> Result<(), Error> {
+    let file = sys::vfs_open(node)?;
+    let result = f(file);
+    sys::vfs_close(file)?;
+    result
+}
+
+fn for_each_child<F: FnMut(u64) -> Result<(), Error>>(node: u64, mut f: F) -> Result<(), Error> {
+    for index in 0.. {
+        match sys::vfs_child(node, index) {
+            Ok(child) => f(child)?,
+            Err(Error::NotFound) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn print_tree(node: u64, level: usize) -> Result<(), Error> {
+    let mut buf = [0u8; 32];
+    for_each_child(node, |child| {
+        for _ in 0..level {
+            print!("    ");
+        }
+        print!("└── {}\r\n", sys::vfs_name(child, &mut buf)?);
+        print_tree(child, level + 1)
+    })
+}
+
+fn print_path(node: u64) -> Result<(), Error> {
+    let mut buf = [0u8; 256];
+    print!("{}", sys::vfs_path(node, &mut buf)?);
+    Ok(())
+}
+
+/// The kernel console's commands, as its registry has them
+fn for_each_kernel_command<F: FnMut(&str)>(mut f: F) {
+    let mut buf = [0u8; 32];
+    for index in 0.. {
+        match sys::debug_command_name(index, &mut buf) {
+            Ok(name) => f(name),
+            Err(_) => break,
+        }
+    }
+}
+
+fn list_names(f: fn(u64, &mut [u8]) -> Result<&str, Error>) -> Result<(), Error> {
+    let mut buf = [0u8; 32];
+    for index in 0.. {
+        match f(index, &mut buf) {
+            Ok(name) => print!("{name}\r\n"),
+            Err(Error::NotFound) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+struct Shell {
+    cwd: u64,
+}
+impl Shell {
+    fn lookup(&self, path: &str) -> Result<u64, Error> {
+        sys::vfs_lookup(self.cwd, path)
+    }
+
+    fn print_prompt(&self) {
+        let mut name = [0u8; 16];
+        let user = sys::whoami(&mut name).unwrap_or("?");
+        print!("{user}@radiant-pc:");
+        let _ = print_path(self.cwd);
+        print!("$ ");
+    }
+
+    fn get_byte() -> u8 {
+        loop {
+            match sys::console_read() {
+                Ok(b) => return b,
+                Err(_) => sys::yield_now(),
+            }
+        }
+    }
+
+    /// Edits a line until it is submitted, `None` for ^C
+    fn read_line<'a>(editor: &'a mut LineEditor, history: &History) -> Option<&'a str> {
+        *editor = LineEditor::new();
+        loop {
+            match editor.feed(Self::get_byte(), history, &mut Console) {
+                Event::Submit => {
+                    print!("\r\n");
+                    return Some(editor.as_str());
+                }
+                Event::Cancel => {
+                    print!("^C\r\n");
+                    return None;
+                }
+                Event::Complete | Event::None => {}
+            }
+        }
+    }
+
+    fn execute(&mut self, line: &str) {
+        let args = match Args::parse(line) {
+            Ok(args) => args,
+            Err(e) => {
+                print!("shell: {e}\r\n");
+                return;
+            }
+        };
+        let Some(name) = args.get(0) else {
+            return;
+        };
+        let result = if let Some(b) = BUILTINS.iter().find(|b| b.name == name) {
+            (b.run)(self, &args)
+        } else if Self::is_kernel_command(name) {
+            sys::debug_command(line).map(|_| ())
+        } else {
+            print!("shell: unknown command <{name}>, try <help>\r\n");
+            return;
+        };
+        if let Err(e) = result {
+            print!("{name}: {e}\r\n");
+        }
+    }
+
+    fn is_kernel_command(name: &str) -> bool {
+        let mut found = false;
+        for_each_kernel_command(|command| found |= command.eq_ignore_ascii_case(name));
+        found
+    }
+}
+
+extern "C" fn main() -> ! {
+    print!("radian shell, worker_{}, type <help>?\r\n", sys::getpid());
+    if !sys::has_capability(capability::DEBUG) {
+        print!("(no debug capability, kernel commands are off)\r\n");
+    }
+    let mut shell = Shell { cwd: 0 };
+    let mut editor = LineEditor::new();
+    let mut history = History::new();
+    loop {
+        shell.print_prompt();
+        if let Some(line) = Shell::read_line(&mut editor, &history) {
+            history.push(line);
+            shell.execute(line);
+        }
+    }
+}